@@ -0,0 +1,17 @@
+//! Bakes the git commit of the build into the binary as `GIT_HASH` (shown by
+//! `/about`). Builds outside of a git checkout just don't have it.
+use std::process::Command;
+
+fn main() {
+    let hash = Command::new("git")
+        .args(&["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .and_then(|out| String::from_utf8(out.stdout).ok());
+    if let Some(hash) = hash {
+        println!("cargo:rustc-env=GIT_HASH={}", hash.trim());
+    }
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+}
@@ -3,13 +3,22 @@ create table if not exists crates
   id serial not null
     constraint crates_pk
       primary key,
-  name varchar(64) not null
+  name varchar(64) not null,
+  registry varchar(64) not null default 'crates-io'
 );
 
+-- for databases created before multi-registry support
+alter table crates
+  add column if not exists registry varchar(64) not null default 'crates-io';
+
 comment on column crates.name is 'crate names are limited to 64 characters, see https://github.com/rust-lang/crates.io/pull/718';
 
-create unique index if not exists crates_name_uindex
-  on crates (name);
+comment on column crates.registry is 'name of the registry from the config, ''crates-io'' is the default one';
+
+drop index if exists crates_name_uindex;
+
+create unique index if not exists crates_registry_name_uindex
+  on crates (registry, name);
 
 create table if not exists subscriptions
 (
@@ -21,6 +30,60 @@ create table if not exists subscriptions
 
 comment on column subscriptions.user_id is 'telegram user id (yeah, telegram is hardcoded)';
 
+-- added without default first, so old rows get null (unknown) instead of now()
+alter table subscriptions
+  add column if not exists created_at timestamptz;
+
+alter table subscriptions
+  alter column created_at set default now();
+
+-- yanks-only subscriptions (`/subscribe_yanks`) don't get new versions,
+-- replaced by `events`
+alter table subscriptions
+  add column if not exists yanks_only boolean not null default false;
+
+-- kinds of updates the subscription gets (`/subscribe <crate> --events ..`), a
+-- bit mask: 1 = new versions (& checksum changes), 2 = (un)yanks
+alter table subscriptions
+  add column if not exists events smallint not null default 3;
+
+-- `/subscribe <crate> --major-only` subscriptions only get major releases (and
+-- yanks), replaced by `min_level`
+alter table subscriptions
+  add column if not exists major_only boolean not null default false;
+
+-- `/subscribe <crate> --only minor|major` subscriptions only get releases of at
+-- least this level (and yanks): 0 = all, 1 = minor & major, 2 = major
+alter table subscriptions
+  add column if not exists min_level smallint not null default 0;
+
+-- `/subscribe <crate> --prereleases` (true) or `--no-prereleases` (false), null
+-- subscriptions follow the chat default (`/prereleases`)
+alter table subscriptions
+  add column if not exists prereleases boolean;
+
+-- `major_only` subscriptions become `--only major` ones (no-op when executed
+-- twice)
+update subscriptions set min_level = 2, major_only = false where major_only;
+
+-- same for `yanks_only` subscriptions, which become `--events yank` ones
+update subscriptions set events = 2, yanks_only = false where yanks_only;
+
+-- free-text label of the subscription (`/subscribe <crate> #label`), to group
+-- subscriptions in `/list`
+alter table subscriptions
+  add column if not exists label varchar(64);
+
+-- `/subscribe <crate> --changelog` subscriptions get release notes excerpts
+-- with new versions
+alter table subscriptions
+  add column if not exists changelog boolean not null default false;
+
+-- `/mute <crate> <duration>`: no notifications until this time (the
+-- subscription is kept)
+alter table subscriptions
+  add column if not exists muted_until timestamptz;
+
 create index if not exists subscriptions_user_id_index
   on subscriptions (user_id)
     include (crate_id);
@@ -31,51 +94,1220 @@ alter table subscriptions
     foreign key (crate_id) references crates
       on delete cascade;
 
-create or replace procedure subscribe(_user_id bigint, _crate varchar(64))
+-- old (single-registry) versions
+drop procedure if exists subscribe(bigint, varchar);
+drop procedure if exists unsubscribe(bigint, varchar);
+drop function if exists list_subscriptions(bigint);
+drop function if exists list_subscribers(varchar);
+drop function if exists subscriber_count(varchar);
+-- versions without `yanks_only`, `major_only` & `label`
+drop function if exists list_subscribers(varchar, varchar);
+drop function if exists list_subscribers_of(varchar, varchar[]);
+drop function if exists is_subscribed(bigint, varchar, varchar);
+drop function if exists is_subscribed(bigint, varchar, varchar, boolean);
+drop procedure if exists subscribe(bigint, varchar, varchar);
+drop procedure if exists subscribe_many(bigint, varchar, varchar[]);
+-- versions without `label`
+drop procedure if exists subscribe(bigint, varchar, varchar, boolean);
+drop procedure if exists subscribe_many(bigint, varchar, varchar[], boolean);
+-- versions without `changelog`
+drop procedure if exists subscribe(bigint, varchar, varchar, boolean, varchar);
+drop procedure if exists subscribe_many(bigint, varchar, varchar[], boolean, varchar);
+drop function if exists is_subscribed(bigint, varchar, varchar, boolean, boolean);
+drop function if exists list_subscriptions(bigint, varchar);
+-- versions with `major_only` instead of `min_level`
+drop procedure if exists subscribe(bigint, varchar, varchar, boolean, boolean, varchar);
+drop procedure if exists subscribe_many(bigint, varchar, varchar[], boolean, boolean, varchar);
+drop function if exists is_subscribed(bigint, varchar, varchar, boolean, boolean, boolean);
+-- versions without `prereleases`
+drop procedure if exists subscribe(bigint, varchar, varchar, smallint, boolean, varchar);
+drop procedure if exists subscribe_many(bigint, varchar, varchar[], smallint, boolean, varchar);
+drop function if exists is_subscribed(bigint, varchar, varchar, boolean, smallint, boolean);
+-- versions with `yanks_only` instead of `events`
+drop procedure if exists subscribe(bigint, varchar, varchar, smallint, boolean, boolean, varchar);
+drop procedure if exists subscribe_many(bigint, varchar, varchar[], smallint, boolean, boolean, varchar);
+drop function if exists is_subscribed(bigint, varchar, varchar, boolean, smallint, boolean, boolean);
+
+-- `_label` replaces the label of an existing subscription, unless it's null
+create or replace procedure subscribe(_user_id bigint, _registry varchar(64), _crate varchar(64), _events smallint,
+                                      _min_level smallint, _prereleases boolean, _changelog boolean,
+                                      _label varchar(64))
     LANGUAGE plpgsql
 AS $$
 begin
-    if not exists (select * from crates where crates.name = _crate) then
-        insert into crates (name) values (_crate) on conflict do nothing;
+    if not exists (select * from crates where crates.registry = _registry and crates.name = _crate) then
+        insert into crates (registry, name) values (_registry, _crate) on conflict do nothing;
     end if;
 
-    insert into subscriptions (user_id, crate_id)
-        select _user_id, id from crates
-            where crates.name = _crate
+    insert into subscriptions (user_id, crate_id, events, min_level, prereleases, changelog, label)
+        select _user_id, id, _events, _min_level, _prereleases, _changelog, _label from crates
+            where crates.registry = _registry and crates.name = _crate
+        on conflict (crate_id, user_id) do update
+            set events = _events, min_level = _min_level, prereleases = _prereleases, changelog = _changelog,
+                label = coalesce(_label, subscriptions.label);
+end
+$$;
+
+-- like `subscribe`, but only yanks are delivered (switches an existing
+-- subscription too, its label is kept)
+create or replace procedure subscribe_yanks(_user_id bigint, _registry varchar(64), _crate varchar(64))
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into crates (registry, name) values (_registry, _crate) on conflict do nothing;
+
+    insert into subscriptions (user_id, crate_id, events)
+        select _user_id, id, 2 from crates
+            where crates.registry = _registry and crates.name = _crate
+        on conflict (crate_id, user_id) do update
+            set events = 2, min_level = 0, prereleases = null, changelog = false;
+end
+$$;
+
+create or replace procedure subscribe_many(_user_id bigint, _registry varchar(64), _crates varchar(64)[],
+                                           _events smallint, _min_level smallint, _prereleases boolean,
+                                           _changelog boolean, _label varchar(64))
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into crates (registry, name)
+        select _registry, unnest(_crates)
         on conflict do nothing;
+
+    insert into subscriptions (user_id, crate_id, events, min_level, prereleases, changelog, label)
+        select _user_id, id, _events, _min_level, _prereleases, _changelog, _label from crates
+            where crates.registry = _registry and crates.name = ANY(_crates)
+        on conflict (crate_id, user_id) do update
+            set events = _events, min_level = _min_level, prereleases = _prereleases, changelog = _changelog,
+                label = coalesce(_label, subscriptions.label);
 end
 $$;
 
-create or replace procedure unsubscribe(_user_id bigint, _crate varchar(64))
+-- old version without the number of removed subscriptions
+drop procedure if exists unsubscribe(bigint, varchar, varchar);
+
+-- `_removed` is set to the number of removed subscriptions (0 or 1)
+create or replace procedure unsubscribe(_user_id bigint, _registry varchar(64), _crate varchar(64), INOUT _removed bigint default 0)
     LANGUAGE plpgsql
 AS $$
 begin
     delete from subscriptions
-        where crate_id = (select id from crates where name = _crate)
+        where crate_id = (select id from crates where registry = _registry and name = _crate)
+            and user_id = _user_id;
+    GET DIAGNOSTICS _removed = ROW_COUNT;
+end
+$$;
+
+-- `null` `_until` unmutes, `_found` is set to the number of updated
+-- subscriptions (0 or 1)
+create or replace procedure mute(_user_id bigint, _registry varchar(64), _crate varchar(64), _until timestamptz,
+                                 INOUT _found bigint default 0)
+    LANGUAGE plpgsql
+AS $$
+begin
+    update subscriptions set muted_until = _until
+        where crate_id = (select id from crates where registry = _registry and name = _crate)
             and user_id = _user_id;
+    GET DIAGNOSTICS _found = ROW_COUNT;
+end
+$$;
+
+-- removes all subscriptions of any kind (crates, keywords, categories, owners,
+-- patterns, alerts, new crate watches)
+create or replace procedure unsubscribe_all(_user_id bigint)
+    LANGUAGE plpgsql
+AS $$
+begin
+    delete from subscriptions where user_id = _user_id;
+    delete from keyword_subscriptions where user_id = _user_id;
+    delete from category_subscriptions where user_id = _user_id;
+    delete from owner_subscriptions where user_id = _user_id;
+    delete from pattern_subscriptions where user_id = _user_id;
+    delete from alerts where user_id = _user_id;
+    delete from crate_watches where user_id = _user_id;
+
+    -- nobody needs the mappings anymore
+    delete from keyword_crates
+        where keyword not in (select keyword from keyword_subscriptions);
+    delete from category_crates
+        where category not in (select category from category_subscriptions);
+    delete from owner_crates
+        where owner not in (select owner from owner_subscriptions);
+end
+$$;
+
+-- subscriptions of the user, only ones with the `_label` (case-insensitive) if
+-- it's not null
+create or replace function list_subscriptions(_user_id bigint, _label varchar(64))
+RETURNS TABLE(registry_name varchar(64), crate_name varchar(64), created_at timestamptz, events smallint,
+              min_level smallint, prereleases boolean, changelog boolean, label varchar(64),
+              muted_until timestamptz)
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select c.registry as registry_name, c.name as crate_name, s.created_at as created_at,
+                        s.events as events, s.min_level as min_level,
+                        s.prereleases as prereleases, s.changelog as changelog,
+                        s.label as label, s.muted_until as muted_until
+        from subscriptions as s
+            inner join crates as c on c.id = s.crate_id
+        where s.user_id = _user_id and (_label is null or lower(s.label) = lower(_label));
+end
+$$;
+
+create or replace function list_subscribers(_registry varchar(64), _crate varchar(64))
+    RETURNS TABLE(user_id bigint, events smallint, min_level smallint, prereleases boolean, changelog boolean,
+                  label varchar(64), muted_until timestamptz)
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select s.user_id as user_id, s.events as events, s.min_level as min_level,
+                        s.prereleases as prereleases, s.changelog as changelog, s.label as label,
+                        s.muted_until as muted_until
+         from subscriptions as s
+              inner join crates as c on c.id = s.crate_id
+         where c.registry = _registry and c.name = _crate;
+end
+$$;
+
+create or replace function list_subscribers_of(_registry varchar(64), _crates varchar(64)[])
+    RETURNS TABLE(crate_name varchar(64), user_id bigint, events smallint, min_level smallint,
+                  prereleases boolean, changelog boolean, label varchar(64), muted_until timestamptz)
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select c.name as crate_name, s.user_id as user_id, s.events as events,
+                        s.min_level as min_level, s.prereleases as prereleases, s.changelog as changelog,
+                        s.label as label, s.muted_until as muted_until
+         from subscriptions as s
+              inner join crates as c on c.id = s.crate_id
+         where c.registry = _registry and c.name = ANY(_crates);
+end
+$$;
+
+-- whether the user has a subscription with the given options
+create or replace function is_subscribed(_user_id bigint, _registry varchar(64), _crate varchar(64), _events smallint,
+                                         _min_level smallint, _prereleases boolean, _changelog boolean)
+    RETURNS boolean
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN exists (select *
+        from subscriptions as s
+            inner join crates as c on c.id = s.crate_id
+        where s.user_id = _user_id and c.registry = _registry and c.name = _crate
+            and s.events = _events and s.min_level = _min_level
+            and s.prereleases is not distinct from _prereleases and s.changelog = _changelog);
+end
+$$;
+
+-- whether the user has a subscription to the crate, whatever its options
+create or replace function has_subscription(_user_id bigint, _registry varchar(64), _crate varchar(64))
+    RETURNS boolean
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN exists (select *
+        from subscriptions as s
+            inner join crates as c on c.id = s.crate_id
+        where s.user_id = _user_id and c.registry = _registry and c.name = _crate);
 end
 $$;
 
-create or replace function list_subscriptions(_user_id bigint)
-RETURNS TABLE(crate_name varchar(64))
+create or replace function subscriber_count(_registry varchar(64), _crate varchar(64))
+    RETURNS bigint
     LANGUAGE plpgsql
 AS $$
 begin
-    RETURN QUERY select c.name as crate_name
+    RETURN (select count(*)
         from subscriptions as s
             inner join crates as c on c.id = s.crate_id
-        where s.user_id = _user_id;
+        where c.registry = _registry and c.name = _crate);
+end
+$$;
+
+-- subscriber counts of all crates the user is subscribed to, for /list
+create or replace function subscriber_counts_of(_user_id bigint)
+    RETURNS TABLE(registry varchar(64), crate_name varchar(64), subscribers bigint)
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select c.registry, c.name as crate_name, count(*) as subscribers
+         from subscriptions as s
+              inner join crates as c on c.id = s.crate_id
+         where s.crate_id in (select crate_id from subscriptions where user_id = _user_id)
+         group by c.registry, c.name;
+end
+$$;
+
+create or replace function top_crates(_registry varchar(64), _limit int)
+    RETURNS TABLE(crate_name varchar(64), subscribers bigint)
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select c.name as crate_name, count(*) as subscribers
+         from subscriptions as s
+              inner join crates as c on c.id = s.crate_id
+         where c.registry = _registry
+         group by c.name
+         order by subscribers desc, c.name
+         limit _limit;
+end
+$$;
+
+create table if not exists events
+(
+  id bigserial not null
+    constraint events_pk
+      primary key,
+  commit_oid char(40) not null,
+  crate varchar(64) not null,
+  version text not null,
+  action varchar(32) not null,
+  created_at timestamptz not null default now()
+);
+
+comment on table events is 'history of processed index changes, for debugging';
+
+create index if not exists events_crate_index
+  on events (crate, created_at);
+
+create table if not exists deliveries
+(
+  event_id bigint not null
+    constraint deliveries_events_id_fk
+      references events
+        on delete cascade,
+  chat_id bigint not null,
+  status text not null,
+  created_at timestamptz not null default now()
+);
+
+comment on column deliveries.status is '''ok'' or error description';
+
+create index if not exists deliveries_event_id_index
+  on deliveries (event_id, chat_id);
+
+create or replace function record_event(_commit_oid char(40), _crate varchar(64), _version text, _action varchar(32))
+    RETURNS bigint
+    LANGUAGE plpgsql
+AS $$
+declare
+    _id bigint;
+begin
+    insert into events (commit_oid, crate, version, action)
+        values (_commit_oid, _crate, _version, _action)
+        returning id into _id;
+    RETURN _id;
 end
 $$;
 
-create or replace function list_subscribers(_crate varchar(64))
+create or replace procedure record_delivery(_event_id bigint, _chat_id bigint, _status text)
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into deliveries (event_id, chat_id, status)
+        values (_event_id, _chat_id, _status);
+end
+$$;
+
+create or replace function list_events(_crate varchar(64), _chat_id bigint, _limit int)
+    RETURNS TABLE(commit_oid char(40), vers text, action_kind varchar(32), happened_at timestamptz, delivery_status text)
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select e.commit_oid, e.version, e.action, e.created_at, d.status
+        from events as e
+            left join deliveries as d on d.event_id = e.id and d.chat_id = _chat_id
+        where e.crate = _crate
+        order by e.created_at desc
+        limit _limit;
+end
+$$;
+
+-- last index commit whose notifications were all sent, so restarts neither
+-- lose nor duplicate notifications
+create table if not exists index_positions
+(
+  registry varchar(64) not null
+    constraint index_positions_pk
+      primary key,
+  commit_oid char(40) not null,
+  updated_at timestamptz not null default now()
+);
+
+create or replace function last_commit(_registry varchar(64))
+    RETURNS char(40)
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN (select p.commit_oid from index_positions as p where p.registry = _registry);
+end
+$$;
+
+create or replace procedure set_last_commit(_registry varchar(64), _commit_oid char(40))
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into index_positions (registry, commit_oid)
+        values (_registry, _commit_oid)
+        on conflict (registry) do update set commit_oid = _commit_oid, updated_at = now();
+end
+$$;
+
+-- versions held back by `crate_cooldown` (& when the crate was last
+-- announced), their commits are already marked as processed
+create table if not exists cooldowns
+(
+  registry varchar(64) not null,
+  crate varchar(64) not null,
+  state text not null,
+  constraint cooldowns_pk
+    primary key (registry, crate)
+);
+
+comment on column cooldowns.state is 'json, see `Cooldowns::snapshot`';
+
+-- `null` `_state` deletes the cooldown
+create or replace procedure save_cooldown(_registry varchar(64), _crate varchar(64), _state text)
+    LANGUAGE plpgsql
+AS $$
+begin
+    if _state is null then
+        delete from cooldowns where registry = _registry and crate = _crate;
+    else
+        insert into cooldowns (registry, crate, state)
+            values (_registry, _crate, _state)
+            on conflict (registry, crate) do update set state = _state;
+    end if;
+end
+$$;
+
+create or replace function list_cooldowns()
+    RETURNS TABLE(registry_name varchar(64), crate_name varchar(64), state text)
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select c.registry as registry_name, c.crate as crate_name, c.state as state
+        from cooldowns as c;
+end
+$$;
+
+-- checksums of announced versions, to catch a version republished with another
+-- checksum even if the old line isn't removed in the same diff
+create table if not exists checksums
+(
+  registry varchar(64) not null,
+  crate varchar(64) not null,
+  version text not null,
+  cksum char(64) not null,
+  constraint checksums_pk
+    primary key (registry, crate, version)
+);
+
+-- save the checksum of the version, returns the previous one if it differs
+create or replace function save_cksum(_registry varchar(64), _crate varchar(64), _version text, _cksum char(64))
+    RETURNS char(64)
+    LANGUAGE plpgsql
+AS $$
+declare
+    _old char(64);
+begin
+    select c.cksum into _old from checksums as c
+        where c.registry = _registry and c.crate = _crate and c.version = _version;
+    insert into checksums (registry, crate, version, cksum)
+        values (_registry, _crate, _version, _cksum)
+        on conflict (registry, crate, version) do update set cksum = _cksum;
+    RETURN nullif(_old, _cksum);
+end
+$$;
+
+create table if not exists keyword_subscriptions
+(
+  user_id bigint not null,
+  keyword varchar(64) not null,
+  constraint keyword_subscriptions_pk
+    primary key (keyword, user_id)
+);
+
+create index if not exists keyword_subscriptions_user_id_index
+  on keyword_subscriptions (user_id);
+
+create table if not exists keyword_crates
+(
+  keyword varchar(64) not null,
+  crate varchar(64) not null,
+  constraint keyword_crates_pk
+    primary key (keyword, crate)
+);
+
+comment on table keyword_crates is 'keyword -> crates mapping from crates.io API, only for subscribed keywords';
+
+create index if not exists keyword_crates_crate_index
+  on keyword_crates (crate);
+
+create or replace procedure subscribe_keyword(_user_id bigint, _keyword varchar(64))
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into keyword_subscriptions (user_id, keyword)
+        values (_user_id, _keyword)
+        on conflict do nothing;
+end
+$$;
+
+create or replace procedure unsubscribe_keyword(_user_id bigint, _keyword varchar(64))
+    LANGUAGE plpgsql
+AS $$
+begin
+    delete from keyword_subscriptions
+        where keyword = _keyword
+            and user_id = _user_id;
+
+    -- nobody needs the mapping anymore
+    if not exists (select * from keyword_subscriptions where keyword = _keyword) then
+        delete from keyword_crates where keyword = _keyword;
+    end if;
+end
+$$;
+
+create or replace function list_keyword_subscriptions(_user_id bigint)
+    RETURNS TABLE(keyword_name varchar(64))
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select ks.keyword as keyword_name
+        from keyword_subscriptions as ks
+        where ks.user_id = _user_id;
+end
+$$;
+
+create or replace function list_subscribed_keywords()
+    RETURNS TABLE(keyword_name varchar(64))
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select distinct ks.keyword as keyword_name
+        from keyword_subscriptions as ks;
+end
+$$;
+
+create or replace procedure set_keyword_crates(_keyword varchar(64), _crates varchar(64)[])
+    LANGUAGE plpgsql
+AS $$
+begin
+    delete from keyword_crates where keyword = _keyword;
+    insert into keyword_crates (keyword, crate)
+        select _keyword, c from unnest(_crates) as c
+        on conflict do nothing;
+end
+$$;
+
+create or replace procedure add_keyword_crate(_keyword varchar(64), _crate varchar(64))
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into keyword_crates (keyword, crate)
+        values (_keyword, _crate)
+        on conflict do nothing;
+end
+$$;
+
+create or replace function list_keyword_subscribers(_crate varchar(64))
     RETURNS TABLE(user_id bigint)
     LANGUAGE plpgsql
 AS $$
 begin
-    RETURN QUERY select s.user_id as user_id
+    RETURN QUERY select distinct ks.user_id as user_id
+        from keyword_subscriptions as ks
+            inner join keyword_crates as kc on kc.keyword = ks.keyword
+        where kc.crate = _crate;
+end
+$$;
+
+create table if not exists category_subscriptions
+(
+  user_id bigint not null,
+  category varchar(64) not null,
+  constraint category_subscriptions_pk
+    primary key (category, user_id)
+);
+
+create index if not exists category_subscriptions_user_id_index
+  on category_subscriptions (user_id);
+
+create table if not exists category_crates
+(
+  category varchar(64) not null,
+  crate varchar(64) not null,
+  constraint category_crates_pk
+    primary key (category, crate)
+);
+
+comment on table category_crates is 'category (slug) -> crates mapping from crates.io API, only for subscribed categories';
+
+create index if not exists category_crates_crate_index
+  on category_crates (crate);
+
+create or replace procedure subscribe_category(_user_id bigint, _category varchar(64))
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into category_subscriptions (user_id, category)
+        values (_user_id, _category)
+        on conflict do nothing;
+end
+$$;
+
+create or replace procedure unsubscribe_category(_user_id bigint, _category varchar(64))
+    LANGUAGE plpgsql
+AS $$
+begin
+    delete from category_subscriptions
+        where category = _category
+            and user_id = _user_id;
+
+    -- nobody needs the mapping anymore
+    if not exists (select * from category_subscriptions where category = _category) then
+        delete from category_crates where category = _category;
+    end if;
+end
+$$;
+
+create or replace function list_category_subscriptions(_user_id bigint)
+    RETURNS TABLE(category_name varchar(64))
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select cs.category as category_name
+        from category_subscriptions as cs
+        where cs.user_id = _user_id;
+end
+$$;
+
+create or replace function list_subscribed_categories()
+    RETURNS TABLE(category_name varchar(64))
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select distinct cs.category as category_name
+        from category_subscriptions as cs;
+end
+$$;
+
+create or replace procedure set_category_crates(_category varchar(64), _crates varchar(64)[])
+    LANGUAGE plpgsql
+AS $$
+begin
+    delete from category_crates where category = _category;
+    insert into category_crates (category, crate)
+        select _category, c from unnest(_crates) as c
+        on conflict do nothing;
+end
+$$;
+
+create or replace procedure add_category_crate(_category varchar(64), _crate varchar(64))
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into category_crates (category, crate)
+        values (_category, _crate)
+        on conflict do nothing;
+end
+$$;
+
+create or replace function list_category_subscribers(_crate varchar(64))
+    RETURNS TABLE(user_id bigint)
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select distinct cs.user_id as user_id
+        from category_subscriptions as cs
+            inner join category_crates as cc on cc.category = cs.category
+        where cc.crate = _crate;
+end
+$$;
+
+create table if not exists owner_subscriptions
+(
+  user_id bigint not null,
+  owner varchar(64) not null,
+  constraint owner_subscriptions_pk
+    primary key (owner, user_id)
+);
+
+create index if not exists owner_subscriptions_user_id_index
+  on owner_subscriptions (user_id);
+
+create table if not exists owner_crates
+(
+  owner varchar(64) not null,
+  crate varchar(64) not null,
+  constraint owner_crates_pk
+    primary key (owner, crate)
+);
+
+comment on table owner_crates is 'owner (lowercase login) -> crates mapping from crates.io API, only for subscribed owners';
+
+create index if not exists owner_crates_crate_index
+  on owner_crates (crate);
+
+create or replace procedure subscribe_owner(_user_id bigint, _owner varchar(64))
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into owner_subscriptions (user_id, owner)
+        values (_user_id, _owner)
+        on conflict do nothing;
+end
+$$;
+
+create or replace procedure unsubscribe_owner(_user_id bigint, _owner varchar(64))
+    LANGUAGE plpgsql
+AS $$
+begin
+    delete from owner_subscriptions
+        where owner = _owner
+            and user_id = _user_id;
+
+    -- nobody needs the mapping anymore
+    if not exists (select * from owner_subscriptions where owner = _owner) then
+        delete from owner_crates where owner = _owner;
+    end if;
+end
+$$;
+
+create or replace function list_owner_subscriptions(_user_id bigint)
+    RETURNS TABLE(owner_name varchar(64))
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select os.owner as owner_name
+        from owner_subscriptions as os
+        where os.user_id = _user_id;
+end
+$$;
+
+create or replace function list_subscribed_owners()
+    RETURNS TABLE(owner_name varchar(64))
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select distinct os.owner as owner_name
+        from owner_subscriptions as os;
+end
+$$;
+
+create or replace procedure set_owner_crates(_owner varchar(64), _crates varchar(64)[])
+    LANGUAGE plpgsql
+AS $$
+begin
+    delete from owner_crates where owner = _owner;
+    insert into owner_crates (owner, crate)
+        select _owner, c from unnest(_crates) as c
+        on conflict do nothing;
+end
+$$;
+
+create or replace procedure add_owner_crate(_owner varchar(64), _crate varchar(64))
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into owner_crates (owner, crate)
+        values (_owner, _crate)
+        on conflict do nothing;
+end
+$$;
+
+create or replace function list_owner_subscribers(_crate varchar(64))
+    RETURNS TABLE(user_id bigint)
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select distinct os.user_id as user_id
+        from owner_subscriptions as os
+            inner join owner_crates as oc on oc.owner = os.owner
+        where oc.crate = _crate;
+end
+$$;
+
+-- subscriptions to all crates whose names start with a prefix (`/subscribe tokio-*`),
+-- including crates that don't exist yet
+create table if not exists pattern_subscriptions
+(
+  user_id bigint not null,
+  registry varchar(64) not null,
+  prefix varchar(64) not null,
+  created_at timestamptz not null default now(),
+  constraint pattern_subscriptions_pk
+    primary key (registry, prefix, user_id)
+);
+
+comment on column pattern_subscriptions.prefix is 'lowercase, crate names are compared case-insensitively';
+
+create index if not exists pattern_subscriptions_user_id_index
+  on pattern_subscriptions (user_id);
+
+create or replace procedure subscribe_pattern(_user_id bigint, _registry varchar(64), _prefix varchar(64))
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into pattern_subscriptions (user_id, registry, prefix)
+        values (_user_id, _registry, _prefix)
+        on conflict do nothing;
+end
+$$;
+
+create or replace procedure unsubscribe_pattern(_user_id bigint, _registry varchar(64), _prefix varchar(64),
+                                                INOUT _found bigint default 0)
+    LANGUAGE plpgsql
+AS $$
+begin
+    delete from pattern_subscriptions
+        where user_id = _user_id and registry = _registry and prefix = _prefix;
+    GET DIAGNOSTICS _found = ROW_COUNT;
+end
+$$;
+
+create or replace function list_pattern_subscriptions(_user_id bigint)
+    RETURNS TABLE(registry_name varchar(64), prefix varchar(64))
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select ps.registry as registry_name, ps.prefix as prefix
+        from pattern_subscriptions as ps
+        where ps.user_id = _user_id
+        order by ps.registry, ps.prefix;
+end
+$$;
+
+-- users with a pattern matching the crate. Patterns can't be looked up by the
+-- name, but there are few of them
+create or replace function list_pattern_subscribers(_registry varchar(64), _crate varchar(64))
+    RETURNS TABLE(user_id bigint)
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select distinct ps.user_id as user_id
+        from pattern_subscriptions as ps
+        where ps.registry = _registry
+          and left(lower(_crate), length(ps.prefix)) = ps.prefix;
+end
+$$;
+
+create or replace function subscription_count(_user_id bigint)
+    RETURNS bigint
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN (select count(*) from subscriptions where user_id = _user_id);
+end
+$$;
+
+-- alerts about brand-new crates whose name or keywords match a glob pattern
+-- (`/watch_new *serde*`)
+
+create table if not exists crate_watches
+(
+  user_id bigint not null,
+  pattern varchar(64) not null,
+  created_at timestamptz not null default now(),
+  constraint crate_watches_pk
+    primary key (pattern, user_id)
+);
+
+comment on column crate_watches.pattern is 'lowercase with `_` replaced by `-`, `*` & `?` are wildcards';
+
+create index if not exists crate_watches_user_id_index
+  on crate_watches (user_id);
+
+create or replace procedure watch_new(_user_id bigint, _pattern varchar(64))
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into crate_watches (user_id, pattern)
+        values (_user_id, _pattern)
+        on conflict do nothing;
+end
+$$;
+
+create or replace procedure unwatch_new(_user_id bigint, _pattern varchar(64), INOUT _found bigint default 0)
+    LANGUAGE plpgsql
+AS $$
+begin
+    delete from crate_watches where user_id = _user_id and pattern = _pattern;
+    GET DIAGNOSTICS _found = ROW_COUNT;
+end
+$$;
+
+create or replace function list_watches(_user_id bigint)
+    RETURNS TABLE(pattern varchar(64))
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select cw.pattern as pattern
+        from crate_watches as cw
+        where cw.user_id = _user_id
+        order by cw.pattern;
+end
+$$;
+
+-- all watches, every new crate is matched against them by the bot
+create or replace function list_all_watches()
+    RETURNS TABLE(user_id bigint, pattern varchar(64))
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select cw.user_id as user_id, cw.pattern as pattern
+        from crate_watches as cw;
+end
+$$;
+
+-- one-shot alerts (`/subscribe_when`)
+
+create table if not exists alerts
+(
+  id serial not null
+    constraint alerts_pk
+      primary key,
+  user_id bigint not null,
+  crate_id int not null
+    constraint alerts_crates_id_fk
+      references crates
+        on delete cascade,
+  req varchar(128) not null
+);
+
+comment on table alerts is 'one-shot subscriptions, deleted after the first matching version';
+
+comment on column alerts.req is 'semver requirement, e.g. ''>=2.0''';
+
+create index if not exists alerts_crate_id_index
+  on alerts (crate_id);
+
+create or replace procedure add_alert(_user_id bigint, _registry varchar(64), _crate varchar(64), _req varchar(128))
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into crates (registry, name) values (_registry, _crate) on conflict do nothing;
+
+    insert into alerts (user_id, crate_id, req)
+        select _user_id, id, _req from crates
+        where crates.registry = _registry and crates.name = _crate;
+end
+$$;
+
+create or replace function list_alerts(_registry varchar(64), _crate varchar(64))
+    RETURNS TABLE(alert_id int, user_id bigint, req varchar(128))
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select a.id as alert_id, a.user_id as user_id, a.req as req
+         from alerts as a
+              inner join crates as c on c.id = a.crate_id
+         where c.registry = _registry and c.name = _crate;
+end
+$$;
+
+-- deletes the alert & returns its user, returns nothing if the alert was
+-- already taken (so every alert fires at most once)
+create or replace function take_alert(_alert_id int)
+    RETURNS TABLE(user_id bigint)
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY with deleted as (
+        delete from alerts as a where a.id = _alert_id returning a.user_id
+    )
+    select deleted.user_id from deleted;
+end
+$$;
+
+-- paused chats (`/pause` & `/resume`)
+
+create table if not exists paused_chats
+(
+  user_id bigint not null
+    constraint paused_chats_pk
+      primary key,
+  paused_at timestamptz not null default now()
+);
+
+-- updates of chats paused with `/pause summary` are accumulated like digests
+-- (see `digest_items`) and sent after `/resume`, others are dropped
+alter table paused_chats
+  add column if not exists catch_up boolean not null default false;
+
+-- versions without `catch_up`
+drop procedure if exists pause(bigint);
+drop function if exists list_paused(bigint[]);
+
+create or replace procedure pause(_user_id bigint, _catch_up boolean)
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into paused_chats (user_id, catch_up) values (_user_id, _catch_up)
+        on conflict (user_id) do update set catch_up = _catch_up;
+end
+$$;
+
+create or replace procedure resume(_user_id bigint)
+    LANGUAGE plpgsql
+AS $$
+begin
+    delete from paused_chats where user_id = _user_id;
+end
+$$;
+
+create or replace function is_paused(_user_id bigint)
+    RETURNS boolean
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN exists (select * from paused_chats where user_id = _user_id);
+end
+$$;
+
+-- paused chats among the given ones
+create or replace function list_paused(_user_ids bigint[])
+    RETURNS TABLE(user_id bigint, catch_up boolean)
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select p.user_id as user_id, p.catch_up as catch_up
+         from paused_chats as p
+         where p.user_id = ANY(_user_ids);
+end
+$$;
+
+-- links shown in notifications (`/links`), chats without a row use the default
+-- ones from the config
+
+create table if not exists link_settings
+(
+  user_id bigint not null
+    constraint link_settings_pk
+      primary key,
+  links varchar(16)[] not null
+);
+
+-- `null` links reset the settings to the default
+create or replace procedure set_links(_user_id bigint, _links varchar(16)[])
+    LANGUAGE plpgsql
+AS $$
+begin
+    if _links is null then
+        delete from link_settings where user_id = _user_id;
+    else
+        insert into link_settings (user_id, links) values (_user_id, _links)
+            on conflict (user_id) do update set links = _links;
+    end if;
+end
+$$;
+
+-- link settings of the given chats (chats with default settings are omitted)
+create or replace function list_links(_user_ids bigint[])
+    RETURNS TABLE(user_id bigint, links varchar(16)[])
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select l.user_id as user_id, l.links as links
+         from link_settings as l
+         where l.user_id = ANY(_user_ids);
+end
+$$;
+
+-- digests (`/digest`): updates of chats with a digest period are accumulated
+-- and sent as one message per period
+
+create table if not exists digest_settings
+(
+  user_id bigint not null
+    constraint digest_settings_pk
+      primary key,
+  period varchar(16) not null,
+  last_sent timestamptz not null default now()
+);
+
+comment on column digest_settings.period is '''daily'' or ''weekly''';
+
+create table if not exists digest_items
+(
+  id bigserial not null
+    constraint digest_items_pk
+      primary key,
+  user_id bigint not null,
+  text text not null,
+  created_at timestamptz not null default now()
+);
+
+create index if not exists digest_items_user_id_index
+  on digest_items (user_id, id);
+
+-- `null` period turns the digest off (accumulated updates are sent with the
+-- next check)
+create or replace procedure set_digest(_user_id bigint, _period varchar(16))
+    LANGUAGE plpgsql
+AS $$
+begin
+    if _period is null then
+        delete from digest_settings where user_id = _user_id;
+    else
+        insert into digest_settings (user_id, period) values (_user_id, _period)
+            on conflict (user_id) do update set period = _period;
+    end if;
+end
+$$;
+
+create or replace function get_digest(_user_id bigint)
+    RETURNS varchar(16)
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN (select d.period from digest_settings as d where d.user_id = _user_id);
+end
+$$;
+
+-- chats with a digest among the given ones
+create or replace function list_digests(_user_ids bigint[])
+    RETURNS TABLE(user_id bigint)
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select d.user_id as user_id
+         from digest_settings as d
+         where d.user_id = ANY(_user_ids);
+end
+$$;
+
+create or replace procedure add_digest_items(_user_ids bigint[], _texts text[])
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into digest_items (user_id, text)
+        select * from unnest(_user_ids, _texts);
+end
+$$;
+
+-- chats with accumulated updates whose digest period is over (or that turned
+-- the digest off, or resumed after `/pause summary`), except paused ones
+create or replace function due_digests()
+    RETURNS TABLE(user_id bigint)
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select distinct i.user_id as user_id
+         from digest_items as i
+              left join digest_settings as d on d.user_id = i.user_id
+         where (d.user_id is null
+            or d.last_sent + case d.period when 'weekly' then interval '7 days' else interval '1 day' end <= now())
+            and not exists (select * from paused_chats as p where p.user_id = i.user_id);
+end
+$$;
+
+-- remove & return accumulated updates of the chat, starting a new period
+create or replace function take_digest(_user_id bigint)
+    RETURNS TABLE(text text, created_at timestamptz)
+    LANGUAGE plpgsql
+AS $$
+begin
+    update digest_settings as d set last_sent = now() where d.user_id = _user_id;
+    RETURN QUERY with taken as (
+        delete from digest_items as i where i.user_id = _user_id
+            returning i.id, i.text, i.created_at
+    )
+    select t.text, t.created_at from taken as t order by t.id;
+end
+$$;
+
+-- chats skipping pre-release versions by default (`/prereleases off`),
+-- subscriptions with `--prereleases` / `--no-prereleases` override it
+
+create table if not exists prerelease_settings
+(
+  user_id bigint not null
+    constraint prerelease_settings_pk
+      primary key
+);
+
+create or replace procedure set_skip_prereleases(_user_id bigint, _skip boolean)
+    LANGUAGE plpgsql
+AS $$
+begin
+    if _skip then
+        insert into prerelease_settings (user_id) values (_user_id) on conflict do nothing;
+    else
+        delete from prerelease_settings where user_id = _user_id;
+    end if;
+end
+$$;
+
+-- chats skipping pre-releases among the given ones
+create or replace function list_skipping_prereleases(_user_ids bigint[])
+    RETURNS TABLE(user_id bigint)
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select p.user_id as user_id
+         from prerelease_settings as p
+         where p.user_id = ANY(_user_ids);
+end
+$$;
+
+-- RustSec advisories already seen by the bot (subscribers are only notified
+-- about new ones)
+
+create table if not exists advisories
+(
+  id varchar(32) not null
+    constraint advisories_pk
+      primary key,
+  crate varchar(64) not null,
+  seen_at timestamptz not null default now()
+);
+
+-- `_new` is set to 1 if the advisory wasn't seen before, 0 otherwise
+create or replace procedure record_advisory(_id varchar(32), _crate varchar(64), INOUT _new bigint default 0)
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into advisories (id, crate) values (_id, _crate) on conflict do nothing;
+    GET DIAGNOSTICS _new = ROW_COUNT;
+end
+$$;
+
+create or replace function advisory_count()
+    RETURNS bigint
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN (select count(*) from advisories);
+end
+$$;
+
+-- maintenance (`/gc`)
+
+-- chats that have any subscriptions (or alerts or watches)
+create or replace function list_chats()
+    RETURNS TABLE(user_id bigint)
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select s.user_id from subscriptions as s
+        union select ks.user_id from keyword_subscriptions as ks
+        union select a.user_id from alerts as a
+        union select cw.user_id from crate_watches as cw;
+end
+$$;
+
+create or replace function list_subscribed_crates()
+    RETURNS TABLE(registry_name varchar(64), crate_name varchar(64), subscribers bigint)
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select c.registry as registry_name, c.name as crate_name, count(*) as subscribers
          from subscriptions as s
               inner join crates as c on c.id = s.crate_id
-         where c.name = _crate;
+         group by c.registry, c.name;
 end
 $$;
@@ -0,0 +1,353 @@
+//! RustSec advisories (<https://rustsec.org>) for subscribed crates.
+//!
+//! The advisory database is a git repo with a `crates/<crate>/<id>.md` file
+//! per advisory (TOML front matter followed by the markdown description). The
+//! bot periodically fetches it and notifies subscribers of the (crates.io)
+//! crate about advisories it hasn't seen before. Advisories found by the first
+//! sync are only recorded, so nobody gets years of old advisories at once.
+use crate::{
+    cfg::{SharedConfig, DEFAULT_REGISTRY},
+    db::Database,
+    queue::Queues,
+    util::escape_html,
+};
+use futures::StreamExt;
+use git2::{Repository, ResetType};
+use std::{fs, path::Path};
+
+/// Branch of the advisory database
+const BRANCH: &str = "main";
+
+/// Periodically sync the advisory database & notify subscribers about new
+/// advisories. Does nothing if `advisory_db_path` isn't set.
+pub async fn sync_loop(db: Database, queues: Queues, config: SharedConfig) {
+    loop {
+        let cfg = config.get();
+        let path = match &cfg.advisory_db_path {
+            Some(path) => path,
+            None => return,
+        };
+
+        match fetch(path, &cfg.advisory_db_url).and_then(|()| read_all(path)) {
+            Ok(advisories) => notify_new(&db, &queues, &config, advisories).await,
+            Err(err) => log::error!("couldn't sync the advisory database: {}", err),
+        }
+
+        tokio::time::delay_for(cfg.advisory_sync_delay).await;
+    }
+}
+
+/// Clone the advisory database or fetch & check out its latest version.
+fn fetch(path: &str, url: &str) -> Result<(), String> {
+    let repo = match Repository::open(path) {
+        Ok(repo) => repo,
+        Err(_) if !Path::new(path).exists() => {
+            log::info!("cloning the advisory database to `{}`", path);
+            return Repository::clone(url, path)
+                .map(drop)
+                .map_err(|err| format!("couldn't clone `{}`: {}", url, err));
+        }
+        Err(err) => return Err(format!("`{}` isn't a git repository: {}", path, err)),
+    };
+
+    let update = || -> Result<(), git2::Error> {
+        repo.find_remote("origin")?.fetch(&[BRANCH], None, None)?;
+        let head = repo.refname_to_id("FETCH_HEAD")?;
+        repo.reset(&repo.find_object(head, None)?, ResetType::Hard, None)
+    };
+    update().map_err(|err| err.to_string())
+}
+
+/// Front matter of an advisory file
+#[derive(serde::Deserialize)]
+struct FrontMatter {
+    advisory: Metadata,
+    #[serde(default)]
+    versions: Versions,
+}
+
+#[derive(serde::Deserialize)]
+struct Metadata {
+    id: String,
+    package: String,
+    /// Only in older advisories, newer ones have it as the markdown heading
+    #[serde(default)]
+    title: Option<String>,
+    /// CVSS v3 vector, e.g. `CVSS:3.1/AV:N/AC:L/PR:N/UI:N/S:U/C:H/I:H/A:H`
+    #[serde(default)]
+    cvss: Option<String>,
+    /// Kind of an informational advisory, e.g. `unmaintained` or `unsound`
+    #[serde(default)]
+    informational: Option<String>,
+    /// Date the advisory was withdrawn (it was wrong)
+    #[serde(default)]
+    withdrawn: Option<String>,
+}
+
+#[derive(Default, serde::Deserialize)]
+struct Versions {
+    /// Semver requirements of patched versions
+    #[serde(default)]
+    patched: Vec<String>,
+}
+
+struct Advisory {
+    id: String,
+    krate: String,
+    title: String,
+    cvss: Option<String>,
+    informational: Option<String>,
+    patched: Vec<String>,
+}
+
+impl Advisory {
+    fn url(&self) -> String {
+        format!("https://rustsec.org/advisories/{}.html", self.id)
+    }
+
+    fn html(&self) -> String {
+        let severity = match (
+            &self.informational,
+            self.cvss.as_deref().and_then(cvss_score),
+        ) {
+            (Some(kind), _) => format!("informational ({})", escape_html(kind)),
+            (None, Some(score)) => format!("{} ({:.1})", severity(score), score),
+            (None, None) => String::from("unknown"),
+        };
+        let patched = if self.patched.is_empty() {
+            String::from("no patched versions")
+        } else {
+            self.patched
+                .iter()
+                .map(|req| format!("<code>{}</code>", escape_html(req)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        format!(
+            "🛡 <b>Security advisory</b> <a href='{url}'>{id}</a> for <code>{krate}</code>: {title}\n\
+             severity: {severity}\n\
+             patched: {patched}",
+            url = self.url(),
+            id = self.id,
+            krate = self.krate,
+            title = escape_html(&self.title),
+            severity = severity,
+            patched = patched,
+        )
+    }
+}
+
+/// Read all (not withdrawn) advisories of crates from the database at `path`.
+fn read_all(path: &str) -> Result<Vec<Advisory>, String> {
+    let crates = Path::new(path).join("crates");
+    let read_dir = |dir: &Path| {
+        fs::read_dir(dir).map_err(|err| format!("couldn't read `{}`: {}", dir.display(), err))
+    };
+
+    let mut advisories = Vec::new();
+    for krate in read_dir(&crates)?.filter_map(Result::ok) {
+        for file in read_dir(&krate.path())?.filter_map(Result::ok) {
+            let path = file.path();
+            if path.extension().map_or(true, |ext| ext != "md") {
+                continue;
+            }
+            let parsed = fs::read_to_string(&path)
+                .map_err(|err| err.to_string())
+                .and_then(|text| parse(&text));
+            match parsed {
+                Ok(Some(advisory)) => advisories.push(advisory),
+                Ok(None) => {}
+                Err(err) => log::warn!("couldn't parse advisory `{}`: {}", path.display(), err),
+            }
+        }
+    }
+
+    Ok(advisories)
+}
+
+/// Parse an advisory file, `None` if the advisory was withdrawn.
+fn parse(text: &str) -> Result<Option<Advisory>, String> {
+    let front = text.strip_prefix("```toml").and_then(|rest| {
+        rest.find("\n```")
+            .map(|end| (&rest[..end], &rest[end + 4..]))
+    });
+    let (front, description) = match front {
+        Some(parts) => parts,
+        None => return Err(String::from("no front matter")),
+    };
+    let FrontMatter { advisory, versions } =
+        toml::from_str(front).map_err(|err| err.to_string())?;
+    if advisory.withdrawn.is_some() {
+        return Ok(None);
+    }
+
+    let heading = description
+        .lines()
+        .find_map(|line| line.strip_prefix("# "))
+        .map(str::trim);
+    let title = match (advisory.title, heading) {
+        (Some(title), _) => title,
+        (None, Some(heading)) => heading.to_owned(),
+        (None, None) => String::from("(no title)"),
+    };
+
+    Ok(Some(Advisory {
+        id: advisory.id,
+        krate: advisory.package,
+        title,
+        cvss: advisory.cvss,
+        informational: advisory.informational,
+        patched: versions.patched,
+    }))
+}
+
+/// Record the advisories & notify subscribers about the ones that are new.
+async fn notify_new(
+    db: &Database,
+    queues: &Queues,
+    config: &SharedConfig,
+    advisories: Vec<Advisory>,
+) {
+    // the first sync only records what's already there
+    let first_sync = match db.advisory_count().await {
+        Ok(count) => count == 0,
+        Err(err) => {
+            log::error!("db error while counting advisories: {}", err);
+            return;
+        }
+    };
+
+    for advisory in advisories {
+        match db.record_advisory(&advisory.id, &advisory.krate).await {
+            Ok(true) if !first_sync => {
+                log::info!("new advisory {} for {}", advisory.id, advisory.krate);
+                notify(db, queues, config, &advisory).await;
+            }
+            Ok(_) => {}
+            Err(err) => log::error!("db error while recording advisory {}: {}", advisory.id, err),
+        }
+    }
+}
+
+/// Send the advisory to subscribers of its crate (except muted & paused
+/// ones).
+async fn notify(db: &Database, queues: &Queues, config: &SharedConfig, advisory: &Advisory) {
+    let subscribers = match db.list_subscribers(DEFAULT_REGISTRY, &advisory.krate).await {
+        Ok(subscribers) => subscribers,
+        Err(err) => {
+            log::error!("db error while getting subscribers: {}", err);
+            return;
+        }
+    };
+    let now = chrono::Utc::now();
+    let chats: Vec<_> = subscribers
+        .filter_map(|res| async move {
+            res.map_err(|err| log::error!("db error while getting subscribers: {}", err))
+                .ok()
+        })
+        .filter(|sub| futures::future::ready(!sub.is_muted(now)))
+        .map(|sub| sub.chat_id)
+        .collect()
+        .await;
+    let paused = db
+        .list_paused(&chats)
+        .await
+        .map_err(|err| log::error!("db error while getting paused chats: {}", err))
+        .unwrap_or_default();
+
+    let text = advisory.html();
+    let delay = config.get().broadcast_delay;
+    for chat_id in chats.into_iter().filter(|c| !paused.contains_key(c)) {
+        queues
+            .send(chat_id, text.clone(), false, None, None, delay)
+            .await;
+    }
+}
+
+/// Qualitative severity of a CVSS v3 score
+fn severity(score: f64) -> &'static str {
+    match score {
+        s if s >= 9.0 => "critical",
+        s if s >= 7.0 => "high",
+        s if s >= 4.0 => "medium",
+        s if s > 0.0 => "low",
+        _ => "none",
+    }
+}
+
+/// Base score of a CVSS v3 vector (see
+/// <https://www.first.org/cvss/v3.1/specification-document#7-1-Base-Metrics-Equations>).
+/// `None` if the vector is invalid.
+fn cvss_score(vector: &str) -> Option<f64> {
+    let metric = |name| metric(vector, name);
+    let changed = match metric("S")? {
+        "U" => false,
+        "C" => true,
+        _ => return None,
+    };
+    let av = match metric("AV")? {
+        "N" => 0.85,
+        "A" => 0.62,
+        "L" => 0.55,
+        "P" => 0.2,
+        _ => return None,
+    };
+    let ac = match metric("AC")? {
+        "L" => 0.77,
+        "H" => 0.44,
+        _ => return None,
+    };
+    let pr = match (metric("PR")?, changed) {
+        ("N", _) => 0.85,
+        ("L", false) => 0.62,
+        ("L", true) => 0.68,
+        ("H", false) => 0.27,
+        ("H", true) => 0.5,
+        _ => return None,
+    };
+    let ui = match metric("UI")? {
+        "N" => 0.85,
+        "R" => 0.62,
+        _ => return None,
+    };
+    let cia = |name| match metric(name)? {
+        "H" => Some(0.56),
+        "L" => Some(0.22),
+        "N" => Some(0.0),
+        _ => None,
+    };
+    let iss = 1.0 - (1.0 - cia("C")?) * (1.0 - cia("I")?) * (1.0 - cia("A")?);
+
+    let impact = if changed {
+        7.52 * (iss - 0.029) - 3.25 * (iss - 0.02_f64).powi(15)
+    } else {
+        6.42 * iss
+    };
+    if impact <= 0.0 {
+        return Some(0.0);
+    }
+    let exploitability = 8.22 * av * ac * pr * ui;
+    let score = if changed {
+        1.08 * (impact + exploitability)
+    } else {
+        impact + exploitability
+    };
+    Some(round_up(score.min(10.0)))
+}
+
+/// Value of the metric in a CVSS vector, e.g. `N` for `AV` in `../AV:N/..`
+fn metric<'a>(vector: &'a str, name: &str) -> Option<&'a str> {
+    vector
+        .split('/')
+        .find_map(|part| part.strip_prefix(name)?.strip_prefix(':'))
+}
+
+/// CVSS "Roundup": the smallest number with one decimal that is >= `value`
+fn round_up(value: f64) -> f64 {
+    let int = (value * 100_000.0).round() as i64;
+    if int % 10_000 == 0 {
+        int as f64 / 100_000.0
+    } else {
+        (int / 10_000 + 1) as f64 / 10.0
+    }
+}
@@ -0,0 +1,544 @@
+//! Minimal client for the [crates.io API](https://crates.io/data-access) (and
+//! a bit of docs.rs).
+//!
+//! Note: crates.io asks to make at most 1 request per second and to set a
+//! user-agent identifying the client.
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Used if the index `config.json` doesn't specify the API url
+const API_URL: &str = "https://crates.io";
+
+const DOCS_RS_URL: &str = "https://docs.rs";
+
+#[cfg(feature = "subscriptions")]
+const GITHUB_API_URL: &str = "https://api.github.com";
+
+const USER_AGENT: &str = concat!(
+    "crate_upd_bot/",
+    env!("CARGO_PKG_VERSION"),
+    " (https://github.com/WaffleLapkin/crate_upd_bot)"
+);
+
+/// Maximum number of pages fetched when listing crates
+const MAX_PAGES: u32 = 10;
+
+const PER_PAGE: u32 = 100;
+
+/// Delay between requests of a multi-request listing, so it doesn't break the
+/// crates.io crawler policy (1 request per second)
+const PAGE_DELAY: Duration = Duration::from_secs(1);
+
+/// For how long repository urls of crates are cached
+const REPOSITORY_TTL: Duration = Duration::from_secs(60 * 60 * 24);
+
+#[derive(Clone)]
+pub struct CratesIo {
+    http: reqwest::Client,
+    /// Base of the API urls, e.g. `https://crates.io/api/v1`
+    api: Arc<str>,
+    /// crate name -> (time of the fetch, repository url)
+    repositories: Arc<Mutex<HashMap<String, (Instant, Option<String>)>>>,
+}
+
+impl CratesIo {
+    /// `api` is the registry API url from the index `config.json`.
+    pub fn new(api: Option<&str>) -> Self {
+        let http = reqwest::Client::builder()
+            .user_agent(USER_AGENT)
+            .build()
+            .expect("couldn't build http client");
+
+        let api = format!("{}/api/v1", api.unwrap_or(API_URL).trim_end_matches('/'));
+        Self {
+            http,
+            api: api.into(),
+            repositories: Arc::default(),
+        }
+    }
+
+    /// Get metadata of the crate.
+    pub async fn crate_info(&self, name: &str) -> reqwest::Result<CrateInfo> {
+        #[derive(Deserialize)]
+        struct Response {
+            #[serde(rename = "crate")]
+            krate: CrateInfo,
+        }
+
+        let url = format!("{}/crates/{}", self.api, name);
+        let resp: Response = self
+            .http
+            .get(&url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(resp.krate)
+    }
+
+    /// Repository url of the crate (cached for `REPOSITORY_TTL`).
+    pub async fn repository(&self, name: &str) -> Option<String> {
+        if let Some((fetched, url)) = self.repositories.lock().unwrap().get(name) {
+            if fetched.elapsed() < REPOSITORY_TTL {
+                return url.clone();
+            }
+        }
+
+        let url = match self.crate_info(name).await {
+            Ok(info) => info.repository,
+            Err(err) => {
+                log::warn!("couldn't get info of crate {}: {}", name, err);
+                // don't cache errors
+                return None;
+            }
+        };
+        self.repositories
+            .lock()
+            .unwrap()
+            .insert(name.to_owned(), (Instant::now(), url.clone()));
+        url
+    }
+
+    /// Best-effort link to the GitHub release of the given version of the
+    /// crate, tries common tag formats (`v1.0.0`, `1.0.0`, `name-v1.0.0`, ...).
+    pub async fn github_release(&self, name: &str, version: &str) -> Option<String> {
+        let repository = self.repository(name).await?;
+        let repository = github_repository(&repository)?;
+        for tag in &release_tags(name, version) {
+            let url = format!("https://github.com/{}/releases/tag/{}", repository, tag);
+            match self.http.head(&url).send().await {
+                Ok(resp) if resp.status().is_success() => return Some(url),
+                Ok(_) => {}
+                Err(err) => {
+                    log::warn!("couldn't check release {}: {}", url, err);
+                    return None;
+                }
+            }
+        }
+        None
+    }
+
+    /// Best-effort release notes of the given version of the crate: text of
+    /// the GitHub release or, if there is none, the section of `CHANGELOG.md`
+    /// (in the root of the GitHub repository) about the version.
+    ///
+    /// Note: the GitHub API allows only 60 unauthenticated requests per hour.
+    #[cfg(feature = "subscriptions")]
+    pub async fn release_notes(&self, name: &str, version: &str) -> Option<String> {
+        #[derive(Deserialize)]
+        struct Release {
+            body: Option<String>,
+        }
+
+        let repository = self.repository(name).await?;
+        let repository = github_repository(&repository)?;
+        for tag in &release_tags(name, version) {
+            let url = format!(
+                "{}/repos/{}/releases/tags/{}",
+                GITHUB_API_URL, repository, tag
+            );
+            match self.http.get(&url).send().await {
+                Ok(resp) if resp.status().is_success() => {
+                    match resp.json::<Release>().await {
+                        Ok(Release { body: Some(body) }) if !body.trim().is_empty() => {
+                            return Some(body)
+                        }
+                        // the release exists, but says nothing
+                        _ => break,
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    log::warn!("couldn't get release {}: {}", url, err);
+                    return None;
+                }
+            }
+        }
+
+        let url = format!(
+            "https://raw.githubusercontent.com/{}/HEAD/CHANGELOG.md",
+            repository
+        );
+        let changelog = match self.http.get(&url).send().await {
+            Ok(resp) if resp.status().is_success() => resp.text().await.ok()?,
+            Ok(_) => return None,
+            Err(err) => {
+                log::warn!("couldn't get changelog {}: {}", url, err);
+                return None;
+            }
+        };
+        changelog_section(&changelog, version)
+    }
+
+    /// Publish times of all versions of the crate (`version -> time`).
+    pub async fn publish_times(
+        &self,
+        name: &str,
+    ) -> reqwest::Result<HashMap<String, DateTime<Utc>>> {
+        #[derive(Deserialize)]
+        struct Response {
+            versions: Vec<Published>,
+        }
+
+        #[derive(Deserialize)]
+        struct Published {
+            num: String,
+            created_at: DateTime<Utc>,
+        }
+
+        let url = format!("{}/crates/{}/versions", self.api, name);
+        let resp: Response = self
+            .http
+            .get(&url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(resp
+            .versions
+            .into_iter()
+            .map(|v| (v.num, v.created_at))
+            .collect())
+    }
+
+    /// Status of the docs.rs build of the given version of the crate.
+    ///
+    /// Note: this uses docs.rs, not crates.io.
+    pub async fn docs_status(&self, name: &str, version: &str) -> reqwest::Result<DocsStatus> {
+        #[derive(Deserialize)]
+        struct Build {
+            build_status: bool,
+        }
+
+        let url = format!("{}/crate/{}/{}/builds.json", DOCS_RS_URL, name, version);
+        let resp = self.http.get(&url).send().await?;
+        // docs.rs doesn't know about the version yet
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(DocsStatus::Pending);
+        }
+
+        let builds: Vec<Build> = resp.error_for_status()?.json().await?;
+        Ok(match builds.first() {
+            None => DocsStatus::Pending,
+            Some(build) if build.build_status => DocsStatus::Ok,
+            Some(_) => DocsStatus::Failed,
+        })
+    }
+
+    /// Most recently updated (or published) crates, as `(name, time of the
+    /// update)`. Used to poll sparse indexes, which don't have a change feed.
+    ///
+    /// Pages are read until one reaches `since` (only the first page without
+    /// it), at most `MAX_PAGES` of them.
+    pub async fn recently_updated(
+        &self,
+        since: Option<DateTime<Utc>>,
+    ) -> reqwest::Result<Vec<(String, DateTime<Utc>)>> {
+        #[derive(Deserialize)]
+        struct Response {
+            crates: Vec<Updated>,
+        }
+
+        #[derive(Deserialize)]
+        struct Updated {
+            name: String,
+            updated_at: DateTime<Utc>,
+        }
+
+        let mut updated = Vec::new();
+        for page in 1..=MAX_PAGES {
+            if page > 1 {
+                tokio::time::delay_for(PAGE_DELAY).await;
+            }
+            let resp: Response = self
+                .http
+                .get(&format!("{}/crates", self.api))
+                .query(&[
+                    ("sort", "recent-updates"),
+                    ("per_page", &PER_PAGE.to_string()),
+                    ("page", &page.to_string()),
+                ])
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+
+            let len = resp.crates.len();
+            let oldest = resp.crates.iter().map(|c| c.updated_at).min();
+            updated.extend(resp.crates.into_iter().map(|c| (c.name, c.updated_at)));
+            let since = match (since, oldest) {
+                (Some(since), Some(oldest)) if oldest > since => since,
+                _ => break,
+            };
+            if len < PER_PAGE as usize {
+                break;
+            }
+            if page == MAX_PAGES {
+                log::warn!(
+                    "more than {} crates were updated since {}, some updates are missed",
+                    MAX_PAGES * PER_PAGE,
+                    since,
+                );
+            }
+        }
+
+        Ok(updated)
+    }
+
+    /// Crates matching the search query, the most relevant first.
+    #[cfg(feature = "subscriptions")]
+    pub async fn search(&self, query: &str, limit: u32) -> reqwest::Result<Vec<CrateInfo>> {
+        #[derive(Deserialize)]
+        struct Response {
+            crates: Vec<CrateInfo>,
+        }
+
+        let resp: Response = self
+            .http
+            .get(&format!("{}/crates", self.api))
+            .query(&[("q", query), ("per_page", &limit.to_string())])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(resp.crates)
+    }
+
+    /// Names of the crates tagged with the keyword (at most
+    /// `MAX_PAGES * PER_PAGE` crates).
+    #[cfg(feature = "subscriptions")]
+    pub async fn keyword_crates(&self, keyword: &str) -> reqwest::Result<Vec<String>> {
+        self.list_crates(("keyword", keyword)).await
+    }
+
+    /// Names of the crates in the category (at most `MAX_PAGES * PER_PAGE`
+    /// crates), including its subcategories.
+    #[cfg(feature = "subscriptions")]
+    pub async fn category_crates(&self, category: &str) -> reqwest::Result<Vec<String>> {
+        self.list_crates(("category", category)).await
+    }
+
+    /// Check that there is a category with the slug.
+    #[cfg(feature = "subscriptions")]
+    pub async fn category_exists(&self, category: &str) -> reqwest::Result<bool> {
+        let resp = self
+            .http
+            .get(&format!("{}/categories/{}", self.api, category))
+            .send()
+            .await?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        resp.error_for_status()?;
+        Ok(true)
+    }
+
+    /// Names of the crates owned by the user with the login (at most
+    /// `MAX_PAGES * PER_PAGE` crates), `None` if there is no such user.
+    #[cfg(feature = "subscriptions")]
+    pub async fn owner_crates(&self, login: &str) -> reqwest::Result<Option<Vec<String>>> {
+        #[derive(Deserialize)]
+        struct Response {
+            user: User,
+        }
+
+        #[derive(Deserialize)]
+        struct User {
+            id: u64,
+        }
+
+        let resp = self
+            .http
+            .get(&format!("{}/users/{}", self.api, login))
+            .send()
+            .await?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let resp: Response = resp.error_for_status()?.json().await?;
+
+        let id = resp.user.id.to_string();
+        tokio::time::delay_for(PAGE_DELAY).await;
+        self.list_crates(("user_id", &id)).await.map(Some)
+    }
+
+    /// Logins of the users owning the crate (teams are skipped).
+    #[cfg(feature = "subscriptions")]
+    pub async fn crate_owners(&self, name: &str) -> reqwest::Result<Vec<String>> {
+        #[derive(Deserialize)]
+        struct Response {
+            users: Vec<Owner>,
+        }
+
+        #[derive(Deserialize)]
+        struct Owner {
+            login: String,
+            kind: String,
+        }
+
+        let resp: Response = self
+            .http
+            .get(&format!("{}/crates/{}/owners", self.api, name))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(resp
+            .users
+            .into_iter()
+            .filter(|owner| owner.kind == "user")
+            .map(|owner| owner.login)
+            .collect())
+    }
+
+    /// Names of the crates matching the `(name, value)` filter of the
+    /// `/crates` endpoint, page by page.
+    #[cfg(feature = "subscriptions")]
+    async fn list_crates(&self, filter: (&str, &str)) -> reqwest::Result<Vec<String>> {
+        #[derive(Deserialize)]
+        struct Response {
+            crates: Vec<Named>,
+        }
+
+        #[derive(Deserialize)]
+        struct Named {
+            name: String,
+        }
+
+        let mut names = Vec::new();
+        for page in 1..=MAX_PAGES {
+            if page > 1 {
+                tokio::time::delay_for(PAGE_DELAY).await;
+            }
+            let resp: Response = self
+                .http
+                .get(&format!("{}/crates", self.api))
+                .query(&[
+                    filter,
+                    ("per_page", &PER_PAGE.to_string()),
+                    ("page", &page.to_string()),
+                ])
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+
+            let len = resp.crates.len();
+            names.extend(resp.crates.into_iter().map(|c| c.name));
+            if len < PER_PAGE as usize {
+                break;
+            }
+        }
+
+        Ok(names)
+    }
+}
+
+/// Crate metadata from the crates.io API (ignoring everything we don't need)
+// without `subscriptions` only `repository` is needed
+#[cfg_attr(not(feature = "subscriptions"), allow(dead_code))]
+#[derive(Debug, Deserialize)]
+pub struct CrateInfo {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// The highest version, including pre-releases
+    #[serde(default)]
+    pub max_version: Option<String>,
+    #[serde(default)]
+    pub max_stable_version: Option<String>,
+    /// All-time downloads
+    #[serde(default)]
+    pub downloads: u64,
+    #[serde(default)]
+    pub documentation: Option<String>,
+    #[serde(default)]
+    pub homepage: Option<String>,
+    #[serde(default)]
+    pub keywords: Vec<String>,
+    /// Slugs of the categories
+    #[serde(default)]
+    pub categories: Vec<String>,
+    #[serde(default)]
+    pub repository: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DocsStatus {
+    Ok,
+    Failed,
+    /// The version wasn't built yet
+    Pending,
+}
+
+impl DocsStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DocsStatus::Ok => "ok",
+            DocsStatus::Failed => "failed",
+            DocsStatus::Pending => "pending",
+        }
+    }
+}
+
+/// Common formats of release tags (`v1.0.0`, `1.0.0`, `name-v1.0.0`, ...).
+fn release_tags(name: &str, version: &str) -> [String; 4] {
+    [
+        format!("v{}", version),
+        version.to_owned(),
+        format!("{}-v{}", name, version),
+        format!("{}-{}", name, version),
+    ]
+}
+
+/// Section of a markdown changelog about the `version`: everything after the
+/// heading mentioning the version, up to the next heading of the same (or
+/// higher) level.
+#[cfg(feature = "subscriptions")]
+fn changelog_section(changelog: &str, version: &str) -> Option<String> {
+    let mentions = |line: &str| {
+        line.split(|c: char| !(c.is_alphanumeric() || c == '.' || c == '-' || c == '+'))
+            .any(|word| word.trim_start_matches('v') == version)
+    };
+    let level = |line: &str| line.chars().take_while(|&c| c == '#').count();
+
+    let mut lines = changelog.lines();
+    let heading = lines
+        .by_ref()
+        .find(|line| level(line) > 0 && mentions(line))?;
+    let heading_level = level(heading);
+    let section: Vec<_> = lines
+        .take_while(|line| !(1..=heading_level).contains(&level(line)))
+        .collect();
+    let section = section.join("\n");
+    Some(section.trim().to_owned()).filter(|s| !s.is_empty())
+}
+
+/// `owner/repo` part of a GitHub repository url, e.g.
+/// `https://github.com/serde-rs/serde.git` -> `serde-rs/serde`.
+fn github_repository(url: &str) -> Option<String> {
+    let path = url
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .trim_start_matches("www.")
+        .strip_prefix("github.com/")?;
+    let mut parts = path.split('/');
+    let owner = parts.next().filter(|s| !s.is_empty())?;
+    let repo = parts.next().filter(|s| !s.is_empty())?;
+    Some(format!("{}/{}", owner, repo.trim_end_matches(".git")))
+}
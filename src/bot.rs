@@ -1,114 +1,2951 @@
-use crate::cfg::RetryDelay;
-use crate::krate::Crate;
+use crate::cfg::{Config, Link, RegistryConfig, SharedConfig, DEFAULT_REGISTRY};
+use crate::chat::{ChatId, Events};
+use crate::krate::{Crate, Dependency, Level};
 use crate::{
-    db::Database,
-    util::{crate_path, tryn},
+    api::CratesIo,
+    channel,
+    db::{Database, Subscription},
+    digest::Period,
+    keywords, manifest,
+    queue::is_permanent,
+    util::{
+        crate_from_url, escape_html, is_valid_category, is_valid_crate_name, is_valid_keyword,
+        is_valid_login, qualified_name, tryn,
+    },
+    watches,
+};
+use futures::{StreamExt, TryStreamExt};
+use semver::{Version, VersionReq};
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
-use fntools::value::ValueExt;
-use std::{future::Future, path::PathBuf, pin::Pin, time::Duration};
 use teloxide::prelude::*;
-use teloxide::utils::command::BotCommand;
+use teloxide::types::{
+    CallbackQuery, ChatAction, ChatOrInlineMessage, InlineKeyboardButton, InlineKeyboardMarkup,
+    InlineQuery, InlineQueryResult, InlineQueryResultArticle, InputFile, InputMessageContent,
+    InputMessageContentText, ParseMode,
+};
+use teloxide::utils::command::{BotCommand, ParseError};
 
 #[derive(Debug, BotCommand)]
-#[command(rename = "lowercase")]
+#[command(rename = "lowercase", description = "Supported commands:")]
 enum Command {
-    Start,
+    #[command(description = "show the welcome message.")]
+    Start(String),
+    #[command(description = "subscribe for updates of a crate.")]
     Subscribe(String),
+    #[command(description = "unsubscribe from updates of a crate.")]
     Unsubscribe(String),
-    List,
-    Help,
+    #[command(
+        rename = "subscribe_yanks",
+        description = "subscribe only for (un)yanks of a crate."
+    )]
+    SubscribeYanks(String),
+    #[command(
+        rename = "subscribe_when",
+        description = "get notified once when a crate version matches a requirement."
+    )]
+    SubscribeWhen(String),
+    #[command(
+        rename = "subscribe_keyword",
+        description = "subscribe for updates of all crates with a keyword."
+    )]
+    SubscribeKeyword(String),
+    #[command(
+        rename = "unsubscribe_keyword",
+        description = "unsubscribe from updates of crates with a keyword."
+    )]
+    UnsubscribeKeyword(String),
+    #[command(
+        rename = "subscribe_category",
+        description = "subscribe for updates of all crates in a category."
+    )]
+    SubscribeCategory(String),
+    #[command(
+        rename = "unsubscribe_category",
+        description = "unsubscribe from updates of crates in a category."
+    )]
+    UnsubscribeCategory(String),
+    #[command(
+        rename = "subscribe_owner",
+        description = "subscribe for updates of all crates of a crates.io user."
+    )]
+    SubscribeOwner(String),
+    #[command(
+        rename = "unsubscribe_owner",
+        description = "unsubscribe from updates of crates of a crates.io user."
+    )]
+    UnsubscribeOwner(String),
+    #[command(
+        rename = "watch_new",
+        description = "get notified about new crates whose name or keywords match a pattern."
+    )]
+    WatchNew(String),
+    #[command(
+        rename = "unwatch_new",
+        description = "stop watching for new crates matching a pattern."
+    )]
+    UnwatchNew(String),
+    #[command(
+        rename = "unsubscribe_all",
+        description = "remove all subscriptions of this chat."
+    )]
+    UnsubscribeAll,
+    #[command(description = "subscribe to dependencies from a Cargo.toml or Cargo.lock.")]
+    Import(String),
+    #[command(description = "download your subscriptions as a file.")]
+    Export(String),
+    #[command(description = "list your subscriptions.")]
+    List(String),
+    #[command(description = "mute notifications about a crate for a while.")]
+    Mute(String),
+    #[command(description = "pause all notifications (subscriptions are kept).")]
+    Pause(String),
+    #[command(description = "resume paused notifications.")]
+    Resume,
+    #[command(description = "choose links shown in notifications.")]
+    Links(String),
+    #[command(description = "get updates as a daily or weekly summary.")]
+    Digest(String),
+    #[command(description = "choose whether to get pre-release versions by default.")]
+    Prereleases(String),
+    #[command(
+        description = "show changes between two versions of a crate.",
+        parse_with = "split"
+    )]
+    Diff(String, String, String),
+    #[command(description = "show recent events of a crate.")]
+    History(String),
+    #[command(description = "show recent versions of a crate.")]
+    Versions(String),
+    #[command(description = "show information about a crate.")]
+    Info(String),
+    #[command(description = "show dependencies of a crate.")]
+    Deps(String),
+    #[command(
+        description = "check whether a version of a crate exists or is yanked.",
+        parse_with = "split"
+    )]
+    Check(String, String),
+    #[command(description = "search crates.io for crates.")]
+    Search(String),
+    #[command(description = "show the most subscribed crates.")]
+    Top,
+    #[command(
+        rename = "whosubscribed",
+        description = "show subscribers of a crate (admins only)."
+    )]
+    WhoSubscribed(String),
+    #[command(description = "remove subscriptions of unreachable chats (admins only).")]
+    Gc,
+    #[command(description = "send feedback to the bot operator.")]
+    Feedback(String),
+    #[command(description = "show version & uptime of the bot.")]
+    About,
+    #[command(description = "show this message or detailed help for a command.")]
+    Help(String),
 }
 
-const START_MESSAGE: &'static str = "
-Hi! I will notify you about updates of crates. Use /subscribe to subscribe for updates of crates you want to be notified about.
+impl Command {
+    /// Detailed help for the command with the given name (without `/`).
+    fn detailed_help(name: &str) -> Option<&'static str> {
+        let help = match name {
+            "start" => {
+                "/start — show the welcome message.\n\n\
+                 Links like <code>t.me/&lt;bot&gt;?start=sub_serde</code> open the bot with \
+                 <code>/start sub_serde</code>, which subscribes to the crate (the same as \
+                 <code>/subscribe serde</code>). They can be shared anywhere to offer one-tap \
+                 subscriptions."
+            }
+            "subscribe" => {
+                "/subscribe &lt;crate&gt;[@registry] [&lt;crate&gt;...] [--events update|yank] \
+                 [--only minor|major] [--no-prereleases] [--changelog] [#label] — subscribe \
+                 for updates of the crate(s).\n\n\
+                 You'll be notified in this chat about new versions of the crate and about \
+                 (un)yanked versions. Several crates may be separated by spaces or commas, the \
+                 reply reports the result for every one of them. Crates from registries other \
+                 than crates.io need \
+                 <code>@registry</code> suffix. Links to crates.io, docs.rs and lib.rs \
+                 work too. With <code>--events yank</code> only (un)yanks are sent (like \
+                 /subscribe_yanks), with <code>--events update</code> only new versions. \
+                 With <code>--only major</code> only major (semver-incompatible) \
+                 releases and yanks are sent, <code>--only minor</code> also lets minor \
+                 (feature) releases through, skipping patches (in <code>0.x.y</code> versions \
+                 <code>x</code> is major and <code>y</code> is minor, as cargo treats them). \
+                 With <code>--no-prereleases</code> pre-release versions (like \
+                 <code>2.0.0-rc.1</code>) are skipped, <code>--prereleases</code> always sends \
+                 them, regardless of /prereleases. \
+                 With <code>--changelog</code> notifications about \
+                 new versions include an excerpt of the release notes (from GitHub releases or \
+                 <code>CHANGELOG.md</code>), when they can be found. Everything after <code>#</code> is a label \
+                 of your choice (e.g. why you follow the crate), see /list. Subscribing again \
+                 with another label changes it.\n\n\
+                 A crate name prefix followed by <code>*</code> (e.g. <code>tokio-*</code>) \
+                 subscribes to all crates whose names start with it, including ones published \
+                 in the future.\n\n\
+                 Example: <code>/subscribe serde</code>, \
+                 <code>/subscribe serde tokio reqwest</code>, \
+                 <code>/subscribe serde, tokio</code>, \
+                 <code>/subscribe openssl --events yank</code>, \
+                 <code>/subscribe tokio --only major</code>, \
+                 <code>/subscribe serde --only minor</code>, \
+                 <code>/subscribe tokio --no-prereleases</code>, \
+                 <code>/subscribe clap --changelog</code>, \
+                 <code>/subscribe axum #work</code>, \
+                 <code>/subscribe tokio-*</code> or \
+                 <code>/subscribe https://crates.io/crates/serde</code>"
+            }
+            "unsubscribe" => {
+                "/unsubscribe &lt;crate&gt; — unsubscribe from updates of the crate (or of \
+                 crates matching a pattern like <code>tokio-*</code>).\n\n\
+                 Example: <code>/unsubscribe serde</code>, <code>/unsubscribe tokio-*</code>"
+            }
+            "subscribe_yanks" => {
+                "/subscribe_yanks &lt;crate&gt;[@registry] — get notified only when versions \
+                 of the crate are yanked or unyanked, without new versions. Use /subscribe \
+                 to switch back to all updates.\n\n\
+                 Example: <code>/subscribe_yanks openssl</code>"
+            }
+            "subscribe_when" => {
+                "/subscribe_when &lt;crate&gt;[@registry] &lt;requirement&gt; — get notified \
+                 once, when the first version matching the semver requirement is published. \
+                 The alert is removed after that.\n\n\
+                 Example: <code>/subscribe_when serde &gt;=2.0</code>"
+            }
+            "subscribe_keyword" => {
+                "/subscribe_keyword &lt;keyword&gt; — subscribe for updates of all crates \
+                 tagged with the crates.io keyword (including crates that will get the \
+                 keyword in the future).\n\n\
+                 Example: <code>/subscribe_keyword cli</code>"
+            }
+            "unsubscribe_keyword" => {
+                "/unsubscribe_keyword &lt;keyword&gt; — unsubscribe from updates of crates \
+                 tagged with the keyword.\n\n\
+                 Example: <code>/unsubscribe_keyword cli</code>"
+            }
+            "subscribe_category" => {
+                "/subscribe_category &lt;category&gt; — subscribe for updates of all crates \
+                 in the crates.io category (and its subcategories), by its slug as in the \
+                 url of the category page.\n\n\
+                 Example: <code>/subscribe_category network-programming</code>"
+            }
+            "unsubscribe_category" => {
+                "/unsubscribe_category &lt;category&gt; — unsubscribe from updates of crates \
+                 in the category.\n\n\
+                 Example: <code>/unsubscribe_category network-programming</code>"
+            }
+            "subscribe_owner" => {
+                "/subscribe_owner &lt;login&gt; — subscribe for updates of all crates owned \
+                 by the crates.io user (including crates they will publish in the future). \
+                 Crates owned only through a team aren't included.\n\n\
+                 Example: <code>/subscribe_owner dtolnay</code>"
+            }
+            "unsubscribe_owner" => {
+                "/unsubscribe_owner &lt;login&gt; — unsubscribe from updates of crates owned \
+                 by the user.\n\n\
+                 Example: <code>/unsubscribe_owner dtolnay</code>"
+            }
+            "watch_new" => {
+                "/watch_new &lt;pattern&gt; — get notified when a new crate (its first \
+                 version) is published on crates.io and its name or one of its keywords \
+                 matches the pattern, e.g. to catch typosquats of your crate. \
+                 <code>*</code> matches any characters, <code>?</code> any single \
+                 character, case and <code>-</code>/<code>_</code> don't matter.\n\n\
+                 Example: <code>/watch_new *serde*</code>, <code>/watch_new tokio?</code>"
+            }
+            "unwatch_new" => {
+                "/unwatch_new &lt;pattern&gt; — stop watching for new crates matching the \
+                 pattern.\n\n\
+                 Example: <code>/unwatch_new *serde*</code>"
+            }
+            "unsubscribe_all" => {
+                "/unsubscribe_all — remove all subscriptions of this chat: crates, keywords, \
+                 categories, owners, patterns, /subscribe_when alerts and /watch_new \
+                 watches. Asks for a confirmation first."
+            }
+            "import" => {
+                "/import &lt;manifest&gt; — subscribe to the crates.io dependencies of a \
+                 <code>Cargo.toml</code> (or to all packages of a <code>Cargo.lock</code>) \
+                 pasted after the command. You can also just send the file to the bot. The \
+                 bot lists the found crates and subscribes to them after a confirmation.\n\n\
+                 Example: <code>/import [dependencies] serde = \"1\" tokio = \"0.2\"</code>"
+            }
+            "export" => {
+                "/export [toml|txt] — get the crates you are subscribed to as a file. By \
+                 default it's a <code>Cargo.toml</code>-like manifest with the current \
+                 versions, which can be sent back to the bot to subscribe again (see \
+                 /import, only crates.io crates are imported back). With <code>txt</code> \
+                 it's a plain list of crates, one per line.\n\n\
+                 Example: <code>/export</code> or <code>/export txt</code>"
+            }
+            "list" => {
+                "/list [#label] — list crates you are subscribed to along with their current \
+                 versions and number of subscribers. With a label only subscriptions with \
+                 this label are listed.\n\n\
+                 Example: <code>/list</code> or <code>/list #work</code>"
+            }
+            "mute" => {
+                "/mute &lt;crate&gt;[@registry] &lt;duration&gt; — don't send notifications \
+                 about the crate for the given time (e.g. <code>12h</code>, <code>7d</code>, \
+                 at most a year), for example during a release storm. The subscription is \
+                 kept, updates published while it's muted are not sent later. \
+                 <code>/mute &lt;crate&gt; off</code> unmutes it right away.\n\n\
+                 Example: <code>/mute aws-sdk-s3 7d</code>"
+            }
+            "pause" => {
+                "/pause — stop all notifications to this chat (e.g. while you are on vacation). \
+                 Your subscriptions are kept, use /resume to get notifications again. By default \
+                 updates published while notifications are paused are not sent later, use \
+                 <code>/pause summary</code> to get them as a single summary after /resume."
+            }
+            "resume" => {
+                "/resume — resume notifications paused with /pause. If they were paused with \
+                 <code>/pause summary</code>, updates published in the meantime are sent shortly."
+            }
+            "links" => {
+                "/links [link]... — choose links shown in your notifications, any of \
+                 <code>docs.rs</code>, <code>crates.io</code>, <code>lib.rs</code> and \
+                 <code>repo</code> (repository of the crate). Without arguments shows the \
+                 current choice, <code>/links default</code> resets it and \
+                 <code>/links none</code> hides all links.\n\n\
+                 Example: <code>/links docs.rs repo</code>"
+            }
+            "digest" => {
+                "/digest daily|weekly|off — instead of a message per update, get all updates \
+                 as a single message once a day (or a week). Alerts of /subscribe_when are \
+                 still sent right away. Without arguments shows the current setting, \
+                 <code>/digest off</code> sends the accumulated updates and switches back to \
+                 a message per update.\n\n\
+                 Example: <code>/digest daily</code>"
+            }
+            "prereleases" => {
+                "/prereleases on|off — whether to get notified about pre-release versions \
+                 (like <code>1.0.0-alpha.1</code> or <code>2.0.0-rc.1</code>) of your \
+                 subscriptions. Subscriptions made with <code>--prereleases</code> or \
+                 <code>--no-prereleases</code> (see /subscribe) ignore this setting. Without \
+                 arguments shows the current setting.\n\n\
+                 Example: <code>/prereleases off</code>"
+            }
+            "diff" => {
+                "/diff &lt;crate&gt; &lt;v1&gt; &lt;v2&gt; — show changes in features, \
+                 dependencies, MSRV and yanked status between two versions of the crate.\n\n\
+                 Both versions must be exact, valid semver versions.\n\n\
+                 Example: <code>/diff serde 1.0.100 1.0.101</code>"
+            }
+            "history" => {
+                "/history &lt;crate&gt; — show recent updates of the crate processed by the bot \
+                 and whether they were delivered to you.\n\n\
+                 Example: <code>/history serde</code>"
+            }
+            "versions" => {
+                "/versions &lt;crate&gt; [n] — show the last <code>n</code> (15 by default, at \
+                 most 50) published versions of the crate, newest first, with their publish \
+                 numbers and dates. Yanked versions are struck through.\n\n\
+                 Example: <code>/versions tokio</code> or <code>/versions tokio 30</code>"
+            }
+            "info" => {
+                "/info &lt;crate&gt;[@registry] — show the description, latest version, number \
+                 of dependencies and downloads of the crate, with links to its repository and \
+                 docs. For registries other than crates.io only what's in the index is shown.\n\n\
+                 Example: <code>/info serde</code>"
+            }
+            "deps" => {
+                "/deps &lt;crate&gt;[@registry] [version] — show direct dependencies of the crate \
+                 with their version requirements, grouped by kind (normal, build and dev). \
+                 Optional dependencies, enabled features and platform-specific dependencies are \
+                 marked. Without a version the latest stable one is used.\n\n\
+                 Example: <code>/deps reqwest</code> or <code>/deps serde 1.0.100</code>"
+            }
+            "check" => {
+                "/check &lt;crate&gt;[@registry] &lt;version&gt; — check whether the exact \
+                 version of the crate was published and whether it's yanked.\n\n\
+                 Example: <code>/check serde 1.0.150</code>"
+            }
+            "search" => {
+                "/search &lt;query&gt; — search crates.io for crates by name, description and \
+                 keywords. Shows the best matches with their latest versions, tap a button \
+                 under the results to subscribe to a crate.\n\n\
+                 Example: <code>/search async http client</code>"
+            }
+            "top" => {
+                "/top — show the crates with the most subscribers (among the users of this bot), \
+                 with their current versions. The list is updated every few minutes."
+            }
+            "whosubscribed" => {
+                "/whosubscribed &lt;crate&gt; [ids] — show the number of chats subscribed to \
+                 the crate and, with <code>ids</code>, their ids. Only available to the bot \
+                 admins.\n\n\
+                 Example: <code>/whosubscribed serde ids</code>"
+            }
+            "gc" => {
+                "/gc — check all chats with subscriptions & remove subscriptions of chats that \
+                 blocked the bot or don't exist anymore. Also reports subscriptions to crates \
+                 missing from the index. Only available to the bot admins."
+            }
+            "feedback" => {
+                "/feedback &lt;text&gt; — send a message to the operator of the bot (e.g. a bug \
+                 report or a feature request). Can be used once a minute.\n\n\
+                 Example: <code>/feedback please add /foo command</code>"
+            }
+            "about" => {
+                "/about — show version of the bot, how long it has been running and the last \
+                 processed commit of every index (to check whether notifications are up to date)."
+            }
+            "help" => {
+                "/help [command] — show the list of commands or detailed help for a command.\n\n\
+                 Example: <code>/help subscribe</code>"
+            }
+            _ => return None,
+        };
+        Some(help)
+    }
+}
+
+/// Number of events shown by `/history`
+const HISTORY_LEN: i32 = 10;
+
+/// Number of versions shown by `/versions` by default
+const VERSIONS_LEN: usize = 15;
+
+/// Maximum number of versions shown by `/versions`
+const MAX_VERSIONS_LEN: usize = 50;
+
+/// Number of crates shown by `/search`
+const SEARCH_RESULTS: u32 = 5;
+
+/// Descriptions in `/search` results are cut to this many characters
+const SEARCH_DESCRIPTION_LEN: usize = 100;
+
+/// Number of crates shown by `/top`
+const TOP_LEN: i32 = 10;
+
+/// Number of results returned for inline queries
+const INLINE_RESULTS: usize = 10;
+
+/// Inline queries are sent on every keystroke, so the bot waits for the user to
+/// stop typing for this long before searching
+const INLINE_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Number of retries of a failed database query before giving up and
+/// reporting the error to the user
+const DB_RETRIES: usize = 3;
+
+/// Delay before the first retry of a database query, doubled on every retry
+const DB_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// Minimum interval between `/feedback`s of the same chat
+const FEEDBACK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Time of the last `/feedback` of every chat, see [`FEEDBACK_INTERVAL`].
+///
+/// [`FEEDBACK_INTERVAL`]: FEEDBACK_INTERVAL
+type LastFeedback = Arc<Mutex<HashMap<ChatId, Instant>>>;
+
+/// `/subscribe` option to only get releases of at least the given level (&
+/// yanks) of the crate, e.g. `--only minor`
+const ONLY_OPTION: &str = "--only";
+
+/// Older `/subscribe` flag, same as `--only major`
+const MAJOR_ONLY_FLAG: &str = "--major-only";
+
+/// `/subscribe` option to only get some kinds of updates of the crate, e.g.
+/// `--events yank`
+const EVENTS_OPTION: &str = "--events";
+
+/// `/subscribe` flags to always get or skip pre-release versions of the crate,
+/// regardless of `/prereleases`
+const PRERELEASES_FLAG: &str = "--prereleases";
+const NO_PRERELEASES_FLAG: &str = "--no-prereleases";
+
+/// `/subscribe` flag to get release notes excerpts with new versions
+const CHANGELOG_FLAG: &str = "--changelog";
+
+/// Maximum duration of `/mute`
+const MAX_MUTE: Duration = Duration::from_secs(365 * 24 * 60 * 60);
 
-In case you want to see <b>all</b> updates go to @crates_updates
+/// Duration of muting with the "Mute for a week" notification button
+const MUTE_BUTTON_DURATION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
 
-Author: @wafflelapkin
-His channel [ru]: @ihatereality
-My source: <a href='https://github.com/WaffleLapkin/crate_upd_bot'>[github]</a>";
+/// Minimum length of the prefix of pattern subscriptions (`/subscribe tokio-*`),
+/// so a pattern can't match most of the registry
+const MIN_PATTERN_PREFIX: usize = 3;
 
-pub async fn setup(bot: Bot, db: Database) {
-    teloxide::commands_repl(bot, todo!(), |cx, cmd: Command| dispatch(cx, cmd, &db)).await;
+/// Maximum length of a subscription label (in characters)
+const MAX_LABEL_LEN: usize = 64;
+
+/// Callback data prefixes of the notification buttons, followed by the crate
+/// (as in commands, e.g. `serde` or `mycrate@internal`)
+const UNSUBSCRIBE_DATA: &str = "u:";
+const SUBSCRIBE_DATA: &str = "s:";
+const MUTE_DATA: &str = "m:";
+const UNMUTE_DATA: &str = "n:";
+/// Subscribe buttons under `/search` results
+const SEARCH_SUBSCRIBE_DATA: &str = "a:";
+/// Buttons of the `/unsubscribe_all` confirmation (not followed by a crate)
+const UNSUBSCRIBE_ALL_DATA: &str = "x:";
+/// Button of the `/import` confirmation (not followed by a crate)
+const IMPORT_DATA: &str = "i:";
+/// Cancels both confirmations
+const CANCEL_DATA: &str = "c:";
+
+/// Maximum size of a manifest sent as a file for `/import` (in bytes)
+const MAX_MANIFEST_SIZE: u32 = 1024 * 1024;
+
+/// Maximum number of crates subscribed to by a single `/import`
+const MAX_IMPORT: usize = 500;
+
+/// Number of crates listed in the `/import` confirmation
+const IMPORT_PREVIEW: usize = 30;
+
+/// Crates found by `/import` waiting for the confirmation, by chat
+type PendingImports = Arc<Mutex<HashMap<ChatId, Vec<String>>>>;
+
+/// Telegram's limit on the callback data length (in bytes)
+const MAX_CALLBACK_DATA: usize = 64;
+
+/// Prefix of the `/start` payload subscribing to the crate that follows it,
+/// i.e. of deep links like `t.me/<bot>?start=sub_serde`
+const SUBSCRIBE_PAYLOAD: &str = "sub_";
+
+/// Telegram's limit on the `/start` payload length (in bytes)
+const MAX_START_PAYLOAD: usize = 64;
+
+/// Latest inline query id of every user, see [`INLINE_DEBOUNCE`].
+///
+/// [`INLINE_DEBOUNCE`]: INLINE_DEBOUNCE
+type LatestQueries = Arc<Mutex<HashMap<i32, String>>>;
+
+/// `started` is the start time of the bot (for `/about`).
+pub async fn setup(
+    bot: Bot,
+    bot_name: String,
+    db: Database,
+    api: CratesIo,
+    config: SharedConfig,
+    started: Instant,
+) {
+    let inline_bot_name = bot_name.clone();
+    let commands_config = config.clone();
+    let callbacks_config = config.clone();
+    let callbacks_db = db.clone();
+    let latest = LatestQueries::default();
+    let feedback = LastFeedback::default();
+    let imports = PendingImports::default();
+    let callbacks_imports = Arc::clone(&imports);
+    Dispatcher::new(bot)
+        .messages_handler(move |rx: DispatcherHandlerRx<Message>| {
+            rx.for_each_concurrent(None, move |cx| {
+                let db = db.clone();
+                let api = api.clone();
+                let config = commands_config.get();
+                let feedback = Arc::clone(&feedback);
+                let imports = Arc::clone(&imports);
+                let cmd = cx
+                    .update
+                    .text()
+                    .map(|text| Command::parse(text, bot_name.as_str()));
+                async move {
+                    let bot = cx.bot.clone();
+                    let chat_id = cx.chat_id();
+                    // other messages are ignored, except for manifests
+                    // sent as files (see `/import`)
+                    let res = match cmd {
+                        Some(Ok(cmd)) => {
+                            dispatch(cx, cmd, &db, &api, &config, &feedback, &imports, started)
+                                .await
+                        }
+                        Some(Err(err)) => malformed_command(cx, err).await,
+                        None => import_document(cx, &imports).await,
+                    };
+                    match res {
+                        Ok(()) => {}
+                        Err(HErr::Bd(err)) => {
+                            log::error!("db error while handling command: {}", err);
+                            bot.send_message(
+                                chat_id,
+                                "Error: database is temporarily unavailable, please try again later.",
+                            )
+                            .send()
+                            .await
+                            .map_err(|err| log::error!("couldn't report db error: {}", err))
+                            .ok();
+                        }
+                        Err(err) => log::error!("error while handling command: {}", err),
+                    }
+                }
+            })
+        })
+        .callback_queries_handler(move |rx: DispatcherHandlerRx<CallbackQuery>| {
+            rx.for_each_concurrent(None, move |cx| {
+                let db = callbacks_db.clone();
+                let config = callbacks_config.get();
+                let imports = Arc::clone(&callbacks_imports);
+                async move {
+                    if let Err(err) = callback(cx, &db, &config, &imports).await {
+                        log::error!("error while handling callback query: {}", err);
+                    }
+                }
+            })
+        })
+        .inline_queries_handler(move |rx: DispatcherHandlerRx<InlineQuery>| {
+            rx.for_each_concurrent(None, move |cx| {
+                let config = config.get();
+                let latest = Arc::clone(&latest);
+                let bot_name = inline_bot_name.clone();
+                async move {
+                    if let Err(err) = inline(cx, &bot_name, &config, &latest).await {
+                        log::error!("error while answering inline query: {}", err);
+                    }
+                }
+            })
+        })
+        .dispatch()
+        .await;
+}
+
+/// "Unsubscribe" & "Mute" (or "Unmute", if the crate is `muted`) buttons
+/// attached to notifications of subscribers. `None` if the crate name is too
+/// long to fit into the callback data.
+pub fn notification_buttons(
+    krate: &str,
+    registry: &RegistryConfig,
+    muted: bool,
+) -> Option<InlineKeyboardMarkup> {
+    let mute = if muted {
+        ("🔔 Unmute", UNMUTE_DATA)
+    } else {
+        ("🔇 Mute for a week", MUTE_DATA)
+    };
+    buttons(
+        &[("🔕 Unsubscribe", UNSUBSCRIBE_DATA), mute],
+        &qualified_name(krate, registry),
+    )
+}
+
+/// Deep link subscribing to the crate of the default registry on tap, `None`
+/// if the crate name is too long to fit into the `/start` payload.
+fn subscribe_link(bot_name: &str, krate: &str) -> Option<String> {
+    let payload = format!("{}{}", SUBSCRIBE_PAYLOAD, krate);
+    if payload.len() > MAX_START_PAYLOAD {
+        return None;
+    }
+    Some(format!("https://t.me/{}?start={}", bot_name, payload))
+}
+
+/// Row of `(text, callback data prefix)` buttons for the crate.
+fn buttons(buttons: &[(&str, &str)], krate: &str) -> Option<InlineKeyboardMarkup> {
+    let mut row = Vec::with_capacity(buttons.len());
+    for (text, prefix) in buttons {
+        let data = format!("{}{}", prefix, krate);
+        if data.len() > MAX_CALLBACK_DATA {
+            return None;
+        }
+        row.push(InlineKeyboardButton::callback((*text).to_owned(), data));
+    }
+    Some(InlineKeyboardMarkup::default().append_row(row))
+}
+
+/// Handle taps on notification buttons: unsubscribe from the crate (and
+/// subscribe back, in case of a mistake), mute it for a week or unmute it.
+/// Also handles subscribe buttons under `/search` results and the
+/// `/unsubscribe_all` & `/import` confirmations.
+async fn callback(
+    cx: UpdateWithCx<CallbackQuery>,
+    db: &Database,
+    config: &Config,
+    imports: &PendingImports,
+) -> Result<(), HErr> {
+    let query = &cx.update;
+    let (message, data) = match (&query.message, &query.data) {
+        (Some(message), Some(data)) => (message, data),
+        _ => return Ok(()),
+    };
+    if data == IMPORT_DATA {
+        return confirm_import(&cx, message, db, config, imports).await;
+    }
+    if data == CANCEL_DATA {
+        imports.lock().unwrap().remove(&ChatId(message.chat.id));
+    }
+    if data == UNSUBSCRIBE_ALL_DATA || data == CANCEL_DATA {
+        return confirm_unsubscribe_all(&cx, message, data == UNSUBSCRIBE_ALL_DATA, db).await;
+    }
+    let from_search = data.starts_with(SEARCH_SUBSCRIBE_DATA);
+    let subscribe = from_search || data.starts_with(SUBSCRIBE_DATA);
+    let mute = data.starts_with(MUTE_DATA);
+    let unmute = data.starts_with(UNMUTE_DATA);
+    if !subscribe && !mute && !unmute && !data.starts_with(UNSUBSCRIBE_DATA) {
+        return Ok(());
+    }
+    // all prefixes have the same length
+    let (krate, registry) = match parse_crate(&data[UNSUBSCRIBE_DATA.len()..], config) {
+        Ok(parsed) => parsed,
+        Err(_) => return Ok(()),
+    };
+    let chat_id = ChatId(message.chat.id);
+    let name = qualified_name(krate, registry);
+
+    let (text, keyboard) = if subscribe {
+        let existing = retry(|| db.has_subscription(chat_id, &registry.name, krate)).await?;
+        if from_search && existing {
+            // the message has buttons of other crates too, so it's not edited
+            cx.bot
+                .answer_callback_query(query.id.clone())
+                .text(format!("You're already subscribed to {}.", name))
+                .send()
+                .await?;
+            return Ok(());
+        }
+        let limit_reached = !existing
+            && !config.admins.contains(&i64::from(query.from.id))
+            && retry(|| db.subscription_count(chat_id)).await? >= config.max_subscriptions;
+        if limit_reached {
+            let text = format!(
+                "Sorry, you've reached the limit of {} subscriptions.",
+                config.max_subscriptions
+            );
+            cx.bot
+                .answer_callback_query(query.id.clone())
+                .text(text)
+                .send()
+                .await?;
+            return Ok(());
+        }
+        retry(|| {
+            db.subscribe(
+                chat_id,
+                &registry.name,
+                krate,
+                Events::ALL,
+                Level::Patch,
+                None,
+                false,
+                None,
+            )
+        })
+        .await?;
+        if from_search {
+            cx.bot
+                .answer_callback_query(query.id.clone())
+                .text(format!("You've subscribed to {}.", name))
+                .send()
+                .await?;
+            return Ok(());
+        }
+        (
+            format!("You've subscribed back to {}.", name),
+            notification_buttons(krate, registry, false),
+        )
+    } else if mute || unmute {
+        // can't overflow, the duration is a constant
+        let until =
+            Some(chrono::Utc::now() + chrono::Duration::from_std(MUTE_BUTTON_DURATION).unwrap())
+                .filter(|_| mute);
+        if !retry(|| db.mute(chat_id, &registry.name, krate, until)).await? {
+            cx.bot
+                .answer_callback_query(query.id.clone())
+                .text(format!("You aren't subscribed to {}.", name))
+                .send()
+                .await?;
+            return Ok(());
+        }
+        let text = match until {
+            Some(until) => format!(
+                "{} is muted until {} UTC.",
+                name,
+                until.format("%Y-%m-%d %H:%M")
+            ),
+            None => format!("{} is unmuted.", name),
+        };
+        (text, notification_buttons(krate, registry, mute))
+    } else {
+        retry(|| db.unsubscribe(chat_id, &registry.name, krate)).await?;
+        (
+            format!("You've unsubscribed from {}.", name),
+            buttons(&[("🔔 Subscribe back", SUBSCRIBE_DATA)], &name),
+        )
+    };
+
+    cx.bot
+        .answer_callback_query(query.id.clone())
+        .text(text)
+        .send()
+        .await?;
+    let target = ChatOrInlineMessage::Chat {
+        chat_id: chat_id.into(),
+        message_id: message.id,
+    };
+    let mut request = cx.bot.edit_message_reply_markup(target);
+    if let Some(keyboard) = keyboard {
+        request = request.reply_markup(keyboard);
+    }
+    request.send().await?;
+    Ok(())
+}
+
+/// Handle the answer to the `/unsubscribe_all` confirmation, the question is
+/// replaced with the result (removing the buttons).
+async fn confirm_unsubscribe_all(
+    cx: &UpdateWithCx<CallbackQuery>,
+    message: &Message,
+    confirmed: bool,
+    db: &Database,
+) -> Result<(), HErr> {
+    let chat_id = ChatId(message.chat.id);
+    let text = if confirmed {
+        retry(|| db.unsubscribe_all(chat_id)).await?;
+        "You've unsubscribed from everything. Use /subscribe to subscribe to some crate."
+    } else {
+        "Cancelled, your subscriptions are kept."
+    };
+
+    cx.bot
+        .answer_callback_query(cx.update.id.clone())
+        .send()
+        .await?;
+    let target = ChatOrInlineMessage::Chat {
+        chat_id: chat_id.into(),
+        message_id: message.id,
+    };
+    cx.bot.edit_message_text(target, text).send().await?;
+    Ok(())
+}
+
+/// Handle the confirmation of `/import`: subscribe to the crates found in the
+/// manifest & report the results.
+async fn confirm_import(
+    cx: &UpdateWithCx<CallbackQuery>,
+    message: &Message,
+    db: &Database,
+    config: &Config,
+    imports: &PendingImports,
+) -> Result<(), HErr> {
+    let query = &cx.update;
+    let chat_id = ChatId(message.chat.id);
+    let names = imports.lock().unwrap().remove(&chat_id);
+    let mut request = cx.bot.answer_callback_query(query.id.clone());
+    if names.is_none() {
+        // the bot was restarted or there was a newer `/import`
+        request = request.text("This import has expired, please send the manifest again.");
+    }
+    request.send().await?;
+    let target = ChatOrInlineMessage::Chat {
+        chat_id: chat_id.into(),
+        message_id: message.id,
+    };
+    cx.bot.edit_message_reply_markup(target).send().await?;
+    let names = match names {
+        Some(names) => names,
+        None => return Ok(()),
+    };
+
+    let admin = config.admins.contains(&i64::from(query.from.id));
+    let args: Vec<_> = names.iter().map(String::as_str).collect();
+    let lines = subscribe_all(
+        chat_id,
+        admin,
+        &args,
+        db,
+        config,
+        Events::ALL,
+        Level::Patch,
+        None,
+        false,
+        None,
+    )
+    .await?;
+    let lines: Vec<_> = lines.iter().map(|line| format!("— {}", line)).collect();
+    let lines: Vec<_> = lines.iter().map(String::as_str).collect();
+    for (text, _) in channel::split("📥 Import results:", &lines) {
+        cx.bot.send_message(message.chat.id, text).send().await?;
+    }
+    Ok(())
 }
 
-async fn dispatch(cx: UpdateWithCx<Message>, cmd: Command, db: &Database) -> Result<(), HErr> {
+/// Append a `/list` section: an empty line (unless it's the first section),
+/// the `title` & the `items`. Nothing is added if there are no `items`.
+fn list_section(out: &mut Vec<String>, title: &str, items: impl Iterator<Item = String>) {
+    let mut items = items.peekable();
+    if items.peek().is_none() {
+        return;
+    }
+    if !out.is_empty() {
+        out.push(String::new());
+    }
+    out.push(String::from(title));
+    out.extend(items);
+}
+
+/// Answer inline query with crates from the default registry whose names
+/// contain the query. Every result has a "Subscribe" deep link, so a crate
+/// shared in any chat can be followed in one tap.
+async fn inline(
+    cx: UpdateWithCx<InlineQuery>,
+    bot_name: &str,
+    config: &Config,
+    latest: &LatestQueries,
+) -> Result<(), HErr> {
+    let query = cx.update.query.trim().to_lowercase();
+    if query.is_empty() {
+        return Ok(());
+    }
+
+    let user = cx.update.from.id;
+    let id = cx.update.id.clone();
+    latest.lock().unwrap().insert(user, id.clone());
+    tokio::time::delay_for(INLINE_DEBOUNCE).await;
+    {
+        let mut latest = latest.lock().unwrap();
+        if latest.get(&user) != Some(&id) {
+            // the user kept typing, the newer query will be answered instead
+            return Ok(());
+        }
+        latest.remove(&user);
+    }
+
+    let index = &config.default_registry().index_path;
+    let names = Crate::search(index, &query, INLINE_RESULTS)
+        .await
+        .map_err(|err| log::error!("couldn't search the index: {}", err))
+        .unwrap_or_default();
+
+    let mut results = Vec::with_capacity(names.len());
+    for name in names {
+        let krate = match Crate::latest_stable(index, &name).await {
+            Ok(krate) => krate,
+            Err(_) => continue, /* silently ignore error & just don't show the crate */
+        };
+        let deps = krate.deps.iter().filter(|dep| dep.is_normal()).count();
+        let mut info = match deps {
+            1 => String::from("1 dependency"),
+            n => format!("{} dependencies", n),
+        };
+        if let Some(pubtime) = krate.pubtime {
+            info.push_str(&format!(", published {}", pubtime.format("%Y-%m-%d")));
+        }
+        let text = format!(
+            "<code>{}#{}</code> {}\n{}",
+            krate.id.name,
+            krate.id.vers,
+            krate.html_links(),
+            info,
+        );
+        let content = InputMessageContent::Text(
+            InputMessageContentText::new(text)
+                .parse_mode(ParseMode::HTML)
+                .disable_web_page_preview(true),
+        );
+        let title = format!("{} {}", krate.id.name, krate.id.vers);
+        let mut article =
+            InlineQueryResultArticle::new(krate.id.name.clone(), title, content).description(info);
+        if let Some(url) = subscribe_link(bot_name, &krate.id.name) {
+            let button = InlineKeyboardButton::url(String::from("🔔 Subscribe"), url);
+            article =
+                article.reply_markup(InlineKeyboardMarkup::default().append_row(vec![button]));
+        }
+        results.push(InlineQueryResult::Article(article));
+    }
+
+    cx.bot
+        .answer_inline_query(id, results)
+        .cache_time(60)
+        .send()
+        .await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip(cx, db, api, config, imports), fields(chat_id = cx.chat_id()))]
+#[allow(clippy::too_many_arguments)]
+async fn dispatch(
+    cx: UpdateWithCx<Message>,
+    cmd: Command,
+    db: &Database,
+    api: &CratesIo,
+    config: &Config,
+    feedback: &LastFeedback,
+    imports: &PendingImports,
+    started: Instant,
+) -> Result<(), HErr> {
+    let chat_id = ChatId(cx.chat_id());
+    // deep links (`t.me/<bot>?start=sub_serde`) subscribe to the crate
+    let cmd = match cmd {
+        Command::Start(payload) if payload.starts_with(SUBSCRIBE_PAYLOAD) => {
+            Command::Subscribe(payload[SUBSCRIBE_PAYLOAD.len()..].to_owned())
+        }
+        cmd => cmd,
+    };
     match cmd {
-        Command::Start => {
-            cx.answer_str(START_MESSAGE).await?;
-        }
-        Command::Subscribe(crate_name) => {
-            let krate = crate_name.as_str();
-            if PathBuf::from("./index")
-                .also(|p| p.push(crate_path(krate)))
-                .exists()
-            {
-                db.subscribe(cx.chat_id(), krate).await?;
-                let v = match Crate::read_last(krate).await {
+        Command::Start(_) => {
+            cx.answer_str(&config.start_message).await?;
+        }
+        Command::Subscribe(arg) => {
+            let (arg, label) = split_label(&arg);
+            if label.map_or(false, |l| l.chars().count() > MAX_LABEL_LEN) {
+                let text = format!(
+                    "Error: labels can't be longer than {} characters.",
+                    MAX_LABEL_LEN
+                );
+                cx.answer_str(text).await?;
+                return Ok(());
+            }
+            // crates may be separated by spaces or commas (`/subscribe serde, tokio`)
+            let mut args: Vec<_> = arg
+                .split(|c: char| c.is_whitespace() || c == ',')
+                .filter(|arg| !arg.is_empty())
+                .collect();
+            args.dedup();
+            let min_level = match take_option(&mut args, ONLY_OPTION) {
+                None if take_flag(&mut args, MAJOR_ONLY_FLAG) => Level::Major,
+                None => Level::Patch,
+                Some(Some(level)) => match Level::parse(level) {
+                    Some(level) => level,
+                    None => {
+                        let text = format!("Error: unknown release level <code>{}</code>, use <code>--only minor</code> or <code>--only major</code>.", escape_html(level));
+                        cx.answer_str(text).await?;
+                        return Ok(());
+                    }
+                },
+                Some(None) => {
+                    cx.answer_str("Error: <code>--only</code> needs a release level, e.g. <code>/subscribe serde --only minor</code>.").await?;
+                    return Ok(());
+                }
+            };
+            let events = match take_option(&mut args, EVENTS_OPTION) {
+                None => Events::ALL,
+                Some(Some(events)) => match Events::parse(events) {
+                    Some(events) => events,
+                    None => {
+                        let text = format!("Error: unknown event <code>{}</code>, use <code>--events update</code> or <code>--events yank</code>.", escape_html(events));
+                        cx.answer_str(text).await?;
+                        return Ok(());
+                    }
+                },
+                Some(None) => {
+                    cx.answer_str("Error: <code>--events</code> needs a kind of updates, e.g. <code>/subscribe openssl --events yank</code>.").await?;
+                    return Ok(());
+                }
+            };
+            let prereleases = match (
+                take_flag(&mut args, PRERELEASES_FLAG),
+                take_flag(&mut args, NO_PRERELEASES_FLAG),
+            ) {
+                (true, true) => {
+                    cx.answer_str("Error: <code>--prereleases</code> and <code>--no-prereleases</code> can't be used together.").await?;
+                    return Ok(());
+                }
+                (true, false) => Some(true),
+                (false, true) => Some(false),
+                (false, false) => None,
+            };
+            let changelog = take_flag(&mut args, CHANGELOG_FLAG);
+            if args.len() > 1 {
+                return subscribe_many(
+                    &cx,
+                    &args,
+                    db,
+                    config,
+                    events,
+                    min_level,
+                    prereleases,
+                    changelog,
+                    label,
+                )
+                .await;
+            }
+            let has_options = events != Events::ALL
+                || min_level != Level::Patch
+                || prereleases.is_some()
+                || changelog
+                || label.is_some();
+            if let Some(pattern) = args.first().and_then(|arg| parse_pattern(arg, config)) {
+                let text = match pattern {
+                    Err(text) => text,
+                    Ok(_) if has_options => String::from(
+                        "Error: flags and labels aren't supported for patterns, e.g. <code>/subscribe tokio-*</code>.",
+                    ),
+                    Ok((prefix, registry)) => {
+                        retry(|| db.subscribe_pattern(chat_id, &registry.name, &prefix)).await?;
+                        format!(
+                            "You've successfully subscribed for updates on all <code>{}</code> crates, including future ones. Use /unsubscribe to unsubscribe.",
+                            escape_html(&qualified_name(&format!("{}*", prefix), registry))
+                        )
+                    }
+                };
+                cx.answer_str(text).await?;
+                return Ok(());
+            }
+
+            let arg = args.first().copied().unwrap_or_default();
+            let (krate, registry) = match parse_crate(arg, config) {
+                Ok(parsed) => parsed,
+                Err(text) => {
+                    cx.answer_str(text).await?;
+                    return Ok(());
+                }
+            };
+            let name = qualified_name(krate, registry);
+            let subscribed = retry(|| {
+                db.is_subscribed(
+                    chat_id,
+                    &registry.name,
+                    krate,
+                    events,
+                    min_level,
+                    prereleases,
+                    changelog,
+                )
+            })
+            .await?;
+            // subscribing again with a label just changes the label
+            if subscribed && label.is_none() {
+                let text = format!(
+                    "You're already subscribed to <code>{}</code>. Use /list to see all your subscriptions.",
+                    escape_html(&name)
+                );
+                cx.answer_str(text).await?;
+                return Ok(());
+            }
+            // changing options of a subscription doesn't need a free slot
+            let limit_reached = !is_admin(&cx, config).unwrap_or(false)
+                && !retry(|| db.has_subscription(chat_id, &registry.name, krate)).await?
+                && retry(|| db.subscription_count(chat_id)).await? >= config.max_subscriptions;
+            if limit_reached {
+                let text = format!("Sorry, you've reached the limit of {} subscriptions. Use /unsubscribe to free some space.", config.max_subscriptions);
+                cx.answer_str(text).await?;
+            } else if Crate::exists(&registry.index_path, krate).await {
+                retry(|| {
+                    db.subscribe(
+                        chat_id,
+                        &registry.name,
+                        krate,
+                        events,
+                        min_level,
+                        prereleases,
+                        changelog,
+                        label,
+                    )
+                })
+                .await?;
+                let v = match Crate::latest_stable(&registry.index_path, krate).await {
                     Ok(krate) => format!(
                         " (current version <code>{}</code> {})",
                         krate.id.vers,
-                        krate.html_links()
+                        links(&krate, registry)
                     ),
                     Err(_) => String::new(),
                 };
-                let text = format!("You've successfully subscribed for updates on <code>{}</code>{} crate. Use /unsubscribe to unsubscribe.", krate, v);
+                let count = retry(|| db.subscriber_count(&registry.name, krate)).await?;
+                let options: Vec<_> = events_text(events)
+                    .into_iter()
+                    .chain(level_text(min_level))
+                    .chain(prereleases.map(prereleases_text))
+                    .chain(Some("with release notes").filter(|_| changelog))
+                    .collect();
+                let only = if options.is_empty() {
+                    String::new()
+                } else {
+                    format!(" ({})", options.join(", "))
+                };
+                let labeled = label
+                    .map(|l| format!(" with label <b>#{}</b>", escape_html(l)))
+                    .unwrap_or_default();
+                let text = format!("You've successfully subscribed for updates{} on <code>{}</code>{} crate ({}){}. Use /unsubscribe to unsubscribe.", only, name, v, subscribers(count), labeled);
                 cx.answer(text)
                     .disable_web_page_preview(true)
                     .send()
                     .await?;
             } else {
-                let text = format!("Error: there is no such crate <code>{}</code>.", krate);
+                let text = format!(
+                    "Error: there is no such crate <code>{}</code>.",
+                    escape_html(&name)
+                );
                 cx.answer_str(text).await?;
             }
         }
-        Command::Unsubscribe(crate_name) => {
-            let krate = crate_name.as_str();
-            db.unsubscribe(cx.chat_id(), krate).await?;
-            let text = format!("You've successfully unsubscribed for updates on <code>{}</code> crate. Use /subscribe to subscribe back.", krate);
+        Command::Unsubscribe(arg) => {
+            if let Some(pattern) = parse_pattern(&arg, config) {
+                let text = match pattern {
+                    Err(text) => text,
+                    Ok((prefix, registry)) => {
+                        let name = escape_html(&qualified_name(&format!("{}*", prefix), registry));
+                        if retry(|| db.unsubscribe_pattern(chat_id, &registry.name, &prefix))
+                            .await?
+                        {
+                            format!("You've successfully unsubscribed for updates on <code>{}</code> crates.", name)
+                        } else {
+                            format!("You weren't subscribed to <code>{}</code> crates. Use /list to see your subscriptions.", name)
+                        }
+                    }
+                };
+                cx.answer_str(text).await?;
+                return Ok(());
+            }
+            let (krate, registry) = match parse_crate(&arg, config) {
+                Ok(parsed) => parsed,
+                Err(text) => {
+                    cx.answer_str(text).await?;
+                    return Ok(());
+                }
+            };
+            let name = escape_html(&qualified_name(krate, registry));
+            let text = if retry(|| db.unsubscribe(chat_id, &registry.name, krate)).await? {
+                format!("You've successfully unsubscribed for updates on <code>{}</code> crate. Use /subscribe to subscribe back.", name)
+            } else {
+                format!("You weren't subscribed to <code>{}</code> crate. Use /list to see your subscriptions.", name)
+            };
             cx.answer_str(text).await?;
         }
-        Command::List => {
-            let mut subscriptions = db.list_subscriptions(cx.chat_id()).await?;
-            for sub in &mut subscriptions {
-                match Crate::read_last(sub).await {
-                    Ok(krate) => {
-                        sub.push('#');
-                        sub.push_str(&krate.id.vers);
-                        sub.push_str("</code> ");
-                        sub.push_str(&krate.html_links());
+        Command::SubscribeYanks(arg) => {
+            let (krate, registry) = match parse_crate(&arg, config) {
+                Ok(parsed) => parsed,
+                Err(text) => {
+                    cx.answer_str(text).await?;
+                    return Ok(());
+                }
+            };
+            let name = escape_html(&qualified_name(krate, registry));
+            if retry(|| {
+                db.is_subscribed(
+                    chat_id,
+                    &registry.name,
+                    krate,
+                    Events::YANKS,
+                    Level::Patch,
+                    None,
+                    false,
+                )
+            })
+            .await?
+            {
+                let text = format!(
+                    "You're already subscribed to yanks of <code>{}</code>. Use /list to see all your subscriptions.",
+                    name
+                );
+                cx.answer_str(text).await?;
+                return Ok(());
+            }
+            // switching an existing subscription doesn't take more space
+            let limit_reached = !is_admin(&cx, config).unwrap_or(false)
+                && !retry(|| {
+                    db.is_subscribed(
+                        chat_id,
+                        &registry.name,
+                        krate,
+                        Events::ALL,
+                        Level::Patch,
+                        None,
+                        false,
+                    )
+                })
+                .await?
+                && retry(|| db.subscription_count(chat_id)).await? >= config.max_subscriptions;
+            let text = if limit_reached {
+                format!("Sorry, you've reached the limit of {} subscriptions. Use /unsubscribe to free some space.", config.max_subscriptions)
+            } else if Crate::exists(&registry.index_path, krate).await {
+                retry(|| db.subscribe_yanks(chat_id, &registry.name, krate)).await?;
+                format!("You've successfully subscribed for yanks of <code>{}</code> crate, new versions won't be sent. Use /subscribe to get all updates.", name)
+            } else {
+                format!("Error: there is no such crate <code>{}</code>.", name)
+            };
+            cx.answer_str(text).await?;
+        }
+        Command::SubscribeWhen(args) => {
+            let mut args = args.trim().splitn(2, char::is_whitespace);
+            let arg = args.next().unwrap_or_default();
+            let req = args.next().unwrap_or_default().trim();
+            let (krate, registry) = match parse_crate(arg, config) {
+                Ok(parsed) => parsed,
+                Err(text) => {
+                    cx.answer_str(text).await?;
+                    return Ok(());
+                }
+            };
+            let name = escape_html(&qualified_name(krate, registry));
+            let parsed = match VersionReq::parse(req) {
+                Ok(parsed) if !req.is_empty() => parsed,
+                _ => {
+                    let text = "Error: requirement must be a valid semver requirement, e.g. <code>/subscribe_when serde &gt;=2.0</code>.";
+                    cx.answer_str(text).await?;
+                    return Ok(());
+                }
+            };
+
+            let text = match Crate::read_newest(&registry.index_path, krate).await {
+                Err(_) => format!("Error: there is no such crate <code>{}</code>.", name),
+                Ok(newest) if newest.id.version().map_or(false, |v| parsed.matches(&v)) => {
+                    format!(
+                        "Version <code>{}</code> of <code>{}</code> already matches <code>{}</code>.",
+                        newest.id.vers,
+                        name,
+                        escape_html(req)
+                    )
+                }
+                Ok(_) => {
+                    retry(|| db.add_alert(chat_id, &registry.name, krate, req)).await?;
+                    format!(
+                        "You'll be notified once, when a version of <code>{}</code> matching <code>{}</code> is published.",
+                        name,
+                        escape_html(req)
+                    )
+                }
+            };
+            cx.answer_str(text).await?;
+        }
+        Command::SubscribeKeyword(keyword) => {
+            let keyword = keyword.trim().to_lowercase();
+            if is_valid_keyword(&keyword) {
+                retry(|| db.subscribe_keyword(chat_id, &keyword)).await?;
+
+                // Fetch crates of the keyword right away, instead of waiting
+                // for the next periodic refresh
+                let (api, db, kw) = (api.clone(), db.clone(), keyword.clone());
+                tokio::spawn(async move { keywords::refresh(&api, &db, &kw).await });
+
+                let text = format!("You've successfully subscribed for updates on crates with <code>{}</code> keyword. Use /unsubscribe_keyword to unsubscribe.", keyword);
+                cx.answer_str(text).await?;
+            } else {
+                let text = format!(
+                    "Error: <code>{}</code> is not a valid keyword.",
+                    escape_html(&keyword)
+                );
+                cx.answer_str(text).await?;
+            }
+        }
+        Command::UnsubscribeKeyword(keyword) => {
+            let keyword = keyword.trim().to_lowercase();
+            retry(|| db.unsubscribe_keyword(chat_id, &keyword)).await?;
+            let text = format!("You've successfully unsubscribed for updates on crates with <code>{}</code> keyword. Use /subscribe_keyword to subscribe back.", escape_html(&keyword));
+            cx.answer_str(text).await?;
+        }
+        Command::SubscribeCategory(category) => {
+            // also accept links to category pages
+            let category = category.trim().trim_end_matches('/').to_lowercase();
+            let category = category.rsplit('/').next().unwrap_or_default();
+            if !is_valid_category(category) {
+                let text = format!(
+                    "Error: <code>{}</code> is not a valid category, see https://crates.io/categories.",
+                    escape_html(category)
+                );
+                cx.answer_str(text).await?;
+                return Ok(());
+            }
+            let text = match api.category_exists(category).await {
+                Ok(true) => {
+                    retry(|| db.subscribe_category(chat_id, category)).await?;
+
+                    // Fetch crates of the category right away, instead of
+                    // waiting for the next periodic refresh
+                    let (api, db, cat) = (api.clone(), db.clone(), category.to_owned());
+                    tokio::spawn(async move { keywords::refresh_category(&api, &db, &cat).await });
+
+                    format!("You've successfully subscribed for updates on crates in <code>{}</code> category. Use /unsubscribe_category to unsubscribe.", category)
+                }
+                Ok(false) => format!(
+                    "Error: there is no category <code>{}</code>, see https://crates.io/categories.",
+                    category
+                ),
+                Err(err) => {
+                    log::warn!("couldn't check category {}: {}", category, err);
+                    String::from("Error: couldn't check the category on crates.io, please try again later.")
+                }
+            };
+            cx.answer_str(text).await?;
+        }
+        Command::UnsubscribeCategory(category) => {
+            let category = category.trim().to_lowercase();
+            retry(|| db.unsubscribe_category(chat_id, &category)).await?;
+            let text = format!("You've successfully unsubscribed for updates on crates in <code>{}</code> category. Use /subscribe_category to subscribe back.", escape_html(&category));
+            cx.answer_str(text).await?;
+        }
+        Command::SubscribeOwner(login) => {
+            let owner = login.trim().trim_start_matches('@').to_lowercase();
+            if !is_valid_login(&owner) {
+                let text = format!(
+                    "Error: <code>{}</code> is not a valid crates.io user login.",
+                    escape_html(&owner)
+                );
+                cx.answer_str(text).await?;
+                return Ok(());
+            }
+            // crates are fetched right away, both to check that the user
+            // exists & to not wait for the next periodic refresh
+            let text = match api.owner_crates(&owner).await {
+                Ok(Some(crates)) => {
+                    retry(|| db.subscribe_owner(chat_id, &owner)).await?;
+                    retry(|| db.set_owner_crates(&owner, &crates)).await?;
+                    format!("You've successfully subscribed for updates on crates of <code>{}</code> ({} currently). Use /unsubscribe_owner to unsubscribe.", owner, crates.len())
+                }
+                Ok(None) => format!("Error: there is no crates.io user <code>{}</code>.", owner),
+                Err(err) => {
+                    log::warn!("couldn't get crates of owner {}: {}", owner, err);
+                    String::from("Error: couldn't get crates of the user from crates.io, please try again later.")
+                }
+            };
+            cx.answer_str(text).await?;
+        }
+        Command::UnsubscribeAll => {
+            let keyboard = buttons(
+                &[
+                    ("🗑 Yes, unsubscribe", UNSUBSCRIBE_ALL_DATA),
+                    ("Cancel", CANCEL_DATA),
+                ],
+                "",
+            );
+            let text = "Are you sure you want to remove all subscriptions of this chat (crates, keywords, categories, owners, patterns, alerts and watches)? This can't be undone.";
+            let mut request = cx.answer(text);
+            if let Some(keyboard) = keyboard {
+                request = request.reply_markup(keyboard);
+            }
+            request.send().await?;
+        }
+        Command::Import(manifest) => {
+            if manifest.trim().is_empty() {
+                let text = "Send <code>Cargo.toml</code> or <code>Cargo.lock</code> as a file, or paste it after the command: <code>/import [dependencies] serde = \"1\"</code>.";
+                cx.answer_str(text).await?;
+                return Ok(());
+            }
+            offer_import(&cx, &manifest, imports).await?;
+        }
+        Command::Export(format) => {
+            let toml = match format.trim().to_lowercase().as_str() {
+                "" | "toml" => true,
+                "txt" => false,
+                _ => {
+                    cx.answer_str("Error: expected <code>toml</code> or <code>txt</code>, e.g. <code>/export txt</code>.")
+                        .await?;
+                    return Ok(());
+                }
+            };
+            let subscriptions = retry(|| db.list_subscriptions(chat_id, None)).await?;
+            if subscriptions.is_empty() {
+                cx.answer_str("You don't have subscriptions to export. Use /subscribe to subscribe to some crate.")
+                    .await?;
+                return Ok(());
+            }
+
+            let (file_name, contents) = if toml {
+                (
+                    "subscriptions.toml",
+                    export_toml(&subscriptions, config).await,
+                )
+            } else {
+                let names: Vec<_> = subscriptions
+                    .iter()
+                    .map(|sub| match config.registry(&sub.registry) {
+                        Some(registry) => qualified_name(&sub.krate, registry),
+                        None => format!("{}@{}", sub.krate, sub.registry),
+                    })
+                    .collect();
+                ("subscriptions.txt", names.join("\n") + "\n")
+            };
+            let file = InputFile::Memory {
+                file_name: file_name.to_owned(),
+                data: contents.into_bytes().into(),
+            };
+            cx.bot.send_document(cx.chat_id(), file).send().await?;
+        }
+        Command::UnsubscribeOwner(login) => {
+            let owner = login.trim().trim_start_matches('@').to_lowercase();
+            retry(|| db.unsubscribe_owner(chat_id, &owner)).await?;
+            let text = format!("You've successfully unsubscribed for updates on crates of <code>{}</code>. Use /subscribe_owner to subscribe back.", escape_html(&owner));
+            cx.answer_str(text).await?;
+        }
+        Command::WatchNew(pattern) => {
+            let text = match watches::parse_pattern(&pattern) {
+                Some(pattern) => {
+                    retry(|| db.watch_new(chat_id, &pattern)).await?;
+                    format!("You'll be notified about new crates matching <code>{}</code>. Use /unwatch_new to stop.", pattern)
+                }
+                None => format!(
+                    "Error: <code>{}</code> is not a valid pattern, it must be a crate name or keyword with <code>*</code> or <code>?</code> wildcards and at least {} other characters, e.g. <code>/watch_new *serde*</code>.",
+                    escape_html(pattern.trim()),
+                    watches::MIN_LITERAL_CHARS
+                ),
+            };
+            cx.answer_str(text).await?;
+        }
+        Command::UnwatchNew(pattern) => {
+            let found = match watches::parse_pattern(&pattern) {
+                Some(pattern) => retry(|| db.unwatch_new(chat_id, &pattern)).await?,
+                None => false,
+            };
+            let text = if found {
+                format!(
+                    "You won't be notified about new crates matching <code>{}</code> anymore.",
+                    escape_html(pattern.trim())
+                )
+            } else {
+                format!(
+                    "You weren't watching for <code>{}</code>. Use /list to see your watches.",
+                    escape_html(pattern.trim())
+                )
+            };
+            cx.answer_str(text).await?;
+        }
+        Command::Mute(arg) => {
+            let mut args = arg.split_whitespace();
+            let (krate, registry) = match parse_crate(args.next().unwrap_or_default(), config) {
+                Ok(parsed) => parsed,
+                Err(text) => {
+                    cx.answer_str(text).await?;
+                    return Ok(());
+                }
+            };
+            let name = escape_html(&qualified_name(krate, registry));
+            let duration = args.next().unwrap_or_default();
+            let until = match duration {
+                "off" => None,
+                duration => match humantime::parse_duration(duration) {
+                    Ok(duration) if duration <= MAX_MUTE => {
+                        // can't overflow, as the duration is limited
+                        let duration = chrono::Duration::from_std(duration).unwrap();
+                        Some(chrono::Utc::now() + duration)
+                    }
+                    Ok(_) => {
+                        cx.answer_str("Error: a crate can be muted for at most a year.")
+                            .await?;
+                        return Ok(());
                     }
                     Err(_) => {
-                        sub.push_str(" </code>");
+                        let text = "Error: expected a duration like <code>12h</code> or <code>7d</code>, e.g. <code>/mute serde 7d</code>.";
+                        cx.answer_str(text).await?;
+                        return Ok(());
+                    }
+                },
+            };
+            let text = if !retry(|| db.mute(chat_id, &registry.name, krate, until)).await? {
+                format!("You aren't subscribed to <code>{}</code> crate. Use /list to see your subscriptions.", name)
+            } else if let Some(until) = until {
+                format!(
+                    "Notifications about <code>{}</code> are muted until {} UTC. Use <code>/mute {} off</code> to unmute it.",
+                    name,
+                    until.format("%Y-%m-%d %H:%M"),
+                    name
+                )
+            } else {
+                format!("Notifications about <code>{}</code> are unmuted.", name)
+            };
+            cx.answer_str(text).await?;
+        }
+        Command::Pause(arg) => {
+            let catch_up = match arg.trim().to_lowercase().as_str() {
+                "" | "drop" => false,
+                "summary" => true,
+                _ => {
+                    cx.answer_str("Error: expected <code>summary</code> or <code>drop</code>, e.g. <code>/pause summary</code>.")
+                        .await?;
+                    return Ok(());
+                }
+            };
+            retry(|| db.pause(chat_id, catch_up)).await?;
+            let text = if catch_up {
+                "Notifications are paused, updates will be sent as a summary after /resume."
+            } else {
+                "Notifications are paused. Use /resume to resume them."
+            };
+            cx.answer_str(text).await?;
+        }
+        Command::Resume => {
+            retry(|| db.resume(chat_id)).await?;
+            cx.answer_str("Notifications are resumed. Use /pause to pause them again.")
+                .await?;
+        }
+        Command::Links(arg) => {
+            let args: Vec<_> = arg.split_whitespace().collect();
+            let text = match args.as_slice() {
+                [] => {
+                    let links = retry(|| db.list_links(&[chat_id])).await?;
+                    match links.get(&chat_id) {
+                        Some(links) => format!("Your notifications show {}.", link_names(links)),
+                        None => format!(
+                            "Your notifications show {} (default).",
+                            link_names(&config.links)
+                        ),
+                    }
+                }
+                ["default"] => {
+                    retry(|| db.set_links(chat_id, None)).await?;
+                    format!(
+                        "Your notifications will show the default links: {}.",
+                        link_names(&config.links)
+                    )
+                }
+                args => {
+                    let parsed: Option<Vec<_>> = match args {
+                        ["none"] => Some(Vec::new()),
+                        args => args
+                            .iter()
+                            .map(|a| Link::parse(&a.to_lowercase()))
+                            .collect(),
+                    };
+                    match parsed {
+                        Some(links) => {
+                            retry(|| db.set_links(chat_id, Some(&links))).await?;
+                            format!("Your notifications will show {}.", link_names(&links))
+                        }
+                        None => String::from(
+                            "Error: unknown link. Available links are <code>docs.rs</code>, \
+                             <code>crates.io</code>, <code>lib.rs</code> and <code>repo</code>.",
+                        ),
+                    }
+                }
+            };
+            cx.answer_str(text).await?;
+        }
+        Command::Digest(arg) => {
+            let arg = arg.trim().to_lowercase();
+            let text = match arg.as_str() {
+                "" => match retry(|| db.digest(chat_id)).await? {
+                    Some(period) => format!(
+                        "You get updates as a {} digest. Use <code>/digest off</code> to get a message per update.",
+                        period.as_str()
+                    ),
+                    None => String::from("You get a message per update. Use <code>/digest daily</code> or <code>/digest weekly</code> to get a summary instead."),
+                },
+                "off" => {
+                    retry(|| db.set_digest(chat_id, None)).await?;
+                    String::from("Digest is turned off, you'll get a message per update. Updates accumulated so far will be sent shortly.")
+                }
+                arg => match Period::parse(arg) {
+                    Some(period) => {
+                        retry(|| db.set_digest(chat_id, Some(period))).await?;
+                        format!(
+                            "You'll get updates as a {} digest. Use <code>/digest off</code> to get a message per update.",
+                            period.as_str()
+                        )
+                    }
+                    None => String::from("Error: expected <code>daily</code>, <code>weekly</code> or <code>off</code>, e.g. <code>/digest daily</code>."),
+                },
+            };
+            cx.answer_str(text).await?;
+        }
+        Command::Prereleases(arg) => {
+            let arg = arg.trim().to_lowercase();
+            let text = match arg.as_str() {
+                "" => {
+                    let skipping = retry(|| db.list_skipping_prereleases(&[chat_id])).await?;
+                    if skipping.is_empty() {
+                        String::from("You get pre-release versions. Use <code>/prereleases off</code> to skip them.")
+                    } else {
+                        String::from("You don't get pre-release versions. Use <code>/prereleases on</code> to get them.")
+                    }
+                }
+                "on" => {
+                    retry(|| db.set_skip_prereleases(chat_id, false)).await?;
+                    String::from("You'll get pre-release versions (except for subscriptions made with <code>--no-prereleases</code>).")
+                }
+                "off" => {
+                    retry(|| db.set_skip_prereleases(chat_id, true)).await?;
+                    String::from("You won't get pre-release versions (except for subscriptions made with <code>--prereleases</code>).")
+                }
+                _ => String::from("Error: expected <code>on</code> or <code>off</code>, e.g. <code>/prereleases off</code>."),
+            };
+            cx.answer_str(text).await?;
+        }
+        Command::List(arg) => {
+            let arg = arg.trim();
+            let label = Some(arg.trim_start_matches('#')).filter(|l| !l.is_empty());
+            let paused = retry(|| db.is_paused(chat_id)).await?;
+            let digest = retry(|| db.digest(chat_id)).await?;
+            // keyword, category, owner & pattern subscriptions and watches
+            // don't have labels
+            let (keywords, categories, owners, patterns, watches) = match label {
+                Some(_) => (Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new()),
+                None => (
+                    retry(|| db.list_keyword_subscriptions(chat_id)).await?,
+                    retry(|| db.list_category_subscriptions(chat_id)).await?,
+                    retry(|| db.list_owner_subscriptions(chat_id)).await?,
+                    retry(|| db.list_pattern_subscriptions(chat_id)).await?,
+                    retry(|| db.list_watches(chat_id)).await?,
+                ),
+            };
+            let subscriptions = retry(|| db.list_subscriptions(chat_id, label)).await?;
+            // silently ignore error & just don't show the counts
+            let counts = db.subscriber_counts(chat_id).await.unwrap_or_default();
+            let mut lines = Vec::with_capacity(subscriptions.len());
+            for sub in &subscriptions {
+                let registry = config.registry(&sub.registry);
+                let mut line = match registry {
+                    Some(registry) => qualified_name(&sub.krate, registry),
+                    None => format!("{}@{}", sub.krate, sub.registry),
+                };
+                let count = counts
+                    .get(&(sub.registry.clone(), sub.krate.clone()))
+                    .copied();
+                let krate = match registry {
+                    Some(registry) => Crate::latest_stable(&registry.index_path, &sub.krate)
+                        .await
+                        .map(|krate| (krate, registry)),
+                    None => Err(std::io::ErrorKind::NotFound.into()),
+                };
+                match krate {
+                    Ok((krate, registry)) => {
+                        line.push('#');
+                        line.push_str(&krate.id.vers);
+                        line.push_str("</code> ");
+                        line.push_str(&links(&krate, registry));
+                    }
+                    Err(_) => {
+                        line.push_str(" </code>");
                         /* silently ignore error & just don't add links */
                     }
                 }
+                if let Some(count) = count {
+                    line.push_str(" (");
+                    line.push_str(&subscribers(count));
+                    line.push(')');
+                }
+                match sub.created_at {
+                    Some(at) => line.push_str(&format!(", since {}", at.format("%Y-%m-%d"))),
+                    None => line.push_str(", since unknown"),
+                }
+                if let Some(events) = events_text(sub.events) {
+                    line.push_str(", ");
+                    line.push_str(events);
+                }
+                if let Some(level) = level_text(sub.min_level) {
+                    line.push_str(", ");
+                    line.push_str(level);
+                }
+                if let Some(prereleases) = sub.prereleases {
+                    line.push_str(", ");
+                    line.push_str(prereleases_text(prereleases));
+                }
+                if sub.changelog {
+                    line.push_str(", with release notes");
+                }
+                match sub.muted_until {
+                    Some(until) if until > chrono::Utc::now() => {
+                        line.push_str(&format!(", muted until {}", until.format("%Y-%m-%d %H:%M")))
+                    }
+                    _ => {}
+                }
+                if let Some(label) = &sub.label {
+                    line.push_str(&format!(", <b>#{}</b>", escape_html(label)));
+                }
+                lines.push(line);
             }
 
-            if subscriptions.is_empty() {
+            if let (Some(label), true) = (label, lines.is_empty()) {
+                let text = format!(
+                    "You don't have subscriptions with label <b>#{}</b>. Use /list to see all your subscriptions.",
+                    escape_html(label)
+                );
+                cx.answer_str(text).await?;
+            } else if lines.is_empty()
+                && keywords.is_empty()
+                && categories.is_empty()
+                && owners.is_empty()
+                && patterns.is_empty()
+                && watches.is_empty()
+            {
                 let text = "Currently you aren't subscribed to anything. Use /subscribe to subscribe to some crate.";
                 cx.answer_str(text).await?;
             } else {
+                let mut out = Vec::new();
+                let lines = lines.iter().map(|line| format!("— <code>{}", line));
+                list_section(&mut out, "You are currently subscribed to:", lines);
+                let code = |item: &String| format!("— <code>{}</code>", item);
+                let keywords = keywords.iter().map(code);
+                list_section(&mut out, "Keywords you are subscribed to:", keywords);
+                let categories = categories.iter().map(code);
+                list_section(&mut out, "Categories you are subscribed to:", categories);
+                let owners = owners.iter().map(code);
+                list_section(&mut out, "Owners you are subscribed to:", owners);
+                let patterns = patterns.iter().map(|(registry, prefix)| {
+                    let name = match config.registry(registry) {
+                        Some(registry) => qualified_name(&format!("{}*", prefix), registry),
+                        None => format!("{}*@{}", prefix, registry),
+                    };
+                    code(&escape_html(&name))
+                });
+                list_section(&mut out, "Patterns you are subscribed to:", patterns);
+                let watches = watches.iter().map(code);
+                list_section(&mut out, "New crates you are watching for:", watches);
+                if paused {
+                    out.push(String::new());
+                    out.push(String::from(
+                        "⏸ Notifications are paused, use /resume to resume them.",
+                    ));
+                }
+                if let Some(period) = digest {
+                    out.push(String::new());
+                    out.push(format!(
+                        "📰 Updates are sent as a {} digest, see /digest.",
+                        period.as_str()
+                    ));
+                }
+
+                // long lists are split into several messages, each starting
+                // with the first header
+                let (header, rest) = out.split_first().expect("the list isn't empty");
+                let rest: Vec<_> = rest.iter().map(String::as_str).collect();
+                for (text, _) in channel::split(header, &rest) {
+                    cx.answer(text)
+                        .disable_web_page_preview(true)
+                        .send()
+                        .await?;
+                }
+            }
+        }
+        Command::Diff(arg, old, new) => {
+            let (krate, registry) = match parse_crate(&arg, config) {
+                Ok(parsed) => parsed,
+                Err(text) => {
+                    cx.answer_str(text).await?;
+                    return Ok(());
+                }
+            };
+            let index = &registry.index_path;
+            let name = escape_html(&qualified_name(krate, registry));
+            let text = match (Version::parse(&old), Version::parse(&new)) {
+                (Ok(o), Ok(n)) => diff_text(index, krate, &name, &o, &n).await,
+                _ => String::from("Error: versions must be valid semver, e.g. <code>/diff serde 1.0.0 1.0.1</code>."),
+            };
+            cx.answer_str(text).await?;
+        }
+        Command::History(krate) => {
+            let events = retry(|| db.list_events(&krate, chat_id, HISTORY_LEN)).await?;
+            if events.is_empty() {
                 let text = format!(
-                    "You are currently subscribed to:\n— <code>{}",
-                    subscriptions.join("\n— <code>")
+                    "There are no recorded events for <code>{}</code> crate.",
+                    escape_html(&krate)
                 );
-                cx.answer(text)
-                    .disable_web_page_preview(true)
-                    .send()
+                cx.answer_str(text).await?;
+            } else {
+                let events: Vec<_> = events
+                    .into_iter()
+                    .map(|e| {
+                        let status = match e.delivery_status.as_deref() {
+                            None => String::from("not sent to you"),
+                            Some("ok") => String::from("delivered"),
+                            Some(err) => format!("failed: {}", escape_html(err)),
+                        };
+                        format!(
+                            "— <code>{}</code> {} at {} (commit <code>{}</code>): {}",
+                            e.version,
+                            e.action,
+                            e.happened_at.format("%Y-%m-%d %H:%M UTC"),
+                            &e.commit_oid[..7],
+                            status,
+                        )
+                    })
+                    .collect();
+                let text = format!(
+                    "Recent events for <code>{}</code>:\n{}",
+                    krate,
+                    events.join("\n")
+                );
+                cx.answer_str(text).await?;
+            }
+        }
+        Command::Versions(arg) => {
+            let mut args = arg.split_whitespace();
+            let arg = args.next().unwrap_or_default();
+            let n = match args.next().map(str::parse::<usize>) {
+                None => VERSIONS_LEN,
+                Some(Ok(n)) if n >= 1 => n.min(MAX_VERSIONS_LEN),
+                Some(_) => {
+                    let text = "Error: the number of versions must be a positive number, e.g. <code>/versions tokio 30</code>.";
+                    cx.answer_str(text).await?;
+                    return Ok(());
+                }
+            };
+            let (krate, registry) = match parse_crate(arg, config) {
+                Ok(parsed) => parsed,
+                Err(text) => {
+                    cx.answer_str(text).await?;
+                    return Ok(());
+                }
+            };
+            let name = escape_html(&qualified_name(krate, registry));
+            let versions = match Crate::read_last_n(&registry.index_path, krate, n).await {
+                Ok(versions) if !versions.is_empty() => versions,
+                _ => {
+                    let text = format!("Error: there is no such crate <code>{}</code>.", name);
+                    cx.answer_str(text).await?;
+                    return Ok(());
+                }
+            };
+
+            let total = versions.last().map_or(0, |(number, _)| *number);
+            let lines: Vec<_> = versions
+                .iter()
+                .rev()
+                .map(|(number, krate)| {
+                    let mut line = if krate.yanked {
+                        format!(
+                            "— #{} <s>{}</s> (yanked)",
+                            number,
+                            escape_html(&krate.id.vers)
+                        )
+                    } else {
+                        format!("— #{} <code>{}</code>", number, escape_html(&krate.id.vers))
+                    };
+                    if let Some(published) = krate.pubtime {
+                        line.push_str(&format!(", {}", published.format("%Y-%m-%d")));
+                    }
+                    line
+                })
+                .collect();
+            let text = if total > lines.len() {
+                format!(
+                    "Last {} of {} versions of <code>{}</code>:\n{}",
+                    lines.len(),
+                    total,
+                    name,
+                    lines.join("\n")
+                )
+            } else {
+                format!(
+                    "Versions of <code>{}</code> ({} total):\n{}",
+                    name,
+                    total,
+                    lines.join("\n")
+                )
+            };
+            cx.answer_str(text).await?;
+        }
+        Command::Info(arg) => {
+            let (krate, registry) = match parse_crate(&arg, config) {
+                Ok(parsed) => parsed,
+                Err(text) => {
+                    cx.answer_str(text).await?;
+                    return Ok(());
+                }
+            };
+            let name = escape_html(&qualified_name(krate, registry));
+            let latest = match Crate::latest_stable(&registry.index_path, krate).await {
+                Ok(latest) => latest,
+                Err(_) => {
+                    let text = format!("Error: there is no such crate <code>{}</code>.", name);
+                    cx.answer_str(text).await?;
+                    return Ok(());
+                }
+            };
+            // crates.io doesn't know about other registries
+            let info = if registry.is_default() {
+                api.crate_info(krate)
+                    .await
+                    .map_err(|err| log::warn!("couldn't get info of crate {}: {}", krate, err))
+                    .ok()
+            } else {
+                None
+            };
+
+            let mut text = format!("<code>{}#{}</code>", name, escape_html(&latest.id.vers));
+            if let Some(description) = info.as_ref().and_then(|i| i.description.as_deref()) {
+                text.push('\n');
+                text.push_str(&escape_html(description.trim()));
+            }
+            let normal = latest.deps.iter().filter(|dep| dep.is_normal()).count();
+            text.push_str(&format!(
+                "\n\nDependencies: {} (+{} dev/build)",
+                normal,
+                latest.deps.len() - normal
+            ));
+            if let Some(info) = &info {
+                text.push_str(&format!("\nDownloads: {}", info.downloads));
+            }
+            if let Some(published) = latest.pubtime {
+                text.push_str(&format!("\nPublished: {}", published.format("%Y-%m-%d")));
+            }
+            let urls = info.as_ref().map(|info| {
+                [
+                    ("repository", &info.repository),
+                    ("docs", &info.documentation),
+                    ("homepage", &info.homepage),
+                ]
+                .iter()
+                .filter_map(|&(title, url)| {
+                    url.as_ref()
+                        .map(|url| format!("<a href='{}'>[{}]</a>", escape_html(url), title))
+                })
+                .collect::<Vec<_>>()
+            });
+            text.push_str("\n\n");
+            text.push_str(&links(&latest, registry));
+            if let Some(urls) = urls.filter(|urls| !urls.is_empty()) {
+                text.push(' ');
+                text.push_str(&urls.join(" "));
+            }
+            cx.answer(text)
+                .disable_web_page_preview(true)
+                .send()
+                .await?;
+        }
+        Command::Deps(arg) => {
+            let mut args = arg.split_whitespace();
+            let (krate, registry) = match parse_crate(args.next().unwrap_or_default(), config) {
+                Ok(parsed) => parsed,
+                Err(text) => {
+                    cx.answer_str(text).await?;
+                    return Ok(());
+                }
+            };
+            let name = escape_html(&qualified_name(krate, registry));
+            let index = &registry.index_path;
+            let found = match args.next() {
+                Some(version) => match Version::parse(version) {
+                    Ok(parsed) => Crate::read_version(index, krate, &parsed).await.map(|found| {
+                        found.ok_or_else(|| {
+                            format!(
+                                "Version <code>{}</code> of <code>{}</code> was never published. Use /versions to see the existing ones.",
+                                escape_html(version),
+                                name
+                            )
+                        })
+                    }),
+                    Err(_) => Ok(Err(format!(
+                        "Error: <code>{}</code> is not a valid semver version.",
+                        escape_html(version)
+                    ))),
+                },
+                None => Crate::latest_stable(index, krate).await.map(Ok),
+            };
+            let found = match found {
+                Ok(Ok(found)) => found,
+                Ok(Err(text)) => {
+                    cx.answer_str(text).await?;
+                    return Ok(());
+                }
+                Err(_) => {
+                    let text = format!("Error: there is no such crate <code>{}</code>.", name);
+                    cx.answer_str(text).await?;
+                    return Ok(());
+                }
+            };
+
+            let header = format!(
+                "Dependencies of <code>{}#{}</code>:",
+                name,
+                escape_html(&found.id.vers)
+            );
+            if found.deps.is_empty() {
+                cx.answer_str(format!("{}\nnone 🎉", header)).await?;
+                return Ok(());
+            }
+            let mut lines = Vec::new();
+            for (kind, title) in &[
+                ("normal", "\n<b>Normal</b>"),
+                ("build", "\n<b>Build</b>"),
+                ("dev", "\n<b>Dev</b>"),
+            ] {
+                let mut deps: Vec<_> = found
+                    .deps
+                    .iter()
+                    .filter(|dep| dep.kind.as_deref().unwrap_or("normal") == *kind)
+                    .collect();
+                if deps.is_empty() {
+                    continue;
+                }
+                deps.sort_by(|a, b| a.name.cmp(&b.name));
+                lines.push(String::from(*title));
+                lines.extend(deps.into_iter().map(dep_line));
+            }
+            let lines: Vec<_> = lines.iter().map(String::as_str).collect();
+            for (text, _) in channel::split(&header, &lines) {
+                cx.answer_str(text).await?;
+            }
+        }
+        Command::Check(arg, version) => {
+            let (krate, registry) = match parse_crate(&arg, config) {
+                Ok(parsed) => parsed,
+                Err(text) => {
+                    cx.answer_str(text).await?;
+                    return Ok(());
+                }
+            };
+            let name = escape_html(&qualified_name(krate, registry));
+            let version = match Version::parse(&version) {
+                Ok(version) => version,
+                Err(_) => {
+                    let text = format!(
+                        "Error: <code>{}</code> is not a valid semver version.",
+                        escape_html(&version)
+                    );
+                    cx.answer_str(text).await?;
+                    return Ok(());
+                }
+            };
+            let text = match Crate::read_version(&registry.index_path, krate, &version).await {
+                Err(_) => format!("Error: there is no such crate <code>{}</code>.", name),
+                Ok(None) => format!(
+                    "Version <code>{}</code> of <code>{}</code> was never published. Use /versions to see the existing ones.",
+                    version, name
+                ),
+                Ok(Some(found)) if found.yanked => format!(
+                    "Version <code>{}</code> of <code>{}</code> is yanked ❌ {}",
+                    version,
+                    name,
+                    links(&found, registry)
+                ),
+                Ok(Some(found)) => format!(
+                    "Version <code>{}</code> of <code>{}</code> is published and not yanked ✅ {}",
+                    version,
+                    name,
+                    links(&found, registry)
+                ),
+            };
+            cx.answer(text)
+                .disable_web_page_preview(true)
+                .send()
+                .await?;
+        }
+        Command::Search(query) => {
+            let query = query.trim();
+            if query.is_empty() {
+                let text =
+                    "Error: expected a search query, e.g. <code>/search async http client</code>.";
+                cx.answer_str(text).await?;
+                return Ok(());
+            }
+            let found = match api.search(query, SEARCH_RESULTS).await {
+                Ok(found) => found,
+                Err(err) => {
+                    log::warn!("couldn't search crates.io: {}", err);
+                    let text =
+                        "Error: crates.io is not available right now, please try again later.";
+                    cx.answer_str(text).await?;
+                    return Ok(());
+                }
+            };
+            if found.is_empty() {
+                let text = format!(
+                    "No crates matching <code>{}</code> were found.",
+                    escape_html(query)
+                );
+                cx.answer_str(text).await?;
+                return Ok(());
+            }
+
+            let registry = config.default_registry();
+            let mut lines = Vec::with_capacity(found.len());
+            let mut keyboard = InlineKeyboardMarkup::default();
+            for info in found {
+                let version = info
+                    .max_stable_version
+                    .as_deref()
+                    .or_else(|| info.max_version.as_deref())
+                    .unwrap_or("?");
+                let mut line = format!("<code>{}#{}</code>", info.name, escape_html(version));
+                // the search may find crates not in the local index yet
+                if let Ok(krate) = Crate::latest_stable(&registry.index_path, &info.name).await {
+                    line.push(' ');
+                    line.push_str(&links(&krate, registry));
+                }
+                if let Some(description) = &info.description {
+                    let description = description.split_whitespace().collect::<Vec<_>>().join(" ");
+                    let mut short: String =
+                        description.chars().take(SEARCH_DESCRIPTION_LEN).collect();
+                    if short.len() < description.len() {
+                        short.push('…');
+                    }
+                    line.push_str("\n    ");
+                    line.push_str(&escape_html(&short));
+                }
+                lines.push(line);
+
+                let data = format!("{}{}", SEARCH_SUBSCRIBE_DATA, info.name);
+                if data.len() <= MAX_CALLBACK_DATA {
+                    let text = format!("🔔 {}", info.name);
+                    keyboard =
+                        keyboard.append_row(vec![InlineKeyboardButton::callback(text, data)]);
+                }
+            }
+
+            let text = format!(
+                "Crates matching <code>{}</code>:\n— {}",
+                escape_html(query),
+                lines.join("\n— ")
+            );
+            cx.answer(text)
+                .disable_web_page_preview(true)
+                .reply_markup(keyboard)
+                .send()
+                .await?;
+        }
+        Command::Top => {
+            let registry = config.default_registry();
+            let top = retry(|| db.top_crates(&registry.name, TOP_LEN)).await?;
+            if top.is_empty() {
+                cx.answer_str("Nobody is subscribed to anything yet.")
+                    .await?;
+                return Ok(());
+            }
+
+            let mut lines = Vec::with_capacity(top.len());
+            for (i, (name, count)) in top.iter().enumerate() {
+                let mut line = format!("{}. <code>{}", i + 1, escape_html(name));
+                match Crate::latest_stable(&registry.index_path, name).await {
+                    Ok(krate) => {
+                        line.push('#');
+                        line.push_str(&krate.id.vers);
+                        line.push_str("</code> ");
+                        line.push_str(&links(&krate, registry));
+                    }
+                    Err(_) => {
+                        line.push_str(" </code>");
+                        /* silently ignore error & just don't add links */
+                    }
+                }
+                line.push_str(&format!(" ({})", subscribers(*count)));
+                lines.push(line);
+            }
+            let text = format!("Most subscribed crates:\n{}", lines.join("\n"));
+            cx.answer(text)
+                .disable_web_page_preview(true)
+                .send()
+                .await?;
+        }
+        Command::WhoSubscribed(arg) => {
+            if !is_admin(&cx, config)? {
+                cx.answer_str("Error: this command is only available to the bot admins.")
+                    .await?;
+                return Ok(());
+            }
+
+            let mut args = arg.split_whitespace();
+            let (krate, registry) = match parse_crate(args.next().unwrap_or_default(), config) {
+                Ok(parsed) => parsed,
+                Err(text) => {
+                    cx.answer_str(text).await?;
+                    return Ok(());
+                }
+            };
+            let name = escape_html(&qualified_name(krate, registry));
+            let count = retry(|| db.subscriber_count(&registry.name, krate)).await?;
+            let mut text = format!("<code>{}</code> has {}.", name, subscribers(count));
+            if args.next() == Some("ids") && count > 0 {
+                let list = || async {
+                    let ids = db.list_subscribers(&registry.name, krate).await?;
+                    ids.map_ok(|sub| sub.chat_id.to_string())
+                        .try_collect::<Vec<_>>()
+                        .await
+                };
+                let ids = retry(list).await?;
+                text.push_str(&format!("\n<code>{}</code>", ids.join("</code>, <code>")));
+            }
+            cx.answer_str(text).await?;
+        }
+        Command::Gc => {
+            if !is_admin(&cx, config)? {
+                cx.answer_str("Error: this command is only available to the bot admins.")
                     .await?;
+                return Ok(());
+            }
+
+            let chats = retry(|| db.list_chats()).await?;
+            cx.answer_str(format!(
+                "Checking {} chats, this may take a while...",
+                chats.len()
+            ))
+            .await?;
+            let mut removed = 0;
+            for chat in chats {
+                // the cheapest request that fails for blocked/deleted chats
+                let res = cx
+                    .bot
+                    .send_chat_action(chat, ChatAction::Typing)
+                    .send()
+                    .await;
+                match res {
+                    Err(err) if is_permanent(&err) => {
+                        log::info!(
+                            "{} is unreachable ({}), removing its subscriptions",
+                            chat,
+                            err
+                        );
+                        retry(|| db.unsubscribe_all(chat)).await?;
+                        removed += 1;
+                    }
+                    _ => {}
+                }
+                tokio::time::delay_for(config.broadcast_delay).await;
+            }
+
+            let mut missing = Vec::new();
+            for (registry, krate, count) in retry(|| db.list_subscribed_crates()).await? {
+                let exists = match config.registry(&registry) {
+                    Some(r) => Crate::exists(&r.index_path, &krate).await,
+                    None => false,
+                };
+                if !exists {
+                    missing.push(format!(
+                        "<code>{}@{}</code> ({})",
+                        escape_html(&krate),
+                        escape_html(&registry),
+                        subscribers(count)
+                    ));
+                }
+            }
+
+            let mut text = format!("Removed subscriptions of {} unreachable chats.", removed);
+            if !missing.is_empty() {
+                text.push_str(&format!(
+                    "\n\nSubscriptions to crates missing from the index (not removed):\n— {}",
+                    missing.join("\n— ")
+                ));
+            }
+            cx.answer_str(text).await?;
+        }
+        Command::About => {
+            cx.answer(about(config, started))
+                .disable_web_page_preview(true)
+                .send()
+                .await?;
+        }
+        Command::Feedback(text) => {
+            let text = text.trim();
+            let destination = match config.feedback_chat {
+                Some(chat) => chat,
+                None => {
+                    cx.answer_str("Error: feedback is not enabled for this bot.")
+                        .await?;
+                    return Ok(());
+                }
+            };
+            if text.is_empty() {
+                cx.answer_str("Usage: /feedback &lt;text&gt;").await?;
+                return Ok(());
+            }
+            let too_often = {
+                let mut last = feedback.lock().unwrap();
+                match last.get(&chat_id) {
+                    Some(at) if at.elapsed() < FEEDBACK_INTERVAL => true,
+                    _ => {
+                        last.insert(chat_id, Instant::now());
+                        false
+                    }
+                }
+            };
+            if too_often {
+                cx.answer_str(
+                    "Error: you can send feedback only once a minute, please try again later.",
+                )
+                .await?;
+                return Ok(());
+            }
+
+            let from = match cx.update.from().and_then(|user| user.username.as_deref()) {
+                Some(username) => format!(" (@{})", escape_html(username)),
+                None => String::new(),
+            };
+            let message = format!(
+                "Feedback from <code>{}</code>{}:\n{}",
+                chat_id,
+                from,
+                escape_html(text)
+            );
+            cx.bot
+                .send_message(destination, message)
+                .disable_web_page_preview(true)
+                .send()
+                .await?;
+            cx.answer_str("Thanks! Your feedback was sent to the operator of the bot.")
+                .await?;
+        }
+        Command::Help(command) => {
+            let command = command.trim().trim_start_matches('/');
+            let text = match Command::detailed_help(command) {
+                Some(help) => help.to_owned(),
+                None if command.is_empty() => escape_html(&Command::descriptions()),
+                None => format!(
+                    "Error: there is no such command <code>/{}</code>.\n\n{}",
+                    escape_html(command),
+                    escape_html(&Command::descriptions())
+                ),
+            };
+            cx.answer_str(text).await?;
+        }
+    };
+    Ok(())
+}
+
+/// `/subscribe a b c [--events update|yank] [--only minor|major] [--[no-]prereleases]
+/// [--changelog] [#label]`
+#[allow(clippy::too_many_arguments)]
+async fn subscribe_many(
+    cx: &UpdateWithCx<Message>,
+    args: &[&str],
+    db: &Database,
+    config: &Config,
+    events: Events,
+    min_level: Level,
+    prereleases: Option<bool>,
+    changelog: bool,
+    label: Option<&str>,
+) -> Result<(), HErr> {
+    let chat_id = ChatId(cx.chat_id());
+    let admin = is_admin(cx, config).unwrap_or(false);
+    let lines = subscribe_all(
+        chat_id,
+        admin,
+        args,
+        db,
+        config,
+        events,
+        min_level,
+        prereleases,
+        changelog,
+        label,
+    )
+    .await?;
+    let text = format!(
+        "— {}\n\nUse /unsubscribe to unsubscribe.",
+        lines.join("\n— ")
+    );
+    cx.answer_str(text).await?;
+    Ok(())
+}
+
+/// Subscribe to all crates in `args` (`admin`s aren't limited by
+/// `max_subscriptions`), returns the result for every crate.
+#[allow(clippy::too_many_arguments)]
+async fn subscribe_all(
+    chat_id: ChatId,
+    admin: bool,
+    args: &[&str],
+    db: &Database,
+    config: &Config,
+    events: Events,
+    min_level: Level,
+    prereleases: Option<bool>,
+    changelog: bool,
+    label: Option<&str>,
+) -> Result<Vec<String>, HErr> {
+    let mut available = if admin {
+        i64::MAX
+    } else {
+        config.max_subscriptions - retry(|| db.subscription_count(chat_id)).await?
+    };
+
+    let mut lines = Vec::with_capacity(args.len());
+    // registry name -> crates to subscribe to
+    let mut batches = HashMap::<&str, Vec<&str>>::new();
+    // (registry, crate) already handled, so repeated arguments are skipped
+    let mut seen = HashSet::new();
+    for arg in args {
+        let (krate, registry) = match parse_crate(arg, config) {
+            Ok(parsed) => parsed,
+            Err(text) => {
+                lines.push(text);
+                continue;
+            }
+        };
+        if !seen.insert((registry.name.as_str(), krate)) {
+            continue;
+        }
+        let name = escape_html(&qualified_name(krate, registry));
+        // a subscription with other options is updated, it doesn't count
+        // against the limit
+        let existing = retry(|| db.has_subscription(chat_id, &registry.name, krate)).await?;
+        let subscribed = existing
+            && retry(|| {
+                db.is_subscribed(
+                    chat_id,
+                    &registry.name,
+                    krate,
+                    events,
+                    min_level,
+                    prereleases,
+                    changelog,
+                )
+            })
+            .await?;
+        if subscribed && label.is_none() {
+            lines.push(format!("<code>{}</code>: already subscribed", name));
+        } else if !Crate::exists(&registry.index_path, krate).await {
+            lines.push(format!(
+                "Error: there is no such crate <code>{}</code>.",
+                name
+            ));
+        } else if !existing && available <= 0 {
+            lines.push(format!(
+                "<code>{}</code>: not subscribed, you've reached the limit of {} subscriptions.",
+                name, config.max_subscriptions
+            ));
+        } else {
+            if !existing {
+                available -= 1;
             }
+            batches
+                .entry(registry.name.as_str())
+                .or_default()
+                .push(krate);
+            let version = match Crate::latest_stable(&registry.index_path, krate).await {
+                Ok(krate) => format!(" (current version <code>{}</code>)", krate.id.vers),
+                Err(_) => String::new(),
+            };
+            lines.push(format!("<code>{}</code>: subscribed{}", name, version));
+        }
+    }
+
+    for (registry, krates) in batches {
+        retry(|| {
+            db.subscribe_many(
+                chat_id,
+                registry,
+                &krates,
+                events,
+                min_level,
+                prereleases,
+                changelog,
+                label,
+            )
+        })
+        .await?;
+    }
+
+    Ok(lines)
+}
+
+/// Reply to `/diff`, the index file is read once for both versions.
+async fn diff_text(index: &str, krate: &str, name: &str, old: &Version, new: &Version) -> String {
+    let all = match Crate::read_all(index, krate).await {
+        Ok(all) => all,
+        Err(_) => return format!("Error: there is no such crate <code>{}</code>.", name),
+    };
+    let find = |version: &Version| {
+        all.iter()
+            .find(|krate| krate.id.version().map_or(false, |v| &v == version))
+    };
+    match (find(old), find(new)) {
+        (Some(o), Some(n)) => format!(
+            "Changes of <code>{}</code> between <code>{}</code> and <code>{}</code>:\n{}",
+            name,
+            o.id.vers,
+            n.id.vers,
+            o.changes(n).html()
+        ),
+        (None, _) => format!(
+            "Error: there is no version <code>{}</code> of <code>{}</code> crate.",
+            old, name
+        ),
+        (_, None) => format!(
+            "Error: there is no version <code>{}</code> of <code>{}</code> crate.",
+            new, name
+        ),
+    }
+}
+
+/// `Cargo.toml`-like manifest with the subscribed crates (and their current
+/// versions) as dependencies, which can be sent back to `/import`. Crates of
+/// other registries have `registry = ".."` (`/import` skips them, as any
+/// alternative registry dependency), labels are left as comments.
+async fn export_toml(subscriptions: &[Subscription], config: &Config) -> String {
+    let mut text = format!(
+        "# Subscriptions exported on {}, send this file to the bot to subscribe again\n",
+        chrono::Utc::now().format("%Y-%m-%d")
+    );
+    if subscriptions
+        .iter()
+        .any(|sub| sub.registry != DEFAULT_REGISTRY)
+    {
+        text.push_str(
+            "# (only crates.io crates are imported back, crates of other registries are listed for reference)\n",
+        );
+    }
+    text.push_str("\n[dependencies]\n");
+    for sub in subscriptions {
+        let version = match config.registry(&sub.registry) {
+            Some(registry) => Crate::latest_stable(&registry.index_path, &sub.krate)
+                .await
+                .map(|krate| krate.id.vers)
+                .unwrap_or_else(|_| String::from("*")),
+            None => String::from("*"),
+        };
+        if sub.registry == DEFAULT_REGISTRY {
+            text.push_str(&format!("{} = \"{}\"", sub.krate, version));
+        } else {
+            text.push_str(&format!(
+                "{} = {{ version = \"{}\", registry = \"{}\" }}",
+                sub.krate, version, sub.registry
+            ));
+        }
+        if let Some(label) = &sub.label {
+            text.push_str(&format!(" # #{}", label.replace('\n', " ")));
         }
-        Command::Help => {
-            cx.answer_str(Command::descriptions()).await?;
+        text.push('\n');
+    }
+    text
+}
+
+/// Reply to a command with wrong arguments (only ones parsed with `split`,
+/// like `/diff`, can fail) with its usage. Unknown commands & commands of other
+/// bots are ignored.
+async fn malformed_command(cx: UpdateWithCx<Message>, err: ParseError) -> Result<(), HErr> {
+    if matches!(
+        err,
+        ParseError::UnknownCommand(_) | ParseError::WrongBotName(_)
+    ) {
+        return Ok(());
+    }
+    let name = cx
+        .update
+        .text()
+        .and_then(|text| text.split_whitespace().next())
+        .map(|command| command.trim_start_matches('/'))
+        .and_then(|command| command.split('@').next())
+        .unwrap_or_default();
+    let text = match Command::detailed_help(name) {
+        Some(help) => format!("Error: wrong arguments of the command.\n\n{}", help),
+        None => String::from("Error: wrong arguments of the command, see /help."),
+    };
+    cx.answer_str(text).await?;
+    Ok(())
+}
+
+/// Offer to subscribe to the dependencies of a manifest sent as a file (see
+/// `/import`). Other files are ignored.
+async fn import_document(cx: UpdateWithCx<Message>, imports: &PendingImports) -> Result<(), HErr> {
+    let document = match cx.update.document() {
+        Some(document) => document,
+        None => return Ok(()),
+    };
+    let is_manifest = document.file_name.as_deref().map_or(false, |name| {
+        name.ends_with(".toml") || name.ends_with(".lock")
+    });
+    if !is_manifest {
+        return Ok(());
+    }
+    if document
+        .file_size
+        .map_or(false, |size| size > MAX_MANIFEST_SIZE)
+    {
+        cx.answer_str("Error: the file is too big.").await?;
+        return Ok(());
+    }
+
+    let file = cx.bot.get_file(document.file_id.clone()).send().await?;
+    let mut contents = Vec::new();
+    cx.bot.download_file(&file.file_path, &mut contents).await?;
+    offer_import(&cx, &String::from_utf8_lossy(&contents), imports).await
+}
+
+/// List the crates.io dependencies of the manifest & ask for a confirmation
+/// to subscribe to them.
+async fn offer_import(
+    cx: &UpdateWithCx<Message>,
+    manifest: &str,
+    imports: &PendingImports,
+) -> Result<(), HErr> {
+    let mut names = match manifest::dependencies(manifest) {
+        Ok(names) => names,
+        Err(err) => {
+            let text = format!(
+                "Error: couldn't parse the manifest: {}",
+                escape_html(&err.to_string())
+            );
+            cx.answer_str(text).await?;
+            return Ok(());
         }
     };
+    names.retain(|name| is_valid_crate_name(name));
+    if names.is_empty() {
+        cx.answer_str("Error: there are no crates.io dependencies in the manifest.")
+            .await?;
+        return Ok(());
+    }
+    names.truncate(MAX_IMPORT);
+
+    let mut text = format!(
+        "Found {} crates: <code>{}</code>",
+        names.len(),
+        names
+            .iter()
+            .take(IMPORT_PREVIEW)
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join("</code>, <code>")
+    );
+    if names.len() > IMPORT_PREVIEW {
+        text.push_str(&format!(" and {} more", names.len() - IMPORT_PREVIEW));
+    }
+    text.push_str(".\n\nSubscribe to all of them?");
+    imports.lock().unwrap().insert(ChatId(cx.chat_id()), names);
+
+    let keyboard = buttons(
+        &[
+            ("📥 Subscribe to all", IMPORT_DATA),
+            ("Cancel", CANCEL_DATA),
+        ],
+        "",
+    );
+    let mut request = cx.answer(text);
+    if let Some(keyboard) = keyboard {
+        request = request.reply_markup(keyboard);
+    }
+    request.send().await?;
     Ok(())
 }
 
+/// Remove the `flag` from `args`, returns whether it was there.
+fn take_flag(args: &mut Vec<&str>, flag: &str) -> bool {
+    let len = args.len();
+    args.retain(|&arg| arg != flag);
+    args.len() != len
+}
+
+/// Remove `option` and its value from `args`. `Some(None)` if the option is
+/// given without a value.
+fn take_option<'a>(args: &mut Vec<&'a str>, option: &str) -> Option<Option<&'a str>> {
+    let pos = args.iter().position(|&arg| arg == option)?;
+    args.remove(pos);
+    if pos < args.len() && !args[pos].starts_with("--") {
+        Some(Some(args.remove(pos)))
+    } else {
+        Some(None)
+    }
+}
+
+/// Description of an `--events` filter for `/subscribe` replies & `/list`,
+/// `None` if all updates are sent.
+fn events_text(events: Events) -> Option<&'static str> {
+    if events == Events::YANKS {
+        Some("yanks only")
+    } else if events == Events::UPDATES {
+        Some("new versions only")
+    } else {
+        None
+    }
+}
+
+/// Description of `--prereleases` (`true`) or `--no-prereleases` (`false`) for
+/// `/subscribe` replies & `/list`
+fn prereleases_text(prereleases: bool) -> &'static str {
+    if prereleases {
+        "with pre-releases"
+    } else {
+        "without pre-releases"
+    }
+}
+
+/// Description of a `--only` filter for `/subscribe` replies & `/list`,
+/// `None` if all releases are sent.
+fn level_text(level: Level) -> Option<&'static str> {
+    match level {
+        Level::Patch => None,
+        Level::Minor => Some("minor & major releases only"),
+        Level::Major => Some("major releases only"),
+    }
+}
+
+/// Split `/subscribe` arguments into the crates (& flags) and the label, which
+/// is everything after the first word starting with `#`.
+fn split_label(args: &str) -> (&str, Option<&str>) {
+    let start = args.match_indices('#').map(|(i, _)| i).find(|&i| {
+        args[..i]
+            .chars()
+            .next_back()
+            .map_or(true, char::is_whitespace)
+    });
+    match start {
+        Some(i) => {
+            let label = args[i + 1..].trim();
+            (&args[..i], Some(label).filter(|l| !l.is_empty()))
+        }
+        None => (args, None),
+    }
+}
+
+/// Parse `crate[@registry]` argument (or a crates.io/docs.rs/lib.rs url) into
+/// crate name & registry. Returns error message if the argument is invalid or
+/// the registry is unknown.
+fn parse_crate<'a>(
+    arg: &'a str,
+    config: &'a Config,
+) -> Result<(&'a str, &'a RegistryConfig), String> {
+    let arg = arg.trim();
+    // crate names can't contain `/`, so this must be a url
+    if arg.contains('/') {
+        return match crate_from_url(arg) {
+            Some(name) => Ok((name, config.default_registry())),
+            None => Err(format!(
+                "Error: <code>{}</code> is not a link to a crate.",
+                escape_html(arg)
+            )),
+        };
+    }
+
+    let mut parts = arg.splitn(2, '@');
+    let name = parts.next().unwrap_or_default();
+    if !is_valid_crate_name(name) {
+        return Err(format!(
+            "Error: <code>{}</code> is not a valid crate name.",
+            escape_html(name)
+        ));
+    }
+    match parts.next() {
+        None => Ok((name, config.default_registry())),
+        Some(registry) => config.registry(registry).map(|r| (name, r)).ok_or_else(|| {
+            format!(
+                "Error: unknown registry <code>{}</code>.",
+                escape_html(registry)
+            )
+        }),
+    }
+}
+
+/// Parse a pattern (`tokio-*` or `tokio-*@registry`) of `/subscribe` and
+/// `/unsubscribe` into the lowercase prefix and the registry. `None` if `arg`
+/// isn't a pattern.
+fn parse_pattern<'a>(
+    arg: &str,
+    config: &'a Config,
+) -> Option<Result<(String, &'a RegistryConfig), String>> {
+    let mut parts = arg.trim().splitn(2, '@');
+    let prefix = parts.next().unwrap_or_default().strip_suffix('*')?;
+    if !is_valid_crate_name(prefix) || prefix.len() < MIN_PATTERN_PREFIX {
+        return Some(Err(format!(
+            "Error: <code>{}*</code> is not a valid pattern, it must be a crate name prefix of at least {} characters followed by <code>*</code>.",
+            escape_html(prefix),
+            MIN_PATTERN_PREFIX
+        )));
+    }
+    let registry = match parts.next() {
+        None => config.default_registry(),
+        Some(registry) => match config.registry(registry) {
+            Some(registry) => registry,
+            None => {
+                return Some(Err(format!(
+                    "Error: unknown registry <code>{}</code>.",
+                    escape_html(registry)
+                )))
+            }
+        },
+    };
+    Some(Ok((prefix.to_lowercase(), registry)))
+}
+
+/// `/about` text: version & uptime of the bot and the last processed commit of
+/// every index.
+fn about(config: &Config, started: Instant) -> String {
+    let version = match option_env!("GIT_HASH") {
+        Some(hash) => format!("{} ({})", env!("CARGO_PKG_VERSION"), hash),
+        None => env!("CARGO_PKG_VERSION").to_owned(),
+    };
+    let uptime = Duration::from_secs(started.elapsed().as_secs());
+    let mut text = format!(
+        "crate_upd_bot <code>{}</code>, up for {}",
+        version,
+        humantime::format_duration(uptime)
+    );
+    for registry in &config.registries {
+        let head = match index_head(registry) {
+            Some((id, time)) => {
+                let age = (chrono::Utc::now().timestamp() - time).max(0) as u64;
+                format!(
+                    "<code>{}</code> ({} ago)",
+                    id,
+                    humantime::format_duration(Duration::from_secs(age))
+                )
+            }
+            None => String::from("unknown"),
+        };
+        text.push_str(&format!(
+            "\nIndex of {}: {}",
+            escape_html(&registry.name),
+            head
+        ));
+    }
+    text
+}
+
+/// Short id & time (unix seconds) of the last processed commit of the index.
+fn index_head(registry: &RegistryConfig) -> Option<(String, i64)> {
+    let repo = git2::Repository::open(&registry.index_path).ok()?;
+    let commit = repo
+        .find_reference(&format!("refs/heads/{}", registry.branch))
+        .ok()?
+        .peel_to_commit()
+        .ok()?;
+    let id = commit.as_object().short_id().ok()?;
+    Some((id.as_str()?.to_owned(), commit.time().seconds()))
+}
+
+/// Links to docs.rs/crates.io/lib.rs, they only make sense for the default
+/// registry (crates of other registries get a download link, if possible).
+fn links(krate: &Crate, registry: &RegistryConfig) -> String {
+    if registry.is_default() {
+        krate.html_links()
+    } else {
+        registry
+            .download_url(&krate.id.name, &krate.id.vers, &krate.cksum)
+            .map(|url| format!("<a href='{}'>[download]</a>", escape_html(&url)))
+            .unwrap_or_default()
+    }
+}
+
+/// Line of `/deps` about the dependency, e.g. `— serde ^1.0 (optional,
+/// features: derive)`.
+fn dep_line(dep: &Dependency) -> String {
+    let mut line = format!(
+        "— <code>{} {}</code>",
+        escape_html(&dep.name),
+        escape_html(&dep.req)
+    );
+    let mut notes = Vec::new();
+    if dep.optional {
+        notes.push(String::from("optional"));
+    }
+    if !dep.features.is_empty() {
+        notes.push(format!(
+            "features: {}",
+            escape_html(&dep.features.join(", "))
+        ));
+    }
+    if let Some(target) = &dep.target {
+        notes.push(format!("only for <code>{}</code>", escape_html(target)));
+    }
+    if !notes.is_empty() {
+        line.push_str(&format!(" ({})", notes.join("; ")));
+    }
+    line
+}
+
+/// Run database query, retrying it with a backoff if it fails.
+async fn retry<F, Fut, T>(f: F) -> Result<T, tokio_postgres::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, tokio_postgres::Error>>,
+{
+    tryn(DB_RETRIES, DB_RETRY_DELAY, f).await
+}
+
+fn is_admin(cx: &UpdateWithCx<Message>, config: &Config) -> Result<bool, HErr> {
+    let user = cx.update.from().ok_or(HErr::GetUser)?;
+    Ok(config.admins.contains(&i64::from(user.id)))
+}
+
+/// Human readable list of links, e.g. `docs.rs, repo`.
+fn link_names(links: &[Link]) -> String {
+    if links.is_empty() {
+        return String::from("no links");
+    }
+
+    let names: Vec<_> = Link::ALL
+        .iter()
+        .filter(|link| links.contains(link))
+        .map(|link| format!("<code>{}</code>", link.as_str().replace('_', ".")))
+        .collect();
+    names.join(", ")
+}
+
+fn subscribers(count: i64) -> String {
+    match count {
+        1 => String::from("1 subscriber"),
+        n => format!("{} subscribers", n),
+    }
+}
+
 #[derive(Debug, derive_more::Display, derive_more::From, derive_more::Error)]
 enum HErr {
     Tg(teloxide::RequestError),
+    Download(teloxide::DownloadError),
     Bd(tokio_postgres::Error),
     GetUser,
 }
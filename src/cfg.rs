@@ -1,104 +1,657 @@
+use crate::{chat::ChatId, index::SPARSE_PREFIX};
+#[cfg(feature = "subscriptions")]
 use fntools::value::ValueExt;
-use std::{error::Error, fs::File, io::Read, time::Duration};
+use std::{
+    error::Error,
+    fs::File,
+    io::{self, Read},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    stream::StreamExt,
+};
 
-#[derive(Debug, serde::Deserialize)]
+#[derive(Clone, Debug, serde::Deserialize)]
 pub struct Config {
-    /// Channel to post **ALL** updates
+    /// Channel to post **ALL** updates (shorthand for a `channels` entry
+    /// without a topic)
     #[serde(default)]
-    pub channel: Option<i64>,
+    pub channel: Option<ChatId>,
+    /// Channels (or supergroups) to post **ALL** updates
+    #[serde(default)]
+    pub channels: Vec<ChannelCfg>,
+    /// For how long a channel message is edited to append new updates before
+    /// a new message is started (zero disables editing)
+    #[serde(default = "defaults::channel_rolling_window", with = "humantime_serde")]
+    pub channel_rolling_window: Duration,
+    /// Post all updates of a pull cycle to channels as a single message
+    /// (instead of a message per update)
+    #[serde(default)]
+    pub channel_batch: bool,
+    /// Don't post yanks & unyanks of versions published longer than this ago
+    /// to the channels (0 to post all of them). Subscribers still get them.
+    #[serde(default, with = "humantime_serde")]
+    pub channel_yank_max_age: Duration,
+    /// Don't post yanks & unyanks of versions that are more than this many
+    /// releases behind the newest one to the channels (0 to post all of them).
+    /// Subscribers still get them.
+    #[serde(default)]
+    pub channel_yank_max_behind: usize,
     /// Delay between index fetches
-    #[serde(default = "defaults::pull_delay")]
+    #[serde(default = "defaults::pull_delay", with = "humantime_serde")]
     pub pull_delay: Duration,
+    /// Lower bound of the pull delay, it's shortened towards it while the
+    /// index is busy (defaults to `pull_delay`)
+    #[serde(default, with = "humantime_serde")]
+    pub pull_delay_min: Option<Duration>,
+    /// Upper bound of the pull delay, it's extended towards it while the index
+    /// is idle (defaults to `pull_delay`)
+    #[serde(default, with = "humantime_serde")]
+    pub pull_delay_max: Option<Duration>,
     /// Logging level
     #[serde(default = "defaults::loglevel")]
     pub loglevel: log::Level,
-    /// Url of crates.io index (git repo)
+    /// Format of the logs
+    #[serde(default)]
+    pub log_format: LogFormat,
+    /// Url of crates.io index (git repo). With `sparse+` prefix (e.g.
+    /// `sparse+https://index.crates.io/`) the sparse (HTTP) index is polled
+    /// instead of cloning & pulling the git one
     #[serde(default = "defaults::index_url")]
     pub index_url: String,
     /// The path to the local crates.io index git repository
     #[serde(default = "defaults::index_path")]
     pub index_path: String,
+    /// Additional registries to watch (in addition to the one configured by
+    /// `index_url` & `index_path`)
+    #[serde(default)]
+    pub registries: Vec<RegistryConfig>,
     /// Delay after which bot will retry telegram-request
+    #[serde(default = "defaults::retry_delay", with = "humantime_serde")]
+    pub retry_delay: Duration,
+    /// Base delay between broadcast send messages (see `queue::Queues`)
+    #[serde(default = "defaults::broadcast_delay", with = "humantime_serde")]
+    pub broadcast_delay: Duration,
+    /// Maximum number of messages being sent at the same time (to different
+    /// chats, messages to the same chat are always sent one by one & in order)
+    #[serde(default = "defaults::send_concurrency")]
+    pub send_concurrency: usize,
+    /// Delay between notifying about updates
+    #[serde(default = "defaults::update_delay", with = "humantime_serde")]
+    pub update_delay: Duration,
+    /// Announce only the newest version of a crate if several versions were
+    /// published since the previous fetch
     #[serde(default)]
-    pub retry_delay: RetryDelay,
-    /// Delay between broadcast send messages
+    pub collapse_updates: bool,
+    /// After announcing a new version of a crate, don't announce further new
+    /// versions of it for this long & then announce only the newest one
+    /// (zero disables the cooldown). Yanks & unyanks are always announced.
+    #[serde(default, with = "humantime_serde")]
+    pub crate_cooldown: Duration,
+    /// Show status of the docs.rs build in new version notifications (pending
+    /// builds are re-checked & messages are edited later)
     #[serde(default)]
-    pub broadcast_delay_millis: BroadcastDelay,
-    /// Delay between notifying about updates
+    pub docs_status: bool,
+    /// Link GitHub releases of new versions of crates.io crates (takes a few
+    /// requests, so it's only looked up if someone gets the notification)
+    #[serde(default)]
+    pub release_links: bool,
+    /// Maximum number of changed features shown in new version notifications
+    /// (zero disables feature changes)
+    #[serde(default = "defaults::feature_changes_limit")]
+    pub feature_changes_limit: usize,
+    /// Maximum number of added/removed/bumped dependencies shown in new
+    /// version notifications (zero disables dependency changes)
+    #[serde(default = "defaults::dep_changes_limit")]
+    pub dep_changes_limit: usize,
+    /// Delay between refreshes of crates of subscribed keywords & categories
+    #[serde(default = "defaults::keyword_refresh_delay", with = "humantime_serde")]
+    pub keyword_refresh_delay: Duration,
+    /// Delay between refreshes of crates of subscribed owners
+    #[serde(default = "defaults::owner_refresh_delay", with = "humantime_serde")]
+    pub owner_refresh_delay: Duration,
+    /// Where the RustSec advisory database is cloned to (advisories of
+    /// subscribed crates aren't sent if not set)
+    #[cfg(feature = "subscriptions")]
     #[serde(default)]
-    pub update_delay_millis: UpdateDelay,
+    pub advisory_db_path: Option<String>,
+    /// Url of the RustSec advisory database (git repo)
+    #[cfg(feature = "subscriptions")]
+    #[serde(default = "defaults::advisory_db_url")]
+    pub advisory_db_url: String,
+    /// Delay between syncs of the advisory database
+    #[serde(default = "defaults::advisory_sync_delay", with = "humantime_serde")]
+    pub advisory_sync_delay: Duration,
+    /// Links shown in notifications (users can choose their own with `/links`)
+    #[serde(default = "defaults::links")]
+    pub links: Vec<Link>,
+    /// Telegram ids of the bot admins
+    #[cfg(feature = "subscriptions")]
+    #[serde(default)]
+    pub admins: Vec<i64>,
+    /// Chat to forward `/feedback` messages to (the command is disabled if not
+    /// set)
+    #[cfg(feature = "subscriptions")]
+    #[serde(default)]
+    pub feedback_chat: Option<ChatId>,
+    /// Chat for operational alerts, e.g. when the index can't be fetched (no
+    /// alerts are sent if not set)
+    #[serde(default)]
+    pub admin_chat: Option<ChatId>,
+    /// Maximum number of subscriptions per chat (admins are exempt)
+    #[cfg(feature = "subscriptions")]
+    #[serde(default = "defaults::max_subscriptions")]
+    pub max_subscriptions: i64,
+    /// Text of the `/start` message (HTML)
+    #[cfg(feature = "subscriptions")]
+    #[serde(default = "defaults::start_message")]
+    pub start_message: String,
     /// Token of the telegram bot
     pub bot_token: String,
     /// Database configuration
+    #[cfg(feature = "subscriptions")]
     pub db: DbConfig,
 }
 
 impl Config {
+    /// Read `./config.toml` & overlay environment variables on top of it (see
+    /// [`ENV_PREFIX`]). The file may be missing if all required fields
+    /// ([`REQUIRED`]) are set via the environment.
+    ///
+    /// [`ENV_PREFIX`]: ENV_PREFIX
+    /// [`REQUIRED`]: REQUIRED
     pub fn read() -> Result<Self, Box<dyn Error>> {
-        let mut str = String::new();
-        File::open("./config.toml")?.read_to_string(&mut str)?;
-        Ok(toml::from_str(&str)?)
+        let mut table = match File::open("./config.toml") {
+            Ok(mut file) => {
+                let mut str = String::new();
+                file.read_to_string(&mut str)?;
+                toml::from_str(&str)?
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => toml::value::Table::new(),
+            Err(err) => return Err(err.into()),
+        };
+        overlay_env(&mut table, std::env::vars());
+        for path in REQUIRED {
+            let mut parts = path.split('.');
+            let first = parts.next().and_then(|key| table.get(key));
+            let value = parts.fold(first, |value, key| value.and_then(|v| v.get(key)));
+            if value.is_none() {
+                let var = format!("{}{}", ENV_PREFIX, path.replace('.', "__").to_uppercase());
+                return Err(format!(
+                    "`{}` is not set (set it in config.toml or via {} environment variable)",
+                    path, var
+                )
+                .into());
+            }
+        }
+        let mut config: Self = toml::Value::Table(table).try_into()?;
+
+        // Make the default registry first in the list, so all registries can be
+        // handled the same way
+        let default = RegistryConfig {
+            name: String::from(DEFAULT_REGISTRY),
+            index_url: config.index_url.clone(),
+            index_path: config.index_path.clone(),
+            branch: defaults::branch(),
+            index: None,
+        };
+        config.registries.insert(0, default);
+
+        if let Some(id) = config.channel {
+            let channel = ChannelCfg {
+                id,
+                message_thread_id: None,
+                actions: None,
+            };
+            config.channels.insert(0, channel);
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check that delays are within reasonable bounds.
+    fn validate(&self) -> Result<(), String> {
+        const ZERO: Duration = Duration::from_secs(0);
+        const SEC: Duration = Duration::from_secs(1);
+        const MIN: Duration = Duration::from_secs(60);
+        const DAY: Duration = Duration::from_secs(60 * 60 * 24);
+
+        let (pull_delay_min, pull_delay_max) = self.pull_delay_bounds();
+        let bounds = [
+            ("pull_delay_min", pull_delay_min, 10 * SEC, self.pull_delay),
+            ("pull_delay", self.pull_delay, 10 * SEC, DAY),
+            ("pull_delay_max", pull_delay_max, self.pull_delay, DAY),
+            ("retry_delay", self.retry_delay, ZERO, 10 * MIN),
+            ("broadcast_delay", self.broadcast_delay, ZERO, 10 * SEC),
+            ("update_delay", self.update_delay, ZERO, MIN),
+            ("crate_cooldown", self.crate_cooldown, ZERO, DAY),
+            (
+                "channel_yank_max_age",
+                self.channel_yank_max_age,
+                ZERO,
+                100 * 365 * DAY,
+            ),
+            // telegram doesn't allow to edit messages older than 48 hours
+            (
+                "channel_rolling_window",
+                self.channel_rolling_window,
+                ZERO,
+                DAY,
+            ),
+            (
+                "keyword_refresh_delay",
+                self.keyword_refresh_delay,
+                MIN,
+                7 * DAY,
+            ),
+            (
+                "owner_refresh_delay",
+                self.owner_refresh_delay,
+                MIN,
+                7 * DAY,
+            ),
+            (
+                "advisory_sync_delay",
+                self.advisory_sync_delay,
+                MIN,
+                7 * DAY,
+            ),
+        ];
+        for (name, value, min, max) in bounds.iter() {
+            if value < min || value > max {
+                return Err(format!(
+                    "`{}` must be between {} and {}, got {}",
+                    name,
+                    humantime::format_duration(*min),
+                    humantime::format_duration(*max),
+                    humantime::format_duration(*value),
+                ));
+            }
+        }
+        // changes of sparse indexes are found via the crates.io API
+        if let Some(registry) = self
+            .registries
+            .iter()
+            .find(|r| !r.is_default() && r.sparse_url().is_some())
+        {
+            return Err(format!(
+                "sparse index of `{}` isn't supported, only the default registry can use the \
+                 sparse protocol",
+                registry.name
+            ));
+        }
+        // telegram allows ~30 messages per second in total anyway
+        if !(1..=30).contains(&self.send_concurrency) {
+            return Err(format!(
+                "`send_concurrency` must be between 1 and 30, got {}",
+                self.send_concurrency
+            ));
+        }
+        Ok(())
+    }
+
+    /// Bounds of the adaptive pull delay, `(min, max)`. Equal bounds mean a
+    /// fixed delay.
+    pub fn pull_delay_bounds(&self) -> (Duration, Duration) {
+        (
+            self.pull_delay_min.unwrap_or(self.pull_delay),
+            self.pull_delay_max.unwrap_or(self.pull_delay),
+        )
+    }
+
+    /// Find registry by its name
+    pub fn registry(&self, name: &str) -> Option<&RegistryConfig> {
+        self.registries.iter().find(|r| r.name == name)
+    }
+
+    /// The registry configured by `index_url` & `index_path`
+    pub fn default_registry(&self) -> &RegistryConfig {
+        &self.registries[0]
     }
 }
 
-#[derive(Debug, serde::Deserialize)]
-pub struct DbConfig {
-    pub host: String,
-    pub user: String,
-    pub dbname: String,
+/// Name of the registry configured by `index_url` & `index_path`
+pub const DEFAULT_REGISTRY: &str = "crates-io";
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+pub struct RegistryConfig {
+    /// Name of the registry, used in commands like `/subscribe crate@name`
+    pub name: String,
+    /// Url of the index: a git repo, or a sparse (HTTP) index if prefixed
+    /// with `sparse+` (like in cargo)
+    pub index_url: String,
+    /// The path to the local index git repository (or to the partial mirror
+    /// of a sparse index, see `index` module)
+    pub index_path: String,
+    /// Branch of the index
+    #[serde(default = "defaults::branch")]
+    pub branch: String,
+    /// `config.json` of the index, read once the index is cloned (see
+    /// [`IndexConfig::read`])
+    ///
+    /// [`IndexConfig::read`]: IndexConfig::read
+    #[serde(skip)]
+    pub index: Option<IndexConfig>,
 }
 
-impl DbConfig {
-    pub fn cfg(&self) -> tokio_postgres::Config {
-        tokio_postgres::Config::new().also(|cfg| {
-            cfg.host(&self.host).user(&self.user).dbname(&self.dbname);
-        })
+impl RegistryConfig {
+    pub fn is_default(&self) -> bool {
+        self.name == DEFAULT_REGISTRY
+    }
+
+    /// Url of the sparse index, if the registry is polled via the sparse
+    /// protocol instead of git.
+    pub fn sparse_url(&self) -> Option<&str> {
+        if self.index_url.starts_with(SPARSE_PREFIX) {
+            Some(&self.index_url[SPARSE_PREFIX.len()..])
+        } else {
+            None
+        }
+    }
+
+    /// Url to download the `.crate` file of the version, if the registry
+    /// `config.json` is known.
+    pub fn download_url(&self, name: &str, version: &str, cksum: &str) -> Option<String> {
+        let dl = &self.index.as_ref()?.dl;
+        if ![
+            "{crate}",
+            "{version}",
+            "{prefix}",
+            "{lowerprefix}",
+            "{sha256-checksum}",
+        ]
+        .iter()
+        .any(|marker| dl.contains(marker))
+        {
+            return Some(format!(
+                "{}/{}/{}/download",
+                dl.trim_end_matches('/'),
+                name,
+                version
+            ));
+        }
+
+        // same as the path of the crate file in the index, without the name
+        let prefix = match name.len() {
+            1 => String::from("1"),
+            2 => String::from("2"),
+            3 => format!("3/{}", &name[..1]),
+            _ => format!("{}/{}", &name[..2], &name[2..4]),
+        };
+        Some(
+            dl.replace("{crate}", name)
+                .replace("{version}", version)
+                .replace("{lowerprefix}", &prefix.to_lowercase())
+                .replace("{prefix}", &prefix)
+                .replace("{sha256-checksum}", cksum),
+        )
     }
 }
 
-#[derive(Clone, Copy, Debug, serde::Deserialize)]
-#[serde(transparent)]
-pub struct BroadcastDelay {
-    pub millis: u64,
+/// Registry metadata from `config.json` in the root of the index
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+pub struct IndexConfig {
+    /// Url (or url template) to download `.crate` files from
+    pub dl: String,
+    /// Url of the registry web API (e.g. `https://crates.io`)
+    #[serde(default)]
+    pub api: Option<String>,
 }
 
-impl Default for BroadcastDelay {
-    fn default() -> Self {
-        Self { millis: 250 } // quoter of a sec
+impl IndexConfig {
+    /// Read `config.json` of the index at `index_path`.
+    pub fn read(index_path: &str) -> Option<Self> {
+        let path = std::path::Path::new(index_path).join("config.json");
+        let res = std::fs::read_to_string(&path)
+            .map_err(|err| err.to_string())
+            .and_then(|json| serde_json::from_str(&json).map_err(|err| err.to_string()));
+        match res {
+            Ok(config) => Some(config),
+            Err(err) => {
+                log::warn!("couldn't read {}: {}", path.display(), err);
+                None
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+pub struct ChannelCfg {
+    /// Id of the channel or supergroup
+    pub id: ChatId,
+    /// Forum topic to post to (only for supergroups with topics enabled)
+    #[serde(default)]
+    pub message_thread_id: Option<i32>,
+    /// Kinds of updates posted to the channel (all if omitted)
+    #[serde(default)]
+    pub actions: Option<Vec<Action>>,
+}
+
+impl ChannelCfg {
+    /// Whether updates of the given kind should be posted to the channel
+    pub fn announces(&self, action: Action) -> bool {
+        self.actions.as_ref().map_or(true, |a| a.contains(&action))
     }
 }
 
-impl From<BroadcastDelay> for Duration {
-    fn from(bd: BroadcastDelay) -> Self {
-        Duration::from_millis(bd.millis)
+/// Link shown in notifications
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Link {
+    DocsRs,
+    CratesIo,
+    LibRs,
+    /// Repository of the crate (from crates.io)
+    Repo,
+}
+
+impl Link {
+    /// All links in the order they are shown
+    pub const ALL: [Link; 4] = [Link::DocsRs, Link::CratesIo, Link::LibRs, Link::Repo];
+
+    /// Name used in the config & the db
+    #[cfg(feature = "subscriptions")]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Link::DocsRs => "docs_rs",
+            Link::CratesIo => "crates_io",
+            Link::LibRs => "lib_rs",
+            Link::Repo => "repo",
+        }
     }
+
+    /// Parse link name, either as in the config (`docs_rs`) or as shown to users
+    /// (`docs.rs`).
+    #[cfg(feature = "subscriptions")]
+    pub fn parse(name: &str) -> Option<Self> {
+        let name = name.replace('.', "_");
+        Self::ALL.iter().copied().find(|link| link.as_str() == name)
+    }
+}
+
+/// Kind of an update, see `ActionKind`
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    NewVersion,
+    Yanked,
+    Unyanked,
+    ChecksumChanged,
 }
 
-#[derive(Clone, Copy, Debug, serde::Deserialize)]
-#[serde(transparent)]
-pub struct UpdateDelay {
-    pub millis: u64,
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable plain text
+    Text,
+    /// Structured json (one object per line)
+    Json,
 }
 
-impl Default for UpdateDelay {
+impl Default for LogFormat {
     fn default() -> Self {
-        Self { millis: 1300 } // 1.3s
+        Self::Text
     }
 }
 
-impl From<UpdateDelay> for Duration {
-    fn from(ud: UpdateDelay) -> Self {
-        Duration::from_millis(ud.millis)
+/// Prefix of environment variables overriding config fields, e.g.
+/// `CRATE_UPD_BOT_BOT_TOKEN` overrides `bot_token`. Fields of tables are
+/// separated by `__`, e.g. `CRATE_UPD_BOT_DB__HOST` overrides `db.host`.
+///
+/// Values are parsed as TOML (so `CRATE_UPD_BOT_ADMINS=[1, 2]` works) and taken
+/// as plain strings if that fails. Values of [`STRINGS`] fields are always
+/// plain strings (e.g. a numeric db password).
+///
+/// [`STRINGS`]: STRINGS
+pub const ENV_PREFIX: &str = "CRATE_UPD_BOT_";
+
+/// String fields, which are set from the environment as is, without parsing as
+/// TOML.
+const STRINGS: &[&str] = &[
+    "bot_token",
+    "db.host",
+    "db.user",
+    "db.dbname",
+    "db.password",
+    "index_url",
+    "index_path",
+    "advisory_db_path",
+    "advisory_db_url",
+    "start_message",
+];
+
+/// Fields without defaults, which must be set either in the file or via the
+/// environment.
+#[cfg(feature = "subscriptions")]
+const REQUIRED: &[&str] = &["bot_token", "db.host", "db.user", "db.dbname"];
+#[cfg(not(feature = "subscriptions"))]
+const REQUIRED: &[&str] = &["bot_token"];
+
+/// Set values from `vars` with [`ENV_PREFIX`] in the `table`, with env taking
+/// precedence.
+///
+/// [`ENV_PREFIX`]: ENV_PREFIX
+fn overlay_env(table: &mut toml::value::Table, vars: impl Iterator<Item = (String, String)>) {
+    for (var, value) in vars {
+        let path = match var.strip_prefix(ENV_PREFIX) {
+            Some(path) if !path.is_empty() => path.to_lowercase(),
+            _ => continue,
+        };
+        let mut keys: Vec<_> = path.split("__").collect();
+        let value = if STRINGS.contains(&keys.join(".").as_str()) {
+            toml::Value::String(value)
+        } else {
+            toml::from_str::<toml::value::Table>(&format!("v = {}", value))
+                .ok()
+                .and_then(|mut t| t.remove("v"))
+                .unwrap_or(toml::Value::String(value))
+        };
+
+        let last = keys.pop().unwrap();
+        let mut table = &mut *table;
+        for key in keys {
+            let entry = table
+                .entry(key)
+                .or_insert(toml::Value::Table(toml::value::Table::new()));
+            if !entry.is_table() {
+                *entry = toml::Value::Table(toml::value::Table::new());
+            }
+            table = entry.as_table_mut().unwrap();
+        }
+        table.insert(last.to_owned(), value);
     }
 }
 
-#[derive(Clone, Copy, Debug, serde::Deserialize)]
-#[serde(transparent)]
-pub struct RetryDelay(pub Duration);
+/// Config that can be reloaded while the bot is running, see [`reload_on_sighup`].
+///
+/// [`reload_on_sighup`]: reload_on_sighup
+#[derive(Clone)]
+pub struct SharedConfig(Arc<RwLock<Arc<Config>>>);
 
-impl Default for RetryDelay {
-    fn default() -> Self {
-        Self(Duration::from_secs(10)) // 10 secs
+impl SharedConfig {
+    pub fn new(config: Config) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(config))))
+    }
+
+    /// Get snapshot of the current config.
+    pub fn get(&self) -> Arc<Config> {
+        Arc::clone(&self.0.read().unwrap())
+    }
+
+    /// Re-read config from the disk & replace the current one.
+    ///
+    /// Fields that can't be changed without restart (token, db, index) are
+    /// left untouched (with a warning if they were changed).
+    pub fn reload(&self) -> Result<(), Box<dyn Error>> {
+        let mut new = Config::read()?;
+        let mut current = self.0.write().unwrap();
+
+        macro_rules! keep {
+            ($($field:ident),*) => {$(
+                if new.$field != current.$field {
+                    log::warn!(concat!("`", stringify!($field), "` can't be changed without restart, ignoring"));
+                }
+                new.$field = current.$field.clone();
+            )*};
+        }
+        keep!(
+            bot_token,
+            index_url,
+            index_path,
+            registries,
+            log_format,
+            send_concurrency
+        );
+        #[cfg(feature = "subscriptions")]
+        keep!(db);
+
+        log::set_max_level(new.loglevel.to_level_filter());
+        *current = Arc::new(new);
+        Ok(())
+    }
+}
+
+/// Reload config every time the process receives `SIGHUP`.
+pub async fn reload_on_sighup(config: SharedConfig) {
+    let mut hups = match signal(SignalKind::hangup()) {
+        Ok(hups) => hups,
+        Err(err) => {
+            log::error!("couldn't install SIGHUP handler: {}", err);
+            return;
+        }
+    };
+
+    while hups.next().await.is_some() {
+        match config.reload() {
+            Ok(()) => log::info!("config reloaded"),
+            Err(err) => log::error!("couldn't reload config: {}", err),
+        }
+    }
+}
+
+#[cfg(feature = "subscriptions")]
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+pub struct DbConfig {
+    pub host: String,
+    pub user: String,
+    pub dbname: String,
+    /// Password of the user (better set via `CRATE_UPD_BOT_DB__PASSWORD`)
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+#[cfg(feature = "subscriptions")]
+impl DbConfig {
+    pub fn cfg(&self) -> tokio_postgres::Config {
+        tokio_postgres::Config::new().also(|cfg| {
+            cfg.host(&self.host).user(&self.user).dbname(&self.dbname);
+            if let Some(password) = &self.password {
+                cfg.password(password);
+            }
+        })
     }
 }
 
@@ -109,15 +662,125 @@ mod defaults {
         Duration::from_secs(60 * 5) // 5 min
     }
 
+    pub(super) const fn retry_delay() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    pub(super) const fn broadcast_delay() -> Duration {
+        Duration::from_millis(250) // quoter of a sec
+    }
+
+    pub(super) fn links() -> Vec<super::Link> {
+        use super::Link::*;
+
+        vec![DocsRs, CratesIo, LibRs]
+    }
+
+    pub(super) const fn update_delay() -> Duration {
+        Duration::from_millis(1300) // 1.3s
+    }
+
+    pub(super) const fn channel_rolling_window() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    pub(super) const fn feature_changes_limit() -> usize {
+        8
+    }
+
+    pub(super) const fn dep_changes_limit() -> usize {
+        8
+    }
+
+    pub(super) const fn keyword_refresh_delay() -> Duration {
+        Duration::from_secs(60 * 60 * 6) // 6 hours
+    }
+
+    #[cfg(feature = "subscriptions")]
+    pub(super) fn advisory_db_url() -> String {
+        String::from("https://github.com/rustsec/advisory-db.git")
+    }
+
+    pub(super) const fn advisory_sync_delay() -> Duration {
+        Duration::from_secs(60 * 60) // 1 hour
+    }
+
+    pub(super) const fn owner_refresh_delay() -> Duration {
+        Duration::from_secs(60 * 60 * 6) // 6 hours
+    }
+
+    pub(super) const fn send_concurrency() -> usize {
+        7
+    }
+
+    #[cfg(feature = "subscriptions")]
+    pub(super) const fn max_subscriptions() -> i64 {
+        200
+    }
+
     pub(super) const fn loglevel() -> log::Level {
         log::Level::Info
     }
 
+    #[cfg(feature = "subscriptions")]
+    pub(super) fn start_message() -> String {
+        String::from(
+            "
+Hi! I will notify you about updates of crates. Use /subscribe to subscribe for updates of crates you want to be notified about.
+
+In case you want to see <b>all</b> updates go to @crates_updates
+
+Author: @wafflelapkin
+His channel [ru]: @ihatereality
+My source: <a href='https://github.com/WaffleLapkin/crate_upd_bot'>[github]</a>",
+        )
+    }
+
     pub(super) fn index_url() -> String {
         String::from("https://github.com/rust-lang/crates.io-index.git")
     }
 
+    pub(super) fn branch() -> String {
+        String::from("master")
+    }
+
     pub(super) fn index_path() -> String {
         String::from("./index")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overlay(vars: &[(&str, &str)]) -> toml::value::Table {
+        let mut table = toml::value::Table::new();
+        let vars = vars.iter().map(|(k, v)| (k.to_string(), v.to_string()));
+        overlay_env(&mut table, vars);
+        table
+    }
+
+    #[test]
+    fn env_strings_stay_strings() {
+        let table = overlay(&[
+            ("CRATE_UPD_BOT_DB__PASSWORD", "123456"),
+            ("CRATE_UPD_BOT_BOT_TOKEN", "true"),
+        ]);
+        assert_eq!(table["db"]["password"].as_str(), Some("123456"));
+        assert_eq!(table["bot_token"].as_str(), Some("true"));
+    }
+
+    #[test]
+    fn env_values_are_toml() {
+        let table = overlay(&[
+            ("CRATE_UPD_BOT_ADMINS", "[1, 2]"),
+            ("CRATE_UPD_BOT_DOCS_STATUS", "true"),
+            ("CRATE_UPD_BOT_INDEX_URL", "https://example.com"),
+            ("OTHER", "1"),
+        ]);
+        assert_eq!(table["admins"].as_array().map(Vec::len), Some(2),);
+        assert_eq!(table["docs_status"].as_bool(), Some(true));
+        assert_eq!(table["index_url"].as_str(), Some("https://example.com"));
+        assert_eq!(table.len(), 3);
+    }
+}
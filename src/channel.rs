@@ -0,0 +1,136 @@
+use crate::{cfg::ChannelCfg, chat::ChatId};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use teloxide::{prelude::Request, types::ChatOrInlineMessage, Bot, RequestError};
+
+/// Maximum length of a telegram message
+const MAX_LEN: usize = 4096;
+
+/// State of the channel posting, kept between pull cycles.
+#[derive(Default)]
+pub struct ChannelState {
+    pub rolling: RollingMessage,
+    /// Updates of the current pull cycle (only with `Config::channel_batch`)
+    pub batch: Vec<Batched>,
+}
+
+/// Update waiting to be posted to the channel at the end of the pull cycle.
+pub struct Batched {
+    pub channel: ChannelCfg,
+    pub line: String,
+    pub silent: bool,
+    /// Event to record the delivery for
+    pub event: Option<i64>,
+}
+
+/// Messages in the channels which are edited to append new updates instead of
+/// posting a new message every time (one message per channel & topic).
+///
+/// A fresh message is started when the current one becomes older than the
+/// rolling window, when appending would exceed telegram's length limit or when
+/// the update must notify (edits never do).
+#[derive(Default)]
+pub struct RollingMessage {
+    current: HashMap<(ChatId, Option<i32>), Current>,
+}
+
+struct Current {
+    message_id: i32,
+    text: String,
+    started: Instant,
+}
+
+impl RollingMessage {
+    pub async fn post(
+        &mut self,
+        bot: &Bot,
+        channel: &ChannelCfg,
+        line: &str,
+        silent: bool,
+        window: Duration,
+    ) -> Result<(), RequestError> {
+        let key = (channel.id, channel.message_thread_id);
+        // loud updates start a new message, edits never notify
+        if let Some(cur) = self.current.get_mut(&key).filter(|_| silent) {
+            let fits = cur.text.len() + 1 + line.len() <= MAX_LEN;
+            if cur.started.elapsed() < window && fits {
+                let text = format!("{}\n{}", cur.text, line);
+                let message = ChatOrInlineMessage::Chat {
+                    chat_id: channel.id.into(),
+                    message_id: cur.message_id,
+                };
+                let res = bot
+                    .edit_message_text(message, &text)
+                    .disable_web_page_preview(true)
+                    .send()
+                    .await;
+                match res {
+                    Ok(_) => {
+                        cur.text = text;
+                        return Ok(());
+                    }
+                    // The message may have been deleted, the update is sent
+                    // as a new message instead
+                    Err(err) => {
+                        log::warn!("couldn't append to the message in {}: {}", channel.id, err);
+                        self.current.remove(&key);
+                    }
+                }
+            }
+        }
+
+        let message_id = send(bot, channel, line, silent).await?;
+        self.current.insert(
+            key,
+            Current {
+                message_id,
+                text: line.to_owned(),
+                started: Instant::now(),
+            },
+        );
+
+        Ok(())
+    }
+}
+
+/// Send message to the channel (or to its topic). Returns id of the message.
+pub async fn send(
+    bot: &Bot,
+    channel: &ChannelCfg,
+    text: &str,
+    silent: bool,
+) -> Result<i32, RequestError> {
+    let mut request = bot
+        .send_message(channel.id, text)
+        .disable_web_page_preview(true)
+        .disable_notification(silent);
+    if let Some(thread) = channel.message_thread_id {
+        request = request.message_thread_id(thread);
+    }
+    Ok(request.send().await?.id)
+}
+
+/// Split `lines` into messages that fit into telegram's length limit, every
+/// message starts with `header`. Returns texts of messages and numbers of lines
+/// in them.
+pub fn split(header: &str, lines: &[&str]) -> Vec<(String, usize)> {
+    let mut messages = Vec::new();
+    let mut text = String::from(header);
+    let mut count = 0;
+    for line in lines {
+        if count > 0 && text.len() + 1 + line.len() > MAX_LEN {
+            messages.push((text, count));
+            text = String::from(header);
+            count = 0;
+        }
+        text.push('\n');
+        text.push_str(line);
+        count += 1;
+    }
+    if count > 0 {
+        messages.push((text, count));
+    }
+    messages
+}
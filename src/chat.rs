@@ -0,0 +1,143 @@
+//! Typed telegram chat ids.
+//!
+//! Raw `i64`s are only used at the edges (db rows, `cx.chat_id()`), so a user
+//! id can't be accidentally passed where a chat is expected.
+use crate::krate::Level;
+use chrono::{DateTime, Utc};
+use std::fmt;
+#[cfg(feature = "subscriptions")]
+use std::time::Duration;
+
+/// Id of a telegram chat (private chat with a user, group or channel).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, serde::Deserialize)]
+#[serde(transparent)]
+pub struct ChatId(pub i64);
+
+/// Kind of a chat, as far as it can be told from its id.
+#[cfg(feature = "subscriptions")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChatKind {
+    /// Private chat with a user
+    User,
+    /// Basic group
+    Group,
+    /// Channel or supergroup (they share the id space)
+    Channel,
+}
+
+#[cfg(feature = "subscriptions")]
+impl ChatId {
+    /// Ids of channels & supergroups are `-100` followed by 10+ digits
+    const CHANNEL_ID_OFFSET: i64 = -1_000_000_000_000;
+
+    pub fn kind(self) -> ChatKind {
+        match self.0 {
+            id if id > 0 => ChatKind::User,
+            id if id <= Self::CHANNEL_ID_OFFSET => ChatKind::Channel,
+            _ => ChatKind::Group,
+        }
+    }
+}
+
+#[cfg(feature = "subscriptions")]
+impl ChatKind {
+    /// Minimal delay between messages to a chat of this kind, see
+    /// https://core.telegram.org/bots/faq#my-bot-is-hitting-limits-how-do-i-avoid-this
+    pub fn min_delay(self) -> Duration {
+        match self {
+            // "avoid sending more than one message per second"
+            ChatKind::User => Duration::from_secs(1),
+            // "not be able to send more than 20 messages per minute"
+            ChatKind::Group | ChatKind::Channel => Duration::from_secs(3),
+        }
+    }
+}
+
+impl fmt::Display for ChatId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl From<i64> for ChatId {
+    fn from(id: i64) -> Self {
+        Self(id)
+    }
+}
+
+impl From<ChatId> for teloxide::types::ChatId {
+    fn from(id: ChatId) -> Self {
+        Self::Id(id.0)
+    }
+}
+
+/// Kinds of updates a subscription gets (`/subscribe <crate> --events ..`), a
+/// bit mask.
+// without `subscriptions` it's only in the type of `Subscriber`
+#[cfg_attr(not(feature = "subscriptions"), allow(dead_code))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Events(i16);
+
+#[cfg(feature = "subscriptions")]
+impl Events {
+    /// New versions (& checksum changes)
+    pub const UPDATES: Self = Self(1);
+    /// Yanks & unyanks
+    pub const YANKS: Self = Self(2);
+    pub const ALL: Self = Self(Self::UPDATES.0 | Self::YANKS.0);
+
+    /// Parse the argument of `/subscribe <crate> --events`
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "all" => Some(Self::ALL),
+            "update" | "updates" => Some(Self::UPDATES),
+            "yank" | "yanks" => Some(Self::YANKS),
+            _ => None,
+        }
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Stored as `smallint` in the db, unknown bits are ignored
+    pub fn from_db(events: i16) -> Self {
+        Self(events & Self::ALL.0)
+    }
+
+    pub fn to_db(self) -> i16 {
+        self.0
+    }
+}
+
+/// Chat subscribed to a crate, with options of the subscription.
+// without `subscriptions` there are no subscribers, but the type is still in
+// the signatures of the update pipeline
+#[cfg_attr(not(feature = "subscriptions"), allow(dead_code))]
+#[derive(Clone, Debug)]
+pub struct Subscriber {
+    pub chat_id: ChatId,
+    /// Kinds of updates that are sent (`/subscribe <crate> --events yank` or
+    /// `/subscribe_yanks`)
+    pub events: Events,
+    /// New versions are sent only if they are releases of at least this level
+    /// (`/subscribe <crate> --only minor`)
+    pub min_level: Level,
+    /// Whether pre-release versions are sent (`/subscribe <crate>
+    /// --prereleases` or `--no-prereleases`), `None` follows the chat default
+    /// (`/prereleases`)
+    pub prereleases: Option<bool>,
+    /// New versions come with release notes (`/subscribe <crate> --changelog`)
+    pub changelog: bool,
+    /// Label of the subscription (`/subscribe <crate> #label`)
+    pub label: Option<String>,
+    /// Notifications are muted until this time (`/mute <crate> <duration>`)
+    pub muted_until: Option<DateTime<Utc>>,
+}
+
+#[cfg(feature = "subscriptions")]
+impl Subscriber {
+    pub fn is_muted(&self, now: DateTime<Utc>) -> bool {
+        self.muted_until.map_or(false, |until| until > now)
+    }
+}
@@ -0,0 +1,205 @@
+//! Per-crate cooldown of new version announcements (see
+//! `Config::crate_cooldown`).
+//!
+//! Times are taken from the index commits (i.e. when the versions were
+//! published), so replaying old commits behaves the same as the live run.
+//!
+//! Commits of suppressed versions are marked as processed, so the state is
+//! saved to the database (see [`Cooldowns::snapshot`]) to not lose the
+//! catch-ups on restart.
+#[cfg(feature = "subscriptions")]
+use crate::{commit_span, krate::Crate};
+use crate::{ActionKind, Update};
+#[cfg(feature = "subscriptions")]
+use git2::Oid;
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+#[derive(Default)]
+pub struct Cooldowns {
+    /// (registry, crate name) -> state
+    crates: Mutex<HashMap<(String, String), Cooldown>>,
+}
+
+struct Cooldown {
+    /// Time of the last announced version (unix seconds)
+    announced: i64,
+    /// The newest version that was not announced because of the cooldown
+    pending: Option<Update>,
+    /// Number of versions that were not announced since `announced`
+    suppressed: usize,
+}
+
+/// Saved [`Cooldown`] (`C` is `&Crate` when saving)
+#[cfg(feature = "subscriptions")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Saved<C> {
+    announced: i64,
+    /// The pending version & its time
+    pending: Option<(C, i64)>,
+    suppressed: usize,
+}
+
+impl Cooldowns {
+    /// Returns the update if it should be announced now, otherwise keeps it
+    /// to be announced once the cooldown is over (see [`take_expired`]).
+    ///
+    /// [`take_expired`]: Cooldowns::take_expired
+    pub fn check(&self, registry: &str, update: Update, cooldown: Duration) -> Option<Update> {
+        if cooldown.as_secs() == 0 || !matches!(update.action, ActionKind::NewVersion) {
+            return Some(update);
+        }
+
+        let key = (registry.to_owned(), update.krate.id.name.clone());
+        let mut crates = self.crates.lock().unwrap();
+        match crates.get_mut(&key) {
+            Some(state) if update.time - state.announced < cooldown.as_secs() as i64 => {
+                state.suppressed += 1 + update.skipped;
+                state.pending = Some(update);
+                None
+            }
+            state => {
+                let mut update = update;
+                // a newer version came after the cooldown, but before the
+                // pending one was announced: it's announced as the catch-up
+                if let Some(state) = state.filter(|state| state.pending.is_some()) {
+                    update.suppressed = state.suppressed + 1 + update.skipped;
+                    update.skipped = 0;
+                }
+                crates.insert(
+                    key,
+                    Cooldown {
+                        announced: update.time,
+                        pending: None,
+                        suppressed: 0,
+                    },
+                );
+                Some(update)
+            }
+        }
+    }
+
+    /// Take versions of the registry crates whose cooldown is over, to be
+    /// announced as a catch-up. `now` is unix time in seconds. Also returns
+    /// names of all crates whose cooldown is over.
+    pub fn take_expired(
+        &self,
+        registry: &str,
+        cooldown: Duration,
+        now: i64,
+    ) -> (Vec<Update>, Vec<String>) {
+        let mut crates = self.crates.lock().unwrap();
+        let mut expired = Vec::new();
+        let mut names = Vec::new();
+        crates.retain(|(reg, name), state| {
+            if reg != registry || now - state.announced < cooldown.as_secs() as i64 {
+                return true;
+            }
+            names.push(name.clone());
+            if let Some(mut update) = state.pending.take() {
+                // already counted in `suppressed`
+                update.skipped = 0;
+                update.suppressed = state.suppressed;
+                expired.push(update);
+            }
+            // the catch-up is passed through `check` again, which starts a
+            // new cooldown
+            false
+        });
+        (expired, names)
+    }
+
+    /// Serialized state of the crate's cooldown, `None` if there is none.
+    #[cfg(feature = "subscriptions")]
+    pub fn snapshot(&self, registry: &str, krate: &str) -> Option<String> {
+        let crates = self.crates.lock().unwrap();
+        let state = crates.get(&(registry.to_owned(), krate.to_owned()))?;
+        let saved = Saved {
+            announced: state.announced,
+            pending: state
+                .pending
+                .as_ref()
+                .map(|update| (&update.krate, update.time)),
+            suppressed: state.suppressed,
+        };
+        serde_json::to_string(&saved)
+            .map_err(|err| log::error!("couldn't serialize cooldown of {}: {}", krate, err))
+            .ok()
+    }
+
+    /// Restore state of the crate's cooldown saved with [`Cooldowns::snapshot`].
+    #[cfg(feature = "subscriptions")]
+    pub fn restore(&self, registry: &str, krate: &str, state: &str) {
+        let saved: Saved<Crate> = match serde_json::from_str(state) {
+            Ok(saved) => saved,
+            Err(err) => {
+                log::warn!("skipping invalid saved cooldown of {}: {}", krate, err);
+                return;
+            }
+        };
+        let pending = saved.pending.map(|(krate, time)| Update {
+            oid: Oid::zero(),
+            time,
+            krate,
+            action: ActionKind::NewVersion,
+            created: false,
+            skipped: 0,
+            suppressed: 0,
+            span: commit_span(Oid::zero()),
+        });
+        let state = Cooldown {
+            announced: saved.announced,
+            pending,
+            suppressed: saved.suppressed,
+        };
+        self.crates
+            .lock()
+            .unwrap()
+            .insert((registry.to_owned(), krate.to_owned()), state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{commit_span, parse_index_line};
+    use git2::Oid;
+
+    fn update(vers: &str, time: i64) -> Update {
+        let line = format!(
+            r#"{{"name":"foo","vers":"{}","deps":[],"cksum":"00","features":{{}},"yanked":false}}"#,
+            vers
+        );
+        Update {
+            oid: Oid::zero(),
+            time,
+            krate: parse_index_line(line.as_bytes()).unwrap(),
+            action: ActionKind::NewVersion,
+            created: false,
+            skipped: 0,
+            suppressed: 0,
+            span: commit_span(Oid::zero()),
+        }
+    }
+
+    #[test]
+    fn newer_version_replaces_pending_catch_up() {
+        let cooldowns = Cooldowns::default();
+        let cooldown = Duration::from_secs(60);
+        assert!(cooldowns
+            .check("crates-io", update("1.0.0", 0), cooldown)
+            .is_some());
+        assert!(cooldowns
+            .check("crates-io", update("1.0.1", 10), cooldown)
+            .is_none());
+
+        // the cooldown is over, but the pending 1.0.1 wasn't taken yet
+        let mut newer = update("1.0.3", 100);
+        newer.skipped = 1;
+        let announced = cooldowns.check("crates-io", newer, cooldown).unwrap();
+        assert_eq!(announced.krate.id.vers, "1.0.3");
+        assert_eq!((announced.suppressed, announced.skipped), (3, 0));
+
+        let (expired, _) = cooldowns.take_expired("crates-io", cooldown, 200);
+        assert!(expired.is_empty());
+    }
+}
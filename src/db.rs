@@ -1,18 +1,42 @@
+use crate::{
+    cfg::Link,
+    chat::{ChatId, Events, Subscriber},
+    digest::Period,
+    krate::Level,
+};
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt};
 use tokio_postgres::tls::MakeTlsConnect;
-use tokio_postgres::types::Type;
+use tokio_postgres::types::{ToSql, Type};
 use tokio_postgres::{Client, Config, Connection, Error, Socket};
 
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// For how long subscriber counts are cached
+const COUNT_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// For how long the most-subscribed crates are cached
+const TOP_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
 
 #[derive(Clone)]
 pub struct Database {
     inner: Arc<Client>, // TODO: WHy doesn't it implement clone?
+    /// (registry, crate name) -> (time of the query, number of subscribers)
+    counts: Arc<Mutex<HashMap<(String, String), (Instant, i64)>>>,
+    /// (registry, limit) -> (time of the query, crates with subscriber counts)
+    top: Arc<Mutex<HashMap<(String, i32), (Instant, Vec<(String, i64)>)>>>,
 }
 
 impl Database {
     pub fn new(client: Client) -> Self {
         Self {
             inner: Arc::new(client),
+            counts: Arc::default(),
+            top: Arc::default(),
         }
     }
 
@@ -29,37 +53,540 @@ impl Database {
             .map(|(client, connection)| (Self::new(client), connection))
     }
 
-    pub async fn subscribe(&self, user_id: i64, krate: &str) -> Result<(), Error> {
+    /// Check that the database works & has the schema from `db.sql`.
+    pub async fn ping(&self) -> Result<(), Error> {
+        self.inner
+            .execute("SELECT subscription_count(0)", &[])
+            .await?;
+
+        Ok(())
+    }
+
+    /// Subscribe the user to the crate (replaces options of an existing
+    /// subscription, its label is kept if `label` is `None`).
+    #[allow(clippy::too_many_arguments)]
+    pub async fn subscribe(
+        &self,
+        user_id: ChatId,
+        registry: &str,
+        krate: &str,
+        events: Events,
+        min_level: Level,
+        prereleases: Option<bool>,
+        changelog: bool,
+        label: Option<&str>,
+    ) -> Result<(), Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "CALL subscribe($1, $2, $3, $4, $5, $6, $7, $8)",
+                &[
+                    Type::INT8,
+                    Type::VARCHAR,
+                    Type::VARCHAR,
+                    Type::INT2,
+                    Type::INT2,
+                    Type::BOOL,
+                    Type::BOOL,
+                    Type::VARCHAR,
+                ],
+            )
+            .await?;
+
+        let params: [&(dyn ToSql + Sync); 8] = [
+            &user_id.0,
+            &registry,
+            &krate,
+            &events.to_db(),
+            &min_level.to_db(),
+            &prereleases,
+            &changelog,
+            &label,
+        ];
+        self.inner.execute(&stmt, &params).await?;
+        self.invalidate_count(registry, krate);
+
+        Ok(())
+    }
+
+    /// Subscribe the user to yanks of the crate only (switches an existing
+    /// subscription to yanks-only).
+    pub async fn subscribe_yanks(
+        &self,
+        user_id: ChatId,
+        registry: &str,
+        krate: &str,
+    ) -> Result<(), Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "CALL subscribe_yanks($1, $2, $3)",
+                &[Type::INT8, Type::VARCHAR, Type::VARCHAR],
+            )
+            .await?;
+
+        self.inner
+            .execute(&stmt, &[&user_id.0, &registry, &krate])
+            .await?;
+        self.invalidate_count(registry, krate);
+
+        Ok(())
+    }
+
+    /// Subscribe the user to several crates of the same registry at once.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn subscribe_many(
+        &self,
+        user_id: ChatId,
+        registry: &str,
+        krates: &[&str],
+        events: Events,
+        min_level: Level,
+        prereleases: Option<bool>,
+        changelog: bool,
+        label: Option<&str>,
+    ) -> Result<(), Error> {
         let stmt = self
             .inner
-            .prepare_typed("CALL subscribe($1, $2)", &[Type::INT8, Type::VARCHAR])
+            .prepare_typed(
+                "CALL subscribe_many($1, $2, $3, $4, $5, $6, $7, $8)",
+                &[
+                    Type::INT8,
+                    Type::VARCHAR,
+                    Type::VARCHAR_ARRAY,
+                    Type::INT2,
+                    Type::INT2,
+                    Type::BOOL,
+                    Type::BOOL,
+                    Type::VARCHAR,
+                ],
+            )
             .await?;
 
-        self.inner.execute(&stmt, &[&user_id, &krate]).await?;
+        let params: [&(dyn ToSql + Sync); 8] = [
+            &user_id.0,
+            &registry,
+            &krates,
+            &events.to_db(),
+            &min_level.to_db(),
+            &prereleases,
+            &changelog,
+            &label,
+        ];
+        self.inner.execute(&stmt, &params).await?;
+        for krate in krates {
+            self.invalidate_count(registry, krate);
+        }
 
         Ok(())
     }
 
-    pub async fn unsubscribe(&self, user_id: i64, krate: &str) -> Result<(), Error> {
+    /// Returns `false` if the user wasn't subscribed to the crate.
+    pub async fn unsubscribe(
+        &self,
+        user_id: ChatId,
+        registry: &str,
+        krate: &str,
+    ) -> Result<bool, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "CALL unsubscribe($1, $2, $3)",
+                &[Type::INT8, Type::VARCHAR, Type::VARCHAR],
+            )
+            .await?;
+
+        let removed: i64 = self
+            .inner
+            .query_one(&stmt, &[&user_id.0, &registry, &krate])
+            .await?
+            .get(0);
+        self.invalidate_count(registry, krate);
+
+        Ok(removed > 0)
+    }
+
+    /// Mute the subscription until the given time (`None` unmutes it).
+    /// Returns `false` if the user isn't subscribed to the crate.
+    pub async fn mute(
+        &self,
+        user_id: ChatId,
+        registry: &str,
+        krate: &str,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<bool, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "CALL mute($1, $2, $3, $4)",
+                &[Type::INT8, Type::VARCHAR, Type::VARCHAR, Type::TIMESTAMPTZ],
+            )
+            .await?;
+
+        let found: i64 = self
+            .inner
+            .query_one(&stmt, &[&user_id.0, &registry, &krate, &until])
+            .await?
+            .get(0);
+
+        Ok(found > 0)
+    }
+
+    /// Remove all subscriptions of the user (including keyword, category,
+    /// owner & pattern subscriptions, alerts and new crate watches).
+    pub async fn unsubscribe_all(&self, user_id: ChatId) -> Result<(), Error> {
         let stmt = self
             .inner
-            .prepare_typed("CALL unsubscribe($1, $2)", &[Type::INT8, Type::VARCHAR])
+            .prepare_typed("CALL unsubscribe_all($1)", &[Type::INT8])
             .await?;
 
-        self.inner.execute(&stmt, &[&user_id, &krate]).await?;
+        self.inner.execute(&stmt, &[&user_id.0]).await?;
+        // we don't know which crates were affected
+        self.counts.lock().unwrap().clear();
 
         Ok(())
     }
 
-    pub async fn list_subscribers(&self, krate: &str) -> Result<Vec<i64>, Error> {
+    /// Number of subscribers of the crate. Results are cached for a short time.
+    pub async fn subscriber_count(&self, registry: &str, krate: &str) -> Result<i64, Error> {
+        let key = (registry.to_owned(), krate.to_owned());
+        let cached = self.counts.lock().unwrap().get(&key).copied();
+        if let Some((at, count)) = cached {
+            if at.elapsed() < COUNT_CACHE_TTL {
+                return Ok(count);
+            }
+        }
+
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "SELECT subscriber_count($1, $2)",
+                &[Type::VARCHAR, Type::VARCHAR],
+            )
+            .await?;
+
+        let count = self
+            .inner
+            .query_one(&stmt, &[&registry, &krate])
+            .await?
+            .get(0);
+        self.counts
+            .lock()
+            .unwrap()
+            .insert(key, (Instant::now(), count));
+
+        Ok(count)
+    }
+
+    /// Subscriber counts of all crates the user is subscribed to, as
+    /// `(registry, crate) -> count`.
+    pub async fn subscriber_counts(
+        &self,
+        user_id: ChatId,
+    ) -> Result<HashMap<(String, String), i64>, Error> {
         let stmt = self
             .inner
-            .prepare_typed("SELECT user_id from list_subscribers($1)", &[Type::VARCHAR])
+            .prepare_typed(
+                "SELECT registry, crate_name, subscribers from subscriber_counts_of($1)",
+                &[Type::INT8],
+            )
             .await?;
 
         let res = self
             .inner
-            .query(&stmt, &[&krate])
+            .query(&stmt, &[&user_id.0])
+            .await?
+            .into_iter()
+            .map(|row| ((row.get(0), row.get(1)), row.get(2)))
+            .collect();
+
+        Ok(res)
+    }
+
+    /// `limit` crates of the registry with the most subscribers, most
+    /// subscribed first.
+    pub async fn top_crates(
+        &self,
+        registry: &str,
+        limit: i32,
+    ) -> Result<Vec<(String, i64)>, Error> {
+        let key = (registry.to_owned(), limit);
+        if let Some((at, top)) = self.top.lock().unwrap().get(&key) {
+            if at.elapsed() < TOP_CACHE_TTL {
+                return Ok(top.clone());
+            }
+        }
+
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "SELECT crate_name, subscribers from top_crates($1, $2)",
+                &[Type::VARCHAR, Type::INT4],
+            )
+            .await?;
+
+        let top: Vec<_> = self
+            .inner
+            .query(&stmt, &[&registry, &limit])
+            .await?
+            .into_iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect();
+        self.top
+            .lock()
+            .unwrap()
+            .insert(key, (Instant::now(), top.clone()));
+
+        Ok(top)
+    }
+
+    /// Whether the user is subscribed to the crate with exactly these options
+    /// (see [`Subscriber`]).
+    ///
+    /// [`Subscriber`]: crate::chat::Subscriber
+    #[allow(clippy::too_many_arguments)]
+    pub async fn is_subscribed(
+        &self,
+        user_id: ChatId,
+        registry: &str,
+        krate: &str,
+        events: Events,
+        min_level: Level,
+        prereleases: Option<bool>,
+        changelog: bool,
+    ) -> Result<bool, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "SELECT is_subscribed($1, $2, $3, $4, $5, $6, $7)",
+                &[
+                    Type::INT8,
+                    Type::VARCHAR,
+                    Type::VARCHAR,
+                    Type::INT2,
+                    Type::INT2,
+                    Type::BOOL,
+                    Type::BOOL,
+                ],
+            )
+            .await?;
+
+        let params: [&(dyn ToSql + Sync); 7] = [
+            &user_id.0,
+            &registry,
+            &krate,
+            &events.to_db(),
+            &min_level.to_db(),
+            &prereleases,
+            &changelog,
+        ];
+        let subscribed = self.inner.query_one(&stmt, &params).await?.get(0);
+
+        Ok(subscribed)
+    }
+
+    /// Whether the user is subscribed to the crate (with any options).
+    pub async fn has_subscription(
+        &self,
+        user_id: ChatId,
+        registry: &str,
+        krate: &str,
+    ) -> Result<bool, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "SELECT has_subscription($1, $2, $3)",
+                &[Type::INT8, Type::VARCHAR, Type::VARCHAR],
+            )
+            .await?;
+
+        let subscribed = self
+            .inner
+            .query_one(&stmt, &[&user_id.0, &registry, &krate])
+            .await?
+            .get(0);
+
+        Ok(subscribed)
+    }
+
+    /// Number of crates the user is subscribed to.
+    pub async fn subscription_count(&self, user_id: ChatId) -> Result<i64, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed("SELECT subscription_count($1)", &[Type::INT8])
+            .await?;
+
+        let count = self.inner.query_one(&stmt, &[&user_id.0]).await?.get(0);
+
+        Ok(count)
+    }
+
+    fn invalidate_count(&self, registry: &str, krate: &str) {
+        self.counts
+            .lock()
+            .unwrap()
+            .remove(&(registry.to_owned(), krate.to_owned()));
+    }
+
+    /// Subscribers of the crate. Rows are streamed from the db, so huge
+    /// numbers of subscribers are never loaded into memory at once.
+    pub async fn list_subscribers(
+        &self,
+        registry: &str,
+        krate: &str,
+    ) -> Result<impl Stream<Item = Result<Subscriber, Error>>, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "SELECT user_id, events, min_level, prereleases, changelog, label, \
+                 muted_until from list_subscribers($1, $2)",
+                &[Type::VARCHAR, Type::VARCHAR],
+            )
+            .await?;
+
+        let params: [&dyn ToSql; 2] = [&registry, &krate];
+        let rows = self.inner.query_raw(&stmt, params.iter().copied()).await?;
+
+        Ok(rows.map(|row| {
+            row.map(|row| Subscriber {
+                chat_id: ChatId(row.get(0)),
+                events: Events::from_db(row.get(1)),
+                min_level: Level::from_db(row.get(2)),
+                prereleases: row.get(3),
+                changelog: row.get(4),
+                label: row.get(5),
+                muted_until: row.get(6),
+            })
+        }))
+    }
+
+    /// Subscribers of several crates at once (`crate name -> subscribers`,
+    /// crates without subscribers are omitted).
+    pub async fn list_subscribers_of(
+        &self,
+        registry: &str,
+        krates: &[String],
+    ) -> Result<HashMap<String, Vec<Subscriber>>, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "SELECT crate_name, user_id, events, min_level, prereleases, changelog, \
+                 label, muted_until from list_subscribers_of($1, $2)",
+                &[Type::VARCHAR, Type::VARCHAR_ARRAY],
+            )
+            .await?;
+
+        let mut res = HashMap::<_, Vec<_>>::new();
+        for row in self.inner.query(&stmt, &[&registry, &krates]).await? {
+            res.entry(row.get(0)).or_default().push(Subscriber {
+                chat_id: ChatId(row.get(1)),
+                events: Events::from_db(row.get(2)),
+                min_level: Level::from_db(row.get(3)),
+                prereleases: row.get(4),
+                changelog: row.get(5),
+                label: row.get(6),
+                muted_until: row.get(7),
+            });
+        }
+
+        Ok(res)
+    }
+
+    /// Subscriptions of the user, only ones with the label (case-insensitive)
+    /// if `label` is given.
+    pub async fn list_subscriptions(
+        &self,
+        user_id: ChatId,
+        label: Option<&str>,
+    ) -> Result<Vec<Subscription>, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "SELECT registry_name, crate_name, created_at, events, min_level, \
+                 prereleases, changelog, label, muted_until from list_subscriptions($1, $2)",
+                &[Type::INT8, Type::VARCHAR],
+            )
+            .await?;
+
+        let res = self
+            .inner
+            .query(&stmt, &[&user_id.0, &label])
+            .await?
+            .into_iter()
+            .map(|row| Subscription {
+                registry: row.get(0),
+                krate: row.get(1),
+                created_at: row.get(2),
+                events: Events::from_db(row.get(3)),
+                min_level: Level::from_db(row.get(4)),
+                prereleases: row.get(5),
+                changelog: row.get(6),
+                label: row.get(7),
+                muted_until: row.get(8),
+            })
+            .collect();
+
+        Ok(res)
+    }
+
+    pub async fn subscribe_keyword(&self, user_id: ChatId, keyword: &str) -> Result<(), Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "CALL subscribe_keyword($1, $2)",
+                &[Type::INT8, Type::VARCHAR],
+            )
+            .await?;
+
+        self.inner.execute(&stmt, &[&user_id.0, &keyword]).await?;
+
+        Ok(())
+    }
+
+    pub async fn unsubscribe_keyword(&self, user_id: ChatId, keyword: &str) -> Result<(), Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "CALL unsubscribe_keyword($1, $2)",
+                &[Type::INT8, Type::VARCHAR],
+            )
+            .await?;
+
+        self.inner.execute(&stmt, &[&user_id.0, &keyword]).await?;
+
+        Ok(())
+    }
+
+    pub async fn list_keyword_subscriptions(&self, user_id: ChatId) -> Result<Vec<String>, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "SELECT keyword_name from list_keyword_subscriptions($1)",
+                &[Type::INT8],
+            )
+            .await?;
+
+        let res = self
+            .inner
+            .query(&stmt, &[&user_id.0])
+            .await?
+            .into_iter()
+            .map(|row| row.get(0))
+            .collect();
+
+        Ok(res)
+    }
+
+    /// List all keywords that have at least one subscriber.
+    pub async fn list_subscribed_keywords(&self) -> Result<Vec<String>, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed("SELECT keyword_name from list_subscribed_keywords()", &[])
+            .await?;
+
+        let res = self
+            .inner
+            .query(&stmt, &[])
             .await?
             .into_iter()
             .map(|row| row.get(0))
@@ -68,18 +595,97 @@ impl Database {
         Ok(res)
     }
 
-    pub async fn list_subscriptions(&self, user_id: i64) -> Result<Vec<String>, Error> {
+    /// Replace crates of the keyword.
+    pub async fn set_keyword_crates(&self, keyword: &str, crates: &[String]) -> Result<(), Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "CALL set_keyword_crates($1, $2)",
+                &[Type::VARCHAR, Type::VARCHAR_ARRAY],
+            )
+            .await?;
+
+        self.inner.execute(&stmt, &[&keyword, &crates]).await?;
+
+        Ok(())
+    }
+
+    pub async fn add_keyword_crate(&self, keyword: &str, krate: &str) -> Result<(), Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "CALL add_keyword_crate($1, $2)",
+                &[Type::VARCHAR, Type::VARCHAR],
+            )
+            .await?;
+
+        self.inner.execute(&stmt, &[&keyword, &krate]).await?;
+
+        Ok(())
+    }
+
+    /// List users subscribed to any keyword of the crate.
+    pub async fn list_keyword_subscribers(&self, krate: &str) -> Result<Vec<ChatId>, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "SELECT user_id from list_keyword_subscribers($1)",
+                &[Type::VARCHAR],
+            )
+            .await?;
+
+        let res = self
+            .inner
+            .query(&stmt, &[&krate])
+            .await?
+            .into_iter()
+            .map(|row| ChatId(row.get(0)))
+            .collect();
+
+        Ok(res)
+    }
+
+    /// Subscribe to crates of the crates.io category (slug).
+    pub async fn subscribe_category(&self, user_id: ChatId, category: &str) -> Result<(), Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "CALL subscribe_category($1, $2)",
+                &[Type::INT8, Type::VARCHAR],
+            )
+            .await?;
+
+        self.inner.execute(&stmt, &[&user_id.0, &category]).await?;
+
+        Ok(())
+    }
+
+    pub async fn unsubscribe_category(&self, user_id: ChatId, category: &str) -> Result<(), Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "CALL unsubscribe_category($1, $2)",
+                &[Type::INT8, Type::VARCHAR],
+            )
+            .await?;
+
+        self.inner.execute(&stmt, &[&user_id.0, &category]).await?;
+
+        Ok(())
+    }
+
+    pub async fn list_category_subscriptions(&self, user_id: ChatId) -> Result<Vec<String>, Error> {
         let stmt = self
             .inner
             .prepare_typed(
-                "SELECT crate_name from list_subscriptions($1)",
+                "SELECT category_name from list_category_subscriptions($1)",
                 &[Type::INT8],
             )
             .await?;
 
         let res = self
             .inner
-            .query(&stmt, &[&user_id])
+            .query(&stmt, &[&user_id.0])
             .await?
             .into_iter()
             .map(|row| row.get(0))
@@ -87,4 +693,944 @@ impl Database {
 
         Ok(res)
     }
+
+    /// List all categories that have at least one subscriber.
+    pub async fn list_subscribed_categories(&self) -> Result<Vec<String>, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "SELECT category_name from list_subscribed_categories()",
+                &[],
+            )
+            .await?;
+
+        let res = self
+            .inner
+            .query(&stmt, &[])
+            .await?
+            .into_iter()
+            .map(|row| row.get(0))
+            .collect();
+
+        Ok(res)
+    }
+
+    /// Replace crates of the category.
+    pub async fn set_category_crates(
+        &self,
+        category: &str,
+        crates: &[String],
+    ) -> Result<(), Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "CALL set_category_crates($1, $2)",
+                &[Type::VARCHAR, Type::VARCHAR_ARRAY],
+            )
+            .await?;
+
+        self.inner.execute(&stmt, &[&category, &crates]).await?;
+
+        Ok(())
+    }
+
+    pub async fn add_category_crate(&self, category: &str, krate: &str) -> Result<(), Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "CALL add_category_crate($1, $2)",
+                &[Type::VARCHAR, Type::VARCHAR],
+            )
+            .await?;
+
+        self.inner.execute(&stmt, &[&category, &krate]).await?;
+
+        Ok(())
+    }
+
+    /// List users subscribed to any category of the crate.
+    pub async fn list_category_subscribers(&self, krate: &str) -> Result<Vec<ChatId>, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "SELECT user_id from list_category_subscribers($1)",
+                &[Type::VARCHAR],
+            )
+            .await?;
+
+        let res = self
+            .inner
+            .query(&stmt, &[&krate])
+            .await?
+            .into_iter()
+            .map(|row| ChatId(row.get(0)))
+            .collect();
+
+        Ok(res)
+    }
+
+    /// Subscribe to crates of the crates.io user (lowercase login).
+    pub async fn subscribe_owner(&self, user_id: ChatId, owner: &str) -> Result<(), Error> {
+        let stmt = self
+            .inner
+            .prepare_typed("CALL subscribe_owner($1, $2)", &[Type::INT8, Type::VARCHAR])
+            .await?;
+
+        self.inner.execute(&stmt, &[&user_id.0, &owner]).await?;
+
+        Ok(())
+    }
+
+    pub async fn unsubscribe_owner(&self, user_id: ChatId, owner: &str) -> Result<(), Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "CALL unsubscribe_owner($1, $2)",
+                &[Type::INT8, Type::VARCHAR],
+            )
+            .await?;
+
+        self.inner.execute(&stmt, &[&user_id.0, &owner]).await?;
+
+        Ok(())
+    }
+
+    pub async fn list_owner_subscriptions(&self, user_id: ChatId) -> Result<Vec<String>, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "SELECT owner_name from list_owner_subscriptions($1)",
+                &[Type::INT8],
+            )
+            .await?;
+
+        let res = self
+            .inner
+            .query(&stmt, &[&user_id.0])
+            .await?
+            .into_iter()
+            .map(|row| row.get(0))
+            .collect();
+
+        Ok(res)
+    }
+
+    /// List all owners that have at least one subscriber.
+    pub async fn list_subscribed_owners(&self) -> Result<Vec<String>, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed("SELECT owner_name from list_subscribed_owners()", &[])
+            .await?;
+
+        let res = self
+            .inner
+            .query(&stmt, &[])
+            .await?
+            .into_iter()
+            .map(|row| row.get(0))
+            .collect();
+
+        Ok(res)
+    }
+
+    /// Replace crates of the owner.
+    pub async fn set_owner_crates(&self, owner: &str, crates: &[String]) -> Result<(), Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "CALL set_owner_crates($1, $2)",
+                &[Type::VARCHAR, Type::VARCHAR_ARRAY],
+            )
+            .await?;
+
+        self.inner.execute(&stmt, &[&owner, &crates]).await?;
+
+        Ok(())
+    }
+
+    pub async fn add_owner_crate(&self, owner: &str, krate: &str) -> Result<(), Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "CALL add_owner_crate($1, $2)",
+                &[Type::VARCHAR, Type::VARCHAR],
+            )
+            .await?;
+
+        self.inner.execute(&stmt, &[&owner, &krate]).await?;
+
+        Ok(())
+    }
+
+    /// List users subscribed to any owner of the crate.
+    pub async fn list_owner_subscribers(&self, krate: &str) -> Result<Vec<ChatId>, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "SELECT user_id from list_owner_subscribers($1)",
+                &[Type::VARCHAR],
+            )
+            .await?;
+
+        let res = self
+            .inner
+            .query(&stmt, &[&krate])
+            .await?
+            .into_iter()
+            .map(|row| ChatId(row.get(0)))
+            .collect();
+
+        Ok(res)
+    }
+
+    /// Subscribe to all crates of the registry whose names start with
+    /// `prefix` (lowercase).
+    pub async fn subscribe_pattern(
+        &self,
+        user_id: ChatId,
+        registry: &str,
+        prefix: &str,
+    ) -> Result<(), Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "CALL subscribe_pattern($1, $2, $3)",
+                &[Type::INT8, Type::VARCHAR, Type::VARCHAR],
+            )
+            .await?;
+
+        self.inner
+            .execute(&stmt, &[&user_id.0, &registry, &prefix])
+            .await?;
+
+        Ok(())
+    }
+
+    /// Returns `false` if the user wasn't subscribed to the pattern.
+    pub async fn unsubscribe_pattern(
+        &self,
+        user_id: ChatId,
+        registry: &str,
+        prefix: &str,
+    ) -> Result<bool, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "CALL unsubscribe_pattern($1, $2, $3)",
+                &[Type::INT8, Type::VARCHAR, Type::VARCHAR],
+            )
+            .await?;
+
+        let found: i64 = self
+            .inner
+            .query_one(&stmt, &[&user_id.0, &registry, &prefix])
+            .await?
+            .get(0);
+
+        Ok(found > 0)
+    }
+
+    /// Pattern subscriptions of the user as `(registry, prefix)`.
+    pub async fn list_pattern_subscriptions(
+        &self,
+        user_id: ChatId,
+    ) -> Result<Vec<(String, String)>, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "SELECT registry_name, prefix from list_pattern_subscriptions($1)",
+                &[Type::INT8],
+            )
+            .await?;
+
+        let res = self
+            .inner
+            .query(&stmt, &[&user_id.0])
+            .await?
+            .into_iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect();
+
+        Ok(res)
+    }
+
+    /// List users subscribed to a pattern matching the crate.
+    pub async fn list_pattern_subscribers(
+        &self,
+        registry: &str,
+        krate: &str,
+    ) -> Result<Vec<ChatId>, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "SELECT user_id from list_pattern_subscribers($1, $2)",
+                &[Type::VARCHAR, Type::VARCHAR],
+            )
+            .await?;
+
+        let res = self
+            .inner
+            .query(&stmt, &[&registry, &krate])
+            .await?
+            .into_iter()
+            .map(|row| ChatId(row.get(0)))
+            .collect();
+
+        Ok(res)
+    }
+
+    /// Watch for new crates matching the (normalized, see
+    /// [`crate::watches::parse_pattern`]) pattern.
+    pub async fn watch_new(&self, user_id: ChatId, pattern: &str) -> Result<(), Error> {
+        let stmt = self
+            .inner
+            .prepare_typed("CALL watch_new($1, $2)", &[Type::INT8, Type::VARCHAR])
+            .await?;
+
+        self.inner.execute(&stmt, &[&user_id.0, &pattern]).await?;
+
+        Ok(())
+    }
+
+    /// Returns `false` if the user wasn't watching the pattern.
+    pub async fn unwatch_new(&self, user_id: ChatId, pattern: &str) -> Result<bool, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed("CALL unwatch_new($1, $2)", &[Type::INT8, Type::VARCHAR])
+            .await?;
+
+        let found: i64 = self
+            .inner
+            .query_one(&stmt, &[&user_id.0, &pattern])
+            .await?
+            .get(0);
+
+        Ok(found > 0)
+    }
+
+    /// Patterns watched by the user.
+    pub async fn list_watches(&self, user_id: ChatId) -> Result<Vec<String>, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed("SELECT pattern from list_watches($1)", &[Type::INT8])
+            .await?;
+
+        let res = self
+            .inner
+            .query(&stmt, &[&user_id.0])
+            .await?
+            .into_iter()
+            .map(|row| row.get(0))
+            .collect();
+
+        Ok(res)
+    }
+
+    /// All watches as `(user, pattern)`.
+    pub async fn list_all_watches(&self) -> Result<Vec<(ChatId, String)>, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed("SELECT user_id, pattern from list_all_watches()", &[])
+            .await?;
+
+        let res = self
+            .inner
+            .query(&stmt, &[])
+            .await?
+            .into_iter()
+            .map(|row| (ChatId(row.get(0)), row.get(1)))
+            .collect();
+
+        Ok(res)
+    }
+
+    /// Record processed index change, returns id of the event.
+    pub async fn record_event(
+        &self,
+        commit_oid: &str,
+        krate: &str,
+        version: &str,
+        action: &str,
+    ) -> Result<i64, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "SELECT record_event($1, $2, $3, $4)",
+                &[Type::BPCHAR, Type::VARCHAR, Type::TEXT, Type::VARCHAR],
+            )
+            .await?;
+
+        let id = self
+            .inner
+            .query_one(&stmt, &[&commit_oid, &krate, &version, &action])
+            .await?
+            .get(0);
+
+        Ok(id)
+    }
+
+    /// The last index commit of the registry whose notifications were all
+    /// sent, `None` if nothing was processed yet.
+    pub async fn last_commit(&self, registry: &str) -> Result<Option<String>, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed("SELECT last_commit($1)", &[Type::VARCHAR])
+            .await?;
+
+        let oid: Option<String> = self.inner.query_one(&stmt, &[&registry]).await?.get(0);
+        Ok(oid)
+    }
+
+    pub async fn set_last_commit(&self, registry: &str, commit_oid: &str) -> Result<(), Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "CALL set_last_commit($1, $2)",
+                &[Type::VARCHAR, Type::BPCHAR],
+            )
+            .await?;
+
+        self.inner.execute(&stmt, &[&registry, &commit_oid]).await?;
+
+        Ok(())
+    }
+
+    /// Save the cooldown state of the crate, `None` deletes it.
+    pub async fn save_cooldown(
+        &self,
+        registry: &str,
+        krate: &str,
+        state: Option<&str>,
+    ) -> Result<(), Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "CALL save_cooldown($1, $2, $3)",
+                &[Type::VARCHAR, Type::VARCHAR, Type::TEXT],
+            )
+            .await?;
+
+        self.inner
+            .execute(&stmt, &[&registry, &krate, &state])
+            .await?;
+
+        Ok(())
+    }
+
+    /// Save the checksum of the version, returns the previously saved one if
+    /// it's different.
+    pub async fn save_cksum(
+        &self,
+        registry: &str,
+        krate: &str,
+        version: &str,
+        cksum: &str,
+    ) -> Result<Option<String>, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "SELECT save_cksum($1, $2, $3, $4)",
+                &[Type::VARCHAR, Type::VARCHAR, Type::TEXT, Type::BPCHAR],
+            )
+            .await?;
+
+        let row = self
+            .inner
+            .query_one(&stmt, &[&registry, &krate, &version, &cksum])
+            .await?;
+
+        Ok(row.get(0))
+    }
+
+    /// All saved cooldowns as `(registry, crate, state)`.
+    pub async fn list_cooldowns(&self) -> Result<Vec<(String, String, String)>, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "SELECT registry_name, crate_name, state from list_cooldowns()",
+                &[],
+            )
+            .await?;
+
+        let res = self
+            .inner
+            .query(&stmt, &[])
+            .await?
+            .into_iter()
+            .map(|row| (row.get(0), row.get(1), row.get(2)))
+            .collect();
+
+        Ok(res)
+    }
+
+    /// Record an attempt to deliver the event to the chat.
+    pub async fn record_delivery(
+        &self,
+        event_id: i64,
+        chat_id: ChatId,
+        status: &str,
+    ) -> Result<(), Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "CALL record_delivery($1, $2, $3)",
+                &[Type::INT8, Type::INT8, Type::TEXT],
+            )
+            .await?;
+
+        self.inner
+            .execute(&stmt, &[&event_id, &chat_id.0, &status])
+            .await?;
+
+        Ok(())
+    }
+
+    /// List the latest `limit` events of the crate along with their delivery
+    /// status for the `chat_id`.
+    pub async fn list_events(
+        &self,
+        krate: &str,
+        chat_id: ChatId,
+        limit: i32,
+    ) -> Result<Vec<Event>, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "SELECT commit_oid, vers, action_kind, happened_at, delivery_status \
+                 from list_events($1, $2, $3)",
+                &[Type::VARCHAR, Type::INT8, Type::INT4],
+            )
+            .await?;
+
+        let res = self
+            .inner
+            .query(&stmt, &[&krate, &chat_id.0, &limit])
+            .await?
+            .into_iter()
+            .map(|row| Event {
+                commit_oid: row.get(0),
+                version: row.get(1),
+                action: row.get(2),
+                happened_at: row.get(3),
+                delivery_status: row.get(4),
+            })
+            .collect();
+
+        Ok(res)
+    }
+
+    pub async fn add_alert(
+        &self,
+        user_id: ChatId,
+        registry: &str,
+        krate: &str,
+        req: &str,
+    ) -> Result<(), Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "CALL add_alert($1, $2, $3, $4)",
+                &[Type::INT8, Type::VARCHAR, Type::VARCHAR, Type::VARCHAR],
+            )
+            .await?;
+
+        self.inner
+            .execute(&stmt, &[&user_id.0, &registry, &krate, &req])
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn list_alerts(&self, registry: &str, krate: &str) -> Result<Vec<Alert>, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "SELECT alert_id, user_id, req from list_alerts($1, $2)",
+                &[Type::VARCHAR, Type::VARCHAR],
+            )
+            .await?;
+
+        let res = self
+            .inner
+            .query(&stmt, &[&registry, &krate])
+            .await?
+            .into_iter()
+            .map(|row| Alert {
+                id: row.get(0),
+                user_id: ChatId(row.get(1)),
+                req: row.get(2),
+            })
+            .collect();
+
+        Ok(res)
+    }
+
+    /// Delete the alert, returning its user. Returns `None` if the alert was
+    /// already taken, so every alert fires at most once.
+    pub async fn take_alert(&self, id: i32) -> Result<Option<ChatId>, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed("SELECT user_id from take_alert($1)", &[Type::INT4])
+            .await?;
+
+        let row = self.inner.query_opt(&stmt, &[&id]).await?;
+        Ok(row.map(|row| ChatId(row.get(0))))
+    }
+
+    /// Pause all notifications to the chat. With `catch_up` updates are
+    /// accumulated and sent as a summary after resuming.
+    pub async fn pause(&self, user_id: ChatId, catch_up: bool) -> Result<(), Error> {
+        let stmt = self
+            .inner
+            .prepare_typed("CALL pause($1, $2)", &[Type::INT8, Type::BOOL])
+            .await?;
+
+        self.inner.execute(&stmt, &[&user_id.0, &catch_up]).await?;
+
+        Ok(())
+    }
+
+    pub async fn resume(&self, user_id: ChatId) -> Result<(), Error> {
+        let stmt = self
+            .inner
+            .prepare_typed("CALL resume($1)", &[Type::INT8])
+            .await?;
+
+        self.inner.execute(&stmt, &[&user_id.0]).await?;
+
+        Ok(())
+    }
+
+    pub async fn is_paused(&self, user_id: ChatId) -> Result<bool, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed("SELECT is_paused($1)", &[Type::INT8])
+            .await?;
+
+        let paused = self.inner.query_one(&stmt, &[&user_id.0]).await?.get(0);
+
+        Ok(paused)
+    }
+
+    /// Paused chats among `user_ids`, as `chat -> whether updates are
+    /// accumulated for a catch-up summary`.
+    pub async fn list_paused(&self, user_ids: &[ChatId]) -> Result<HashMap<ChatId, bool>, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "SELECT user_id, catch_up from list_paused($1)",
+                &[Type::INT8_ARRAY],
+            )
+            .await?;
+
+        let res = self
+            .inner
+            .query(&stmt, &[&raw_ids(user_ids)])
+            .await?
+            .into_iter()
+            .map(|row| (ChatId(row.get(0)), row.get(1)))
+            .collect();
+
+        Ok(res)
+    }
+
+    /// Set links shown to the user in notifications, `None` resets them to
+    /// the default.
+    pub async fn set_links(&self, user_id: ChatId, links: Option<&[Link]>) -> Result<(), Error> {
+        let stmt = self
+            .inner
+            .prepare_typed("CALL set_links($1, $2)", &[Type::INT8, Type::VARCHAR_ARRAY])
+            .await?;
+
+        let links: Option<Vec<_>> = links.map(|links| links.iter().map(|l| l.as_str()).collect());
+        self.inner.execute(&stmt, &[&user_id.0, &links]).await?;
+
+        Ok(())
+    }
+
+    /// Link settings of the given chats, chats with default settings are
+    /// omitted.
+    pub async fn list_links(
+        &self,
+        user_ids: &[ChatId],
+    ) -> Result<HashMap<ChatId, Vec<Link>>, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "SELECT user_id, links from list_links($1)",
+                &[Type::INT8_ARRAY],
+            )
+            .await?;
+
+        let res = self
+            .inner
+            .query(&stmt, &[&raw_ids(user_ids)])
+            .await?
+            .into_iter()
+            .map(|row| {
+                let links: Vec<String> = row.get(1);
+                let links = links.iter().filter_map(|l| Link::parse(l)).collect();
+                (ChatId(row.get(0)), links)
+            })
+            .collect();
+
+        Ok(res)
+    }
+
+    /// Set the digest period of the user, `None` turns the digest off.
+    pub async fn set_digest(&self, user_id: ChatId, period: Option<Period>) -> Result<(), Error> {
+        let stmt = self
+            .inner
+            .prepare_typed("CALL set_digest($1, $2)", &[Type::INT8, Type::VARCHAR])
+            .await?;
+
+        let period = period.map(Period::as_str);
+        self.inner.execute(&stmt, &[&user_id.0, &period]).await?;
+
+        Ok(())
+    }
+
+    pub async fn digest(&self, user_id: ChatId) -> Result<Option<Period>, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed("SELECT get_digest($1)", &[Type::INT8])
+            .await?;
+
+        let period: Option<String> = self.inner.query_one(&stmt, &[&user_id.0]).await?.get(0);
+
+        Ok(period.as_deref().and_then(Period::parse))
+    }
+
+    /// Chats with a digest among `user_ids`.
+    pub async fn list_digests(&self, user_ids: &[ChatId]) -> Result<Vec<ChatId>, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed("SELECT user_id from list_digests($1)", &[Type::INT8_ARRAY])
+            .await?;
+
+        let res = self
+            .inner
+            .query(&stmt, &[&raw_ids(user_ids)])
+            .await?
+            .into_iter()
+            .map(|row| ChatId(row.get(0)))
+            .collect();
+
+        Ok(res)
+    }
+
+    /// Add an update (text of its notification) to digests of the users, one
+    /// text per user.
+    pub async fn add_digest_items(
+        &self,
+        user_ids: &[ChatId],
+        texts: &[String],
+    ) -> Result<(), Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "CALL add_digest_items($1, $2)",
+                &[Type::INT8_ARRAY, Type::TEXT_ARRAY],
+            )
+            .await?;
+
+        self.inner
+            .execute(&stmt, &[&raw_ids(user_ids), &texts])
+            .await?;
+
+        Ok(())
+    }
+
+    /// Chats whose digest is due to be sent.
+    pub async fn due_digests(&self) -> Result<Vec<ChatId>, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed("SELECT user_id from due_digests()", &[])
+            .await?;
+
+        let res = self
+            .inner
+            .query(&stmt, &[])
+            .await?
+            .into_iter()
+            .map(|row| ChatId(row.get(0)))
+            .collect();
+
+        Ok(res)
+    }
+
+    /// Remove accumulated updates of the user's digest & return them as
+    /// `(text, time it was added)`, oldest first.
+    pub async fn take_digest(
+        &self,
+        user_id: ChatId,
+    ) -> Result<Vec<(String, DateTime<Utc>)>, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "SELECT text, created_at from take_digest($1)",
+                &[Type::INT8],
+            )
+            .await?;
+
+        let res = self
+            .inner
+            .query(&stmt, &[&user_id.0])
+            .await?
+            .into_iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect();
+
+        Ok(res)
+    }
+
+    /// Set whether the user skips pre-release versions by default
+    /// (`/prereleases`).
+    pub async fn set_skip_prereleases(&self, user_id: ChatId, skip: bool) -> Result<(), Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "CALL set_skip_prereleases($1, $2)",
+                &[Type::INT8, Type::BOOL],
+            )
+            .await?;
+
+        self.inner.execute(&stmt, &[&user_id.0, &skip]).await?;
+
+        Ok(())
+    }
+
+    /// Chats skipping pre-release versions by default among `user_ids`.
+    pub async fn list_skipping_prereleases(
+        &self,
+        user_ids: &[ChatId],
+    ) -> Result<Vec<ChatId>, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "SELECT user_id from list_skipping_prereleases($1)",
+                &[Type::INT8_ARRAY],
+            )
+            .await?;
+
+        let res = self
+            .inner
+            .query(&stmt, &[&raw_ids(user_ids)])
+            .await?
+            .into_iter()
+            .map(|row| ChatId(row.get(0)))
+            .collect();
+
+        Ok(res)
+    }
+
+    /// Record a RustSec advisory as seen, returns `false` if it already was.
+    pub async fn record_advisory(&self, id: &str, krate: &str) -> Result<bool, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "CALL record_advisory($1, $2)",
+                &[Type::VARCHAR, Type::VARCHAR],
+            )
+            .await?;
+
+        let new: i64 = self.inner.query_one(&stmt, &[&id, &krate]).await?.get(0);
+
+        Ok(new > 0)
+    }
+
+    /// Number of RustSec advisories seen so far.
+    pub async fn advisory_count(&self) -> Result<i64, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed("SELECT advisory_count()", &[])
+            .await?;
+
+        let count = self.inner.query_one(&stmt, &[]).await?.get(0);
+
+        Ok(count)
+    }
+
+    /// All chats with subscriptions (or alerts or watches).
+    pub async fn list_chats(&self) -> Result<Vec<ChatId>, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed("SELECT user_id from list_chats()", &[])
+            .await?;
+
+        let res = self
+            .inner
+            .query(&stmt, &[])
+            .await?
+            .into_iter()
+            .map(|row| ChatId(row.get(0)))
+            .collect();
+
+        Ok(res)
+    }
+
+    /// All crates with subscribers, as `(registry, crate name, number of
+    /// subscribers)`.
+    pub async fn list_subscribed_crates(&self) -> Result<Vec<(String, String, i64)>, Error> {
+        let stmt = self
+            .inner
+            .prepare_typed(
+                "SELECT registry_name, crate_name, subscribers from list_subscribed_crates()",
+                &[],
+            )
+            .await?;
+
+        let res = self
+            .inner
+            .query(&stmt, &[])
+            .await?
+            .into_iter()
+            .map(|row| (row.get(0), row.get(1), row.get(2)))
+            .collect();
+
+        Ok(res)
+    }
+}
+
+pub struct Subscription {
+    pub registry: String,
+    pub krate: String,
+    /// `None` for subscriptions created before the time was recorded
+    pub created_at: Option<DateTime<Utc>>,
+    /// Set with `/subscribe <crate> --events ..` or `/subscribe_yanks`
+    pub events: Events,
+    /// Set with `/subscribe <crate> --only minor|major`
+    pub min_level: Level,
+    /// Set with `/subscribe <crate> --prereleases` (`true`) or
+    /// `--no-prereleases` (`false`), `None` follows `/prereleases`
+    pub prereleases: Option<bool>,
+    /// Subscribed with `/subscribe <crate> --changelog`
+    pub changelog: bool,
+    /// Set with `/subscribe <crate> #label`
+    pub label: Option<String>,
+    /// Set with `/mute <crate> <duration>`, may be in the past
+    pub muted_until: Option<DateTime<Utc>>,
+}
+
+pub struct Event {
+    pub commit_oid: String,
+    pub version: String,
+    pub action: String,
+    pub happened_at: DateTime<Utc>,
+    /// Status of the delivery to the chat for which events were listed
+    /// (`None` if there were no delivery)
+    pub delivery_status: Option<String>,
+}
+
+/// One-shot subscription, see `/subscribe_when`
+pub struct Alert {
+    pub id: i32,
+    pub user_id: ChatId,
+    /// Semver requirement the version must match
+    pub req: String,
+}
+
+/// Chat ids as they are stored in the db.
+fn raw_ids(ids: &[ChatId]) -> Vec<i64> {
+    ids.iter().map(|id| id.0).collect()
 }
@@ -0,0 +1,83 @@
+//! Digests (`/digest`): instead of a message per update, chats with a digest
+//! get all updates of the day (or week) as a single message.
+//!
+//! Notifications to such chats are stored in the database (see
+//! `notify_users`) and periodically sent by [`send_loop`] once the period of
+//! the chat is over.
+use crate::{cfg::SharedConfig, channel, chat::ChatId, db::Database, queue::Queues};
+use std::time::Duration;
+
+/// Interval of checking for digests that are due
+const CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How often a chat gets its digest.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Period {
+    Daily,
+    Weekly,
+}
+
+impl Period {
+    /// Name used in the db & in `/digest`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Period::Daily => "daily",
+            Period::Weekly => "weekly",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "daily" => Some(Period::Daily),
+            "weekly" => Some(Period::Weekly),
+            _ => None,
+        }
+    }
+}
+
+/// Periodically send the digests that are due.
+pub async fn send_loop(db: Database, queues: Queues, config: SharedConfig) {
+    loop {
+        match db.due_digests().await {
+            Ok(chats) => {
+                for chat_id in chats {
+                    send(&db, &queues, &config, chat_id).await;
+                }
+            }
+            Err(err) => log::error!("db error while listing due digests: {}", err),
+        }
+
+        tokio::time::delay_for(CHECK_INTERVAL).await;
+    }
+}
+
+/// Send all accumulated updates of the chat (split into several messages if
+/// they don't fit into one).
+async fn send(db: &Database, queues: &Queues, config: &SharedConfig, chat_id: ChatId) {
+    let items = match db.take_digest(chat_id).await {
+        Ok(items) => items,
+        Err(err) => {
+            log::error!("db error while taking digest of {}: {}", chat_id, err);
+            return;
+        }
+    };
+    let since = match items.first() {
+        Some((_, at)) => at.format("%Y-%m-%d"),
+        None => return,
+    };
+
+    let header = match items.len() {
+        1 => format!("📰 Digest: 1 update since {}", since),
+        n => format!("📰 Digest: {} updates since {}", n, since),
+    };
+    // updates are separated by an empty line
+    let lines: Vec<_> = items
+        .iter()
+        .map(|(text, _)| format!("\n{}", text))
+        .collect();
+    let lines: Vec<_> = lines.iter().map(String::as_str).collect();
+    let delay = config.get().broadcast_delay;
+    for (text, _) in channel::split(&header, &lines) {
+        queues.send(chat_id, text, false, None, None, delay).await;
+    }
+}
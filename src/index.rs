@@ -0,0 +1,234 @@
+//! Local copies of the registry indexes.
+//!
+//! An index is either a clone of the git repository, or a partial mirror of a
+//! [sparse] (HTTP) index: files of crates are downloaded when they are needed
+//! for the first time (see [`fetch_missing`]) and refreshed when the crates.io
+//! API reports the crate as recently updated (see [`SparseIndex::poll`]). This
+//! is a lot lighter than the git index, but inline search only sees the crates that
+//! were downloaded so far.
+//!
+//! [sparse]: https://doc.rust-lang.org/cargo/reference/registry-index.html#sparse-protocol
+use crate::{api::CratesIo, krate::Crate, util::crate_path};
+use chrono::{DateTime, Utc};
+use git2::Repository;
+use std::{
+    collections::HashSet,
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Prefix of `index_url` selecting the sparse protocol (same as in cargo)
+pub const SPARSE_PREFIX: &str = "sparse+";
+
+/// File in the root of the local index with the url of the sparse index it
+/// mirrors
+const SPARSE_MARKER: &str = ".sparse-url";
+
+/// File in the root of the local sparse index mirror with `last_seen`, so
+/// updates published while the bot was down aren't missed
+const LAST_SEEN: &str = ".last-seen";
+
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+pub enum Index {
+    Git(Repository),
+    Sparse(SparseIndex),
+}
+
+pub struct SparseIndex {
+    http: reqwest::Client,
+    /// Base url of the index, ending with `/`
+    url: String,
+    path: PathBuf,
+    /// `updated_at` of the most recently updated crate seen so far (persisted
+    /// in `LAST_SEEN`)
+    last_seen: Option<DateTime<Utc>>,
+    /// The local mirror was just created, so the first poll only fills it
+    /// (otherwise every recently updated crate would look new)
+    baseline: bool,
+}
+
+impl SparseIndex {
+    /// Open (or create) the local mirror of the sparse index at `url` in the
+    /// `path` directory & refresh its `config.json`.
+    pub async fn open(url: &str, path: &str) -> Result<Self, Error> {
+        let url = format!("{}/", url.trim_end_matches('/'));
+        let path = PathBuf::from(path);
+        let marker = path.join(SPARSE_MARKER);
+        let baseline = match tokio::fs::read_to_string(&marker).await {
+            Ok(mirrored) if mirrored.trim() == url => false,
+            Ok(mirrored) => {
+                return Err(format!(
+                    "`{}` is a mirror of another index ({})",
+                    path.display(),
+                    mirrored.trim()
+                )
+                .into())
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                tokio::fs::create_dir_all(&path).await?;
+                tokio::fs::write(&marker, &url).await?;
+                true
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        let last_seen = match tokio::fs::read_to_string(path.join(LAST_SEEN)).await {
+            Ok(at) => DateTime::parse_from_rfc3339(at.trim())
+                .map(|at| at.with_timezone(&Utc))
+                .map_err(|err| log::warn!("invalid `{}`: {}", LAST_SEEN, err))
+                .ok(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+
+        let index = Self {
+            http: http(),
+            url,
+            path,
+            last_seen,
+            baseline,
+        };
+        let config = index
+            .fetch("config.json")
+            .await?
+            .ok_or("the index doesn't have `config.json`")?;
+        write_atomic(&index.path.join("config.json"), &config).await?;
+        Ok(index)
+    }
+
+    /// Refresh files of the crates updated since the previous poll. Returns
+    /// `(name, removed lines, added lines, created)` of every changed crate.
+    ///
+    /// Crates that weren't downloaded before are new or weren't needed so far,
+    /// only their last line (i.e. the latest publish) is counted as added. Such
+    /// a crate is `created` (its first version was published) if that's its
+    /// only line.
+    pub async fn poll(
+        &mut self,
+        api: &CratesIo,
+    ) -> Result<Vec<(String, Vec<Crate>, Vec<Crate>, bool)>, Error> {
+        let since = self.last_seen;
+        let updated = api.recently_updated(since).await?;
+        let newest = updated.iter().map(|(_, at)| *at).max();
+
+        // Everything is downloaded before any file is touched, so if a request
+        // fails the retry still sees all the changes
+        let mut fetched = Vec::new();
+        for (name, at) in updated {
+            if since.map_or(false, |since| at <= since) {
+                continue;
+            }
+            let relative = crate_path(&name);
+            if let Some(new) = self.fetch(&url_path(&relative)).await? {
+                fetched.push((name, relative, new));
+            }
+        }
+        let baseline = std::mem::replace(&mut self.baseline, false);
+
+        let mut changes = Vec::new();
+        for (name, relative, new) in fetched {
+            let file = self.path.join(&relative);
+            let old = match tokio::fs::read_to_string(&file).await {
+                Ok(old) => Some(old),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+                Err(err) => return Err(err.into()),
+            };
+            write_atomic(&file, &new).await?;
+            if baseline {
+                continue;
+            }
+
+            let (removed, added, created) = match &old {
+                Some(old) => {
+                    let old_lines: HashSet<_> = old.lines().collect();
+                    let new_lines: HashSet<_> = new.lines().collect();
+                    let removed = old.lines().filter(|l| !new_lines.contains(l));
+                    let added = new.lines().filter(|l| !old_lines.contains(l));
+                    (parse(removed), parse(added), false)
+                }
+                None => (
+                    Vec::new(),
+                    parse(new.lines().last()),
+                    new.lines().count() == 1,
+                ),
+            };
+            if !removed.is_empty() || !added.is_empty() {
+                changes.push((name, removed, added, created));
+            }
+        }
+
+        // only after all the files are refreshed, so a failed poll is retried
+        if let Some(newest) = newest.filter(|&newest| since.map_or(true, |since| newest > since)) {
+            self.last_seen = Some(newest);
+            write_atomic(&self.path.join(LAST_SEEN), &newest.to_rfc3339()).await?;
+        }
+        Ok(changes)
+    }
+
+    /// Download a file of the index, `None` if there is no such file.
+    async fn fetch(&self, path: &str) -> Result<Option<String>, Error> {
+        fetch(&self.http, &format!("{}{}", self.url, path)).await
+    }
+}
+
+/// Download the index file of the crate if `index` is a mirror of a sparse
+/// index and the crate wasn't needed before. Does nothing for git indexes (or
+/// if the crate doesn't exist).
+pub async fn fetch_missing(index: &str, name: &str) -> io::Result<()> {
+    let root = Path::new(index);
+    let url = match tokio::fs::read_to_string(root.join(SPARSE_MARKER)).await {
+        Ok(url) => url,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+
+    let relative = crate_path(name);
+    let url = format!("{}{}", url.trim(), url_path(&relative));
+    match fetch(&http(), &url).await {
+        Ok(Some(file)) => write_atomic(&root.join(&relative), &file).await,
+        Ok(None) => Ok(()),
+        Err(err) => Err(io::Error::new(io::ErrorKind::Other, err)),
+    }
+}
+
+fn http() -> reqwest::Client {
+    reqwest::Client::builder()
+        .user_agent(concat!("crate_upd_bot/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .expect("couldn't build http client")
+}
+
+async fn fetch(http: &reqwest::Client, url: &str) -> Result<Option<String>, Error> {
+    let resp = http.get(url).send().await?;
+    // some registries answer 403 for missing files, like S3 does
+    if matches!(resp.status().as_u16(), 404 | 403 | 410 | 451) {
+        return Ok(None);
+    }
+    Ok(Some(resp.error_for_status()?.text().await?))
+}
+
+/// Write the file via a temporary one, so readers never see it half-written.
+async fn write_atomic(path: &Path, contents: &str) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, contents).await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+/// `crate_path` with `/` separators, for urls.
+fn url_path(path: &Path) -> String {
+    path.iter()
+        .map(|part| part.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn parse<'a>(lines: impl IntoIterator<Item = &'a str>) -> Vec<Crate> {
+    lines
+        .into_iter()
+        .filter_map(|line| crate::parse_index_line(line.as_bytes()))
+        .collect()
+}
@@ -0,0 +1,163 @@
+//! Subscriptions to crates.io keywords and categories.
+//!
+//! The git index doesn't know anything about keywords or categories, so the
+//! bot keeps `keyword -> crates` & `category -> crates` mappings (only for
+//! ones someone is subscribed to) in the database, refreshed periodically from
+//! the crates.io API.
+use crate::{
+    api::CratesIo, cfg::SharedConfig, db::Database, krate::CrateId, owners, queue::Queues, watches,
+};
+use tokio::sync::mpsc;
+
+/// Queue of newly published crates to re-check, see [`recheck_loop`]
+pub type Rechecks = mpsc::UnboundedSender<Recheck>;
+
+/// A newly published version queued for [`recheck_loop`]
+pub struct Recheck {
+    pub id: CrateId,
+    /// Whether it's the first version of the crate (checked against
+    /// `/watch_new` patterns)
+    pub created: bool,
+}
+
+/// Periodically refresh crates of all subscribed keywords & categories.
+pub async fn refresh_loop(api: CratesIo, db: Database, config: SharedConfig) {
+    loop {
+        match db.list_subscribed_keywords().await {
+            Ok(keywords) => {
+                for keyword in keywords {
+                    refresh(&api, &db, &keyword).await;
+                }
+            }
+            Err(err) => log::error!("db error while listing keywords: {}", err),
+        }
+        match db.list_subscribed_categories().await {
+            Ok(categories) => {
+                for category in categories {
+                    refresh_category(&api, &db, &category).await;
+                }
+            }
+            Err(err) => log::error!("db error while listing categories: {}", err),
+        }
+
+        tokio::time::delay_for(config.get().keyword_refresh_delay).await;
+    }
+}
+
+/// Refresh crates of a single keyword.
+pub async fn refresh(api: &CratesIo, db: &Database, keyword: &str) {
+    let crates = match api.keyword_crates(keyword).await {
+        Ok(crates) => crates,
+        Err(err) => {
+            log::error!("couldn't get crates of keyword {}: {}", keyword, err);
+            return;
+        }
+    };
+
+    if let Err(err) = db.set_keyword_crates(keyword, &crates).await {
+        log::error!(
+            "db error while setting crates of keyword {}: {}",
+            keyword,
+            err
+        );
+    }
+
+    // Don't break the crates.io crawler policy (1 request per second)
+    tokio::time::delay_for(std::time::Duration::from_secs(1)).await;
+}
+
+/// Refresh crates of a single category.
+pub async fn refresh_category(api: &CratesIo, db: &Database, category: &str) {
+    let crates = match api.category_crates(category).await {
+        Ok(crates) => crates,
+        Err(err) => {
+            log::error!("couldn't get crates of category {}: {}", category, err);
+            return;
+        }
+    };
+
+    if let Err(err) = db.set_category_crates(category, &crates).await {
+        log::error!(
+            "db error while setting crates of category {}: {}",
+            category,
+            err
+        );
+    }
+
+    // Don't break the crates.io crawler policy (1 request per second)
+    tokio::time::delay_for(std::time::Duration::from_secs(1)).await;
+}
+
+/// Re-check crates queued by the pull loop one at a time (watches of new
+/// crates, keywords & categories, then owners), so the crates.io API isn't
+/// called more than once per second and notifications aren't held up by it. A
+/// crate that just got a subscribed keyword or owner is thus notified about
+/// from its next version on (or after the next refresh).
+pub async fn recheck_loop(
+    api: CratesIo,
+    db: Database,
+    queues: Queues,
+    config: SharedConfig,
+    mut crates: mpsc::UnboundedReceiver<Recheck>,
+) {
+    while let Some(Recheck { id, created }) = crates.recv().await {
+        if created {
+            watches::check(&api, &db, &queues, &config.get(), &id).await;
+        }
+        recheck(&api, &db, &id.name).await;
+        owners::recheck(&api, &db, &id.name).await;
+    }
+}
+
+/// Re-check keywords & categories of a (newly published) crate, so new
+/// crates/keywords/categories are picked up without waiting for the next
+/// refresh.
+async fn recheck(api: &CratesIo, db: &Database, krate: &str) {
+    let (keywords, categories) = match (
+        db.list_subscribed_keywords().await,
+        db.list_subscribed_categories().await,
+    ) {
+        (Ok(keywords), Ok(categories)) if keywords.is_empty() && categories.is_empty() => return,
+        (Ok(keywords), Ok(categories)) => (keywords, categories),
+        (Err(err), _) | (_, Err(err)) => {
+            log::error!("db error while listing keywords & categories: {}", err);
+            return;
+        }
+    };
+
+    let info = api.crate_info(krate).await;
+    // Don't break the crates.io crawler policy (1 request per second)
+    tokio::time::delay_for(std::time::Duration::from_secs(1)).await;
+    let info = match info {
+        Ok(info) => info,
+        Err(err) => {
+            log::warn!("couldn't get info of crate {}: {}", krate, err);
+            return;
+        }
+    };
+
+    for keyword in info.keywords.iter().filter(|k| keywords.contains(k)) {
+        if let Err(err) = db.add_keyword_crate(keyword, krate).await {
+            log::error!(
+                "db error while adding crate to keyword {}: {}",
+                keyword,
+                err
+            );
+        }
+    }
+    // crates of subcategories (`parent::sub`) belong to the parent too
+    let in_category = |category: &str| {
+        info.categories.iter().any(|c| {
+            c == category || (c.starts_with(category) && c[category.len()..].starts_with("::"))
+        })
+    };
+    for category in categories.iter().filter(|c| in_category(c)) {
+        if let Err(err) = db.add_category_crate(category, krate).await {
+            log::error!(
+                "db error while adding crate to category {}: {}",
+                category,
+                err
+            );
+        }
+    }
+}
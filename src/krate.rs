@@ -1,5 +1,12 @@
-use crate::util::crate_path;
-use std::path::Path;
+use crate::{
+    cfg::Link,
+    util::{crate_path, escape_html},
+};
+use chrono::{DateTime, Utc};
+use semver::{SemVerError, Version};
+use std::{collections::BTreeMap, path::Path};
+#[cfg(feature = "subscriptions")]
+use std::{collections::VecDeque, path::PathBuf};
 use tokio::fs::File;
 use tokio::io;
 use tokio::io::{AsyncBufReadExt, BufReader};
@@ -11,15 +18,115 @@ pub struct Crate {
     #[serde(flatten)]
     pub id: CrateId,
     pub yanked: bool,
+    /// sha256 checksum of the `.crate` file
+    pub cksum: String,
+    #[serde(default)]
+    pub deps: Vec<Dependency>,
+    #[serde(default)]
+    pub features: BTreeMap<String, Vec<String>>,
+    /// Features using the newer `dep:name` & `name?/feature` syntax, kept
+    /// apart from `features` so older cargo versions can read the index
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub features2: BTreeMap<String, Vec<String>>,
+    /// Minimum supported Rust version (`package.rust-version`), like `1.70`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rust_version: Option<String>,
+    /// Publish time (only in entries added since 2024)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pubtime: Option<DateTime<Utc>>,
     // ignore all unrelated stuff :D
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Dependency {
+    pub name: String,
+    pub req: String,
+    #[serde(default)]
+    pub features: Vec<String>,
+    #[serde(default)]
+    pub optional: bool,
+    #[serde(default)]
+    pub target: Option<String>,
+    /// `"normal"`, `"dev"` or `"build"` (missing means `"normal"`)
+    #[serde(default)]
+    pub kind: Option<String>,
+}
+
+impl Dependency {
+    /// Not a dev- or build-dependency
+    pub fn is_normal(&self) -> bool {
+        self.kind.as_deref().map_or(true, |kind| kind == "normal")
+    }
+}
+
 #[derive(Debug, Hash, PartialEq, Eq, Clone, serde::Serialize, serde::Deserialize)]
 pub struct CrateId {
     pub name: String,
     pub vers: String,
 }
 
+impl CrateId {
+    pub fn version(&self) -> Result<Version, SemVerError> {
+        Version::parse(&self.vers)
+    }
+}
+
+/// Level of a release compared to the previous version, as cargo treats
+/// compatibility: for `0.x.y` versions `x` is the major part and `y` the minor
+/// one, and every `0.0.z` release is major.
+// without `subscriptions` it's only in the type of `Subscriber`
+#[cfg_attr(not(feature = "subscriptions"), allow(dead_code))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Patch,
+    Minor,
+    Major,
+}
+
+#[cfg(feature = "subscriptions")]
+impl Level {
+    pub fn between(prev: &Version, new: &Version) -> Self {
+        match (prev.major, new.major) {
+            (0, 0) if prev.minor == 0 || new.minor == 0 => {
+                if prev.minor != new.minor || prev.patch != new.patch {
+                    Level::Major
+                } else {
+                    Level::Patch
+                }
+            }
+            (0, 0) if prev.minor != new.minor => Level::Major,
+            (0, 0) if prev.patch != new.patch => Level::Minor,
+            (old, new_major) if old != new_major => Level::Major,
+            _ if prev.minor != new.minor => Level::Minor,
+            _ => Level::Patch,
+        }
+    }
+
+    /// Parse the argument of `/subscribe <crate> --only` (`minor`, `major`, or
+    /// `all` to get every release)
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "all" | "patch" => Some(Level::Patch),
+            "minor" => Some(Level::Minor),
+            "major" => Some(Level::Major),
+            _ => None,
+        }
+    }
+
+    /// Stored as `smallint` in the db
+    pub fn from_db(level: i16) -> Self {
+        match level {
+            2 => Level::Major,
+            1 => Level::Minor,
+            _ => Level::Patch,
+        }
+    }
+
+    pub fn to_db(self) -> i16 {
+        self as i16
+    }
+}
+
 impl Crate {
     // TODO: struct: Display
 
@@ -45,6 +152,7 @@ impl Crate {
         )
     }
 
+    #[cfg(feature = "subscriptions")]
     pub fn html_links(&self) -> String {
         format!(
             "<a href='{docs}'>[docs.rs]</a> \
@@ -56,14 +164,452 @@ impl Crate {
         )
     }
 
-    pub async fn read_last(name: &str) -> io::Result<Self> {
-        let file = File::open(Path::new("./index").join(crate_path(name))).await?;
+    /// Like [`html_links`], but only with the given links (`Link::Repo` is
+    /// shown only if `repository` is known).
+    ///
+    /// [`html_links`]: Crate::html_links
+    pub fn html_links_with(&self, links: &[Link], repository: Option<&str>) -> String {
+        let links: Vec<_> = Link::ALL
+            .iter()
+            .filter(|link| links.contains(link))
+            .filter_map(|link| match link {
+                Link::DocsRs => Some(format!("<a href='{}'>[docs.rs]</a>", self.docsrs())),
+                Link::CratesIo => Some(format!("<a href='{}'>[crates.io]</a>", self.cratesio())),
+                Link::LibRs => Some(format!("<a href='{}'>[lib.rs]</a>", self.librs())),
+                Link::Repo => {
+                    repository.map(|url| format!("<a href='{}'>[repo]</a>", escape_html(url)))
+                }
+            })
+            .collect();
+        links.join(" ")
+    }
+
+    /// Whether the crate has the feature (in `features` or `features2`)
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.contains_key(feature) || self.features2.contains_key(feature)
+    }
+
+    /// Names of all features (from `features` & `features2`), sorted
+    pub fn feature_names(&self) -> Vec<&str> {
+        let mut names: Vec<_> = self
+            .features
+            .keys()
+            .chain(self.features2.keys())
+            .map(String::as_str)
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+        names
+    }
+
+    /// Compare `self` with a `newer` version of the same crate.
+    pub fn changes<'a>(&'a self, newer: &'a Crate) -> Changes<'a> {
+        let features_added = newer
+            .feature_names()
+            .into_iter()
+            .filter(|f| !self.has_feature(f))
+            .collect();
+        let features_removed = self
+            .feature_names()
+            .into_iter()
+            .filter(|f| !newer.has_feature(f))
+            .collect();
+
+        let find = |deps: &'a [Dependency], dep: &Dependency| {
+            deps.iter()
+                .find(|d| d.name == dep.name && d.kind == dep.kind && d.target == dep.target)
+        };
+        let deps_added = newer
+            .deps
+            .iter()
+            .filter(|d| find(&self.deps, *d).is_none())
+            .collect();
+        let deps_removed = self
+            .deps
+            .iter()
+            .filter(|d| find(&newer.deps, *d).is_none())
+            .collect();
+        let deps_changed = self
+            .deps
+            .iter()
+            .filter_map(|old| find(&newer.deps, old).map(|new| (old, new)))
+            .filter(|(old, new)| old != new)
+            .collect();
+
+        Changes {
+            #[cfg(feature = "subscriptions")]
+            yanked: if self.yanked != newer.yanked {
+                Some(newer.yanked)
+            } else {
+                None
+            },
+            // `1.70` → `1.70.0` isn't a change
+            rust_version: if self.rust_version.as_deref().map(rust_version_key)
+                != newer.rust_version.as_deref().map(rust_version_key)
+            {
+                Some((self.rust_version.as_deref(), newer.rust_version.as_deref()))
+            } else {
+                None
+            },
+            features_added,
+            features_removed,
+            deps_added,
+            deps_removed,
+            deps_changed,
+        }
+    }
+
+    /// Whether the crate exists in the index at `index` path.
+    #[cfg(feature = "subscriptions")]
+    pub async fn exists(index: &str, name: &str) -> bool {
+        open_index_file(index, name).await.is_ok()
+    }
+
+    /// Read all versions of the crate from the index at `index` path.
+    pub async fn read_all(index: &str, name: &str) -> io::Result<Vec<Self>> {
+        let file = open_index_file(index, name).await?;
         let mut lines = BufReader::new(file).lines();
-        let mut last = None;
-        while let next @ Some(_) = lines.next().await.transpose()? {
-            last = next
+        let mut all = Vec::new();
+        while let Some(line) = lines.next().await.transpose()? {
+            let krate = serde_json::from_str(&line)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+            all.push(krate);
+        }
+        Ok(all)
+    }
+
+    /// Read the index entry of the given `version` of the crate.
+    ///
+    /// Returns `Ok(None)` if the crate exists, but doesn't have such version.
+    pub async fn read_version(
+        index: &str,
+        name: &str,
+        version: &Version,
+    ) -> io::Result<Option<Self>> {
+        let all = Self::read_all(index, name).await?;
+        Ok(all
+            .into_iter()
+            .find(|krate| krate.id.version().map_or(false, |v| &v == version)))
+    }
+
+    /// Read the index entry of the highest (by semver) version of the crate.
+    ///
+    /// Note that unlike [`read_last`] this doesn't care about the publication
+    /// order, so e.g. a patch to an old major version won't be returned.
+    ///
+    /// [`read_last`]: Crate::read_last
+    pub async fn read_newest(index: &str, name: &str) -> io::Result<Self> {
+        Self::read_all(index, name)
+            .await?
+            .into_iter()
+            .filter_map(|krate| krate.id.version().ok().map(|v| (v, krate)))
+            .max_by(|(a, _), (b, _)| a.cmp(b))
+            .map(|(_, krate)| krate)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no versions"))
+    }
+
+    /// Read the index entry of the highest (by semver) non-yanked,
+    /// non-prerelease version of the crate, i.e. the one `cargo add` would
+    /// pick. Falls back to [`read_last`] if there are no such versions.
+    ///
+    /// [`read_last`]: Crate::read_last
+    #[cfg(feature = "subscriptions")]
+    pub async fn latest_stable(index: &str, name: &str) -> io::Result<Self> {
+        let stable = Self::read_all(index, name)
+            .await?
+            .into_iter()
+            .filter(|krate| !krate.yanked)
+            .filter_map(|krate| krate.id.version().ok().map(|v| (v, krate)))
+            .filter(|(v, _)| !v.is_prerelease())
+            .max_by(|(a, _), (b, _)| a.cmp(b))
+            .map(|(_, krate)| krate);
+
+        match stable {
+            Some(krate) => Ok(krate),
+            None => Self::read_last(index, name).await,
         }
-        serde_json::from_str(&last.unwrap())
-            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))
     }
+
+    /// Read the index entry of the highest (by semver) version that is lower
+    /// than `self`'s one.
+    pub async fn read_previous(&self, index: &str) -> io::Result<Option<Self>> {
+        let version = match self.id.version() {
+            Ok(version) => version,
+            Err(_) => return Ok(None),
+        };
+
+        Ok(Self::read_all(index, &self.id.name)
+            .await?
+            .into_iter()
+            .filter_map(|krate| krate.id.version().ok().map(|v| (v, krate)))
+            .filter(|(v, _)| *v < version)
+            .max_by(|(a, _), (b, _)| a.cmp(b))
+            .map(|(_, krate)| krate))
+    }
+
+    /// Names of the crates in the index at `index` path which contain `query`,
+    /// the exact match goes first, followed by the shortest names.
+    ///
+    /// Note: this scans the whole index, so it's quite slow.
+    #[cfg(feature = "subscriptions")]
+    pub async fn search(index: &str, query: &str, limit: usize) -> io::Result<Vec<String>> {
+        let index = PathBuf::from(index);
+        let query = query.to_lowercase();
+        tokio::task::spawn_blocking(move || {
+            let mut found = Vec::new();
+            search_dir(&index, &query, &mut found)?;
+            found.sort_by(|a, b| (a != &query, a.len(), a).cmp(&(b != &query, b.len(), b)));
+            found.truncate(limit);
+            Ok(found)
+        })
+        .await
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?
+    }
+
+    /// Read the index entry of the last published version of the crate.
+    #[cfg(feature = "subscriptions")]
+    pub async fn read_last(index: &str, name: &str) -> io::Result<Self> {
+        Self::read_last_n(index, name, 1)
+            .await?
+            .pop()
+            .map(|(_, krate)| krate)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no versions"))
+    }
+
+    /// Read the index entries of the last `n` published versions of the crate
+    /// (in publish order), along with their publish numbers (starting at 1).
+    ///
+    /// Only the last `n` lines of the index file are parsed, so this is cheap
+    /// even for crates with a lot of versions.
+    #[cfg(feature = "subscriptions")]
+    pub async fn read_last_n(index: &str, name: &str, n: usize) -> io::Result<Vec<(usize, Self)>> {
+        if n == 0 {
+            return Ok(Vec::new());
+        }
+
+        let file = open_index_file(index, name).await?;
+        let mut lines = BufReader::new(file).lines();
+        let mut last = VecDeque::with_capacity(n);
+        let mut count = 0;
+        while let Some(line) = lines.next().await.transpose()? {
+            count += 1;
+            if last.len() == n {
+                last.pop_front();
+            }
+            last.push_back(line);
+        }
+
+        let first = count - last.len() + 1;
+        last.iter()
+            .enumerate()
+            .map(|(i, line)| {
+                serde_json::from_str(line)
+                    .map(|krate| (first + i, krate))
+                    .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))
+            })
+            .collect()
+    }
+}
+
+/// Open the index file of the crate, downloading it first if the index is a
+/// sparse mirror which doesn't have it yet (see [`index::fetch_missing`]).
+///
+/// [`index::fetch_missing`]: crate::index::fetch_missing
+async fn open_index_file(index: &str, name: &str) -> io::Result<File> {
+    let path = Path::new(index).join(crate_path(name));
+    match File::open(&path).await {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            crate::index::fetch_missing(index, name).await?;
+            File::open(&path).await
+        }
+        res => res,
+    }
+}
+
+#[cfg(feature = "subscriptions")]
+fn search_dir(dir: &Path, query: &str, found: &mut Vec<String>) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => continue,
+        };
+        // `.git`, `config.json` & co aren't crates (crate names can't have
+        // dots)
+        if name.contains('.') {
+            continue;
+        }
+
+        if entry.file_type()?.is_dir() {
+            search_dir(&entry.path(), query, found)?;
+        } else if name.contains(query) {
+            found.push(name.to_owned());
+        }
+    }
+    Ok(())
+}
+
+/// Differences between two versions of the same crate, see [`Crate::changes`].
+///
+/// [`Crate::changes`]: Crate::changes
+#[derive(Debug)]
+pub struct Changes<'a> {
+    /// New `yanked` status (`None` if it wasn't changed)
+    #[cfg(feature = "subscriptions")]
+    pub yanked: Option<bool>,
+    /// `(old, new)` MSRV (`None` if it wasn't changed)
+    pub rust_version: Option<(Option<&'a str>, Option<&'a str>)>,
+    pub features_added: Vec<&'a str>,
+    pub features_removed: Vec<&'a str>,
+    pub deps_added: Vec<&'a Dependency>,
+    pub deps_removed: Vec<&'a Dependency>,
+    /// `(old, new)` pairs of dependencies with changed requirements/features/etc
+    pub deps_changed: Vec<(&'a Dependency, &'a Dependency)>,
+}
+
+impl Changes<'_> {
+    /// Line like `MSRV raised: 1.63 → 1.70` (raising is emphasized, as it
+    /// breaks builds on older toolchains). Returns `None` if the MSRV wasn't
+    /// changed.
+    pub fn msrv_html(&self) -> Option<String> {
+        let line = match self.rust_version? {
+            (Some(old), Some(new)) if rust_version_key(new) > rust_version_key(old) => format!(
+                "⚠️ <b>MSRV raised</b>: <code>{}</code> → <code>{}</code>",
+                escape_html(old),
+                escape_html(new)
+            ),
+            (Some(old), Some(new)) => format!(
+                "MSRV lowered: <code>{}</code> → <code>{}</code>",
+                escape_html(old),
+                escape_html(new)
+            ),
+            (None, Some(new)) => format!("⚠️ <b>MSRV set</b>: <code>{}</code>", escape_html(new)),
+            (Some(old), None) => format!("MSRV <code>{}</code> removed", escape_html(old)),
+            (None, None) => return None,
+        };
+        Some(line)
+    }
+
+    /// Added & removed features like `features: +async, -legacy`, showing at
+    /// most `limit` features. Returns `None` if features weren't changed.
+    pub fn features_html(&self, limit: usize) -> Option<String> {
+        let total = self.features_added.len() + self.features_removed.len();
+        if total == 0 {
+            return None;
+        }
+
+        let mut features = self
+            .features_added
+            .iter()
+            .map(|f| format!("+{}", escape_html(f)))
+            .chain(
+                self.features_removed
+                    .iter()
+                    .map(|f| format!("-{}", escape_html(f))),
+            )
+            .take(limit)
+            .collect::<Vec<_>>();
+        if total > limit {
+            features.push(format!("… and {} more", total - limit));
+        }
+
+        Some(format!("features: {}", features.join(", ")))
+    }
+
+    /// Added, removed & bumped (requirement changed) normal dependencies like
+    /// `dependencies: +tokio 1, -log 0.4, serde 1.0.100 → 1.0.150`, showing at
+    /// most `limit` of them. Returns `None` if dependencies weren't changed.
+    pub fn deps_html(&self, limit: usize) -> Option<String> {
+        let added = self
+            .deps_added
+            .iter()
+            .filter(|dep| dep.is_normal())
+            .map(|dep| format!("+<code>{} {}</code>", dep.name, escape_html(&dep.req)));
+        let removed = self
+            .deps_removed
+            .iter()
+            .filter(|dep| dep.is_normal())
+            .map(|dep| format!("-<code>{} {}</code>", dep.name, escape_html(&dep.req)));
+        let bumped = self
+            .deps_changed
+            .iter()
+            .filter(|(old, new)| new.is_normal() && old.req != new.req)
+            .map(|(old, new)| {
+                format!(
+                    "<code>{} {}</code> → <code>{}</code>",
+                    old.name,
+                    escape_html(&old.req),
+                    escape_html(&new.req)
+                )
+            });
+
+        let all: Vec<_> = added.chain(removed).chain(bumped).collect();
+        if all.is_empty() {
+            return None;
+        }
+
+        let total = all.len();
+        let mut deps: Vec<_> = all.into_iter().take(limit).collect();
+        if total > limit {
+            deps.push(format!("… and {} more", total - limit));
+        }
+
+        Some(format!("dependencies: {}", deps.join(", ")))
+    }
+
+    #[cfg(feature = "subscriptions")]
+    pub fn html(&self) -> String {
+        let mut lines = Vec::new();
+
+        if let Some(yanked) = self.yanked {
+            lines.push(format!("yanked: {} → {}", !yanked, yanked));
+        }
+
+        if let Some(msrv) = self.msrv_html() {
+            lines.push(msrv);
+        }
+
+        if let Some(features) = self.features_html(usize::MAX) {
+            lines.push(features);
+        }
+
+        for dep in &self.deps_added {
+            lines.push(format!(
+                "+ <code>{} {}</code>",
+                dep.name,
+                escape_html(&dep.req)
+            ));
+        }
+        for dep in &self.deps_removed {
+            lines.push(format!(
+                "- <code>{} {}</code>",
+                dep.name,
+                escape_html(&dep.req)
+            ));
+        }
+        for (old, new) in &self.deps_changed {
+            lines.push(format!(
+                "~ <code>{} {}</code> → <code>{}</code>",
+                old.name,
+                escape_html(&old.req),
+                escape_html(&new.req)
+            ));
+        }
+
+        if lines.is_empty() {
+            String::from("no changes in features, dependencies, MSRV or yanked status")
+        } else {
+            lines.join("\n")
+        }
+    }
+}
+
+/// `1.70` & `1.70.0` as comparable `[1, 70, 0]` (non-numeric parts count as 0)
+fn rust_version_key(version: &str) -> [u64; 3] {
+    let mut key = [0; 3];
+    for (part, k) in version.split('.').zip(key.iter_mut()) {
+        *k = part.parse().unwrap_or(0);
+    }
+    key
 }
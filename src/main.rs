@@ -1,34 +1,156 @@
 // TODO: somehow better handle rate-limits (https://core.telegram.org/bots/faq#broadcasting-to-users)
-//       maybe concat many messages into one (in channel) + queues to properly handle limits
+//       maybe concat many messages into one (in channel)
 
-use crate::{bot::setup, db::Database, krate::Crate, util::tryn};
+use crate::{
+    api::{CratesIo, DocsStatus},
+    cfg::{IndexConfig, Link, RegistryConfig},
+    channel::{Batched, ChannelState},
+    chat::{ChatId, Subscriber},
+    cooldown::Cooldowns,
+    index::{Index, SparseIndex},
+    krate::{Crate, CrateId},
+    newest::NewestVersions,
+    util::{escape_html, is_crate_file, qualified_name},
+};
+#[cfg(feature = "subscriptions")]
+use crate::{
+    bot::{notification_buttons, setup},
+    chat::Events,
+    db::Database,
+    krate::Level,
+    queue::{record_delivery, Queues},
+    util::tryn,
+};
+#[cfg(feature = "subscriptions")]
 use arraylib::Slice;
 use fntools::{self, value::ValueExt};
-use git2::{Delta, Diff, DiffOptions, Repository, Sort};
+#[cfg(feature = "subscriptions")]
+use futures::{future, stream, Stream, StreamExt};
+use git2::{Commit, Delta, Diff, DiffOptions, Oid, Repository, Sort};
 use log::info;
-use std::str;
-use teloxide::prelude::{OnError, Request};
+#[cfg(feature = "subscriptions")]
+use semver::VersionReq;
+#[cfg(feature = "subscriptions")]
+use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet},
+    str,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
 use teloxide::types::ParseMode;
+#[cfg(feature = "subscriptions")]
+use teloxide::{prelude::Request, types::InlineKeyboardMarkup};
 use teloxide::{Bot, BotBuilder};
+#[cfg(feature = "subscriptions")]
+use tokio::sync::oneshot;
+#[cfg(feature = "subscriptions")]
 use tokio_postgres::NoTls;
+use tracing::Instrument;
 
+#[cfg(feature = "subscriptions")]
+mod advisories;
+mod api;
+#[cfg(feature = "subscriptions")]
 mod bot;
 mod cfg;
+mod channel;
+mod chat;
+mod cooldown;
+#[cfg(feature = "subscriptions")]
 mod db;
+#[cfg(feature = "subscriptions")]
+mod digest;
+mod index;
+#[cfg(feature = "subscriptions")]
+mod keywords;
 mod krate;
+#[cfg(feature = "subscriptions")]
+mod manifest;
+mod newest;
+#[cfg(feature = "subscriptions")]
+mod owners;
+#[cfg(feature = "subscriptions")]
+mod queue;
 mod util;
+#[cfg(feature = "subscriptions")]
+mod watches;
 
 #[tokio::main]
 async fn main() {
-    let config = cfg::Config::read().expect("couldn't read config");
+    // for uptime in `/about`
+    #[cfg(feature = "subscriptions")]
+    let started = std::time::Instant::now();
+    let args: Vec<_> = std::env::args().skip(1).collect();
+    // `crate_upd_bot replay <from>..<to> [registry]` prints notifications for
+    // the commit range instead of sending them
+    let mut replay_args = None;
+    // `crate_upd_bot test-notify <crate>[@registry] <version> <action> [chat]`
+    // sends a notification about a made-up update to the channels (or to the
+    // chat)
+    let mut test_args = None;
+    // `crate_upd_bot --once` pulls all registries once (sending notifications
+    // about all commits since the last processed one) & exits, e.g. to run the
+    // bot as a cron job
+    let mut once = false;
+    match args.as_slice() {
+        [] => {}
+        [flag] if flag == "--once" => once = true,
+        [cmd, range, registry @ ..] if cmd == "replay" && registry.len() <= 1 => {
+            replay_args = Some((range.clone(), registry.first().cloned()));
+        }
+        [cmd, krate, version, action, chat @ ..] if cmd == "test-notify" && chat.len() <= 1 => {
+            let action = toml::Value::String(action.clone())
+                .try_into::<cfg::Action>()
+                .unwrap_or_else(|_| {
+                    eprintln!(
+                        "unknown action `{}`, expected one of: new_version, yanked, unyanked, \
+                         checksum_changed",
+                        action
+                    );
+                    std::process::exit(2);
+                });
+            let chat = chat.first().map(|chat| {
+                chat.parse().map(ChatId).unwrap_or_else(|_| {
+                    eprintln!("chat must be a numeric id, got `{}`", chat);
+                    std::process::exit(2);
+                })
+            });
+            test_args = Some((krate.clone(), version.clone(), action, chat));
+        }
+        _ => {
+            eprintln!(
+                "usage: crate_upd_bot [--once | replay <from>..<to> [registry]]\n       \
+                 crate_upd_bot test-notify <crate>[@registry] <version> <action> [chat]"
+            );
+            std::process::exit(2);
+        }
+    }
 
-    simple_logger::init_with_level(config.loglevel).unwrap();
+    let config = cfg::Config::read().unwrap_or_else(|err| {
+        eprintln!("couldn't read config (./config.toml): {}", err);
+        std::process::exit(1);
+    });
+
+    init_logging(&config);
     info!("starting");
 
+    // Startup self-check: fail early with a clear message instead of
+    // panicking somewhere later
+    if config.bot_token.trim().is_empty() {
+        fail("`bot_token` is not set in the config");
+    }
+
+    #[cfg(feature = "subscriptions")]
     let db = {
         let (d, conn) = Database::connect(&config.db.cfg(), NoTls)
             .await
-            .expect("couldn't connect to the database");
+            .unwrap_or_else(|err| {
+                fail(format!(
+                    "couldn't connect to the database `{}` at `{}` as `{}`: {}",
+                    config.db.dbname, config.db.host, config.db.user, err
+                ))
+            });
 
         // docs says to do so
         tokio::spawn(async move {
@@ -37,40 +159,372 @@ async fn main() {
             }
         });
 
+        if let Err(err) = d.ping().await {
+            fail(format!(
+                "database doesn't work (did you execute db.sql?): {}",
+                err
+            ));
+        }
+
         info!("connected to db");
         d
     };
 
-    let index_url = &config.index_url; // Closures still borrow full struct :|
-    let index_path = &config.index_path;
-    let repo = Repository::open(index_path).unwrap_or_else(move |_| {
-        info!("start cloning");
-        Repository::clone(&index_url, index_path)
-            .unwrap()
-            .also(|_| info!("cloning finished"))
+    let mut config = config;
+    let mut repos = Vec::with_capacity(config.registries.len());
+    for registry in &mut config.registries {
+        if let Some(url) = registry.sparse_url() {
+            let index = SparseIndex::open(url, &registry.index_path)
+                .await
+                .unwrap_or_else(|err| {
+                    fail(format!(
+                        "couldn't open sparse index of {} at `{}`: {}",
+                        registry.name, url, err
+                    ))
+                });
+            registry.index = IndexConfig::read(&registry.index_path);
+            repos.push((registry.clone(), Index::Sparse(index)));
+            continue;
+        }
+
+        let repo = match Repository::open(&registry.index_path) {
+            Ok(repo) => repo,
+            Err(err) if std::path::Path::new(&registry.index_path).exists() => fail(format!(
+                "`{}` (index of {}) exists, but isn't a git repository: {}",
+                registry.index_path, registry.name, err
+            )),
+            Err(_) => {
+                info!("start cloning {}", registry.name);
+                Repository::clone(&registry.index_url, &registry.index_path)
+                    .unwrap_or_else(|err| {
+                        fail(format!(
+                            "couldn't clone index of {} from `{}`: {}",
+                            registry.name, registry.index_url, err
+                        ))
+                    })
+                    .also(|_| info!("cloning {} finished", registry.name))
+            }
+        };
+        registry.index = IndexConfig::read(&registry.index_path);
+        repos.push((registry.clone(), Index::Git(repo)));
+    }
+
+    let bot = BotBuilder::new()
+        .token(&config.bot_token)
+        .parse_mode(ParseMode::HTML)
+        .build();
+    let me = bot.get_me().send().await.unwrap_or_else(|err| {
+        fail(format!(
+            "couldn't get bot info, is `bot_token` valid? {}",
+            err
+        ))
     });
+    let bot_name = me.user.username.unwrap_or_default();
+
+    info!(
+        "self-check passed: bot @{}, registries: {}",
+        bot_name,
+        config
+            .registries
+            .iter()
+            .map(|r| r.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    let api = CratesIo::new(
+        config
+            .default_registry()
+            .index
+            .as_ref()
+            .and_then(|index| index.api.as_deref()),
+    );
+    #[cfg(feature = "subscriptions")]
+    let queues = Queues::new(bot.clone(), db.clone(), config.send_concurrency);
+    // crates are re-checked in the background, only by the main loop
+    #[cfg(feature = "subscriptions")]
+    let (rechecks, recheck_queue) = tokio::sync::mpsc::unbounded_channel();
+    // versions held back before a restart
+    let cooldowns = Cooldowns::default();
+    #[cfg(feature = "subscriptions")]
+    match db.list_cooldowns().await {
+        Ok(saved) => {
+            for (registry, krate, state) in saved {
+                cooldowns.restore(&registry, &krate, &state);
+            }
+        }
+        Err(err) => log::error!("db error while loading cooldowns: {}", err),
+    }
+
+    if let Some((range, registry)) = replay_args {
+        let registry = registry.as_deref().unwrap_or(cfg::DEFAULT_REGISTRY);
+        let (registry, repo) = match repos.iter().find(|(r, _)| r.name == registry) {
+            Some((registry, Index::Git(repo))) => (registry, repo),
+            Some(_) => fail("replay needs a git index"),
+            None => fail("unknown registry"),
+        };
+        let ctx = Ctx {
+            bot: &bot,
+            #[cfg(feature = "subscriptions")]
+            db: &db,
+            api: &api,
+            cfg: &config,
+            #[cfg(feature = "subscriptions")]
+            queues: &queues,
+            #[cfg(feature = "subscriptions")]
+            rechecks: &rechecks,
+            cooldowns: &Cooldowns::default(),
+            newest: &NewestVersions::default(),
+            throttle: &AlertThrottle::default(),
+            dry_run: true,
+        };
+        replay(ctx, registry, repo, &range)
+            .await
+            .expect("replay failed");
+        return;
+    }
+
+    if let Some((krate, version, action, chat)) = test_args {
+        let ctx = Ctx {
+            bot: &bot,
+            #[cfg(feature = "subscriptions")]
+            db: &db,
+            api: &api,
+            cfg: &config,
+            #[cfg(feature = "subscriptions")]
+            queues: &queues,
+            #[cfg(feature = "subscriptions")]
+            rechecks: &rechecks,
+            cooldowns: &Cooldowns::default(),
+            newest: &NewestVersions::default(),
+            throttle: &AlertThrottle::default(),
+            dry_run: false,
+        };
+        if let Err(err) = test_notify(ctx, &krate, &version, action, chat).await {
+            fail(err);
+        }
+        return;
+    }
+
+    if once {
+        let ctx = Ctx {
+            bot: &bot,
+            #[cfg(feature = "subscriptions")]
+            db: &db,
+            api: &api,
+            cfg: &config,
+            #[cfg(feature = "subscriptions")]
+            queues: &queues,
+            #[cfg(feature = "subscriptions")]
+            rechecks: &rechecks,
+            cooldowns: &cooldowns,
+            newest: &NewestVersions::default(),
+            throttle: &AlertThrottle::default(),
+            dry_run: false,
+        };
+        let mut channels = ChannelState::default();
+        let mut failed = false;
+        for (registry, index) in &mut repos {
+            if let Err(err) = pull_index(ctx, registry, index, &mut channels).await {
+                log::error!("couldn't pull {}: {}", registry.name, err);
+                failed = true;
+            }
+        }
+        // Messages to subscribers are sent in the background
+        #[cfg(feature = "subscriptions")]
+        queues.drain().await;
+        info!("caught up, exiting");
+        std::process::exit(if failed { 1 } else { 0 });
+    }
 
-    let bot = BotBuilder::new().parse_mode(ParseMode::HTML).build();
+    let config = cfg::SharedConfig::new(config);
+    tokio::spawn(cfg::reload_on_sighup(config.clone()));
 
-    tokio::spawn(setup(bot.clone(), db.clone()));
+    #[cfg(feature = "subscriptions")]
+    {
+        tokio::spawn(keywords::refresh_loop(
+            api.clone(),
+            db.clone(),
+            config.clone(),
+        ));
+        tokio::spawn(keywords::recheck_loop(
+            api.clone(),
+            db.clone(),
+            queues.clone(),
+            config.clone(),
+            recheck_queue,
+        ));
+        tokio::spawn(owners::refresh_loop(
+            api.clone(),
+            db.clone(),
+            config.clone(),
+        ));
+        tokio::spawn(digest::send_loop(
+            db.clone(),
+            queues.clone(),
+            config.clone(),
+        ));
+        tokio::spawn(advisories::sync_loop(
+            db.clone(),
+            queues.clone(),
+            config.clone(),
+        ));
 
+        tokio::spawn(setup(
+            bot.clone(),
+            bot_name,
+            db.clone(),
+            api.clone(),
+            config.clone(),
+            started,
+        ));
+    }
+
+    let mut channels = ChannelState::default();
+    let newest = NewestVersions::default();
+    let throttle = AlertThrottle::default();
+    #[cfg(feature = "subscriptions")]
+    tokio::spawn(newest::warmup(db.clone(), config.clone(), newest.clone()));
+    let mut pull_delay = config.get().pull_delay;
+    let mut idle_cycles = 0;
+    // registry name -> number of failed pulls in a row
+    let mut failures = HashMap::new();
     loop {
-        log::info!("start pulling updates");
-        pull(&repo, &bot, &db, &config).await.expect("pull failed");
-        log::info!("pulling updates finished");
+        let config = config.get();
+        let ctx = Ctx {
+            bot: &bot,
+            #[cfg(feature = "subscriptions")]
+            db: &db,
+            api: &api,
+            cfg: &config,
+            #[cfg(feature = "subscriptions")]
+            queues: &queues,
+            #[cfg(feature = "subscriptions")]
+            rechecks: &rechecks,
+            cooldowns: &cooldowns,
+            newest: &newest,
+            throttle: &throttle,
+            dry_run: false,
+        };
 
-        tokio::time::delay_for(config.pull_delay).await; // delay for 5 min
+        let mut busy = false;
+        for (registry, index) in &mut repos {
+            log::info!("start pulling updates of {}", registry.name);
+            match pull_index(ctx, registry, index, &mut channels).await {
+                Ok(new) => {
+                    busy |= new;
+                    if failures.remove(&registry.name).unwrap_or(0) >= DEGRADED_AFTER {
+                        log::info!("pulling {} works again", registry.name);
+                        alert(
+                            ctx,
+                            &format!("✅ Index of {} is reachable again.", registry.name),
+                        )
+                        .await;
+                    }
+                }
+                // Commands keep working with the local index, so don't crash &
+                // just try again next time
+                Err(err) => {
+                    let count = failures.entry(registry.name.clone()).or_insert(0);
+                    *count += 1;
+                    log::error!(
+                        "couldn't pull {} ({} time(s) in a row): {}",
+                        registry.name,
+                        count,
+                        err
+                    );
+                    if *count == DEGRADED_AFTER {
+                        alert(
+                            ctx,
+                            &format!(
+                                "⚠️ Index of {} couldn't be pulled {} times in a row, \
+                                 notifications are delayed until it works again. Last error: {}",
+                                registry.name,
+                                count,
+                                escape_html(&err.to_string())
+                            ),
+                        )
+                        .await;
+                    }
+                }
+            }
+            // `config.json` may be changed by the pulled commits
+            registry.index = IndexConfig::read(&registry.index_path);
+            log::info!("pulling updates of {} finished", registry.name);
+        }
+
+        pull_delay = next_pull_delay(&config, pull_delay, busy, &mut idle_cycles);
+        log::debug!("next pull in {}", humantime::format_duration(pull_delay));
+        tokio::time::delay_for(pull_delay).await;
+    }
+}
+
+/// Report startup error & exit.
+fn fail(message: impl std::fmt::Display) -> ! {
+    log::error!("{}", message);
+    eprintln!("error: {}", message);
+    std::process::exit(1);
+}
+
+fn init_logging(config: &cfg::Config) {
+    let level = match config.loglevel {
+        log::Level::Error => tracing::Level::ERROR,
+        log::Level::Warn => tracing::Level::WARN,
+        log::Level::Info => tracing::Level::INFO,
+        log::Level::Debug => tracing::Level::DEBUG,
+        log::Level::Trace => tracing::Level::TRACE,
+    };
+    // Note: this also redirects `log` records to `tracing`, so they are
+    // printed with the current spans (e.g. the trace id of an update)
+    let logger = tracing_subscriber::fmt().with_max_level(level);
+    match config.log_format {
+        cfg::LogFormat::Text => logger.init(),
+        cfg::LogFormat::Json => logger.json().init(),
     }
+    log::set_max_level(config.loglevel.to_level_filter());
+}
+
+/// Delay between re-checks of pending docs.rs builds
+#[cfg(feature = "subscriptions")]
+const DOCS_RECHECK_DELAY: Duration = Duration::from_secs(60 * 5);
+
+/// Number of re-checks of a pending docs.rs build before giving up
+#[cfg(feature = "subscriptions")]
+const DOCS_RECHECKS: usize = 12;
+
+/// Number of subscribers handled at once while notifying (e.g. checked for
+/// being paused with one query)
+#[cfg(feature = "subscriptions")]
+const FANOUT_CHUNK: usize = 1000;
+
+/// Things needed to process & deliver updates
+#[derive(Clone, Copy)]
+struct Ctx<'a> {
+    bot: &'a Bot,
+    #[cfg(feature = "subscriptions")]
+    db: &'a Database,
+    api: &'a CratesIo,
+    cfg: &'a cfg::Config,
+    #[cfg(feature = "subscriptions")]
+    queues: &'a Queues,
+    /// Newly published crates to re-check keywords, categories & owners of
+    #[cfg(feature = "subscriptions")]
+    rechecks: &'a keywords::Rechecks,
+    cooldowns: &'a Cooldowns,
+    newest: &'a NewestVersions,
+    throttle: &'a AlertThrottle,
+    /// Print notifications to stdout instead of sending them (see `replay`)
+    dry_run: bool,
 }
 
 // from https://stackoverflow.com/a/58778350
-fn fast_forward(repo: &Repository, commit: &git2::Commit) -> Result<(), git2::Error> {
+fn fast_forward(repo: &Repository, branch: &str, commit: &git2::Commit) -> Result<(), git2::Error> {
     let fetch_commit = repo.find_annotated_commit(commit.id())?;
     let analysis = repo.merge_analysis(&[&fetch_commit])?;
     if analysis.0.is_up_to_date() {
         Ok(())
     } else if analysis.0.is_fast_forward() {
-        let mut reference = repo.find_reference("refs/heads/master")?;
+        let mut reference = repo.find_reference(&format!("refs/heads/{}", branch))?;
         reference.set_target(fetch_commit.id(), "Fast-Forward")?;
         repo.set_head(reference.name().unwrap())?;
         repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force()))
@@ -79,161 +533,2180 @@ fn fast_forward(repo: &Repository, commit: &git2::Commit) -> Result<(), git2::Er
     }
 }
 
+/// Number of failed pulls in a row after which admins are alerted.
+const DEGRADED_AFTER: u32 = 3;
+
+/// Send an operational alert to `Config::admin_chat` (if set).
+async fn alert(ctx: Ctx<'_>, text: &str) {
+    let chat = match ctx.cfg.admin_chat {
+        Some(chat) => chat,
+        None => return,
+    };
+    ctx.bot
+        .send_message(chat, text)
+        .disable_web_page_preview(true)
+        .send()
+        .await
+        .map_err(|err| log::error!("couldn't send alert to {}: {}", chat, err))
+        .ok();
+}
+
+/// Maximum number of alerts about unexpected index changes per
+/// [`UNEXPECTED_WINDOW`], the rest are only logged.
+///
+/// [`UNEXPECTED_WINDOW`]: UNEXPECTED_WINDOW
+const UNEXPECTED_ALERTS: u32 = 5;
+
+/// Window of the unexpected change alerts limit
+const UNEXPECTED_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Rate limit of alerts about unexpected index changes (a change of the index
+/// format could otherwise trigger one for every commit).
+#[derive(Default)]
+struct AlertThrottle {
+    /// Start of the current window, number of alerts sent in it & number of
+    /// alerts suppressed since the last sent one
+    state: Mutex<Option<(Instant, u32, u32)>>,
+}
+
+impl AlertThrottle {
+    /// Returns the number of suppressed alerts if one can be sent now.
+    fn allow(&self) -> Option<u32> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let (start, sent, suppressed) = state.get_or_insert((now, 0, 0));
+        if now.duration_since(*start) >= UNEXPECTED_WINDOW {
+            *start = now;
+            *sent = 0;
+        }
+        if *sent < UNEXPECTED_ALERTS {
+            *sent += 1;
+            Some(std::mem::take(suppressed))
+        } else {
+            *suppressed += 1;
+            None
+        }
+    }
+}
+
+/// Alert admins about an unexpected change of the index (e.g. a removed
+/// version), rate-limited by [`AlertThrottle`].
+///
+/// [`AlertThrottle`]: AlertThrottle
+async fn alert_unexpected(ctx: Ctx<'_>, registry: &RegistryConfig, what: &str) {
+    if ctx.dry_run {
+        return;
+    }
+    let suppressed = match ctx.throttle.allow() {
+        Some(suppressed) => suppressed,
+        None => {
+            log::info!("alert about unexpected index change suppressed");
+            return;
+        }
+    };
+
+    let mut text = format!(
+        "🧐 Unexpected change of {} index: {}",
+        registry.name,
+        escape_html(what)
+    );
+    if suppressed > 0 {
+        text.push_str(&format!(
+            "\n({} more alerts were suppressed, see the logs)",
+            suppressed
+        ));
+    }
+    alert(ctx, &text).await;
+}
+
+/// Number of fetches without new commits after which the pull delay is
+/// extended.
+const IDLE_CYCLES: u32 = 3;
+
+/// Adapt the pull delay to the index activity: poll sooner after a fetch with
+/// new commits, back off after several fetches without them.
+fn next_pull_delay(
+    config: &cfg::Config,
+    current: Duration,
+    busy: bool,
+    idle_cycles: &mut u32,
+) -> Duration {
+    let (min, max) = config.pull_delay_bounds();
+    let next = if busy {
+        *idle_cycles = 0;
+        current / 2
+    } else {
+        *idle_cycles += 1;
+        if *idle_cycles < IDLE_CYCLES {
+            current
+        } else {
+            *idle_cycles = 0;
+            current * 2
+        }
+    };
+    // clamp also handles config reloads that moved the bounds
+    next.max(min).min(max)
+}
+
+/// Fetch new changes of the index & notify about them. Returns whether there
+/// were any.
+async fn pull_index(
+    ctx: Ctx<'_>,
+    registry: &RegistryConfig,
+    index: &mut Index,
+    channels: &mut ChannelState,
+) -> Result<bool, index::Error> {
+    match index {
+        Index::Git(repo) => Ok(pull(ctx, registry, repo, channels).await?),
+        Index::Sparse(index) => pull_sparse(ctx, registry, index, channels).await,
+    }
+}
+
+/// Poll the sparse index for updated crates and process their changes.
+async fn pull_sparse(
+    ctx: Ctx<'_>,
+    registry: &RegistryConfig,
+    index: &mut SparseIndex,
+    channels: &mut ChannelState,
+) -> Result<bool, index::Error> {
+    let changed = index.poll(ctx.api).await?;
+    if changed.is_empty() {
+        log::info!("no updated crates in {}", registry.name);
+        return Ok(false);
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    let mut updates = Vec::new();
+    let mut unexpected = Vec::new();
+    for (name, removed, added, created) in changed {
+        let span = tracing::info_span!("sparse", trace_id = %trace_id(), krate = %name);
+        let _enter = span.enter();
+        let mut found = Vec::new();
+        for (krate, action) in classify(removed, added, &mut found) {
+            updates.push(Update {
+                oid: Oid::zero(),
+                time: now,
+                created: created && matches!(action, ActionKind::NewVersion),
+                krate,
+                action,
+                skipped: 0,
+                suppressed: 0,
+                span: span.clone(),
+            });
+        }
+        unexpected.extend(
+            found
+                .into_iter()
+                .map(|what| format!("{} (crate {})", what, name)),
+        );
+    }
+    for what in unexpected {
+        alert_unexpected(ctx, registry, &what).await;
+    }
+    if ctx.cfg.collapse_updates {
+        updates = collapse(updates);
+    }
+    process_updates(ctx, registry, None, updates, channels).await?;
+    Ok(true)
+}
+
+/// Fetch the index and process new commits. Returns whether there were any.
 async fn pull(
+    ctx: Ctx<'_>,
+    registry: &RegistryConfig,
     repo: &Repository,
-    bot: &Bot,
-    db: &Database,
-    cfg: &cfg::Config,
-) -> Result<(), git2::Error> {
+    channels: &mut ChannelState,
+) -> Result<bool, git2::Error> {
     // fetch changes from remote index
-    repo.find_remote("origin")
-        .expect("couldn't find 'origin' remote")
-        .fetch(&["master"], None, None)
-        .expect("couldn't fetch new version of the index");
+    repo.find_remote("origin")?
+        .fetch(&[&registry.branch], None, None)?;
+
+    let fetch_head = repo.refname_to_id("FETCH_HEAD")?;
+    let from = last_processed(ctx, registry, repo, fetch_head).await?;
+    if from == fetch_head {
+        log::info!("no new commits in {}", registry.name);
+        return Ok(false);
+    }
+
+    let mut walk = repo.revwalk()?;
+    walk.push(fetch_head)?;
+    walk.hide(from)?;
+    walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+    let commits: Result<Vec<_>, _> = std::iter::once(repo.find_commit(from))
+        .chain(walk.map(|oid| repo.find_commit(oid?)))
+        .collect();
+    process_commits(ctx, registry, repo, &commits?, channels).await?;
+    Ok(true)
+}
+
+/// The last commit whose notifications were all sent: the one saved in the db
+/// (it's saved before the local index is fast-forwarded, so it's never behind)
+/// or `HEAD` of the local index if there is none or it's not in the history
+/// anymore (e.g. after the index was squashed).
+#[cfg(feature = "subscriptions")]
+async fn last_processed(
+    ctx: Ctx<'_>,
+    registry: &RegistryConfig,
+    repo: &Repository,
+    fetch_head: Oid,
+) -> Result<Oid, git2::Error> {
+    let head = repo.refname_to_id("HEAD")?;
+    let saved = match ctx.db.last_commit(&registry.name).await {
+        Ok(Some(saved)) => saved,
+        Ok(None) => return Ok(head),
+        Err(err) => {
+            log::error!("db error while getting the last commit: {}", err);
+            return Ok(head);
+        }
+    };
+    let known = Oid::from_str(&saved).ok().filter(|&oid| {
+        oid == fetch_head || repo.graph_descendant_of(fetch_head, oid).unwrap_or(false)
+    });
+    match known {
+        Some(oid) => Ok(oid),
+        None => {
+            log::warn!(
+                "saved last commit {} of {} isn't in the history, resuming from {}",
+                saved,
+                registry.name,
+                head
+            );
+            Ok(head)
+        }
+    }
+}
+
+/// Without the db only the local index knows what was processed.
+#[cfg(not(feature = "subscriptions"))]
+async fn last_processed(
+    _: Ctx<'_>,
+    _: &RegistryConfig,
+    repo: &Repository,
+    _: Oid,
+) -> Result<Oid, git2::Error> {
+    repo.refname_to_id("HEAD")
+}
+
+/// Remember that all notifications up to the `commit` were sent: save it to the
+/// db & fast-forward the local index to it.
+async fn mark_processed(
+    ctx: Ctx<'_>,
+    registry: &RegistryConfig,
+    repo: &Repository,
+    commit: &Commit<'_>,
+) -> Result<(), git2::Error> {
+    if ctx.dry_run {
+        return Ok(());
+    }
+
+    #[cfg(feature = "subscriptions")]
+    if let Err(err) = ctx
+        .db
+        .set_last_commit(&registry.name, &commit.id().to_string())
+        .await
+    {
+        log::error!("db error while saving the last commit: {}", err);
+    }
+    fast_forward(repo, &registry.branch, commit)
+}
+
+/// Print notifications for the `<from>..<to>` commit range without sending
+/// anything or touching the local index.
+async fn replay(
+    ctx: Ctx<'_>,
+    registry: &RegistryConfig,
+    repo: &Repository,
+    range: &str,
+) -> Result<(), git2::Error> {
+    let from = repo
+        .revparse(range)?
+        .from()
+        .ok_or_else(|| git2::Error::from_str("expected a commit range like `<from>..<to>`"))?
+        .peel_to_commit()?;
 
     let mut walk = repo.revwalk()?;
-    walk.push_range("HEAD~1..FETCH_HEAD")?;
+    walk.push_range(range)?;
     walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
-    let commits: Result<Vec<_>, _> = walk.map(|oid| repo.find_commit(oid?)).collect();
+    let commits: Result<Vec<_>, _> = std::iter::once(Ok(from))
+        .chain(walk.map(|oid| repo.find_commit(oid?)))
+        .collect();
+    process_commits(ctx, registry, repo, &commits?, &mut ChannelState::default()).await
+}
+
+/// Send a notification about a made-up update of the crate, formatted like a
+/// real one, to the `chat` or (if it's not given) to all channels. Nothing is
+/// sent to the subscribers & nothing is recorded.
+async fn test_notify(
+    ctx: Ctx<'_>,
+    krate: &str,
+    version: &str,
+    action: cfg::Action,
+    chat: Option<ChatId>,
+) -> Result<(), String> {
+    let mut parts = krate.splitn(2, '@');
+    let name = parts.next().unwrap_or_default();
+    let registry = match parts.next() {
+        Some(registry) => ctx
+            .cfg
+            .registry(registry)
+            .ok_or_else(|| format!("unknown registry `{}`", registry))?,
+        None => ctx.cfg.default_registry(),
+    };
+    let parsed = semver::Version::parse(version)
+        .map_err(|err| format!("invalid version `{}`: {}", version, err))?;
+
+    // Use the real index entry if there is one, so links, features, etc are
+    // realistic
+    let existing = Crate::read_version(&registry.index_path, name, &parsed)
+        .await
+        .ok()
+        .flatten();
+    let mut krate = existing.unwrap_or_else(|| Crate {
+        id: CrateId {
+            name: name.to_owned(),
+            vers: version.to_owned(),
+        },
+        yanked: false,
+        cksum: "0".repeat(64),
+        deps: Vec::new(),
+        features: Default::default(),
+        features2: Default::default(),
+        rust_version: None,
+        pubtime: None,
+    });
+    let action = match action {
+        cfg::Action::NewVersion => ActionKind::NewVersion,
+        cfg::Action::Yanked => ActionKind::Yanked,
+        cfg::Action::Unyanked => ActionKind::Unyanked,
+        cfg::Action::ChecksumChanged => ActionKind::ChecksumChanged {
+            old: "f".repeat(64),
+        },
+    };
+    krate.yanked = matches!(action, ActionKind::Yanked);
+
+    let docs = match action {
+        ActionKind::NewVersion => docs_status(ctx, registry, &krate).await,
+        _ => None,
+    };
+    let mut template = format_update(ctx, registry, &krate, &action, 0, 0, docs).await;
+    with_release(ctx, registry, &krate, &mut [&mut template]).await;
+    let links = links_html(ctx, registry, &krate, &ctx.cfg.links).await;
+    let message = template.replace(LINKS, &links);
+
+    let targets = match chat {
+        Some(id) => vec![cfg::ChannelCfg {
+            id,
+            message_thread_id: None,
+            actions: None,
+        }],
+        None if ctx.cfg.channels.is_empty() => {
+            return Err(String::from("no channels configured, pass a chat id"))
+        }
+        None => ctx.cfg.channels.clone(),
+    };
+    for target in &targets {
+        channel::send(ctx.bot, target, &message, false)
+            .await
+            .map_err(|err| format!("couldn't send the notification to {}: {}", target.id, err))?;
+        info!("sent test notification to {}", target.id);
+    }
+    Ok(())
+}
+
+/// Process changes introduced by `commits[1..]` (`commits[0]` must be the last
+/// already processed commit).
+async fn process_commits(
+    ctx: Ctx<'_>,
+    registry: &RegistryConfig,
+    repo: &Repository,
+    commits: &[Commit<'_>],
+    channels: &mut ChannelState,
+) -> Result<(), git2::Error> {
+    let mut updates = Vec::new();
+    let mut unexpected = Vec::new();
+    for [prev, next] in commits.array_windows::<[_; 2]>() {
+        updates.extend(read_update(repo, prev, next, &mut unexpected)?);
+    }
+    for what in unexpected {
+        alert_unexpected(ctx, registry, &what).await;
+    }
+    if ctx.cfg.collapse_updates {
+        updates = collapse(updates);
+    }
+
+    process_updates(ctx, registry, Some(repo), updates, channels).await?;
+    // Some commits (non-crate ones & ones of a collapsed range) don't have a
+    // corresponding update, so mark the whole range
+    if let Some(last) = commits.last() {
+        mark_processed(ctx, registry, repo, last).await?;
+    }
+
+    Ok(())
+}
+
+/// Notify about the updates (& catch-ups of crates whose cooldown is over).
+///
+/// Without collapsing, commits of the `repo` are marked as processed as soon as
+/// all of their updates are (and their messages to subscribers are sent), so a
+/// crash doesn't lead to lost or duplicate notifications.
+async fn process_updates(
+    ctx: Ctx<'_>,
+    registry: &RegistryConfig,
+    repo: Option<&Repository>,
+    updates: Vec<Update>,
+    channels: &mut ChannelState,
+) -> Result<(), git2::Error> {
+    let subscribers = prefetch_subscribers(ctx, registry, &updates).await;
+
+    // Notifications are sent in the background, commits are only marked once
+    // everything they enqueued is sent, so a crash doesn't lose any
+    #[cfg(feature = "subscriptions")]
+    let mut batch = ctx.queues.start_batch();
+    let cooldown = ctx.cfg.crate_cooldown;
+    let mut updates = updates.into_iter().peekable();
+    while let Some(mut update) = updates.next() {
+        let oid = update.oid;
+        let name = update.krate.id.name.clone();
+        check_cksum(ctx, registry, &mut update).await;
+        if let Some(update) = ctx.cooldowns.check(&registry.name, update, cooldown) {
+            let users = subscribers
+                .as_ref()
+                .map(|s| s.get(&update.krate.id.name).cloned().unwrap_or_default());
+            let span = update.span.clone();
+            process(ctx, registry, update, users, channels)
+                .instrument(span)
+                .await;
+        }
+        save_cooldown(ctx, registry, &name).await;
+        let commit_done = updates.peek().map_or(true, |next| next.oid != oid);
+        match repo {
+            Some(repo) if commit_done && !ctx.cfg.collapse_updates => {
+                #[cfg(feature = "subscriptions")]
+                {
+                    ctx.queues.wait(batch).await;
+                    batch = ctx.queues.start_batch();
+                }
+                mark_processed(ctx, registry, repo, &repo.find_commit(oid)?).await?
+            }
+            _ => {}
+        }
+    }
+    // Catch-ups of crates whose cooldown is over
+    let now = chrono::Utc::now().timestamp();
+    let (expired, names) = ctx.cooldowns.take_expired(&registry.name, cooldown, now);
+    for update in expired {
+        if let Some(update) = ctx.cooldowns.check(&registry.name, update, cooldown) {
+            let span = update.span.clone();
+            process(ctx, registry, update, None, channels)
+                .instrument(span)
+                .await;
+        }
+    }
+    for name in names {
+        save_cooldown(ctx, registry, &name).await;
+    }
+    flush_batch(ctx, std::mem::take(&mut channels.batch)).await;
+    // the rest is marked by the caller
+    #[cfg(feature = "subscriptions")]
+    ctx.queues.wait(batch).await;
+    Ok(())
+}
+
+/// Save the checksum of a new version (or of a changed one) & turn a new
+/// version into [`ActionKind::ChecksumChanged`] if the version was seen before
+/// with another checksum. `classify` only sees changes where the old line is
+/// removed in the same diff.
+#[cfg(feature = "subscriptions")]
+async fn check_cksum(ctx: Ctx<'_>, registry: &RegistryConfig, update: &mut Update) {
+    if ctx.dry_run
+        || !matches!(
+            update.action,
+            ActionKind::NewVersion | ActionKind::ChecksumChanged { .. }
+        )
+    {
+        return;
+    }
+    let krate = &update.krate;
+    let saved = ctx
+        .db
+        .save_cksum(&registry.name, &krate.id.name, &krate.id.vers, &krate.cksum)
+        .await;
+    match saved {
+        Ok(Some(old)) if matches!(update.action, ActionKind::NewVersion) => {
+            log::warn!("Checksum changed: {:?}, was {}", krate, old);
+            update.action = ActionKind::ChecksumChanged { old };
+        }
+        Ok(_) => {}
+        Err(err) => log::error!("db error while saving checksum: {}", err),
+    }
+}
+
+/// Without the db only changes within a diff are caught.
+#[cfg(not(feature = "subscriptions"))]
+async fn check_cksum(_: Ctx<'_>, _: &RegistryConfig, _: &mut Update) {}
+
+/// Save the cooldown state of the crate (or delete it if there is none), see
+/// [`Cooldowns::snapshot`].
+#[cfg(feature = "subscriptions")]
+async fn save_cooldown(ctx: Ctx<'_>, registry: &RegistryConfig, krate: &str) {
+    if ctx.dry_run || ctx.cfg.crate_cooldown.as_secs() == 0 {
+        return;
+    }
+    let state = ctx.cooldowns.snapshot(&registry.name, krate);
+    if let Err(err) = ctx
+        .db
+        .save_cooldown(&registry.name, krate, state.as_deref())
+        .await
+    {
+        log::error!("db error while saving cooldown of {}: {}", krate, err);
+    }
+}
+
+/// Without the db the state is only kept in memory.
+#[cfg(not(feature = "subscriptions"))]
+async fn save_cooldown(_: Ctx<'_>, _: &RegistryConfig, _: &str) {}
+
+/// Fetch subscribers of all crates at once, instead of doing a query per update.
+#[cfg(feature = "subscriptions")]
+async fn prefetch_subscribers(
+    ctx: Ctx<'_>,
+    registry: &RegistryConfig,
+    updates: &[Update],
+) -> Option<HashMap<String, Vec<Subscriber>>> {
+    if updates.len() <= 1 {
+        return None;
+    }
+
+    let mut names: Vec<_> = updates.iter().map(|u| u.krate.id.name.clone()).collect();
+    names.sort_unstable();
+    names.dedup();
+    ctx.db
+        .list_subscribers_of(&registry.name, &names)
+        .await
+        .map_err(|err| log::error!("db error while getting subscribers: {}", err))
+        .ok()
+}
+
+#[cfg(not(feature = "subscriptions"))]
+async fn prefetch_subscribers(
+    _: Ctx<'_>,
+    _: &RegistryConfig,
+    _: &[Update],
+) -> Option<HashMap<String, Vec<Subscriber>>> {
+    None
+}
+
+/// Post updates of the pull cycle accumulated with `Config::channel_batch`, as
+/// one message per channel (split if it's too long).
+async fn flush_batch(ctx: Ctx<'_>, batch: Vec<Batched>) {
+    // channel -> updates, in order of the first update
+    let mut groups: Vec<(&cfg::ChannelCfg, Vec<&Batched>)> = Vec::new();
+    for item in &batch {
+        let same = |ch: &cfg::ChannelCfg| {
+            ch.id == item.channel.id && ch.message_thread_id == item.channel.message_thread_id
+        };
+        match groups.iter_mut().find(|(ch, _)| same(ch)) {
+            Some((_, items)) => items.push(item),
+            None => groups.push((&item.channel, vec![item])),
+        }
+    }
+
+    for (ch, items) in groups {
+        let header = match items.len() {
+            1 => String::from("1 update this cycle:"),
+            n => format!("{} updates this cycle:", n),
+        };
+        let lines: Vec<_> = items.iter().map(|i| i.line.as_str()).collect();
+        let mut items = items.iter();
+        for (text, count) in channel::split(&header, &lines) {
+            let chunk: Vec<_> = items.by_ref().take(count).collect();
+            // Checksum changes are not sent silently
+            let silent = chunk.iter().all(|i| i.silent);
+            let res = channel::send(ctx.bot, ch, &text, silent).await.map(drop);
+            if let Err(err) = &res {
+                log::error!("couldn't post to the channel {}: {}", ch.id, err);
+            }
+            for item in chunk {
+                #[cfg(feature = "subscriptions")]
+                record_delivery(ctx.db, item.event, ch.id, &res).await;
+            }
+            tokio::time::delay_for(ctx.cfg.broadcast_delay).await;
+        }
+    }
+}
+
+/// Single change of the index
+struct Update {
+    /// Commit that introduced the change (zero for sparse indexes, which
+    /// don't have commits)
+    oid: Oid,
+    /// Time of the commit (unix seconds)
+    time: i64,
+    krate: Crate,
+    action: ActionKind,
+    /// `krate` is the first version of the crate ever published (its index
+    /// file was just added), new crates are checked against `/watch_new`
+    /// patterns
+    created: bool,
+    /// Number of versions published before `krate` in the same pull range that
+    /// were not announced (see `Config::collapse_updates`)
+    skipped: usize,
+    /// Number of versions (including this one) that were held back by
+    /// `Config::crate_cooldown`, zero if this isn't a catch-up
+    suppressed: usize,
+    /// Span with the trace id of the commit, all logs about processing &
+    /// sending the update are written inside it
+    span: tracing::Span,
+}
+
+/// Span of a commit being processed, with a random id to correlate logs about
+/// the same update (from diffing to the last send).
+fn commit_span(oid: Oid) -> tracing::Span {
+    tracing::info_span!("commit", trace_id = %trace_id(), %oid)
+}
+
+/// Random id to correlate logs about the same update.
+fn trace_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+/// Read the changes introduced by the `next` commit. Returns nothing if the
+/// commit doesn't touch any crate (e.g. it only changes `config.json`).
+/// Descriptions of changes the bot doesn't know how to handle are pushed to
+/// `unexpected`.
+fn read_update(
+    repo: &Repository,
+    prev: &Commit,
+    next: &Commit,
+    unexpected: &mut Vec<String>,
+) -> Result<Vec<Update>, git2::Error> {
+    let span = commit_span(next.id());
+    let _enter = span.enter();
     let mut opts = DiffOptions::default();
     let opts = opts.context_lines(0).minimal(true);
-    for [prev, next] in commits?.array_windows::<[_; 2]>() {
-        let diff: Diff =
-            repo.diff_tree_to_tree(Some(&prev.tree()?), Some(&next.tree()?), Some(opts))?;
-        let (krate, action) = diff_one(diff)?;
-        notify(krate, action, bot, db, cfg).await;
-        fast_forward(repo, next)?;
+    let diff: Diff =
+        repo.diff_tree_to_tree(Some(&prev.tree()?), Some(&next.tree()?), Some(opts))?;
+    let touches_crates = diff
+        .deltas()
+        .any(|delta| delta.new_file().path().map_or(false, is_crate_file));
+    if !touches_crates {
+        log::info!("skipping commit {} which doesn't touch crates", next.id());
+        return Ok(Vec::new());
+    }
+
+    let mut found = Vec::new();
+    let mut created = Vec::new();
+    let changes = diff_changes(diff, &mut found, &mut created)?;
+    unexpected.extend(
+        found
+            .into_iter()
+            .map(|what| format!("{} (commit {})", what, next.id())),
+    );
+    match changes.len() {
+        0 => log::info!("commit {} doesn't change any version", next.id()),
+        1 => {}
+        n => log::info!("commit {} changes {} versions", next.id(), n),
+    }
+    Ok(changes
+        .into_iter()
+        .map(|(krate, action)| Update {
+            oid: next.id(),
+            time: next.time().seconds(),
+            created: matches!(action, ActionKind::NewVersion)
+                && created
+                    .iter()
+                    .any(|c| c.eq_ignore_ascii_case(&krate.id.name)),
+            krate,
+            action,
+            skipped: 0,
+            suppressed: 0,
+            span: span.clone(),
+        })
+        .collect())
+}
+
+/// `users` are the subscribers of the crate if they were already fetched.
+async fn process(
+    ctx: Ctx<'_>,
+    registry: &RegistryConfig,
+    update: Update,
+    users: Option<Vec<Subscriber>>,
+    channels: &mut ChannelState,
+) {
+    #[cfg(feature = "subscriptions")]
+    if let (ActionKind::NewVersion, true, false) =
+        (&update.action, registry.is_default(), ctx.dry_run)
+    {
+        // the receiver is only gone when the bot is shutting down
+        let _ = ctx.rechecks.send(keywords::Recheck {
+            id: update.krate.id.clone(),
+            created: update.created,
+        });
+    }
+    tracing::info!(
+        oid = %update.oid,
+        registry = %registry.name,
+        krate = %update.krate.id.name,
+        version = %update.krate.id.vers,
+        action = ?update.action,
+        skipped = update.skipped,
+        "processing commit"
+    );
+    if let (ActionKind::NewVersion, Ok(version)) = (&update.action, update.krate.id.version()) {
+        ctx.newest
+            .update(&registry.name, &update.krate.id.name, version);
+    }
+    notify(ctx, registry, update, users, channels).await;
+    if !ctx.dry_run {
         // Try to prevent "too many requests" error from telegram
-        tokio::time::delay_for(cfg.update_delay_millis.into()).await;
+        tokio::time::delay_for(ctx.cfg.update_delay).await;
     }
+}
 
-    Ok(())
+/// Leave only the last new version of every crate, counting the dropped ones in
+/// `Update::skipped` (and keeping `Update::created` of the first one). Other
+/// actions (yanks, etc) are left as is.
+fn collapse(updates: Vec<Update>) -> Vec<Update> {
+    // crate name -> (index of the last new version, number of new versions)
+    let mut last = HashMap::new();
+    let mut created = HashSet::new();
+    for (i, update) in updates.iter().enumerate() {
+        if let ActionKind::NewVersion = update.action {
+            let entry = last.entry(update.krate.id.name.clone()).or_insert((i, 0));
+            *entry = (i, entry.1 + 1);
+            if update.created {
+                created.insert(update.krate.id.name.clone());
+            }
+        }
+    }
+
+    updates
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, mut update)| match update.action {
+            ActionKind::NewVersion => {
+                let (idx, count) = last[&update.krate.id.name];
+                if idx == i {
+                    update.skipped = count - 1;
+                    update.created |= created.contains(&update.krate.id.name);
+                    Some(update)
+                } else {
+                    None
+                }
+            }
+            _ => Some(update),
+        })
+        .collect()
 }
 
+#[derive(Debug)]
 enum ActionKind {
     NewVersion,
     Yanked,
     Unyanked,
+    /// Checksum of an already published version was changed. This should never
+    /// happen, so it's most likely a sign of something nasty going on.
+    ChecksumChanged {
+        old: String,
+    },
 }
 
-fn diff_one(diff: Diff) -> Result<(Crate, ActionKind), git2::Error> {
-    let mut prev = None;
-    let mut next = None;
+impl ActionKind {
+    fn is_yank(&self) -> bool {
+        matches!(self, ActionKind::Yanked | ActionKind::Unyanked)
+    }
+
+    /// Subscriptions with these events (`/subscribe <crate> --events ..`) are
+    /// notified
+    #[cfg(feature = "subscriptions")]
+    fn events(&self) -> Events {
+        if self.is_yank() {
+            Events::YANKS
+        } else {
+            Events::UPDATES
+        }
+    }
+
+    fn kind(&self) -> cfg::Action {
+        match self {
+            ActionKind::NewVersion => cfg::Action::NewVersion,
+            ActionKind::Yanked => cfg::Action::Yanked,
+            ActionKind::Unyanked => cfg::Action::Unyanked,
+            ActionKind::ChecksumChanged { .. } => cfg::Action::ChecksumChanged,
+        }
+    }
+
+    /// Short name used in the event history
+    #[cfg(feature = "subscriptions")]
+    fn as_str(&self) -> &'static str {
+        match self {
+            ActionKind::NewVersion => "new_version",
+            ActionKind::Yanked => "yanked",
+            ActionKind::Unyanked => "unyanked",
+            ActionKind::ChecksumChanged { .. } => "checksum_changed",
+        }
+    }
+}
+
+/// Read changes from a diff of crate files. Usually a commit adds one line
+/// (new version) or replaces one line (yank/unyank), but sometimes the index
+/// rewrites several lines at once (e.g. backfills of metadata), so removed &
+/// added lines are matched by version and only actual changes are returned.
+///
+/// Names (as in the file names, i.e. lowercase) of crates whose files were
+/// added, i.e. brand-new crates, are pushed to `created`.
+fn diff_changes(
+    diff: Diff,
+    unexpected: &mut Vec<String>,
+    created: &mut Vec<String>,
+) -> Result<Vec<(Crate, ActionKind)>, git2::Error> {
+    let mut lines = DiffLines::default();
 
     diff.foreach(
         &mut |_, _| true,
         None,
         None,
         Some(&mut |delta, _hunk, line| {
+            if !delta.new_file().path().map_or(false, is_crate_file) {
+                // e.g. `config.json`
+                return true;
+            }
+
             match delta.status() {
-                // New version of a crate or (un)yanked old version
+                // New version of a crate or (un)yanked old version, the file
+                // is added by the first version of a new crate
                 Delta::Modified | Delta::Added => {
-                    assert!(delta.nfiles() == 2 || delta.nfiles() == 1);
-                    match line.origin() {
-                        '-' => {
-                            assert!(
-                                prev.is_none(),
-                                "Expected number of deletions <= 1 per commit"
-                            );
-                            let krate = str::from_utf8(line.content()).expect("non-utf8 diff");
-                            let krate = serde_json::from_str::<Crate>(krate)
-                                .expect("cound't deserialize crate");
-
-                            prev = Some(krate);
-                        }
-                        '+' => {
-                            assert!(
-                                next.is_none(),
-                                "Expected number of additions = 1 per commit"
-                            );
-                            let krate = str::from_utf8(line.content()).expect("non-utf8 diff");
-                            let krate = serde_json::from_str::<Crate>(krate)
-                                .expect("cound't deserialize crate");
-
-                            next = Some(krate);
+                    if delta.status() == Delta::Added {
+                        let name = delta.new_file().path().and_then(|p| p.file_name());
+                        match name.and_then(|n| n.to_str()) {
+                            Some(name) if !created.iter().any(|c| c == name) => {
+                                created.push(name.to_owned())
+                            }
+                            _ => {}
                         }
-                        _ => { /* don't care */ }
                     }
+                    let origin = line.origin();
+                    if origin != '-' && origin != '+' {
+                        return true; /* don't care */
+                    }
+                    lines.push(origin, line.content());
                 }
-                delta => {
-                    log::warn!("Unexpected delta: {:?}", delta);
+                status => {
+                    log::warn!("Unexpected delta: {:?}", status);
+                    let path = delta
+                        .new_file()
+                        .path()
+                        .unwrap_or_else(|| std::path::Path::new("?"));
+                    let what = format!("{:?} file {}", status, path.display());
+                    // the callback is called for every line of the file
+                    if !unexpected.contains(&what) {
+                        unexpected.push(what);
+                    }
                 }
             }
 
             true
         }),
     )?;
+    // the last line of a file may have no newline
+    lines.flush();
 
-    let next = next.expect("Expected number of additions = 1 per commit");
-    match (prev.as_ref().map(|c| c.yanked), next.yanked) {
-        /* was yanked, is yanked */
-        (None, false) => {
-            // There were no deleted line & crate is not yanked.
-            // New version.
-            Ok((next, ActionKind::NewVersion))
+    Ok(classify(lines.removed, lines.added, unexpected))
+}
+
+/// Removed & added lines of index files in a diff. libgit2 may pass a line in
+/// several chunks, so they are accumulated until the newline.
+#[derive(Default)]
+struct DiffLines {
+    removed: Vec<Crate>,
+    added: Vec<Crate>,
+    /// origin & content of the current line
+    pending: Option<(char, Vec<u8>)>,
+}
+
+impl DiffLines {
+    /// Add a chunk of a `-` or `+` line.
+    fn push(&mut self, origin: char, content: &[u8]) {
+        if self.pending.as_ref().map_or(false, |(o, _)| *o != origin) {
+            self.flush();
         }
-        (Some(false), true) => {
-            // The crate was not yanked and now is yanked.
-            // Crate yanked.
-            Ok((next, ActionKind::Yanked))
+        self.pending
+            .get_or_insert_with(|| (origin, Vec::new()))
+            .1
+            .extend_from_slice(content);
+        if content.ends_with(b"\n") {
+            self.flush();
+        }
+    }
+
+    /// Parse the current line (skipping it if it's malformed).
+    fn flush(&mut self) {
+        if let Some((origin, content)) = self.pending.take() {
+            let lines = if origin == '-' {
+                &mut self.removed
+            } else {
+                &mut self.added
+            };
+            lines.extend(parse_index_line(&content));
         }
-        (Some(true), false) => {
+    }
+}
+
+/// Tell what happened from the `removed` & `added` lines of index files.
+fn classify(
+    mut removed: Vec<Crate>,
+    added: Vec<Crate>,
+    unexpected: &mut Vec<String>,
+) -> Vec<(Crate, ActionKind)> {
+    let mut changes = Vec::new();
+    for next in added {
+        let prev = removed
+            .iter()
+            .position(|p| p.id == next.id)
+            .map(|i| removed.swap_remove(i));
+        match prev {
+            Some(prev) if prev.cksum != next.cksum => {
+                log::warn!("Checksum changed: {:?}, {:?}", next, prev);
+                let old = prev.cksum;
+                changes.push((next, ActionKind::ChecksumChanged { old }));
+            }
+            // The crate was not yanked and now is yanked.
+            Some(prev) if !prev.yanked && next.yanked => changes.push((next, ActionKind::Yanked)),
             // The crate was yanked and now is not yanked.
-            // Crate unyanked.
-            Ok((next, ActionKind::Unyanked))
+            Some(prev) if prev.yanked && !next.yanked => changes.push((next, ActionKind::Unyanked)),
+            // Only other fields (e.g. features) were changed
+            Some(_) => log::info!("ignoring rewrite of {:?}", next.id),
+            // There were no deleted line & crate is not yanked.
+            // New version.
+            None if !next.yanked => changes.push((next, ActionKind::NewVersion)),
+            None => {
+                log::warn!("Unexpected new yanked version: {:?}", next);
+                unexpected.push(format!(
+                    "new version {} {} is already yanked",
+                    next.id.name, next.id.vers
+                ));
+            }
         }
-        _unexpected => {
-            // Something unexpected happened
-            log::warn!("Unexpected diff_one input: {:?}, {:?}", next, prev);
-            Err(git2::Error::from_str("Unexpected diff"))
+    }
+    for prev in removed {
+        log::warn!("Version was removed from the index: {:?}", prev.id);
+        unexpected.push(format!(
+            "version {} {} was removed",
+            prev.id.name, prev.id.vers
+        ));
+    }
+
+    changes
+}
+
+/// Parse a line of an index file, logging & skipping it if it's not valid
+/// UTF-8 or not a crate version.
+fn parse_index_line(line: &[u8]) -> Option<Crate> {
+    let line = match str::from_utf8(line) {
+        Ok(line) => line.trim(),
+        Err(err) => {
+            log::warn!("skipping non-utf8 index line: {}", err);
+            return None;
         }
+    };
+    if line.is_empty() {
+        return None;
     }
+
+    serde_json::from_str(line)
+        .map_err(|err| log::warn!("skipping unparseable index line {:?}: {}", line, err))
+        .ok()
 }
 
-async fn notify(krate: Crate, action: ActionKind, bot: &Bot, db: &Database, cfg: &cfg::Config) {
-    let message = match action {
+#[tracing::instrument(
+    skip(ctx, registry, update, users, channels),
+    fields(registry = %registry.name, krate = %update.krate.id.name, version = %update.krate.id.vers)
+)]
+async fn notify(
+    ctx: Ctx<'_>,
+    registry: &RegistryConfig,
+    update: Update,
+    users: Option<Vec<Subscriber>>,
+    channels: &mut ChannelState,
+) {
+    let Ctx { bot, cfg, .. } = ctx;
+    let Update {
+        oid,
+        krate,
+        action,
+        skipped,
+        suppressed,
+        ..
+    } = update;
+    // Checksum changes are rare & important, so don't send them silently
+    let silent = !matches!(action, ActionKind::ChecksumChanged { .. });
+    let is_new_version = matches!(action, ActionKind::NewVersion);
+    let kind = action.kind();
+    let event = record_event(ctx, oid, &krate, &action).await;
+    let links = links_html(ctx, registry, &krate, &cfg.links).await;
+    let docs = if is_new_version {
+        docs_status(ctx, registry, &krate).await
+    } else {
+        None
+    };
+    // Links are substituted per user (see `/links`)
+    let mut template =
+        format_update(ctx, registry, &krate, &action, skipped, suppressed, docs).await;
+
+    let mut message = template.replace(LINKS, &links);
+    // Old yanks would only clutter the channels, subscribers still get them
+    let to_channels = !is_old_yank(ctx, registry, &krate, &action).await;
+    if ctx.dry_run || (to_channels && cfg.channels.iter().any(|ch| ch.announces(kind))) {
+        with_release(ctx, registry, &krate, &mut [&mut template, &mut message]).await;
+    }
+
+    if ctx.dry_run {
+        let channels: Vec<_> = cfg
+            .channels
+            .iter()
+            .filter(|ch| to_channels && ch.announces(kind))
+            .map(|ch| ch.id)
+            .collect();
+        println!("{}\n  channels: {:?}", message, channels);
+    } else {
+        for ch in cfg
+            .channels
+            .iter()
+            .filter(|ch| to_channels && ch.announces(kind))
+        {
+            if cfg.channel_batch {
+                channels.batch.push(Batched {
+                    channel: ch.clone(),
+                    line: message.clone(),
+                    silent,
+                    event,
+                });
+                continue;
+            }
+
+            let res = channels
+                .rolling
+                .post(bot, ch, &message, silent, cfg.channel_rolling_window)
+                .await;
+            if let Err(err) = &res {
+                log::error!("couldn't post to the channel {}: {}", ch.id, err);
+            }
+            #[cfg(feature = "subscriptions")]
+            record_delivery(ctx.db, event, ch.id, &res).await;
+            tokio::time::delay_for(cfg.broadcast_delay).await;
+        }
+    }
+
+    #[cfg(feature = "subscriptions")]
+    {
+        let notification = Notification {
+            message,
+            template,
+            silent,
+            is_new_version,
+            events: action.events(),
+            level: if is_new_version {
+                release_level(&registry.index_path, &krate).await
+            } else {
+                Level::Patch
+            },
+            event,
+            docs,
+        };
+        notify_users(ctx, registry, krate, users, notification).await;
+    }
+    #[cfg(not(feature = "subscriptions"))]
+    let _ = (users, docs, template);
+    if ctx.dry_run {
+        println!();
+    }
+}
+
+/// Text of the update notification with [`LINKS`] & [`RELEASE`] placeholders
+/// instead of the links & the release link.
+///
+/// [`LINKS`]: LINKS
+/// [`RELEASE`]: RELEASE
+async fn format_update(
+    ctx: Ctx<'_>,
+    registry: &RegistryConfig,
+    krate: &Crate,
+    action: &ActionKind,
+    skipped: usize,
+    suppressed: usize,
+    docs: Option<DocsStatus>,
+) -> String {
+    let name = qualified_name(&krate.id.name, registry);
+    // the previous version is read once for everything compared with it
+    let prev = match action {
+        ActionKind::NewVersion => krate
+            .read_previous(&registry.index_path)
+            .await
+            .ok()
+            .flatten(),
+        _ => None,
+    };
+    match action {
+        ActionKind::NewVersion if suppressed > 0 => format!(
+            "Crate was updated {n} more time{s}, now at <code>{krate}#{version}</code>{skipped}{since} {links}{release}{docs}{changes}",
+            n = suppressed,
+            s = if suppressed == 1 { "" } else { "s" },
+            krate = name,
+            version = krate.id.vers,
+            skipped = match skipped {
+                0 => String::new(),
+                1 => String::from(" (1 intermediate version skipped)"),
+                n => format!(" ({} intermediate versions skipped)", n),
+            },
+            since = since_previous(ctx, registry, krate, prev.as_ref()).await,
+            links = LINKS,
+            release = RELEASE,
+            docs = docs.map(docs_note).unwrap_or_default(),
+            changes = version_changes(prev.as_ref(), krate, ctx.cfg),
+        ),
         ActionKind::NewVersion => format!(
-            "Crate was updated: <code>{krate}#{version}</code> {links}",
-            krate = krate.id.name,
+            "Crate was updated: <code>{krate}#{version}</code>{skipped}{since} {links}{release}{docs}{changes}",
+            krate = name,
             version = krate.id.vers,
-            links = krate.html_links(),
+            skipped = match skipped {
+                0 => String::new(),
+                1 => String::from(" (1 intermediate version skipped)"),
+                n => format!(" ({} intermediate versions skipped)", n),
+            },
+            since = since_previous(ctx, registry, krate, prev.as_ref()).await,
+            links = LINKS,
+            release = RELEASE,
+            docs = docs.map(docs_note).unwrap_or_default(),
+            changes = version_changes(prev.as_ref(), krate, ctx.cfg),
         ),
         ActionKind::Yanked => format!(
-            "Crate was yanked: <code>{krate}#{version}</code> {links}",
-            krate = krate.id.name,
+            "Crate was yanked: <code>{krate}#{version}</code>{historical} {links}",
+            krate = name,
             version = krate.id.vers,
-            links = krate.html_links(),
+            historical = historical(ctx, registry, krate).await,
+            links = LINKS,
         ),
         ActionKind::Unyanked => format!(
-            "Crate was unyanked: <code>{krate}#{version}</code> {links}",
-            krate = krate.id.name,
+            "Crate was unyanked: <code>{krate}#{version}</code>{historical} {links}",
+            krate = name,
+            version = krate.id.vers,
+            historical = historical(ctx, registry, krate).await,
+            links = LINKS,
+        ),
+        ActionKind::ChecksumChanged { old } => format!(
+            "⚠️ <b>Checksum changed</b>: <code>{krate}#{version}</code> \
+             (<code>{old}</code> → <code>{new}</code>) {links}",
+            krate = name,
             version = krate.id.vers,
-            links = krate.html_links(),
+            old = old,
+            new = krate.cksum,
+            links = LINKS,
         ),
+    }
+}
+
+/// Record the event to the history, returns its id.
+#[cfg(feature = "subscriptions")]
+async fn record_event(ctx: Ctx<'_>, oid: Oid, krate: &Crate, action: &ActionKind) -> Option<i64> {
+    if ctx.dry_run {
+        return None;
+    }
+
+    ctx.db
+        .record_event(
+            &oid.to_string(),
+            &krate.id.name,
+            &krate.id.vers,
+            action.as_str(),
+        )
+        .await
+        .map_err(|err| log::error!("db error while recording event: {}", err))
+        .ok()
+}
+
+/// Without the db there is no history.
+#[cfg(not(feature = "subscriptions"))]
+async fn record_event(_: Ctx<'_>, _: Oid, _: &Crate, _: &ActionKind) -> Option<i64> {
+    None
+}
+
+/// Notification that is being sent.
+#[cfg(feature = "subscriptions")]
+struct Notification {
+    message: String,
+    /// `message` with [`LINKS`] placeholder instead of the links
+    ///
+    /// [`LINKS`]: LINKS
+    template: String,
+    silent: bool,
+    is_new_version: bool,
+    /// Kind of the update, subscribers without it in
+    /// [`Subscriber::events`] aren't notified
+    events: Events,
+    /// Level of the new version, subscribers with a higher
+    /// [`Subscriber::min_level`] aren't notified
+    level: Level,
+    event: Option<i64>,
+    docs: Option<DocsStatus>,
+}
+
+/// Send the notification to subscribers of the crate (& its keywords,
+/// categories, owners and matching patterns) and
+/// fire alerts. `users` are the subscribers of the crate if they were already
+/// fetched.
+#[cfg(feature = "subscriptions")]
+async fn notify_users(
+    ctx: Ctx<'_>,
+    registry: &RegistryConfig,
+    krate: Crate,
+    users: Option<Vec<Subscriber>>,
+    notification: Notification,
+) {
+    let Ctx { db, cfg, .. } = ctx;
+    let Notification {
+        mut message,
+        mut template,
+        silent,
+        is_new_version,
+        events,
+        level,
+        event,
+        docs,
+    } = notification;
+
+    let mut indirect_users = Vec::new();
+    // keywords, categories & owners are only known for crates.io crates
+    if registry.is_default() {
+        indirect_users.extend(
+            db.list_keyword_subscribers(&krate.id.name)
+                .await
+                .map_err(|err| log::error!("db error while getting keyword subscribers: {}", err))
+                .unwrap_or_default(),
+        );
+        indirect_users.extend(
+            db.list_category_subscribers(&krate.id.name)
+                .await
+                .map_err(|err| log::error!("db error while getting category subscribers: {}", err))
+                .unwrap_or_default(),
+        );
+        indirect_users.extend(
+            db.list_owner_subscribers(&krate.id.name)
+                .await
+                .map_err(|err| log::error!("db error while getting owner subscribers: {}", err))
+                .unwrap_or_default(),
+        );
+    }
+    indirect_users.extend(
+        db.list_pattern_subscribers(&registry.name, &krate.id.name)
+            .await
+            .map_err(|err| log::error!("db error while getting pattern subscribers: {}", err))
+            .unwrap_or_default(),
+    );
+    indirect_users.sort_unstable();
+    indirect_users.dedup();
+
+    let is_prerelease = is_new_version && krate.id.version().map_or(false, |v| v.is_prerelease());
+
+    let alerts = if is_new_version {
+        take_alerts(ctx, registry, &krate).await
+    } else {
+        Vec::new()
+    };
+    // users with a fired alert get the alert message instead of the usual one
+    let mut skip: Vec<_> = alerts.iter().map(|(u, _)| *u).collect();
+    indirect_users.retain(|u| !skip.contains(u));
+    skip.sort_unstable();
+    // Chats subscribed to the crate itself (whether they want this update or
+    // not). Settings of their subscription (mute, `--only`, `--events`, label,
+    // buttons) win over indirect (keyword, category, owner & pattern) ones
+    let direct = Arc::new(Mutex::new(HashSet::new()));
+    let seen = Arc::clone(&direct);
+    let is_direct = move |chat_id: &ChatId| direct.lock().unwrap().contains(chat_id);
+    let has_subscription = is_direct.clone();
+
+    // Subscribers of the crate are streamed, as there may be a lot of them
+    // (indirect subscribers & alerts are few, so they are loaded at once)
+    let users = match users {
+        Some(users) => stream::iter(users).left_stream(),
+        None => stream_subscribers(db, registry, &krate.id.name)
+            .await
+            .right_stream(),
     };
+    let now = chrono::Utc::now();
+    let users = users
+        .inspect(move |sub| {
+            seen.lock().unwrap().insert(sub.chat_id);
+        })
+        .filter_map(move |sub| {
+            let wants = if sub.is_muted(now) {
+                false
+            } else if !sub.events.contains(events) {
+                false
+            } else {
+                !is_new_version || level >= sub.min_level
+            };
+            future::ready(Some(sub).filter(|_| wants))
+        })
+        .filter(move |sub| future::ready(skip.binary_search(&sub.chat_id).is_err()))
+        // indirect subscribers are chained after all direct ones are seen, to
+        // not notify anyone twice
+        .chain(
+            stream::iter(indirect_users)
+                .filter(move |chat_id| future::ready(!is_direct(chat_id)))
+                .map(|chat_id| Subscriber {
+                    chat_id,
+                    events: Events::ALL,
+                    min_level: Level::Patch,
+                    prereleases: None,
+                    changelog: false,
+                    label: None,
+                    muted_until: None,
+                }),
+        )
+        .chunks(FANOUT_CHUNK);
+    futures::pin_mut!(users);
+
+    if ctx.dry_run {
+        let mut all = Vec::new();
+        while let Some(chunk) = users.next().await {
+            let (chunk, _) = without_paused(db, chunk).await;
+            let chunk = without_prereleases(db, chunk, is_prerelease).await;
+            all.extend(chunk.into_iter().map(|s| s.chat_id));
+        }
+        println!("  users: {:?}\n  alerts: {:?}", all, alerts);
+        return;
+    }
+
+    // Messages with pending docs build are edited once the build is finished
+    let docs_pending = docs == Some(DocsStatus::Pending);
+    let keyboard = notification_buttons(&krate.id.name, registry, false);
+    let mut sent = HashMap::<_, Vec<_>>::new();
+    // The message is formatted once (`template`), only the per-chat parts are
+    // applied for every recipient: links (built once per distinct settings,
+    // cached in `variants`) & the subscription label
+    let mut variants = Variants::default();
+    // Release notes are fetched only once someone wants them
+    // (`/subscribe <crate> --changelog`), as it takes a few requests
+    let mut notes = None;
+    while let Some(chunk) = users.next().await {
+        let (chunk, catching_up) = without_paused(db, chunk).await;
+        let chunk = without_prereleases(db, chunk, is_prerelease).await;
+        if !chunk.is_empty() {
+            with_release(ctx, registry, &krate, &mut [&mut template, &mut message]).await;
+        }
+        let ids: Vec<_> = chunk.iter().map(|s| s.chat_id).collect();
+        let prefs = link_settings(ctx, registry, &ids).await;
+        let digests = db
+            .list_digests(&ids)
+            .await
+            .map_err(|err| log::error!("db error while getting digests: {}", err))
+            .unwrap_or_default();
+        // (chats, texts) of the updates to add to digests
+        let mut digested = (Vec::new(), Vec::new());
+        for sub in chunk {
+            let chat_id = sub.chat_id;
+            let mut text = match prefs.get(&chat_id) {
+                Some(links) => {
+                    with_links(ctx, registry, &krate, &template, links, &mut variants).await
+                }
+                None => message.clone(),
+            };
+            if sub.changelog && is_new_version && registry.is_default() {
+                if notes.is_none() {
+                    notes = Some(release_notes(ctx, &krate).await);
+                }
+                if let Some(Some(notes)) = &notes {
+                    text.push_str(notes);
+                }
+            }
+            let text = with_label(text, sub.label.as_deref());
+            if digests.contains(&chat_id) || catching_up.contains(&chat_id) {
+                digested.0.push(chat_id);
+                digested.1.push(text);
+                continue;
+            }
+            // indirect subscribers have nothing to unsubscribe from or mute
+            let keyboard = if has_subscription(&chat_id) {
+                keyboard.clone()
+            } else {
+                None
+            };
+            if docs_pending {
+                let message_id = ctx
+                    .queues
+                    .send_tracked(
+                        chat_id,
+                        text.clone(),
+                        silent,
+                        event,
+                        keyboard.clone(),
+                        cfg.broadcast_delay,
+                    )
+                    .await;
+                sent.entry(text)
+                    .or_default()
+                    .push((chat_id, keyboard, message_id));
+            } else {
+                ctx.queues
+                    .send(chat_id, text, silent, event, keyboard, cfg.broadcast_delay)
+                    .await;
+            }
+        }
+        if !digested.0.is_empty() {
+            if let Err(err) = db.add_digest_items(&digested.0, &digested.1).await {
+                log::error!("db error while adding to digests: {}", err);
+            }
+        }
+    }
+
+    if !alerts.is_empty() {
+        with_release(ctx, registry, &krate, &mut [&mut template, &mut message]).await;
+    }
+    let alerted: Vec<_> = alerts.iter().map(|(u, _)| *u).collect();
+    let prefs = link_settings(ctx, registry, &alerted).await;
+    for (chat_id, req) in alerts {
+        let message = match prefs.get(&chat_id) {
+            Some(links) => with_links(ctx, registry, &krate, &template, links, &mut variants).await,
+            None => message.clone(),
+        };
+        let text = format!(
+            "🔔 Version matches <code>{}</code> (this alert won't fire again):\n{}",
+            escape_html(&req),
+            message
+        );
+        ctx.queues
+            .send(chat_id, text, false, event, None, cfg.broadcast_delay)
+            .await;
+    }
 
-    let users = db
-        .list_subscribers(&krate.id.name)
+    if docs_pending {
+        tokio::spawn(
+            recheck_docs(
+                ctx.api.clone(),
+                ctx.queues.clone(),
+                krate.id,
+                sent,
+                cfg.broadcast_delay,
+            )
+            .in_current_span(),
+        );
+    }
+}
+
+/// Placeholder for links in notification templates
+const LINKS: &str = "\u{0}links\u{0}";
+
+/// Links to crates.io & co (or a download link for other registries).
+async fn links_html(
+    ctx: Ctx<'_>,
+    registry: &RegistryConfig,
+    krate: &Crate,
+    links: &[Link],
+) -> String {
+    if !registry.is_default() {
+        // crates.io & co don't know about other registries, but the crate can
+        // still be downloaded from the registry itself
+        return registry
+            .download_url(&krate.id.name, &krate.id.vers, &krate.cksum)
+            .map(|url| format!("<a href='{}'>[download]</a>", escape_html(&url)))
+            .unwrap_or_default();
+    }
+
+    let repository = if links.contains(&Link::Repo) {
+        ctx.api.repository(&krate.id.name).await
+    } else {
+        None
+    };
+    krate.html_links_with(links, repository.as_deref())
+}
+
+/// Link settings of the users (only those who changed them with `/links`).
+#[cfg(feature = "subscriptions")]
+async fn link_settings(
+    ctx: Ctx<'_>,
+    registry: &RegistryConfig,
+    users: &[ChatId],
+) -> HashMap<ChatId, Vec<Link>> {
+    // other registries don't have links anyway
+    if !registry.is_default() || users.is_empty() {
+        return HashMap::new();
+    }
+
+    ctx.db
+        .list_links(users)
         .await
-        .map_err(|err| log::error!("db error while getting subscribers: {}", err))
+        .map_err(|err| log::error!("db error while getting link settings: {}", err))
+        .unwrap_or_default()
+}
+
+/// Message `template` with the given `links`, `variants` caches already built
+/// messages.
+#[cfg(feature = "subscriptions")]
+async fn with_links(
+    ctx: Ctx<'_>,
+    registry: &RegistryConfig,
+    krate: &Crate,
+    template: &str,
+    links: &[Link],
+    variants: &mut Variants,
+) -> String {
+    variants
+        .get(template, links, || links_html(ctx, registry, krate, links))
+        .await
+}
+
+/// Messages with per-chat links, built from a notification template once per
+/// distinct link settings (not per recipient).
+#[cfg(feature = "subscriptions")]
+#[derive(Default)]
+struct Variants(HashMap<Vec<Link>, String>);
+
+#[cfg(feature = "subscriptions")]
+impl Variants {
+    /// `template` with the `links`, `html` builds them unless the message was
+    /// already built.
+    async fn get<F, Fut>(&mut self, template: &str, links: &[Link], html: F) -> String
+    where
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = String>,
+    {
+        if let Some(text) = self.0.get(links) {
+            return text.clone();
+        }
+
+        let text = template.replace(LINKS, &html().await);
+        self.0.insert(links.to_vec(), text.clone());
+        text
+    }
+}
+
+/// Maximum length (in characters) of release notes excerpts in notifications
+#[cfg(feature = "subscriptions")]
+const RELEASE_NOTES_LEN: usize = 500;
+
+/// Returns `"\n\n📝 <excerpt of the release notes>"` if release notes of the
+/// version were found (see [`CratesIo::release_notes`]).
+///
+/// [`CratesIo::release_notes`]: api::CratesIo::release_notes
+#[cfg(feature = "subscriptions")]
+async fn release_notes(ctx: Ctx<'_>, krate: &Crate) -> Option<String> {
+    let notes = ctx
+        .api
+        .release_notes(&krate.id.name, &krate.id.vers)
+        .await?;
+    let notes = notes.trim();
+    let mut excerpt = String::new();
+    // cut at a line boundary if possible, so the excerpt doesn't end mid-word
+    for line in notes.lines() {
+        if excerpt.chars().count() + line.chars().count() > RELEASE_NOTES_LEN {
+            break;
+        }
+        excerpt.push_str(line);
+        excerpt.push('\n');
+    }
+    if excerpt.is_empty() {
+        excerpt = notes.chars().take(RELEASE_NOTES_LEN).collect();
+    }
+    let cut = if excerpt.trim_end().len() < notes.len() {
+        "\n…"
+    } else {
+        ""
+    };
+    Some(format!("\n\n📝 {}{}", escape_html(excerpt.trim_end()), cut))
+}
+
+/// Append label of the subscription (if any) to the message.
+#[cfg(feature = "subscriptions")]
+fn with_label(mut text: String, label: Option<&str>) -> String {
+    if let Some(label) = label {
+        text.push_str(&format!("\n🏷 <b>#{}</b>", escape_html(label)));
+    }
+    text
+}
+
+/// Subscribers of the crate, read from the db as they are consumed.
+#[cfg(feature = "subscriptions")]
+async fn stream_subscribers(
+    db: &Database,
+    registry: &RegistryConfig,
+    krate: &str,
+) -> impl Stream<Item = Subscriber> {
+    match db.list_subscribers(&registry.name, krate).await {
+        Ok(users) => users
+            .filter_map(move |res| {
+                let res = match res {
+                    Ok(sub) => Some(sub),
+                    Err(err) => {
+                        log::error!("db error while getting subscribers: {}", err);
+                        None
+                    }
+                };
+                future::ready(res)
+            })
+            .left_stream(),
+        Err(err) => {
+            log::error!("db error while getting subscribers: {}", err);
+            stream::empty().right_stream()
+        }
+    }
+}
+
+/// Remove paused chats from `users`, except ones paused with `/pause summary`,
+/// which are also returned separately (their updates go to the catch-up
+/// summary).
+#[cfg(feature = "subscriptions")]
+async fn without_paused(
+    db: &Database,
+    mut users: Vec<Subscriber>,
+) -> (Vec<Subscriber>, Vec<ChatId>) {
+    let ids: Vec<_> = users.iter().map(|s| s.chat_id).collect();
+    let paused = db
+        .list_paused(&ids)
+        .await
+        .map_err(|err| log::error!("db error while getting paused chats: {}", err))
         .unwrap_or_default();
+    users.retain(|s| paused.get(&s.chat_id).map_or(true, |&catch_up| catch_up));
+    let catching_up = paused
+        .into_iter()
+        .filter_map(|(chat_id, catch_up)| Some(chat_id).filter(|_| catch_up))
+        .collect();
+    (users, catching_up)
+}
+
+/// Drop subscribers who don't want pre-releases (`/subscribe <crate>
+/// --no-prereleases`, or `/prereleases off` unless the subscription has
+/// `--prereleases`) if the version is one.
+#[cfg(feature = "subscriptions")]
+async fn without_prereleases(
+    db: &Database,
+    mut users: Vec<Subscriber>,
+    is_prerelease: bool,
+) -> Vec<Subscriber> {
+    if !is_prerelease {
+        return users;
+    }
+
+    let undecided: Vec<_> = users
+        .iter()
+        .filter(|s| s.prereleases.is_none())
+        .map(|s| s.chat_id)
+        .collect();
+    let skipping = if undecided.is_empty() {
+        Vec::new()
+    } else {
+        db.list_skipping_prereleases(&undecided)
+            .await
+            .map_err(|err| log::error!("db error while getting pre-release settings: {}", err))
+            .unwrap_or_default()
+    };
+    users.retain(|s| {
+        s.prereleases
+            .unwrap_or_else(|| !skipping.contains(&s.chat_id))
+    });
+    users
+}
+
+/// Level of the version compared to the previous one (see [`Level`]). The
+/// first release of a crate is major.
+#[cfg(feature = "subscriptions")]
+async fn release_level(index: &str, krate: &Crate) -> Level {
+    let prev = match krate.read_previous(index).await {
+        Ok(Some(prev)) => prev,
+        Ok(None) => return Level::Major,
+        Err(_) => return Level::Patch,
+    };
 
-    if let Some(ch) = cfg.channel {
-        notify_inner(bot, ch, &message, cfg).await;
+    match (prev.id.version(), krate.id.version()) {
+        (Ok(prev), Ok(new)) => Level::between(&prev, &new),
+        _ => Level::Patch,
     }
+}
+
+/// Time since the previous (by semver) release `prev` of the crate, e.g.
+/// ` (12 days since previous release)`. Empty if publish times are unknown.
+async fn since_previous(
+    ctx: Ctx<'_>,
+    registry: &RegistryConfig,
+    krate: &Crate,
+    prev: Option<&Crate>,
+) -> String {
+    let prev = match prev {
+        Some(prev) => prev,
+        None => return String::new(),
+    };
 
-    for chat_id in users {
-        notify_inner(bot, chat_id, &message, cfg).await;
+    let times = match (krate.pubtime, prev.pubtime) {
+        (Some(new), Some(old)) => Some((new, old)),
+        // only crates.io API knows publish times of older versions
+        _ if registry.is_default() => ctx
+            .api
+            .publish_times(&krate.id.name)
+            .await
+            .map_err(|err| log::warn!("couldn't get publish times of {}: {}", krate.id.name, err))
+            .ok()
+            .and_then(|times| {
+                let new = krate
+                    .pubtime
+                    .or_else(|| times.get(&krate.id.vers).copied())?;
+                Some((new, *times.get(&prev.id.vers)?))
+            }),
+        _ => None,
+    };
+
+    match times {
+        Some((new, old)) if new > old => format!(
+            " ({} since previous release)",
+            human_duration((new - old).to_std().unwrap_or_default())
+        ),
+        _ => String::new(),
     }
 }
 
-async fn notify_inner(bot: &Bot, chat_id: i64, msg: &str, cfg: &cfg::Config) {
-    bot.send_message(chat_id, msg)
-        .disable_web_page_preview(true)
-        .disable_notification(true)
-        .send()
+/// Whether the update is a yank (or unyank) of a version too old to be posted
+/// to the channels, see `Config::channel_yank_max_age` &
+/// `Config::channel_yank_max_behind`.
+async fn is_old_yank(
+    ctx: Ctx<'_>,
+    registry: &RegistryConfig,
+    krate: &Crate,
+    action: &ActionKind,
+) -> bool {
+    let cfg = ctx.cfg;
+    if !action.is_yank() {
+        return false;
+    }
+
+    if cfg.channel_yank_max_behind > 0 {
+        let all = Crate::read_all(&registry.index_path, &krate.id.name).await;
+        if let (Ok(all), Ok(version)) = (all, krate.id.version()) {
+            let behind = all
+                .iter()
+                .filter(|k| k.id.version().map_or(false, |v| v > version))
+                .count();
+            if behind > cfg.channel_yank_max_behind {
+                log::info!(
+                    "not posting yank of {:?}: {} releases behind",
+                    krate.id,
+                    behind
+                );
+                return true;
+            }
+        }
+    }
+
+    if cfg.channel_yank_max_age.as_secs() > 0 {
+        let published = match krate.pubtime {
+            Some(time) => Some(time),
+            // only crates.io API knows publish times of older versions
+            None if registry.is_default() => ctx
+                .api
+                .publish_times(&krate.id.name)
+                .await
+                .map_err(|err| {
+                    log::warn!("couldn't get publish times of {}: {}", krate.id.name, err)
+                })
+                .ok()
+                .and_then(|times| times.get(&krate.id.vers).copied()),
+            None => None,
+        };
+        if let Some(published) = published {
+            let age = (chrono::Utc::now() - published)
+                .to_std()
+                .unwrap_or_default();
+            if age > cfg.channel_yank_max_age {
+                log::info!(
+                    "not posting yank of {:?}: published {} ago",
+                    krate.id,
+                    human_duration(age)
+                );
+                return true;
+            }
+        }
+    }
+
+    false
+}
+
+/// Rough human readable duration, e.g. `12 days` or `3 hours`.
+fn human_duration(duration: Duration) -> String {
+    let (n, unit) = match duration.as_secs() {
+        s if s >= 60 * 60 * 24 => (s / (60 * 60 * 24), "day"),
+        s if s >= 60 * 60 => (s / (60 * 60), "hour"),
+        s => ((s / 60).max(1), "minute"),
+    };
+    match n {
+        1 => format!("1 {}", unit),
+        n => format!("{} {}s", n, unit),
+    }
+}
+
+/// Status of the docs.rs build if it's enabled in the config & known.
+async fn docs_status(ctx: Ctx<'_>, registry: &RegistryConfig, krate: &Crate) -> Option<DocsStatus> {
+    // docs.rs only builds crates.io crates
+    if !ctx.cfg.docs_status || !registry.is_default() {
+        return None;
+    }
+
+    ctx.api
+        .docs_status(&krate.id.name, &krate.id.vers)
         .await
-        .log_on_error()
-        .await;
-    tokio::time::delay_for(cfg.broadcast_delay_millis.into()).await;
+        .map_err(|err| log::warn!("couldn't get docs.rs status of {:?}: {}", krate.id, err))
+        .ok()
+}
+
+fn docs_note(status: DocsStatus) -> String {
+    format!(" (docs build: {})", status.as_str())
+}
+
+/// Message sent to a chat: buttons under it & where its id will be sent.
+#[cfg(feature = "subscriptions")]
+type Sent = (ChatId, Option<InlineKeyboardMarkup>, oneshot::Receiver<i32>);
+
+/// Re-check pending docs.rs build & edit `sent` messages once it's finished.
+/// `sent` maps texts of the messages to the chats they were sent to.
+#[cfg(feature = "subscriptions")]
+async fn recheck_docs(
+    api: CratesIo,
+    queues: Queues,
+    id: CrateId,
+    sent: HashMap<String, Vec<Sent>>,
+    delay: Duration,
+) {
+    for _ in 0..DOCS_RECHECKS {
+        tokio::time::delay_for(DOCS_RECHECK_DELAY).await;
+        match api.docs_status(&id.name, &id.vers).await {
+            Ok(DocsStatus::Pending) => {}
+            Ok(status) => {
+                for (message, sent) in sent {
+                    let text = message.replace(&docs_note(DocsStatus::Pending), &docs_note(status));
+                    for (chat_id, keyboard, message_id) in sent {
+                        // the message wasn't sent if the sender was dropped
+                        if let Ok(message_id) = message_id.await {
+                            queues
+                                .edit(chat_id, message_id, text.clone(), keyboard, delay)
+                                .await;
+                        }
+                    }
+                }
+                return;
+            }
+            Err(err) => log::warn!("couldn't get docs.rs status of {:?}: {}", id, err),
+        }
+    }
+}
+
+/// Find one-shot alerts matching the version & delete them from the db
+/// (unless it's a dry run). Returns `(user_id, requirement)` pairs.
+#[cfg(feature = "subscriptions")]
+async fn take_alerts(
+    ctx: Ctx<'_>,
+    registry: &RegistryConfig,
+    krate: &Crate,
+) -> Vec<(ChatId, String)> {
+    let version = match krate.id.version() {
+        Ok(version) => version,
+        Err(_) => return Vec::new(),
+    };
+    let alerts = ctx
+        .db
+        .list_alerts(&registry.name, &krate.id.name)
+        .await
+        .map_err(|err| log::error!("db error while getting alerts: {}", err))
+        .unwrap_or_default();
+
+    let mut fired = Vec::new();
+    for alert in alerts {
+        let matches = VersionReq::parse(&alert.req).map_or(false, |req| req.matches(&version));
+        // alerts of paused chats are kept for later
+        if !matches || ctx.db.is_paused(alert.user_id).await.unwrap_or(false) {
+            continue;
+        }
+
+        if ctx.dry_run {
+            fired.push((alert.user_id, alert.req));
+            continue;
+        }
+        match ctx.db.take_alert(alert.id).await {
+            Ok(Some(user_id)) => fired.push((user_id, alert.req)),
+            Ok(None) => { /* already fired */ }
+            Err(err) => log::error!("db error while taking alert: {}", err),
+        }
+    }
+    fired
+}
+
+/// Returns lines like `"\nMSRV raised: 1.63 → 1.70"`,
+/// `"\nfeatures: +new, -removed"` & `"\ndependencies: +added, …"` for the MSRV,
+/// features & dependencies changed since the previous version `prev`, an empty
+/// string if nothing was changed.
+fn version_changes(prev: Option<&Crate>, krate: &Crate, cfg: &cfg::Config) -> String {
+    let changes = match prev {
+        Some(prev) => prev.changes(krate),
+        None => return String::new(),
+    };
+
+    let features = Some(cfg.feature_changes_limit)
+        .filter(|&limit| limit > 0)
+        .and_then(|limit| changes.features_html(limit));
+    let deps = Some(cfg.dep_changes_limit)
+        .filter(|&limit| limit > 0)
+        .and_then(|limit| changes.deps_html(limit));
+    changes
+        .msrv_html()
+        .into_iter()
+        .chain(features)
+        .chain(deps)
+        .map(|line| format!("\n{}", line))
+        .collect()
+}
+
+/// Placeholder for the GitHub release link in notifications, see
+/// [`with_release`]
+const RELEASE: &str = "\u{0}release\u{0}";
+
+/// Replace the [`RELEASE`] placeholder in the `texts`. The release is looked up
+/// only the first time (it takes a few requests), once someone actually gets
+/// the notification.
+async fn with_release(
+    ctx: Ctx<'_>,
+    registry: &RegistryConfig,
+    krate: &Crate,
+    texts: &mut [&mut String],
+) {
+    if !texts.iter().any(|text| text.contains(RELEASE)) {
+        return;
+    }
+    let release = release(ctx, registry, krate).await;
+    for text in texts.iter_mut() {
+        **text = text.replace(RELEASE, &release);
+    }
+}
+
+/// Returns `" [release]"` link to the GitHub release of the version if it was
+/// found (and `Config::release_links` is on) and an empty string otherwise.
+async fn release(ctx: Ctx<'_>, registry: &RegistryConfig, krate: &Crate) -> String {
+    // The crates.io API only knows about crates.io crates
+    if !ctx.cfg.release_links || !registry.is_default() {
+        return String::new();
+    }
+
+    match ctx.api.github_release(&krate.id.name, &krate.id.vers).await {
+        Some(url) => format!(" <a href='{}'>[release]</a>", url),
+        None => String::new(),
+    }
+}
+
+/// Returns `" (historical)"` if `krate` isn't the newest version of the crate
+/// and an empty string otherwise.
+async fn historical(ctx: Ctx<'_>, registry: &RegistryConfig, krate: &Crate) -> &'static str {
+    let newest = match ctx.newest.get(&registry.name, &krate.id.name) {
+        Some(newest) => Some(newest),
+        None => Crate::read_newest(&registry.index_path, &krate.id.name)
+            .await
+            .ok()
+            .and_then(|newest| newest.id.version().ok()),
+    };
+    match (newest, krate.id.version()) {
+        (Some(newest), Ok(version)) if newest != version => " (historical)",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, path::PathBuf};
+
+    /// Fresh git repo in a temporary directory.
+    fn temp_repo() -> (PathBuf, Repository) {
+        let path = std::env::temp_dir().join(format!("crate_upd_bot-{}", trace_id()));
+        let repo = Repository::init(&path).unwrap();
+        (path, repo)
+    }
+
+    /// Write the `files` & commit them on top of `parent` without moving any
+    /// branch (unless `update_head`).
+    fn commit<'r>(
+        repo: &'r Repository,
+        parent: Option<&Commit<'_>>,
+        files: &[(&str, &str)],
+        update_head: bool,
+    ) -> Commit<'r> {
+        let root = repo.workdir().unwrap();
+        for (path, contents) in files {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        let mut index = repo.index().unwrap();
+        index
+            .add_all(["*"].iter(), git2::IndexAddOption::DEFAULT, None)
+            .unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = git2::Signature::now("test", "test@example.com").unwrap();
+        let parents: Vec<_> = parent.into_iter().collect();
+        let head = if update_head { Some("HEAD") } else { None };
+        let oid = repo
+            .commit(head, &sig, &sig, "update", &tree, &parents)
+            .unwrap();
+        repo.find_commit(oid).unwrap()
+    }
+
+    #[test]
+    fn config_only_commit() {
+        let (path, repo) = temp_repo();
+        let line = r#"{"name":"serde","vers":"1.0.0","deps":[],"cksum":"00","features":{},"yanked":false}"#;
+        let first = commit(
+            &repo,
+            None,
+            &[("config.json", r#"{"dl":"a"}"#), ("se/rd/serde", line)],
+            true,
+        );
+        let second = commit(
+            &repo,
+            Some(&first),
+            &[("config.json", r#"{"dl":"b"}"#)],
+            false,
+        );
+
+        let mut unexpected = Vec::new();
+        let updates = read_update(&repo, &first, &second, &mut unexpected).unwrap();
+        assert!(updates.is_empty());
+        assert!(unexpected.is_empty());
+
+        // `process_commits` marks the whole range, which fast-forwards the
+        // local index over the commit without updates
+        let branch = repo.head().unwrap().shorthand().unwrap().to_owned();
+        fast_forward(&repo, &branch, &second).unwrap();
+        assert_eq!(repo.refname_to_id("HEAD").unwrap(), second.id());
+
+        drop(repo);
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    /// Index entry of `name` `vers` with `features` (as a JSON object).
+    fn entry(name: &str, vers: &str, yanked: bool, features: &str) -> Crate {
+        let line = format!(
+            r#"{{"name":"{}","vers":"{}","deps":[],"cksum":"00","features":{},"yanked":{}}}"#,
+            name, vers, features, yanked
+        );
+        parse_index_line(line.as_bytes()).unwrap()
+    }
+
+    /// `(version, action)` of the changes
+    fn summary(changes: &[(Crate, ActionKind)]) -> Vec<(&str, &'static str)> {
+        changes
+            .iter()
+            .map(|(krate, action)| (krate.id.vers.as_str(), action.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn skips_malformed_lines() {
+        assert!(parse_index_line(b"{\"name\":\"serde\",\"vers\":\"1.0.0\xff\"}\n").is_none());
+        assert!(parse_index_line(b"{\"name\":\"serde\",\"vers\":\"1.0").is_none());
+        assert!(parse_index_line(b"\n").is_none());
+
+        let mut lines = DiffLines::default();
+        lines.push('+', b"\xff\xfe\n");
+        lines.push('+', b"{\"name\":\"serde\",\"vers\":\"1.0\n");
+        lines.push('-', b"{\"name\":");
+        lines.flush();
+        assert!(lines.added.is_empty());
+        assert!(lines.removed.is_empty());
+    }
+
+    #[test]
+    fn joins_chunks_of_a_line() {
+        let line = r#"{"name":"serde","vers":"1.0.0","deps":[],"cksum":"00","features":{},"yanked":false}"#;
+        let (head, tail) = line.split_at(20);
+        let mut lines = DiffLines::default();
+        lines.push('+', head.as_bytes());
+        lines.push('+', format!("{}\n", tail).as_bytes());
+        // a truncated line followed by a line of the other origin
+        lines.push('+', &line.as_bytes()[..30]);
+        lines.push('-', format!("{}\n", line).as_bytes());
+        lines.flush();
+        let versions: Vec<_> = lines.added.iter().map(|k| k.id.vers.as_str()).collect();
+        assert_eq!(versions, ["1.0.0"]);
+        assert_eq!(lines.removed.len(), 1);
+    }
+
+    #[test]
+    fn classify_matches_pairs_by_version() {
+        let removed = vec![
+            entry("serde", "1.0.0", false, "{}"),
+            entry("serde", "1.1.0", false, "{}"),
+            entry("serde", "1.2.0", true, "{}"),
+        ];
+        // rewritten in another order
+        let added = vec![
+            entry("serde", "1.2.0", false, "{}"),
+            entry("serde", "1.0.0", false, "{}"),
+            entry("serde", "1.1.0", true, "{}"),
+        ];
+        let mut unexpected = Vec::new();
+        let changes = classify(removed, added, &mut unexpected);
+        assert_eq!(
+            summary(&changes),
+            [("1.2.0", "unyanked"), ("1.1.0", "yanked")]
+        );
+        assert!(unexpected.is_empty());
+    }
+
+    #[test]
+    fn classify_ignores_features_rewrite() {
+        let removed = vec![
+            entry("serde", "1.0.0", false, "{}"),
+            entry("serde", "1.1.0", false, "{}"),
+        ];
+        let added = vec![
+            entry("serde", "1.0.0", false, r#"{"std":[]}"#),
+            entry("serde", "1.1.0", false, r#"{"std":[]}"#),
+        ];
+        let mut unexpected = Vec::new();
+        assert!(classify(removed, added, &mut unexpected).is_empty());
+        assert!(unexpected.is_empty());
+    }
+
+    #[test]
+    fn classify_yank_with_new_version() {
+        let removed = vec![entry("serde", "1.0.0", false, "{}")];
+        let added = vec![
+            entry("serde", "1.0.0", true, "{}"),
+            entry("serde", "1.0.1", false, "{}"),
+        ];
+        let mut unexpected = Vec::new();
+        let changes = classify(removed, added, &mut unexpected);
+        assert_eq!(
+            summary(&changes),
+            [("1.0.0", "yanked"), ("1.0.1", "new_version")]
+        );
+        assert!(unexpected.is_empty());
+    }
+
+    #[cfg(feature = "subscriptions")]
+    #[tokio::test]
+    async fn links_are_built_per_settings() {
+        let template = format!("Crate was updated {}", LINKS);
+        let settings = [
+            vec![Link::DocsRs, Link::CratesIo],
+            vec![Link::Repo],
+            Vec::new(),
+        ];
+        let built = std::cell::Cell::new(0);
+        let mut variants = Variants::default();
+        for i in 0..1000 {
+            let links = &settings[i % settings.len()];
+            let text = variants
+                .get(&template, links, || async {
+                    built.set(built.get() + 1);
+                    format!("{:?}", links)
+                })
+                .await;
+            assert_eq!(text, format!("Crate was updated {:?}", links));
+        }
+        // a thousand recipients, but the links are built once per settings
+        assert_eq!(built.get(), settings.len());
+    }
 }
@@ -0,0 +1,81 @@
+//! Dependencies of `Cargo.toml` & `Cargo.lock` files, for `/import`.
+use toml::Value;
+
+/// Dependency tables of `Cargo.toml` (also used under `[target.'cfg(..)']`)
+const DEPENDENCY_TABLES: [&str; 3] = ["dependencies", "dev-dependencies", "build-dependencies"];
+
+/// `source`s of crates.io packages in `Cargo.lock`
+const CRATES_IO_SOURCES: [&str; 2] = [
+    "registry+https://github.com/rust-lang/crates.io-index",
+    "sparse+https://index.crates.io/",
+];
+
+/// Names of the crates.io dependencies listed in a `Cargo.toml` or (all
+/// packages of) a `Cargo.lock`, sorted & deduplicated.
+pub fn dependencies(text: &str) -> Result<Vec<String>, toml::de::Error> {
+    let manifest: toml::value::Table = toml::from_str(text)?;
+    let mut names = Vec::new();
+    match manifest.get("package") {
+        // `Cargo.lock` has an array of `[[package]]`s, `Cargo.toml` has a
+        // single `[package]` table
+        Some(Value::Array(packages)) => {
+            let from_crates_io = |package: &&Value| {
+                let source = package.get("source").and_then(Value::as_str);
+                source.map_or(false, |source| CRATES_IO_SOURCES.contains(&source))
+            };
+            names.extend(
+                packages
+                    .iter()
+                    .filter(from_crates_io)
+                    .filter_map(|package| package.get("name")?.as_str())
+                    .map(str::to_owned),
+            );
+        }
+        _ => {
+            let mut tables: Vec<_> = DEPENDENCY_TABLES
+                .iter()
+                .filter_map(|table| manifest.get(*table))
+                .collect();
+            if let Some(Value::Table(targets)) = manifest.get("target") {
+                for target in targets.values() {
+                    tables.extend(DEPENDENCY_TABLES.iter().filter_map(|t| target.get(*t)));
+                }
+            }
+            if let Some(workspace) = manifest.get("workspace") {
+                tables.extend(workspace.get("dependencies"));
+            }
+
+            for table in tables.iter().filter_map(|table| table.as_table()) {
+                names.extend(
+                    table
+                        .iter()
+                        .filter_map(|(key, dep)| dependency_name(key, dep))
+                        .map(str::to_owned),
+                );
+            }
+        }
+    }
+
+    names.sort_unstable();
+    names.dedup();
+    Ok(names)
+}
+
+/// Name of the crate of `key = dep` dependency (it may be renamed with
+/// `package = ".."`). `None` for path, git & alternative registry
+/// dependencies.
+fn dependency_name<'a>(key: &'a str, dep: &'a Value) -> Option<&'a str> {
+    match dep {
+        Value::String(_) => Some(key),
+        Value::Table(dep) => {
+            if ["path", "git", "registry"]
+                .iter()
+                .any(|k| dep.contains_key(*k))
+            {
+                return None;
+            }
+            Some(dep.get("package").and_then(Value::as_str).unwrap_or(key))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,79 @@
+//! Newest versions of the crates with subscribers, so notifications about
+//! popular crates don't re-read their index files every time.
+use semver::Version;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Clone, Default)]
+pub struct NewestVersions {
+    /// (registry, crate name) -> the highest (by semver) version
+    versions: Arc<Mutex<HashMap<(String, String), Version>>>,
+}
+
+impl NewestVersions {
+    pub fn get(&self, registry: &str, krate: &str) -> Option<Version> {
+        let key = (registry.to_owned(), krate.to_owned());
+        self.versions.lock().unwrap().get(&key).cloned()
+    }
+
+    /// Cache the version, unless a higher one is already cached.
+    #[cfg(feature = "subscriptions")]
+    pub fn insert(&self, registry: &str, krate: &str, version: Version) {
+        let key = (registry.to_owned(), krate.to_owned());
+        let mut versions = self.versions.lock().unwrap();
+        match versions.get_mut(&key) {
+            Some(newest) if *newest >= version => {}
+            Some(newest) => *newest = version,
+            None => {
+                versions.insert(key, version);
+            }
+        }
+    }
+
+    /// Record a newly published version. Only crates that are already cached
+    /// are updated, others are read from the index when needed.
+    pub fn update(&self, registry: &str, krate: &str, version: Version) {
+        let key = (registry.to_owned(), krate.to_owned());
+        if let Some(newest) = self.versions.lock().unwrap().get_mut(&key) {
+            if version > *newest {
+                *newest = version;
+            }
+        }
+    }
+}
+
+/// Fill the cache with the crates that have subscribers.
+#[cfg(feature = "subscriptions")]
+pub async fn warmup(
+    db: crate::db::Database,
+    config: crate::cfg::SharedConfig,
+    cache: NewestVersions,
+) {
+    use crate::krate::Crate;
+
+    let crates = match db.list_subscribed_crates().await {
+        Ok(crates) => crates,
+        Err(err) => {
+            log::error!("db error while warming up newest versions: {}", err);
+            return;
+        }
+    };
+
+    let config = config.get();
+    let mut count = 0;
+    for (registry, krate, _) in crates {
+        let index = match config.registry(&registry) {
+            Some(registry) => &registry.index_path,
+            None => continue,
+        };
+        if let Ok(newest) = Crate::read_newest(index, &krate).await {
+            if let Ok(version) = newest.id.version() {
+                cache.insert(&registry, &krate, version);
+                count += 1;
+            }
+        }
+    }
+    log::info!("cached newest versions of {} crates", count);
+}
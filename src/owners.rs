@@ -0,0 +1,74 @@
+//! Subscriptions to crates.io users (`/subscribe_owner`).
+//!
+//! Like keywords (see [`crate::keywords`]), owners aren't in the index, so the
+//! bot keeps an `owner -> crates` mapping (only for owners someone is
+//! subscribed to) in the database, refreshed periodically from the crates.io
+//! API.
+use crate::{api::CratesIo, cfg::SharedConfig, db::Database};
+
+/// Periodically refresh crates of all subscribed owners.
+pub async fn refresh_loop(api: CratesIo, db: Database, config: SharedConfig) {
+    loop {
+        match db.list_subscribed_owners().await {
+            Ok(owners) => {
+                for owner in owners {
+                    refresh(&api, &db, &owner).await;
+                }
+            }
+            Err(err) => log::error!("db error while listing owners: {}", err),
+        }
+
+        tokio::time::delay_for(config.get().owner_refresh_delay).await;
+    }
+}
+
+/// Refresh crates of a single owner.
+pub async fn refresh(api: &CratesIo, db: &Database, owner: &str) {
+    match api.owner_crates(owner).await {
+        Ok(Some(crates)) => {
+            if let Err(err) = db.set_owner_crates(owner, &crates).await {
+                log::error!("db error while setting crates of owner {}: {}", owner, err);
+            }
+        }
+        // the user was deleted (or renamed), the known crates are kept
+        Ok(None) => log::warn!("crates.io user {} doesn't exist anymore", owner),
+        Err(err) => log::error!("couldn't get crates of owner {}: {}", owner, err),
+    }
+
+    // Don't break the crates.io crawler policy (1 request per second)
+    tokio::time::delay_for(std::time::Duration::from_secs(1)).await;
+}
+
+/// Re-check owners of a (newly published) crate, so new crates are picked up
+/// without waiting for the next refresh. Called by
+/// [`crate::keywords::recheck_loop`], off the pull loop.
+pub async fn recheck(api: &CratesIo, db: &Database, krate: &str) {
+    let subscribed = match db.list_subscribed_owners().await {
+        Ok(owners) if owners.is_empty() => return,
+        Ok(owners) => owners,
+        Err(err) => {
+            log::error!("db error while listing owners: {}", err);
+            return;
+        }
+    };
+
+    let owners = api.crate_owners(krate).await;
+    // Don't break the crates.io crawler policy (1 request per second)
+    tokio::time::delay_for(std::time::Duration::from_secs(1)).await;
+    let owners = match owners {
+        Ok(owners) => owners,
+        Err(err) => {
+            log::warn!("couldn't get owners of crate {}: {}", krate, err);
+            return;
+        }
+    };
+
+    for owner in owners.iter().map(|o| o.to_lowercase()) {
+        if !subscribed.contains(&owner) {
+            continue;
+        }
+        if let Err(err) = db.add_owner_crate(&owner, krate).await {
+            log::error!("db error while adding crate to owner {}: {}", owner, err);
+        }
+    }
+}
@@ -0,0 +1,410 @@
+//! Per-chat send queues.
+//!
+//! Every chat gets its own queue (and a task draining it), so a slow or dead
+//! chat only delays messages to itself and not to everyone else. Messages to
+//! different chats are sent concurrently (up to `Config::send_concurrency` at
+//! once), while messages to the same chat are sent one by one, in the order
+//! they were enqueued.
+use crate::{chat::ChatId, db::Database};
+use rand::Rng;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use teloxide::{
+    prelude::Request,
+    types::{ChatOrInlineMessage, InlineKeyboardMarkup},
+    ApiErrorKind, Bot, KnownApiErrorKind, RequestError,
+};
+use tokio::sync::{mpsc, oneshot, Semaphore};
+use tracing::Instrument;
+
+/// Maximum number of messages waiting in all queues. Enqueueing more waits
+/// until some are sent, so fan-out to a huge number of subscribers doesn't
+/// keep all the messages in memory at once.
+const MAX_QUEUED: usize = 10_000;
+
+/// Delays between messages are randomly changed by up to this fraction, so
+/// sends don't bunch up
+const JITTER: f64 = 0.2;
+
+/// Extra delay after the first "too many requests" error, doubled on every
+/// following one
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Maximum extra delay added because of "too many requests" errors
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Queue task exits after being idle for this long
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Interval of checking whether the queues are empty, see [`Queues::drain`]
+const DRAIN_POLL: Duration = Duration::from_millis(100);
+
+struct Job {
+    text: String,
+    silent: bool,
+    /// Event to record the delivery for
+    event: Option<i64>,
+    /// Buttons under the message
+    keyboard: Option<InlineKeyboardMarkup>,
+    /// Delay after sending the message
+    delay: Duration,
+    /// Edit this message instead of sending a new one
+    edit: Option<i32>,
+    /// Where to send the id of the sent message
+    sent: Option<oneshot::Sender<i32>>,
+    /// Span the job was enqueued in, so logs of the send are written in the
+    /// context of the update (& its trace id)
+    span: tracing::Span,
+    /// Keeps the batch the job was enqueued in open until the job is processed
+    /// (& dropped), see [`Queues::start_batch`]
+    batch: Option<mpsc::Sender<()>>,
+}
+
+/// Messages enqueued since [`Queues::start_batch`], see [`Queues::wait`]
+pub struct Batch {
+    /// Closed once senders of all jobs of the batch are dropped
+    rx: mpsc::Receiver<()>,
+}
+
+#[derive(Clone)]
+pub struct Queues {
+    bot: Bot,
+    db: Database,
+    queues: Arc<Mutex<HashMap<ChatId, mpsc::UnboundedSender<Job>>>>,
+    sends: Arc<Semaphore>,
+    /// Free slots in the queues, see [`MAX_QUEUED`]
+    ///
+    /// [`MAX_QUEUED`]: MAX_QUEUED
+    queued: Arc<Semaphore>,
+    /// Extra delay between messages, grows after "too many requests" errors &
+    /// slowly shrinks after successful sends
+    backoff: Arc<Mutex<Duration>>,
+    /// Batch new jobs are added to
+    batch: Arc<Mutex<Option<mpsc::Sender<()>>>>,
+}
+
+impl Queues {
+    /// `concurrency` is the maximum number of messages being sent at the same
+    /// time (across all chats).
+    pub fn new(bot: Bot, db: Database, concurrency: usize) -> Self {
+        Self {
+            bot,
+            db,
+            queues: Arc::default(),
+            sends: Arc::new(Semaphore::new(concurrency)),
+            queued: Arc::new(Semaphore::new(MAX_QUEUED)),
+            backoff: Arc::default(),
+            batch: Arc::default(),
+        }
+    }
+
+    /// Enqueue message to the chat. `delay` is the pause after sending the
+    /// message, before sending the next one to the same chat.
+    ///
+    /// Waits if the queues are full.
+    pub async fn send(
+        &self,
+        chat_id: ChatId,
+        text: String,
+        silent: bool,
+        event: Option<i64>,
+        keyboard: Option<InlineKeyboardMarkup>,
+        delay: Duration,
+    ) {
+        let job = Job {
+            text,
+            silent,
+            event,
+            keyboard,
+            delay,
+            edit: None,
+            sent: None,
+            span: tracing::Span::current(),
+            batch: None,
+        };
+        self.push(chat_id, job).await;
+    }
+
+    /// Same as [`send`], but also returns id of the sent message (the sender
+    /// is dropped if the message wasn't sent).
+    ///
+    /// [`send`]: Queues::send
+    pub async fn send_tracked(
+        &self,
+        chat_id: ChatId,
+        text: String,
+        silent: bool,
+        event: Option<i64>,
+        keyboard: Option<InlineKeyboardMarkup>,
+        delay: Duration,
+    ) -> oneshot::Receiver<i32> {
+        let (tx, rx) = oneshot::channel();
+        let job = Job {
+            text,
+            silent,
+            event,
+            keyboard,
+            delay,
+            edit: None,
+            sent: Some(tx),
+            span: tracing::Span::current(),
+            batch: None,
+        };
+        self.push(chat_id, job).await;
+        rx
+    }
+
+    /// Enqueue edit of a message sent earlier. `keyboard` must be the same as
+    /// in the original message, otherwise it's removed.
+    pub async fn edit(
+        &self,
+        chat_id: ChatId,
+        message_id: i32,
+        text: String,
+        keyboard: Option<InlineKeyboardMarkup>,
+        delay: Duration,
+    ) {
+        let job = Job {
+            text,
+            silent: true,
+            event: None,
+            keyboard,
+            delay,
+            edit: Some(message_id),
+            sent: None,
+            span: tracing::Span::current(),
+            batch: None,
+        };
+        self.push(chat_id, job).await;
+    }
+
+    /// Wait until all the enqueued messages are processed (sent or given up
+    /// on).
+    pub async fn drain(&self) {
+        while self.queued.available_permits() < MAX_QUEUED {
+            tokio::time::delay_for(DRAIN_POLL).await;
+        }
+    }
+
+    /// Start tracking messages enqueued from now on (by anyone), to wait for
+    /// them with [`Queues::wait`]. Replaces the current batch.
+    pub fn start_batch(&self) -> Batch {
+        let (tx, rx) = mpsc::channel(1);
+        *self.batch.lock().unwrap() = Some(tx);
+        Batch { rx }
+    }
+
+    /// Stop adding messages to the batch & wait until all of its messages are
+    /// processed (sent or given up on). Unlike [`Queues::drain`], messages
+    /// enqueued later aren't waited for.
+    pub async fn wait(&self, mut batch: Batch) {
+        self.batch.lock().unwrap().take();
+        // nothing is ever sent, `None` means all the jobs were dropped
+        while batch.rx.recv().await.is_some() {}
+    }
+
+    async fn push(&self, chat_id: ChatId, mut job: Job) {
+        // The slot is returned once the job is processed (see `run`)
+        self.queued.acquire().await.forget();
+        job.batch = self.batch.lock().unwrap().clone();
+
+        let mut queues = self.queues.lock().unwrap();
+        let queue = queues.entry(chat_id).or_insert_with(|| {
+            let (tx, rx) = mpsc::unbounded_channel();
+            tokio::spawn(self.clone().run(chat_id, rx));
+            tx
+        });
+        // The task removes its queue from the map (under the lock) before
+        // exiting, so it's always alive here
+        if queue.send(job).is_err() {
+            log::error!("send queue of {} is closed", chat_id);
+            self.queued.add_permits(1);
+        }
+    }
+
+    async fn run(self, chat_id: ChatId, mut rx: mpsc::UnboundedReceiver<Job>) {
+        // the chat blocked the bot or is otherwise unreachable
+        let mut blocked = false;
+        loop {
+            let job = match tokio::time::timeout(IDLE_TIMEOUT, rx.recv()).await {
+                Ok(Some(job)) => job,
+                Ok(None) => return,
+                Err(_) => {
+                    // `push` sends under the same lock, so the queue is only
+                    // removed if it's empty (otherwise a second task could
+                    // send to the chat concurrently, breaking the order)
+                    let mut queues = self.queues.lock().unwrap();
+                    match rx.try_recv() {
+                        Ok(job) => job,
+                        Err(_) => {
+                            queues.remove(&chat_id);
+                            return;
+                        }
+                    }
+                }
+            };
+            let span = job.span.clone();
+            self.process(chat_id, job, &mut blocked)
+                .instrument(span)
+                .await;
+            self.queued.add_permits(1);
+        }
+    }
+
+    async fn process(&self, chat_id: ChatId, job: Job, blocked: &mut bool) {
+        if *blocked {
+            if let Some(event) = job.event {
+                self.db
+                    .record_delivery(event, chat_id, "chat is unreachable")
+                    .await
+                    .map_err(|err| log::error!("db error while recording delivery: {}", err))
+                    .ok();
+            }
+            return;
+        }
+
+        let res = {
+            let _permit = self.sends.acquire().await;
+            let keyboard = job.keyboard;
+            let res = match job.edit {
+                Some(message_id) => {
+                    edit_inner(&self.bot, chat_id, message_id, &job.text, keyboard).await
+                }
+                None => notify_inner(&self.bot, chat_id, &job.text, job.silent, keyboard).await,
+            };
+            let delay = self.adapt(&res, job.delay);
+            tokio::time::delay_for(delay).await;
+            res
+        };
+        // per-chat limit, waited out without holding the send slot
+        if let Some(rest) = chat_id.kind().min_delay().checked_sub(job.delay) {
+            tokio::time::delay_for(rest).await;
+        }
+        if let (Ok(message_id), Some(sent)) = (&res, job.sent) {
+            let _ = sent.send(*message_id);
+        }
+        let res = res.map(drop);
+        record_delivery(&self.db, job.event, chat_id, &res).await;
+        if let Err(err) = &res {
+            if is_permanent(err) {
+                *blocked = true;
+                log::info!(
+                    "{} is unreachable ({}), removing its subscriptions",
+                    chat_id,
+                    err
+                );
+                self.db
+                    .unsubscribe_all(chat_id)
+                    .await
+                    .map_err(|err| log::error!("db error while unsubscribing {}: {}", chat_id, err))
+                    .ok();
+            }
+        }
+    }
+
+    /// Update the backoff after a send & return jittered delay to wait.
+    fn adapt<T>(&self, res: &Result<T, RequestError>, delay: Duration) -> Duration {
+        let mut backoff = self.backoff.lock().unwrap();
+        *backoff = match res {
+            Err(RequestError::RetryAfter(_)) => (*backoff * 2).max(MIN_BACKOFF).min(MAX_BACKOFF),
+            // relax slowly, so the delay settles near the real limit
+            _ => *backoff * 15 / 16,
+        };
+
+        let jitter = rand::thread_rng().gen_range(1.0 - JITTER, 1.0 + JITTER);
+        delay.mul_f64(jitter) + *backoff
+    }
+}
+
+/// Errors after which the chat won't ever receive messages from the bot (well,
+/// unless the user unblocks the bot or adds it back to the chat & subscribes
+/// again). Transient errors (network, rate limits, etc) are not included.
+pub fn is_permanent(err: &RequestError) -> bool {
+    use KnownApiErrorKind::*;
+
+    match err {
+        RequestError::ApiError {
+            kind: ApiErrorKind::Known(kind),
+            ..
+        } => matches!(
+            kind,
+            BotBlocked | BotKicked | BotKickedFromSupergroup | ChatNotFound | UserDeactivated
+        ),
+        _ => false,
+    }
+}
+
+/// Record delivery status of the event (if any) to the chat.
+pub async fn record_delivery(
+    db: &Database,
+    event: Option<i64>,
+    chat_id: ChatId,
+    res: &Result<(), RequestError>,
+) {
+    if let Some(event) = event {
+        let status = match res {
+            Ok(()) => String::from("ok"),
+            Err(err) => err.to_string(),
+        };
+        db.record_delivery(event, chat_id, &status)
+            .await
+            .map_err(|err| log::error!("db error while recording delivery: {}", err))
+            .ok();
+    }
+}
+
+/// Returns id of the sent message.
+#[tracing::instrument(skip(bot, msg, keyboard))]
+async fn notify_inner(
+    bot: &Bot,
+    chat_id: ChatId,
+    msg: &str,
+    silent: bool,
+    keyboard: Option<InlineKeyboardMarkup>,
+) -> Result<i32, RequestError> {
+    let mut request = bot
+        .send_message(chat_id, msg)
+        .disable_web_page_preview(true)
+        .disable_notification(silent);
+    if let Some(keyboard) = keyboard {
+        request = request.reply_markup(keyboard);
+    }
+    let res = request.send().await.map(|message| message.id);
+    if let Err(err) = &res {
+        log::error!("couldn't send message to {}: {}", chat_id, err);
+    }
+    res
+}
+
+#[tracing::instrument(skip(bot, msg, keyboard))]
+async fn edit_inner(
+    bot: &Bot,
+    chat_id: ChatId,
+    message_id: i32,
+    msg: &str,
+    keyboard: Option<InlineKeyboardMarkup>,
+) -> Result<i32, RequestError> {
+    let message = ChatOrInlineMessage::Chat {
+        chat_id: chat_id.into(),
+        message_id,
+    };
+    let mut request = bot
+        .edit_message_text(message, msg)
+        .disable_web_page_preview(true);
+    if let Some(keyboard) = keyboard {
+        request = request.reply_markup(keyboard);
+    }
+    let res = request.send().await.map(|_| message_id);
+    if let Err(err) = &res {
+        log::error!(
+            "couldn't edit message {} in {}: {}",
+            message_id,
+            chat_id,
+            err
+        );
+    }
+    res
+}
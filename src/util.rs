@@ -1,7 +1,8 @@
-use std::{
-    future::Future,
-    path::{Path, PathBuf},
-};
+use crate::cfg::RegistryConfig;
+#[cfg(feature = "subscriptions")]
+use std::future::Future;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "subscriptions")]
 use tokio::time::{delay_for, Duration};
 
 /// Path to crate file in crates.io-index. Implementation is stolen from
@@ -16,6 +17,107 @@ pub fn crate_path(name: &str) -> PathBuf {
     }
 }
 
+/// Check that `path` (relative to the index root) is a path of a crate file,
+/// and not e.g. `config.json`.
+pub fn is_crate_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .filter(|name| name.is_ascii() && !name.contains('.'))
+        .map_or(false, |name| crate_path(name) == path)
+}
+
+/// Check that `name` is a valid crate name (ascii alphanumeric characters, `-`
+/// and `_`, at most 64 characters).
+#[cfg(feature = "subscriptions")]
+pub fn is_valid_crate_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Extract crate name from a crates.io, docs.rs or lib.rs url, e.g.
+/// `https://crates.io/crates/tokio` -> `tokio`. Returns `None` if `url` isn't
+/// a link to a crate.
+#[cfg(feature = "subscriptions")]
+pub fn crate_from_url(url: &str) -> Option<&str> {
+    let url = url
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .trim_start_matches("www.");
+    let mut parts = url.split(|c| c == '/' || c == '?' || c == '#');
+    let name = match parts.next()? {
+        "crates.io" | "lib.rs" => match parts.next()? {
+            "crates" => parts.next()?,
+            _ => return None,
+        },
+        // `docs.rs/tokio/1.0.0/tokio/` or `docs.rs/crate/tokio/1.0.0`
+        "docs.rs" => match parts.next()? {
+            "crate" => parts.next()?,
+            name => name,
+        },
+        _ => return None,
+    };
+    Some(name).filter(|name| is_valid_crate_name(name))
+}
+
+/// `name` for crates from the default registry and `name@registry` otherwise.
+pub fn qualified_name(name: &str, registry: &RegistryConfig) -> String {
+    if registry.is_default() {
+        name.to_owned()
+    } else {
+        format!("{}@{}", name, registry.name)
+    }
+}
+
+/// Escape `<`, `>` and `&` for use in telegram HTML messages.
+pub fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Check that `keyword` is a valid (lowercase) crates.io keyword. Rules are the
+/// same as in https://github.com/rust-lang/crates.io/blob/master/src/models/keyword.rs
+#[cfg(feature = "subscriptions")]
+pub fn is_valid_keyword(keyword: &str) -> bool {
+    let mut chars = keyword.chars();
+    let first = match chars.next() {
+        Some(c) => c,
+        None => return false,
+    };
+
+    keyword.len() <= 20
+        && first.is_ascii_alphanumeric()
+        && chars.all(|c| {
+            c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-' || c == '+'
+        })
+}
+
+/// Check that `category` looks like a crates.io category slug, e.g.
+/// `network-programming` or `development-tools::cargo-plugins`.
+#[cfg(feature = "subscriptions")]
+pub fn is_valid_category(category: &str) -> bool {
+    !category.is_empty()
+        && category.len() <= 64
+        && category
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == ':')
+}
+
+/// Check that `login` looks like a GitHub login (crates.io users are GitHub
+/// users): up to 39 alphanumeric characters or hyphens, not starting with a
+/// hyphen.
+#[cfg(feature = "subscriptions")]
+pub fn is_valid_login(login: &str) -> bool {
+    !login.is_empty()
+        && login.len() <= 39
+        && !login.starts_with('-')
+        && login.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+#[cfg(feature = "subscriptions")]
 macro_rules! tryok {
     ($e:expr) => {
         match $e {
@@ -25,7 +127,10 @@ macro_rules! tryok {
     };
 }
 
-pub async fn tryn<F, Fut, T, E>(n: usize, del: Duration, mut f: F) -> Result<T, E>
+/// Call `f` until it succeeds, at most `n + 1` times. The delay between calls
+/// starts at `del` and is doubled after every failed retry.
+#[cfg(feature = "subscriptions")]
+pub async fn tryn<F, Fut, T, E>(n: usize, mut del: Duration, mut f: F) -> Result<T, E>
 where
     F: FnMut() -> Fut,
     Fut: Future<Output = Result<T, E>>,
@@ -33,6 +138,7 @@ where
     let mut err = tryok!(f().await);
     for _ in 0..n {
         delay_for(del).await;
+        del *= 2;
         err = tryok!(f().await);
     }
     Err(err)
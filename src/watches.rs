@@ -0,0 +1,142 @@
+//! Alerts about brand-new crates (`/watch_new`).
+//!
+//! Unlike subscriptions, watches are about crates that don't exist yet: when
+//! the first version of a crates.io crate is published, its name and keywords
+//! are matched against the watched patterns (e.g. `*serde*` to catch typosquats
+//! of `serde`). There are few watches, so they are all checked for every new
+//! crate.
+use crate::{
+    api::CratesIo, cfg::Config, db::Database, krate::CrateId, queue::Queues, util::escape_html,
+};
+
+/// Minimum number of non-wildcard characters of a pattern, so a pattern can't
+/// match most of the new crates
+pub const MIN_LITERAL_CHARS: usize = 3;
+
+/// Normalize a pattern, returns `None` if it isn't valid: only characters of
+/// crate names & keywords and the `*` (any sequence) and `?` (any character)
+/// wildcards. Case, `-` and `_` don't matter.
+pub fn parse_pattern(pattern: &str) -> Option<String> {
+    let pattern = normalize(pattern.trim());
+    let valid = pattern.len() <= 64
+        && pattern
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '+' | '*' | '?'))
+        && pattern.chars().filter(|c| !matches!(c, '*' | '?')).count() >= MIN_LITERAL_CHARS;
+    Some(pattern).filter(|_| valid)
+}
+
+/// Check the first version of a new crate against all watches & notify users
+/// of the matching ones (except paused chats).
+pub async fn check(api: &CratesIo, db: &Database, queues: &Queues, cfg: &Config, krate: &CrateId) {
+    let watches = match db.list_all_watches().await {
+        Ok(watches) if watches.is_empty() => return,
+        Ok(watches) => watches,
+        Err(err) => {
+            log::error!("db error while listing watches: {}", err);
+            return;
+        }
+    };
+
+    let name = &krate.name;
+    // keywords aren't in the index, the name is still checked if the api
+    // request fails
+    let info = api.crate_info(name).await;
+    // Don't break the crates.io crawler policy (1 request per second)
+    tokio::time::delay_for(std::time::Duration::from_secs(1)).await;
+    let info = info
+        .map_err(|err| log::warn!("couldn't get info of crate {}: {}", name, err))
+        .ok();
+    let keywords: Vec<_> = info
+        .iter()
+        .flat_map(|info| &info.keywords)
+        .map(|k| normalize(k))
+        .collect();
+    let normalized = normalize(name);
+    let matches = |pattern: &str| {
+        glob(pattern.as_bytes(), normalized.as_bytes())
+            || keywords
+                .iter()
+                .any(|k| glob(pattern.as_bytes(), k.as_bytes()))
+    };
+
+    // user -> matched patterns
+    let mut matched: Vec<(_, Vec<&str>)> = Vec::new();
+    for (user, pattern) in &watches {
+        if !matches(pattern) {
+            continue;
+        }
+        match matched.iter_mut().find(|(u, _)| u == user) {
+            Some((_, patterns)) => patterns.push(pattern.as_str()),
+            None => matched.push((*user, vec![pattern.as_str()])),
+        }
+    }
+    if matched.is_empty() {
+        return;
+    }
+    log::info!("new crate {} matches {} watches", name, matched.len());
+
+    let users: Vec<_> = matched.iter().map(|(user, _)| *user).collect();
+    let paused = db
+        .list_paused(&users)
+        .await
+        .map_err(|err| log::error!("db error while getting paused chats: {}", err))
+        .unwrap_or_default();
+    let description = info
+        .as_ref()
+        .and_then(|info| info.description.as_deref())
+        .map(|d| format!("\n{}", escape_html(d.trim())))
+        .unwrap_or_default();
+    for (user, patterns) in matched {
+        if paused.contains_key(&user) {
+            continue;
+        }
+        let text = format!(
+            "🆕 New crate <a href='https://crates.io/crates/{name}'>{name}</a> \
+             <code>{vers}</code> matches <code>{patterns}</code>{description}",
+            name = name,
+            vers = krate.vers,
+            patterns = patterns.join("</code>, <code>"),
+            description = description,
+        );
+        queues
+            .send(user, text, false, None, None, cfg.broadcast_delay)
+            .await;
+    }
+}
+
+/// Crate names are compared case-insensitively & with `-` and `_` being the
+/// same.
+fn normalize(s: &str) -> String {
+    s.to_lowercase().replace('_', "-")
+}
+
+/// Whether `text` matches the glob `pattern` (`*` is any sequence, `?` is any
+/// single character).
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // position of the last `*` & of the text it currently matches up to
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            // backtrack: let the last `*` match one more character
+            _ => match star {
+                Some((sp, st)) => {
+                    star = Some((sp, st + 1));
+                    p = sp + 1;
+                    t = st + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}